@@ -15,7 +15,7 @@ impl AndroidFile {
             return Err("无效的文件描述符".to_string());
         }
 
-        println!("[AndroidFD] 从 FD 创建文件对象: fd={}", fd);
+        log::info!("[AndroidFD] 从 FD 创建文件对象: fd={}", fd);
 
         // SAFETY: 我们假设 FD 是有效的，由 Android ContentResolver 提供
         let file = unsafe { std::fs::File::from_raw_fd(fd) };
@@ -31,7 +31,7 @@ impl AndroidFile {
     /// 从 content:// URI 获取文件描述符
     /// 这需要通过 JNI 调用 Android 的 ContentResolver
     pub fn from_content_uri(uri: &str) -> Result<Self, String> {
-        println!("[AndroidFD] 尝试从 content URI 获取 FD: {}", uri);
+        log::info!("[AndroidFD] 尝试从 content URI 获取 FD: {}", uri);
         
         // 使用 ndk-context 获取 Android 上下文
         use jni::objects::{JObject, JValue};
@@ -93,7 +93,7 @@ impl AndroidFile {
         ).map_err(|e| format!("无法分离文件描述符: {}", e))?
         .i().map_err(|e| format!("无法转换为整数: {}", e))?;
         
-        println!("[AndroidFD] 成功获取文件描述符: fd={}", fd);
+        log::info!("[AndroidFD] 成功获取文件描述符: fd={}", fd);
         
         Self::from_fd(fd)
     }
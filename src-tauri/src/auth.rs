@@ -0,0 +1,531 @@
+// 访问认证
+// 本机用户：可选的访问密码，浏览器登录后得到会话（Cookie，也可以作为 Bearer 令牌使用），保护本机的控制接口
+// 节点之间：可选的局域网共享密钥，发送方用 HMAC-SHA256 对发送者、时间戳、请求路径和请求体的摘要签名，保护 /ws、/api/upload 等对方调用的接口
+// 接收方还要核对请求体（和 WebSocket 消息）中的发送者与签名的发送者一致
+// 局域网密钥是所有节点共用的，知道它就能以任何节点 ID 签名：发送方同时用双方身份密钥派生的签名密钥（见 e2e::auth_key）
+// 再签一次，接收方记录过发送者的身份公钥后还要校验这个签名，其他节点不能冒充它
+// 本机用户连续输错访问密码后限制登录频率
+// 两者都没有设置时不做任何检查（与旧版本兼容）
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+
+pub const SESSION_COOKIE: &str = "lanchat_session";
+// 会话有效期（秒）
+const SESSION_TTL: i64 = 7 * 24 * 3600;
+// 节点签名允许的时间误差（秒），超过的请求视为重放
+const MAX_CLOCK_SKEW: i64 = 300;
+// 连续输错访问密码这么多次之后，每次失败等待的时间翻倍（最长 LOGIN_MAX_DELAY 秒）
+const LOGIN_FREE_ATTEMPTS: u32 = 5;
+const LOGIN_MAX_DELAY: i64 = 300;
+
+// 节点签名使用的请求头（浏览器的 WebSocket 不能设置请求头，也可以放在同名的查询参数里）
+pub const PEER_HEADER: &str = "x-lanchat-peer";
+pub const TIME_HEADER: &str = "x-lanchat-time";
+pub const SIGNATURE_HEADER: &str = "x-lanchat-signature";
+pub const CONTENT_HASH_HEADER: &str = "x-lanchat-content-sha256";
+// 发送方的身份公钥和用双方签名密钥计算的签名
+pub const IDENTITY_KEY_HEADER: &str = "x-lanchat-identity-key";
+pub const IDENTITY_SIGNATURE_HEADER: &str = "x-lanchat-identity-signature";
+
+/// 流式上传的分块不计算请求体摘要（只用于 POST /api/upload）：
+/// 分块数据由端到端加密保护，接收方核对分块中的 peer_id 与签名的发送者一致
+pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+pub const UNAUTHORIZED_ERROR: &str = "需要登录";
+
+type PeerKey = [u8; 32];
+
+// 同一进程只使用一个数据库，认证状态放在全局，网络模块签名时不需要额外传参
+static PASSWORD_ENABLED: AtomicBool = AtomicBool::new(false);
+static PEER_KEY: RwLock<Option<PeerKey>> = RwLock::new(None);
+// 会话令牌 -> 过期时间（只保存在内存中，重启后需要重新登录）
+static SESSIONS: Mutex<BTreeMap<String, i64>> = Mutex::new(BTreeMap::new());
+// 连续登录失败的次数和下次允许尝试的时间
+static LOGIN_FAILURES: Mutex<(u32, i64)> = Mutex::new((0, 0));
+
+/// 认证状态
+#[derive(Debug, serde::Serialize)]
+pub struct AuthStatus {
+    pub password_enabled: bool,
+    pub authenticated: bool,
+    pub peer_key_enabled: bool,
+}
+
+pub fn status(authenticated: bool) -> AuthStatus {
+    AuthStatus {
+        password_enabled: password_enabled(),
+        authenticated: authenticated || !password_enabled(),
+        peer_key_enabled: peer_key_enabled(),
+    }
+}
+
+pub fn password_enabled() -> bool {
+    PASSWORD_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn peer_key_enabled() -> bool {
+    PEER_KEY.read().unwrap().is_some()
+}
+
+/// 启动时读取认证设置
+pub async fn load_state(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        "SELECT key, value FROM settings WHERE key IN ('web_password_hash', 'peer_auth_key')",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut password = false;
+    let mut peer_key = None;
+    for (key, value) in rows {
+        match key.as_str() {
+            "web_password_hash" => password = true,
+            "peer_auth_key" => peer_key = decode_hex(&value).and_then(|k| k.try_into().ok()),
+            _ => {}
+        }
+    }
+
+    PASSWORD_ENABLED.store(password, Ordering::Relaxed);
+    *PEER_KEY.write().unwrap() = peer_key;
+    if password || peer_key.is_some() {
+        log::info!(
+            "[Auth] 访问密码: {}, 局域网密钥: {}",
+            if password { "已设置" } else { "未设置" },
+            if peer_key.is_some() {
+                "已设置"
+            } else {
+                "未设置"
+            }
+        );
+    }
+    Ok(())
+}
+
+/// 用访问密码登录，返回会话令牌；连续输错后需要等待一段时间才能再试
+pub async fn login(pool: &Pool<Sqlite>, password: &str) -> Result<String, String> {
+    if !password_enabled() {
+        return Err("没有设置访问密码".to_string());
+    }
+    let now = chrono::Utc::now().timestamp();
+    let retry_at = LOGIN_FAILURES.lock().unwrap().1;
+    if now < retry_at {
+        return Err(format!("密码错误次数过多，请 {} 秒后再试", retry_at - now));
+    }
+    if let Err(e) = verify_password(pool, password).await {
+        let mut failures = LOGIN_FAILURES.lock().unwrap();
+        failures.0 = failures.0.saturating_add(1);
+        failures.1 = now + login_delay(failures.0);
+        log::warn!("[Auth] 登录失败（连续 {} 次）", failures.0);
+        return Err(e);
+    }
+    *LOGIN_FAILURES.lock().unwrap() = (0, 0);
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = encode_hex(&bytes);
+
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|_, expires| *expires > now);
+    sessions.insert(token.clone(), now + SESSION_TTL);
+
+    log::info!("[Auth] ✓ 登录成功");
+    Ok(token)
+}
+
+// 第 failures 次连续失败之后需要等待的秒数
+fn login_delay(failures: u32) -> i64 {
+    match failures.checked_sub(LOGIN_FREE_ATTEMPTS) {
+        None => 0,
+        Some(extra) => (1i64 << extra.min(16)).min(LOGIN_MAX_DELAY),
+    }
+}
+
+pub fn logout(token: &str) {
+    SESSIONS.lock().unwrap().remove(token);
+}
+
+/// 会话令牌是否有效（没有设置访问密码时总是有效）
+pub fn session_valid(token: Option<&str>) -> bool {
+    if !password_enabled() {
+        return true;
+    }
+    let Some(token) = token else {
+        return false;
+    };
+    let now = chrono::Utc::now().timestamp();
+    SESSIONS
+        .lock()
+        .unwrap()
+        .get(token)
+        .is_some_and(|expires| *expires > now)
+}
+
+/// 设置、修改或取消（new_password 为空）访问密码；已有密码时需要提供当前密码
+/// 所有已登录的会话都会失效
+pub async fn set_password(
+    pool: &Pool<Sqlite>,
+    current_password: &str,
+    new_password: &str,
+) -> Result<(), String> {
+    if password_enabled() {
+        verify_password(pool, current_password).await?;
+    }
+    reset_password(pool, new_password).await
+}
+
+/// 不校验当前密码直接重设访问密码（命令行启动参数使用，忘记密码时可以用它重设）
+pub async fn reset_password(pool: &Pool<Sqlite>, new_password: &str) -> Result<(), String> {
+    if new_password.is_empty() {
+        sqlx::query("DELETE FROM settings WHERE key = 'web_password_hash'")
+            .execute(pool)
+            .await
+            .map_err(|e| format!("保存访问密码失败: {}", e))?;
+        PASSWORD_ENABLED.store(false, Ordering::Relaxed);
+        log::info!("[Auth] 已取消访问密码");
+    } else {
+        if new_password.chars().count() < 8 {
+            return Err("访问密码至少需要 8 个字符".to_string());
+        }
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(new_password.as_bytes(), &salt)
+            .map_err(|e| format!("生成密码摘要失败: {}", e))?
+            .to_string();
+        sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES ('web_password_hash', ?)")
+            .bind(hash)
+            .execute(pool)
+            .await
+            .map_err(|e| format!("保存访问密码失败: {}", e))?;
+        PASSWORD_ENABLED.store(true, Ordering::Relaxed);
+        log::info!("[Auth] ✓ 访问密码已更新");
+    }
+
+    SESSIONS.lock().unwrap().clear();
+    Ok(())
+}
+
+async fn verify_password(pool: &Pool<Sqlite>, password: &str) -> Result<(), String> {
+    let stored: Option<String> =
+        sqlx::query_scalar("SELECT value FROM settings WHERE key = 'web_password_hash'")
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| format!("读取访问密码失败: {}", e))?;
+    let Some(stored) = stored else {
+        return Err("没有设置访问密码".to_string());
+    };
+
+    let hash = PasswordHash::new(&stored).map_err(|_| "访问密码设置已损坏".to_string())?;
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .map_err(|_| "密码错误".to_string())
+}
+
+/// 设置或取消（key 为空）局域网共享密钥；所有节点需要设置相同的密钥才能互相通信
+pub async fn set_peer_key(pool: &Pool<Sqlite>, key: &str) -> Result<(), String> {
+    if key.is_empty() {
+        sqlx::query("DELETE FROM settings WHERE key = 'peer_auth_key'")
+            .execute(pool)
+            .await
+            .map_err(|e| format!("保存局域网密钥失败: {}", e))?;
+        *PEER_KEY.write().unwrap() = None;
+        log::info!("[Auth] 已取消局域网密钥");
+        return Ok(());
+    }
+
+    if key.chars().count() < 8 {
+        return Err("局域网密钥至少需要 8 个字符".to_string());
+    }
+    let derived: PeerKey = Sha256::digest(format!("lanchat-peer-key:{}", key)).into();
+    sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES ('peer_auth_key', ?)")
+        .bind(encode_hex(&derived))
+        .execute(pool)
+        .await
+        .map_err(|e| format!("保存局域网密钥失败: {}", e))?;
+    *PEER_KEY.write().unwrap() = Some(derived);
+    log::info!("[Auth] ✓ 局域网密钥已更新");
+    Ok(())
+}
+
+/// 请求体的摘要（签名的一部分），没有请求体时是空内容的摘要
+pub fn content_hash(body: &[u8]) -> String {
+    encode_hex(&Sha256::digest(body))
+}
+
+/// 发给其他节点的请求需要附带的签名（请求头名, 值）；没有设置局域网密钥时为空
+/// content_hash 是 content_hash(请求体) 或 UNSIGNED_PAYLOAD
+/// identity 是和对方之间的签名密钥（e2e::auth_key），还不知道对方的身份公钥时为 None
+pub fn peer_headers(
+    my_id: &str,
+    identity: Option<&[u8; 32]>,
+    method: &str,
+    path: &str,
+    content_hash: &str,
+) -> Vec<(&'static str, String)> {
+    let Some(key) = *PEER_KEY.read().unwrap() else {
+        return Vec::new();
+    };
+    let request = SignedRequest {
+        peer_id: my_id,
+        time: chrono::Utc::now().timestamp(),
+        method,
+        path,
+        content_hash,
+    };
+    let mut headers = vec![
+        (PEER_HEADER, my_id.to_string()),
+        (TIME_HEADER, request.time.to_string()),
+        (CONTENT_HASH_HEADER, content_hash.to_string()),
+        (SIGNATURE_HEADER, sign(&key, &request)),
+    ];
+    if let Some(identity) = identity {
+        headers.push((IDENTITY_KEY_HEADER, crate::e2e::public_key()));
+        headers.push((IDENTITY_SIGNATURE_HEADER, sign(identity, &request)));
+    }
+    headers
+}
+
+/// 校验其他节点的请求签名；没有设置局域网密钥时不检查
+pub fn verify_peer(
+    peer_id: Option<&str>,
+    time: Option<&str>,
+    signature: Option<&str>,
+    method: &str,
+    path: &str,
+    content_hash: &str,
+) -> Result<(), String> {
+    let Some(key) = *PEER_KEY.read().unwrap() else {
+        return Ok(());
+    };
+    let (Some(peer_id), Some(time), Some(signature)) = (peer_id, time, signature) else {
+        return Err("缺少节点签名".to_string());
+    };
+    let request = SignedRequest {
+        peer_id,
+        time: time.parse().map_err(|_| "节点签名时间无效".to_string())?,
+        method,
+        path,
+        content_hash,
+    };
+    verify_with(&key, &request, signature, chrono::Utc::now().timestamp())
+}
+
+/// 校验身份签名（verify_peer 通过之后调用）；auth_key 是和发送者之间的签名密钥（e2e::sender_auth_key），
+/// 为 None 时（没有记录过发送者的身份公钥，不支持端到端加密的旧版本）不检查
+pub fn verify_identity(
+    auth_key: Option<&[u8; 32]>,
+    peer_id: &str,
+    time: &str,
+    signature: Option<&str>,
+    method: &str,
+    path: &str,
+    content_hash: &str,
+) -> Result<(), String> {
+    let Some(auth_key) = auth_key else {
+        return Ok(());
+    };
+    let signature = signature.ok_or_else(|| format!("缺少节点 {} 的身份签名", peer_id))?;
+    let request = SignedRequest {
+        peer_id,
+        time: time.parse().map_err(|_| "节点签名时间无效".to_string())?,
+        method,
+        path,
+        content_hash,
+    };
+    verify_with(
+        auth_key,
+        &request,
+        signature,
+        chrono::Utc::now().timestamp(),
+    )
+    .map_err(|_| format!("节点 {} 的身份签名无效", peer_id))
+}
+
+// 签名覆盖的内容
+struct SignedRequest<'a> {
+    peer_id: &'a str,
+    time: i64,
+    method: &'a str,
+    path: &'a str,
+    content_hash: &'a str,
+}
+
+fn verify_with(
+    key: &PeerKey,
+    request: &SignedRequest,
+    signature: &str,
+    now: i64,
+) -> Result<(), String> {
+    if (now - request.time).abs() > MAX_CLOCK_SKEW {
+        return Err("节点签名已过期".to_string());
+    }
+    let signature = decode_hex(signature).ok_or("节点签名无效")?;
+    mac(key, request)
+        .verify_slice(&signature)
+        .map_err(|_| "节点签名无效".to_string())
+}
+
+fn sign(key: &PeerKey, request: &SignedRequest) -> String {
+    encode_hex(&mac(key, request).finalize().into_bytes())
+}
+
+fn mac(key: &PeerKey, request: &SignedRequest) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC 接受任意长度的密钥");
+    mac.update(
+        format!(
+            "{}\n{}\n{}\n{}\n{}",
+            request.peer_id,
+            request.time,
+            request.method.to_uppercase(),
+            request.path,
+            request.content_hash
+        )
+        .as_bytes(),
+    );
+    mac
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: PeerKey = [7u8; 32];
+
+    fn request<'a>(
+        peer_id: &'a str,
+        method: &'a str,
+        path: &'a str,
+        body: &'a str,
+    ) -> SignedRequest<'a> {
+        SignedRequest {
+            peer_id,
+            time: 1000,
+            method,
+            path,
+            content_hash: body,
+        }
+    }
+
+    #[test]
+    fn signature_round_trip() {
+        let signature = sign(&KEY, &request("peer-a", "POST", "/api/offer", "abc"));
+        assert!(verify_with(
+            &KEY,
+            &request("peer-a", "post", "/api/offer", "abc"),
+            &signature,
+            1010
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn signature_is_bound_to_request() {
+        let signature = sign(&KEY, &request("peer-a", "POST", "/api/offer", "abc"));
+        assert!(verify_with(
+            &KEY,
+            &request("peer-b", "POST", "/api/offer", "abc"),
+            &signature,
+            1000
+        )
+        .is_err());
+        assert!(verify_with(
+            &KEY,
+            &request("peer-a", "POST", "/api/upload", "abc"),
+            &signature,
+            1000
+        )
+        .is_err());
+        assert!(verify_with(
+            &[8u8; 32],
+            &request("peer-a", "POST", "/api/offer", "abc"),
+            &signature,
+            1000
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn signature_is_bound_to_body() {
+        let body = content_hash(br#"{"sender_id":"peer-a"}"#);
+        let signature = sign(&KEY, &request("peer-a", "POST", "/api/offer", &body));
+
+        let replayed = content_hash(br#"{"sender_id":"peer-b"}"#);
+        assert!(verify_with(
+            &KEY,
+            &request("peer-a", "POST", "/api/offer", &replayed),
+            &signature,
+            1000
+        )
+        .is_err());
+        assert!(verify_with(
+            &KEY,
+            &request("peer-a", "POST", "/api/offer", UNSIGNED_PAYLOAD),
+            &signature,
+            1000
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn stale_signature_is_rejected() {
+        let signature = sign(&KEY, &request("peer-a", "GET", "/ws", "abc"));
+        assert!(verify_with(
+            &KEY,
+            &request("peer-a", "GET", "/ws", "abc"),
+            &signature,
+            1000 + MAX_CLOCK_SKEW + 1
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn identity_signature_needs_the_pair_key() {
+        let pair_key = [9u8; 32];
+        let signed = request("peer-a", "POST", "/api/offer", "abc");
+        let signature = sign(&pair_key, &signed);
+        assert!(verify_with(&pair_key, &signed, &signature, 1000).is_ok());
+        // 只知道局域网密钥的节点算不出这个签名
+        assert!(verify_with(&pair_key, &signed, &sign(&KEY, &signed), 1000).is_err());
+    }
+
+    #[test]
+    fn login_delay_doubles_after_free_attempts() {
+        assert_eq!(login_delay(1), 0);
+        assert_eq!(login_delay(LOGIN_FREE_ATTEMPTS - 1), 0);
+        assert_eq!(login_delay(LOGIN_FREE_ATTEMPTS), 1);
+        assert_eq!(login_delay(LOGIN_FREE_ATTEMPTS + 3), 8);
+        assert_eq!(login_delay(LOGIN_FREE_ATTEMPTS + 20), LOGIN_MAX_DELAY);
+        assert_eq!(login_delay(u32::MAX), LOGIN_MAX_DELAY);
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(
+            decode_hex(&encode_hex(&[0, 15, 255])),
+            Some(vec![0, 15, 255])
+        );
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+}
@@ -0,0 +1,407 @@
+// 数据库备份与恢复
+// 备份使用 VACUUM INTO，可以在数据库正常使用时生成一致的快照
+// 恢复不会替换正在使用的数据库，而是先校验并暂存，下次启动初始化数据库时再换入
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use sqlx::{Pool, Sqlite};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+// 暂存待恢复数据库的文件名后缀（与 lanchat.db 放在同一目录）
+const RESTORE_SUFFIX: &str = "restore";
+// 备份文件名前缀，定时备份清理旧文件时只处理带这个前缀的文件
+const BACKUP_PREFIX: &str = "lanchat-backup-";
+// 定时备份任务检查是否需要备份的间隔
+const BACKUP_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 备份数据库
+/// dest 是目录时自动生成带时间的文件名；是文件路径时直接写入（文件不能已存在）
+pub async fn backup_database(pool: &Pool<Sqlite>, dest: &Path) -> Result<PathBuf, String> {
+    let target = if dest.is_dir() {
+        dest.join(format!(
+            "{}{}.db",
+            BACKUP_PREFIX,
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ))
+    } else {
+        dest.to_path_buf()
+    };
+
+    if target.exists() {
+        return Err(format!("备份文件已存在: {:?}", target));
+    }
+    if let Some(parent) = target.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("无法创建备份目录: {}", e))?;
+        }
+    }
+
+    log::info!("[Backup] 正在备份数据库到: {:?}", target);
+
+    sqlx::query("VACUUM INTO ?")
+        .bind(target.to_string_lossy().to_string())
+        .execute(pool)
+        .await
+        .map_err(|e| format!("备份失败: {}", e))?;
+
+    log::info!("[Backup] ✓ 备份完成");
+    Ok(target)
+}
+
+/// 校验备份文件：能以只读方式打开、完整性检查通过、是 LANChat 的数据库且版本不高于当前程序
+/// 返回备份的数据库版本
+pub async fn validate_backup(path: &Path) -> Result<i64, String> {
+    if !path.is_file() {
+        return Err(format!("备份文件不存在: {:?}", path));
+    }
+
+    let options = SqliteConnectOptions::from_str(&format!("sqlite:{}", path.to_string_lossy()))
+        .map_err(|e| format!("无效的备份路径: {}", e))?
+        .read_only(true);
+    let pool = SqlitePool::connect_with(options)
+        .await
+        .map_err(|e| format!("无法打开备份文件: {}", e))?;
+
+    let result = check_backup(&pool).await;
+    pool.close().await;
+    result
+}
+
+async fn check_backup(pool: &Pool<Sqlite>) -> Result<i64, String> {
+    let integrity: String = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| format!("备份文件不是有效的 SQLite 数据库: {}", e))?;
+    if integrity != "ok" {
+        return Err(format!("备份文件已损坏: {}", integrity));
+    }
+
+    let has_user: Option<String> =
+        sqlx::query_scalar("SELECT value FROM settings WHERE key = 'user_id'")
+            .fetch_optional(pool)
+            .await
+            .map_err(|_| "备份文件不是 LANChat 的数据库".to_string())?;
+    if has_user.is_none() {
+        return Err("备份文件中没有用户信息".to_string());
+    }
+
+    // 旧版本数据库没有版本表，视为 0，启动时会自动迁移
+    let version: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+        .fetch_one(pool)
+        .await
+        .unwrap_or(None);
+    let version = version.unwrap_or(0);
+    if version > crate::migrations::latest_version() {
+        return Err(format!(
+            "备份文件的数据库版本 ({}) 高于当前程序支持的版本 ({})",
+            version,
+            crate::migrations::latest_version()
+        ));
+    }
+
+    Ok(version)
+}
+
+/// 校验备份并暂存，下次初始化数据库时换入
+pub async fn stage_restore(db_path: &Path, backup: &Path) -> Result<(), String> {
+    let version = validate_backup(backup).await?;
+    log::info!("[Backup] 备份文件校验通过，数据库版本: {}", version);
+
+    let staged = restore_path(db_path);
+    std::fs::copy(backup, &staged).map_err(|e| format!("暂存备份文件失败: {}", e))?;
+
+    log::info!("[Backup] ✓ 已暂存恢复文件，重新启动后生效: {:?}", staged);
+    Ok(())
+}
+
+/// 如果有暂存的恢复文件，换入为当前数据库（必须在打开数据库之前调用）
+/// 原数据库保留为 lanchat.db.before-restore-<时间>
+pub fn apply_pending_restore(db_path: &Path) -> Result<bool, String> {
+    let staged = restore_path(db_path);
+    if !staged.exists() {
+        return Ok(false);
+    }
+
+    log::info!("[Backup] 发现待恢复的数据库，正在换入...");
+
+    if db_path.exists() {
+        let saved = with_suffix(
+            db_path,
+            &format!(
+                "before-restore-{}",
+                chrono::Local::now().format("%Y%m%d-%H%M%S")
+            ),
+        );
+        std::fs::rename(db_path, &saved).map_err(|e| format!("保存原数据库失败: {}", e))?;
+        log::info!("[Backup] 原数据库已保存为: {:?}", saved);
+    }
+
+    // 旧数据库残留的日志文件不能应用到恢复的数据库上
+    for suffix in ["wal", "shm", "journal"] {
+        let _ = std::fs::remove_file(with_suffix(db_path, suffix));
+    }
+
+    std::fs::rename(&staged, db_path).map_err(|e| format!("换入恢复文件失败: {}", e))?;
+    log::info!("[Backup] ✓ 数据库恢复完成");
+    Ok(true)
+}
+
+/// 查询当前连接的数据库文件路径
+pub async fn database_file(pool: &Pool<Sqlite>) -> Result<PathBuf, String> {
+    let file: String =
+        sqlx::query_scalar("SELECT file FROM pragma_database_list WHERE name = 'main'")
+            .fetch_one(pool)
+            .await
+            .map_err(|e| format!("查询数据库路径失败: {}", e))?;
+    Ok(PathBuf::from(file))
+}
+
+/// 读取定时备份设置
+pub async fn get_schedule(pool: &Pool<Sqlite>) -> Result<crate::models::BackupSchedule, String> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        "SELECT key, value FROM settings WHERE key IN ('backup_dir', 'backup_interval_hours', 'backup_keep')",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("读取备份设置失败: {}", e))?;
+
+    let mut schedule = crate::models::BackupSchedule::default();
+    for (key, value) in rows {
+        match key.as_str() {
+            "backup_dir" => schedule.dir = Some(value).filter(|v| !v.is_empty()),
+            "backup_interval_hours" => schedule.interval_hours = value.parse().unwrap_or(0),
+            "backup_keep" => schedule.keep = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    Ok(schedule)
+}
+
+/// 保存定时备份设置（interval_hours 为 0 表示关闭定时备份）
+pub async fn update_schedule(
+    pool: &Pool<Sqlite>,
+    schedule: &crate::models::BackupSchedule,
+) -> Result<(), String> {
+    if schedule.interval_hours < 0 || schedule.keep < 0 {
+        return Err("备份间隔和保留数量不能为负数".to_string());
+    }
+    let has_dir = schedule
+        .dir
+        .as_deref()
+        .map(|d| !d.trim().is_empty())
+        .unwrap_or(false);
+    if schedule.interval_hours > 0 && !has_dir {
+        return Err("开启定时备份需要设置备份目录".to_string());
+    }
+
+    log::info!(
+        "[Backup] 更新定时备份: 目录={:?}, 间隔={} 小时, 保留={}",
+        schedule.dir, schedule.interval_hours, schedule.keep
+    );
+
+    let values = [
+        ("backup_dir", schedule.dir.clone().unwrap_or_default()),
+        ("backup_interval_hours", schedule.interval_hours.to_string()),
+        ("backup_keep", schedule.keep.to_string()),
+    ];
+    for (key, value) in values {
+        sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)")
+            .bind(key)
+            .bind(value)
+            .execute(pool)
+            .await
+            .map_err(|e| format!("保存备份设置失败: {}", e))?;
+    }
+    Ok(())
+}
+
+/// 启动定时备份任务（每分钟检查一次是否到了备份时间，设置修改后无需重启）
+pub async fn start_backup_job(pool: Pool<Sqlite>) {
+    loop {
+        if let Err(e) = run_scheduled_backup(&pool).await {
+            log::error!("[Backup] 定时备份失败: {}", e);
+        }
+        tokio::time::sleep(BACKUP_CHECK_INTERVAL).await;
+    }
+}
+
+async fn run_scheduled_backup(pool: &Pool<Sqlite>) -> Result<(), String> {
+    let schedule = get_schedule(pool).await?;
+    let Some(dir) = schedule.dir.filter(|_| schedule.interval_hours > 0) else {
+        return Ok(());
+    };
+
+    let last: Option<String> =
+        sqlx::query_scalar("SELECT value FROM settings WHERE key = 'backup_last_at'")
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| format!("读取上次备份时间失败: {}", e))?;
+    let last = last.and_then(|v| v.parse::<i64>().ok()).unwrap_or(0);
+    let now = chrono::Utc::now().timestamp();
+    if now - last < schedule.interval_hours * 3600 {
+        return Ok(());
+    }
+
+    let dir = PathBuf::from(dir);
+    std::fs::create_dir_all(&dir).map_err(|e| format!("无法创建备份目录: {}", e))?;
+    backup_database(pool, &dir).await?;
+
+    sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES ('backup_last_at', ?)")
+        .bind(now.to_string())
+        .execute(pool)
+        .await
+        .map_err(|e| format!("保存备份时间失败: {}", e))?;
+
+    if schedule.keep > 0 {
+        prune_old_backups(&dir, schedule.keep as usize);
+    }
+    Ok(())
+}
+
+// 只保留最新的 keep 个定时备份（文件名带时间，按名称排序即按时间排序）
+fn prune_old_backups(dir: &Path, keep: usize) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut backups: Vec<PathBuf> = entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.starts_with(BACKUP_PREFIX) && n.ends_with(".db"))
+                .unwrap_or(false)
+        })
+        .collect();
+    backups.sort();
+
+    let excess = backups.len().saturating_sub(keep);
+    for old in backups.into_iter().take(excess) {
+        match std::fs::remove_file(&old) {
+            Ok(_) => log::info!("[Backup] 删除旧备份: {:?}", old),
+            Err(e) => log::error!("[Backup] 删除旧备份失败 {:?}: {}", old, e),
+        }
+    }
+}
+
+fn restore_path(db_path: &Path) -> PathBuf {
+    with_suffix(db_path, RESTORE_SUFFIX)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lanchat-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn backup_is_validated_staged_and_swapped_in() {
+        let dir = temp_dir();
+        // 和实际使用时一样用文件数据库（内存数据库上 VACUUM INTO 不会生成文件）
+        let options =
+            SqliteConnectOptions::from_str(&format!("sqlite:{}", dir.join("source.db").display()))
+                .unwrap()
+                .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        crate::migrations::run(&pool).await.unwrap();
+        sqlx::query("INSERT INTO settings (key, value) VALUES ('user_id', 'my-id')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let backup = backup_database(&pool, &dir).await.unwrap();
+        assert!(backup.starts_with(&dir));
+        assert_eq!(
+            validate_backup(&backup).await.unwrap(),
+            crate::migrations::latest_version()
+        );
+        assert!(backup_database(&pool, &backup).await.is_err());
+
+        let db_path = dir.join("lanchat.db");
+        std::fs::write(&db_path, b"current").unwrap();
+        stage_restore(&db_path, &backup).await.unwrap();
+        assert!(apply_pending_restore(&db_path).unwrap());
+        assert!(!apply_pending_restore(&db_path).unwrap());
+
+        assert!(validate_backup(&db_path).await.is_ok());
+        let saved = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .find(|e| e.file_name().to_string_lossy().contains("before-restore-"))
+            .unwrap();
+        assert_eq!(std::fs::read(saved.path()).unwrap(), b"current");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_refuses_files_that_are_not_lanchat_backups() {
+        let dir = temp_dir();
+        let db_path = dir.join("lanchat.db");
+
+        let text = dir.join("notes.txt");
+        std::fs::write(&text, b"not a database").unwrap();
+        assert!(stage_restore(&db_path, &text).await.is_err());
+        assert!(stage_restore(&db_path, &dir.join("missing.db"))
+            .await
+            .is_err());
+
+        // 没有用户信息的 SQLite 数据库
+        let empty = dir.join("empty.db");
+        let options = SqliteConnectOptions::from_str(&format!("sqlite:{}", empty.display()))
+            .unwrap()
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::query("CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        assert!(stage_restore(&db_path, &empty).await.is_err());
+
+        assert!(!restore_path(&db_path).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_backups() {
+        let dir = temp_dir();
+        for name in [
+            "lanchat-backup-20260101-000000.db",
+            "lanchat-backup-20260102-000000.db",
+            "lanchat-backup-20260103-000000.db",
+            "other.db",
+        ] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+
+        prune_old_backups(&dir, 2);
+
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "lanchat-backup-20260102-000000.db",
+                "lanchat-backup-20260103-000000.db",
+                "other.db"
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
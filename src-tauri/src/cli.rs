@@ -0,0 +1,619 @@
+// 命令行客户端：peers / send / send-file / history / tail
+// 和节点共用数据库（同一个身份、聊天记录和证书），节点正在运行时也可以使用
+// 在线节点通过监听局域网心跳发现；联系人可以用节点 ID（或唯一的前缀）、用户名或配置文件 [aliases] 中的别名指定
+// 命令的结果输出到标准输出，日志输出到标准错误，便于在脚本中使用，例如:
+//   make 2>&1 | lanchat-web send alice
+use crate::config::Config;
+use crate::peers::{Peer, PeerManager};
+use clap::Subcommand;
+use sqlx::{Pool, Sqlite};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+// 心跳间隔默认 5 秒，多等一点才能看到所有节点
+const DEFAULT_WAIT_SECS: u64 = 6;
+// tail 查询新消息的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// 加密的数据库从这个环境变量读取口令
+const PASSPHRASE_ENV: &str = "LANCHAT_PASSPHRASE";
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// 列出在线的节点（节点 ID、用户名、地址、别名，以制表符分隔）
+    Peers {
+        /// 等待心跳的时间（秒）
+        #[arg(long, default_value_t = DEFAULT_WAIT_SECS)]
+        wait: u64,
+    },
+    /// 发送消息；不写内容或内容为 - 时从标准输入读取
+    Send {
+        /// 节点 ID、用户名或别名
+        peer: String,
+        text: Option<String>,
+        /// 等待对方心跳的最长时间（秒）
+        #[arg(long, default_value_t = DEFAULT_WAIT_SECS)]
+        wait: u64,
+    },
+    /// 发送文件（对方接受后开始上传）
+    SendFile {
+        /// 节点 ID、用户名或别名
+        peer: String,
+        path: PathBuf,
+        /// 等待对方心跳的最长时间（秒）
+        #[arg(long, default_value_t = DEFAULT_WAIT_SECS)]
+        wait: u64,
+    },
+    /// 显示和某个节点的聊天记录
+    History {
+        /// 节点 ID、用户名或别名（用户名需要对方在线）
+        peer: String,
+        /// 显示最近多少条
+        #[arg(short = 'n', long, default_value_t = 50)]
+        limit: i32,
+    },
+    /// 持续输出收到的消息；没有节点在运行时同时启动节点接收消息
+    Tail {
+        /// 只显示这个节点的消息
+        #[arg(long)]
+        peer: Option<String>,
+    },
+}
+
+/// 桌面端的参数：lanchat <子命令>
+#[derive(clap::Parser, Debug)]
+#[command(name = "lanchat")]
+struct DesktopArgs {
+    /// 配置文件（TOML），默认读取 ~/.config/lanchat/lanchat.toml
+    #[arg(short, long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// 命令的结果输出到标准输出；库里的日志经过 log 输出到标准错误，不会混进结果
+pub struct Output(Box<dyn Write + Send>);
+
+impl Output {
+    pub fn stdout() -> Self {
+        Output(Box::new(std::io::stdout()))
+    }
+
+    fn line(&mut self, text: &str) {
+        let _ = writeln!(self.0, "{}", text);
+        let _ = self.0.flush();
+    }
+}
+
+/// 桌面端：带子命令启动时作为命令行客户端运行，返回退出码；没有子命令时返回 None（启动界面）
+pub fn run_from_args() -> Option<i32> {
+    use clap::{CommandFactory, Parser};
+    let first = std::env::args().nth(1)?;
+    DesktopArgs::command().find_subcommand(&first)?;
+
+    let args = DesktopArgs::parse();
+    let output = Output::stdout();
+    let config = match &args.config {
+        Some(path) => crate::config::load(path),
+        None => crate::config::load_default(),
+    }
+    .and_then(|config| config.validate().map(|_| config));
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("错误: {}", e);
+            return Some(1);
+        }
+    };
+    crate::config::init_logging(config.log_level());
+
+    // 桌面端的数据目录（与 Tauri 的 app_data_dir 相同）
+    let data_dir = dirs::data_dir().map(|dir| dir.join("com.lanchat.app"));
+    let runtime = tokio::runtime::Runtime::new().expect("无法创建运行时");
+    Some(runtime.block_on(run(args.command, config, data_dir, output)))
+}
+
+/// 执行一个子命令，返回退出码；data_dir 为 None 时使用 config.db_path 或默认数据目录
+pub async fn run(
+    command: Command,
+    config: Config,
+    data_dir: Option<PathBuf>,
+    output: Output,
+) -> i32 {
+    let data_dir = config.db_path.clone().or(data_dir);
+    let result = match Client::open(config, data_dir, output).await {
+        Ok(mut client) => client.execute(command).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("错误: {}", e);
+            1
+        }
+    }
+}
+
+struct Client {
+    pool: Pool<Sqlite>,
+    config: Config,
+    my_id: String,
+    my_name: String,
+    peers: Arc<PeerManager>,
+    output: Output,
+    discovering: bool,
+}
+
+impl Client {
+    async fn open(
+        config: Config,
+        data_dir: Option<PathBuf>,
+        output: Output,
+    ) -> Result<Self, String> {
+        let pool = crate::db::init_db_standalone(data_dir)
+            .await
+            .map_err(|e| format!("无法打开数据库: {}", e))?;
+        let my_id = crate::db::get_user_id(&pool).await?;
+        let my_name = crate::db::get_username(&pool).await?;
+        Ok(Client {
+            pool,
+            config,
+            my_id,
+            my_name,
+            peers: Arc::new(PeerManager::new()),
+            output,
+            discovering: false,
+        })
+    }
+
+    async fn execute(&mut self, command: Command) -> Result<(), String> {
+        match command {
+            Command::Peers { wait } => self.list_peers(wait).await,
+            Command::Send { peer, text, wait } => self.send(&peer, text, wait).await,
+            Command::SendFile { peer, path, wait } => self.send_file(&peer, &path, wait).await,
+            Command::History { peer, limit } => self.history(&peer, limit).await,
+            Command::Tail { peer } => self.tail(peer.as_deref()).await,
+        }
+    }
+
+    async fn list_peers(&mut self, wait: u64) -> Result<(), String> {
+        self.start_discovery();
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+
+        let mut peers = self.peers.get_active_peers();
+        peers.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+        for peer in peers {
+            let alias = self.alias_of(&peer).unwrap_or_default();
+            let line = format!("{}\t{}\t{}\t{}", peer.id, peer.name, peer.addr, alias);
+            self.output.line(line.trim_end());
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, query: &str, text: Option<String>, wait: u64) -> Result<(), String> {
+        self.unlock().await?;
+        let content = match text.filter(|text| text != "-") {
+            Some(text) => text,
+            None => read_stdin().await?,
+        };
+        if content.trim().is_empty() {
+            return Err("消息内容为空".to_string());
+        }
+
+        let peer = self.find_online(query, wait).await?;
+        let msg_uuid = uuid::Uuid::new_v4().to_string();
+        crate::network::messaging::send_text_message(
+            &peer.addr,
+            &peer.id,
+            self.my_id.clone(),
+            self.my_name.clone(),
+            msg_uuid.clone(),
+            content.clone(),
+        )
+        .await?;
+        crate::db::save_text_message(&self.pool, peer.id.clone(), msg_uuid, content).await?;
+        eprintln!("[CLI] ✓ 已发送给 {} ({})", peer.name, peer.id);
+        Ok(())
+    }
+
+    async fn send_file(
+        &mut self,
+        query: &str,
+        path: &std::path::Path,
+        wait: u64,
+    ) -> Result<(), String> {
+        self.unlock().await?;
+        let path = std::fs::canonicalize(path)
+            .map_err(|e| format!("无法读取文件 {}: {}", path.display(), e))?;
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or("无效的文件路径")?;
+        let file = tokio::fs::File::open(&path)
+            .await
+            .map_err(|e| format!("打开文件失败: {}", e))?;
+
+        let peer = self.find_online(query, wait).await?;
+        eprintln!(
+            "[CLI] 正在发送 {} 给 {}（对方确认后开始上传）",
+            file_name, peer.name
+        );
+        let transfer_id = crate::network::transfer::send_file(
+            &self.pool,
+            &peer.id,
+            &peer.addr,
+            &file_name,
+            &path.to_string_lossy(),
+            file,
+            |progress| {
+                eprintln!(
+                    "[CLI] 已上传 {}/{} MB ({:.2} MB/s)",
+                    progress.uploaded / (1024 * 1024),
+                    progress.total / (1024 * 1024),
+                    progress.speed_mb_s
+                );
+            },
+        )
+        .await?;
+        eprintln!("[CLI] ✓ 文件已发送: {}", file_name);
+        self.output.line(&transfer_id);
+        Ok(())
+    }
+
+    async fn history(&mut self, query: &str, limit: i32) -> Result<(), String> {
+        self.unlock().await?;
+        let peer_id = self.find_peer_id(query).await?;
+        let messages =
+            crate::network::messaging::get_chat_history_with_offset(&self.pool, &peer_id, limit, 0)
+                .await?;
+        for message in messages {
+            let Ok(message) = serde_json::from_value::<crate::models::MessageResponse>(message)
+            else {
+                continue;
+            };
+            let line = self.format_message(&message);
+            self.output.line(&line);
+        }
+        Ok(())
+    }
+
+    async fn tail(&mut self, query: Option<&str>) -> Result<(), String> {
+        self.unlock().await?;
+        let filter = match query {
+            Some(query) => Some(self.find_peer_id(query).await?),
+            None => None,
+        };
+        // 显示发送者的用户名
+        self.start_discovery();
+
+        // 本机没有节点在运行时，自己接收其他节点的消息
+        let node = start_node_if_idle(&self.pool, &self.config, &self.my_id, &self.peers).await;
+
+        let stopped = node.clone();
+        let node_stopped = async move {
+            match stopped {
+                Some(shutdown) => shutdown.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(node_stopped);
+
+        let mut last_id = crate::db::get_last_message_id(&self.pool).await?;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = tokio::signal::ctrl_c() => break,
+                _ = &mut node_stopped => {
+                    self.pool.close().await;
+                    return Err("节点无法启动，收不到新消息".to_string());
+                }
+            }
+            for message in crate::db::get_received_messages_after(&self.pool, last_id).await? {
+                last_id = message.id;
+                if filter.as_ref().is_some_and(|id| *id != message.sender_id) {
+                    continue;
+                }
+                let line = self.format_message(&crate::models::MessageResponse::from(message));
+                self.output.line(&line);
+            }
+        }
+
+        if let Some(shutdown) = node {
+            stop_node(&self.config, &self.my_id, shutdown);
+        }
+        self.pool.close().await;
+        Ok(())
+    }
+
+    // 监听局域网心跳，发现的节点记录在 self.peers 中
+    fn start_discovery(&mut self) {
+        if self.discovering {
+            return;
+        }
+        self.discovering = true;
+        listen_for_peers(
+            &self.pool,
+            &self.config,
+            &self.my_id,
+            &self.my_name,
+            &self.peers,
+        );
+    }
+
+    // 按节点 ID、ID 前缀、用户名或别名找到在线的节点，最多等待 wait 秒
+    async fn find_online(&mut self, query: &str, wait: u64) -> Result<Peer, String> {
+        self.start_discovery();
+        let target = self.resolve_alias(query);
+        let deadline = Instant::now() + Duration::from_secs(wait);
+        loop {
+            let peers = self.peers.get_active_peers();
+            if let Some(peer) = pick_peer(&peers, &target)? {
+                return Ok(peer);
+            }
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "没有找到在线的节点 {}（等待了 {} 秒）",
+                    query, wait
+                ));
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    // 聊天记录不需要对方在线：节点 ID（或有过会话的 ID 前缀）直接使用，用户名需要对方在线
+    async fn find_peer_id(&mut self, query: &str) -> Result<String, String> {
+        let target = self.resolve_alias(query);
+        let known: Vec<String> = crate::db::get_conversations(&self.pool)
+            .await?
+            .into_iter()
+            .map(|conversation| conversation.peer_id)
+            .collect();
+        if known.contains(&target) {
+            return Ok(target);
+        }
+        let matches: Vec<&String> = known.iter().filter(|id| id.starts_with(&target)).collect();
+        match matches.as_slice() {
+            [id] => return Ok((*id).clone()),
+            [] => {}
+            _ => return Err(format!("{} 匹配了多个节点，请写出更长的 ID", query)),
+        }
+        self.find_online(query, DEFAULT_WAIT_SECS)
+            .await
+            .map(|peer| peer.id)
+    }
+
+    // 别名换成它指向的节点 ID 或用户名
+    fn resolve_alias(&self, query: &str) -> String {
+        self.config
+            .aliases
+            .as_ref()
+            .and_then(|aliases| aliases.get(query))
+            .cloned()
+            .unwrap_or_else(|| query.to_string())
+    }
+
+    fn alias_of(&self, peer: &Peer) -> Option<String> {
+        self.config.aliases.as_ref().and_then(|aliases| {
+            aliases
+                .iter()
+                .find(|(_, target)| **target == peer.id || **target == peer.name)
+                .map(|(alias, _)| alias.clone())
+        })
+    }
+
+    // 发送者显示为用户名（在线时）、别名或 ID 的前 8 位
+    fn sender_name(&self, sender_id: &str) -> String {
+        if sender_id == "me" || sender_id == self.my_id {
+            return self.my_name.clone();
+        }
+        if let Some(peer) = self.peers.get_peer(sender_id) {
+            return peer.name;
+        }
+        self.config
+            .aliases
+            .iter()
+            .flatten()
+            .find(|(_, target)| *target == sender_id)
+            .map(|(alias, _)| alias.clone())
+            .unwrap_or_else(|| sender_id.chars().take(8).collect())
+    }
+
+    fn format_message(&self, message: &crate::models::MessageResponse) -> String {
+        format!(
+            "{}  {}: {}",
+            format_time(message.timestamp),
+            self.sender_name(&message.from_id),
+            message_text(message)
+        )
+    }
+
+    async fn unlock(&self) -> Result<(), String> {
+        unlock_from_env(&self.pool).await
+    }
+}
+
+/// 本机没有节点在运行时（节点端口空闲）启动节点接口和心跳，返回用来停止节点的 token；
+/// 节点已经在运行时返回 None，新消息由那个节点写入数据库。节点接口出错时 token 被取消
+pub async fn start_node_if_idle(
+    pool: &Pool<Sqlite>,
+    config: &Config,
+    my_id: &str,
+    peers: &Arc<PeerManager>,
+) -> Option<CancellationToken> {
+    match std::net::TcpListener::bind(config.peer_addr()) {
+        Ok(listener) => drop(listener),
+        Err(_) => {
+            log::info!("[CLI] 节点正在运行，从数据库读取新消息");
+            return None;
+        }
+    }
+    log::info!(
+        "[CLI] 没有正在运行的节点，开始在 {} 接收消息",
+        config.peer_addr()
+    );
+    // 上次运行时没有完成的上传标记为中断，可以从断点续传
+    if let Err(e) = crate::db::mark_interrupted_uploads(pool).await {
+        log::error!("[DB] {}", e);
+    }
+
+    let shutdown = CancellationToken::new();
+    let bus = Arc::new(crate::events::BroadcastSink::new());
+    let events: crate::events::Events = bus.clone();
+    let server = crate::web_server::start_server(
+        config.peer_addr(),
+        config.control_addr(pool).await,
+        pool.clone(),
+        peers.clone(),
+        bus,
+        events,
+        shutdown.clone(),
+    );
+    // 节点接口出错时停止心跳，调用方通过 token 得知节点已经停止
+    let failed = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("[CLI] ✗ 节点无法启动: {}", e);
+            failed.cancel();
+        }
+    });
+    let announce = crate::network::discovery::start_announcing(
+        config.port(),
+        my_id.to_string(),
+        pool.clone(),
+        config.discovery_options(),
+    );
+    let stop = shutdown.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = announce => {}
+            _ = stop.cancelled() => {}
+        }
+    });
+    Some(shutdown)
+}
+
+/// 停止 start_node_if_idle 启动的节点，通知其他节点本机下线
+pub fn stop_node(config: &Config, my_id: &str, shutdown: CancellationToken) {
+    shutdown.cancel();
+    crate::network::discovery::announce_offline(config.port(), my_id, &config.discovery_options());
+}
+
+/// 监听局域网心跳，发现的节点记录在 peers 中（节点正在运行时与它共用端口）
+pub fn listen_for_peers(
+    pool: &Pool<Sqlite>,
+    config: &Config,
+    my_id: &str,
+    my_name: &str,
+    peers: &Arc<PeerManager>,
+) {
+    tokio::spawn(crate::network::discovery::start_listening(
+        config.port(),
+        my_id.to_string(),
+        my_name.to_string(),
+        pool.clone(),
+        Arc::new(crate::events::BroadcastSink::new()),
+        peers.clone(),
+    ));
+}
+
+/// 加密的数据库需要口令才能读写消息，口令从环境变量 LANCHAT_PASSPHRASE 读取
+pub async fn unlock_from_env(pool: &Pool<Sqlite>) -> Result<(), String> {
+    if crate::encryption::ensure_unlocked().is_ok() {
+        return Ok(());
+    }
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => crate::encryption::unlock(pool, &passphrase).await,
+        Err(_) => Err(format!(
+            "聊天记录已加密，请通过环境变量 {} 提供口令",
+            PASSPHRASE_ENV
+        )),
+    }
+}
+
+/// 消息时间（本地时区）
+pub fn format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+/// 消息的文字内容，文件消息显示为 [文件] 文件名 (状态)
+pub fn message_text(message: &crate::models::MessageResponse) -> String {
+    if message.msg_type == "file" {
+        format!(
+            "[文件] {} ({})",
+            message.file_name.as_deref().unwrap_or(&message.content),
+            message.file_status.as_deref().unwrap_or("未知")
+        )
+    } else {
+        message.content.clone()
+    }
+}
+
+// 在节点列表中查找：完整 ID 优先，其次是唯一的 ID 前缀或用户名（不区分大小写）
+fn pick_peer(peers: &[Peer], target: &str) -> Result<Option<Peer>, String> {
+    if let Some(peer) = peers.iter().find(|peer| peer.id == target) {
+        return Ok(Some(peer.clone()));
+    }
+    let matches: Vec<&Peer> = peers
+        .iter()
+        .filter(|peer| peer.name.eq_ignore_ascii_case(target) || peer.id.starts_with(target))
+        .collect();
+    match matches.as_slice() {
+        [] => Ok(None),
+        [peer] => Ok(Some((*peer).clone())),
+        _ => Err(format!(
+            "{} 匹配了多个节点，请使用节点 ID: {}",
+            target,
+            matches
+                .iter()
+                .map(|peer| format!("{} ({})", peer.name, peer.id))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+async fn read_stdin() -> Result<String, String> {
+    use tokio::io::AsyncReadExt;
+    let mut content = String::new();
+    tokio::io::stdin()
+        .read_to_string(&mut content)
+        .await
+        .map_err(|e| format!("读取标准输入失败: {}", e))?;
+    Ok(content.trim_end_matches('\n').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str, name: &str) -> Peer {
+        Peer {
+            id: id.to_string(),
+            name: name.to_string(),
+            addr: "127.0.0.1:8888".to_string(),
+            last_seen: 0,
+            is_offline: false,
+            available_memory_mb: 0,
+        }
+    }
+
+    #[test]
+    fn pick_peer_by_id_prefix_or_name() {
+        let peers = vec![peer("abc123", "Alice"), peer("abd456", "Bob")];
+        assert_eq!(pick_peer(&peers, "abc123").unwrap().unwrap().name, "Alice");
+        assert_eq!(pick_peer(&peers, "abd").unwrap().unwrap().name, "Bob");
+        assert_eq!(pick_peer(&peers, "alice").unwrap().unwrap().id, "abc123");
+        assert!(pick_peer(&peers, "carol").unwrap().is_none());
+        // 前缀匹配了多个节点
+        assert!(pick_peer(&peers, "ab").is_err());
+    }
+}
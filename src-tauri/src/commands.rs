@@ -9,7 +9,7 @@ use crate::peers::{Peer, PeerManager};
 use std::sync::Arc;
 
 #[cfg(feature = "desktop")]
-use tauri::{Emitter, Manager, State};
+use tauri::State;
 
 // 用于管理 PeerManager 的状态
 #[cfg(feature = "desktop")]
@@ -17,71 +17,26 @@ pub struct PeerState {
     pub manager: Arc<PeerManager>,
 }
 
+// 事件出口（桌面前端和浏览器），与节点接口共用
 #[cfg(feature = "desktop")]
-
-/// 根据设备内存和文件大小计算最优分块大小
-fn calculate_optimal_chunk_size(_file_size: usize) -> usize {
-    #[cfg(feature = "desktop")]
-    {
-        use sysinfo::System;
-
-        let mut sys = System::new_all();
-        sys.refresh_all();
-
-        // 获取可用内存（字节）
-        let available_memory = sys.available_memory() as usize * 1024; // sysinfo 返回的是 KB
-
-        // 使用可用内存的 80%（大胆使用内存以获得更快的速度）
-        let max_chunk_memory = available_memory * 80 / 100;
-
-        // 动态计算分块大小：使用可用内存的 80%，但最小 50MB，最大 500MB
-        let chunk_size = std::cmp::max(
-            50 * 1024 * 1024, // 最小 50MB
-            std::cmp::min(
-                max_chunk_memory,  // 使用可用内存的 80%
-                500 * 1024 * 1024, // 最大 500MB
-            ),
-        );
-
-        println!(
-            "[Command] 系统可用内存: {} MB",
-            available_memory / (1024 * 1024)
-        );
-        println!(
-            "[Command] 内存预算: {} MB",
-            max_chunk_memory / (1024 * 1024)
-        );
-        println!(
-            "[Command] 计算的分块大小: {} MB",
-            chunk_size / (1024 * 1024)
-        );
-
-        chunk_size
-    }
-    
-    #[cfg(not(feature = "desktop"))]
-    {
-        // Web 端：使用保守的固定值
-        println!("[Command] Web 端使用固定分块大小: 100 MB");
-        100 * 1024 * 1024
-    }
+pub struct EventState {
+    pub events: crate::events::Events,
 }
 
 /// 统一的文件上传实现
-/// 接受一个实现了 AsyncRead 的文件对象
-async fn upload_file_internal<R: tokio::io::AsyncRead + Unpin>(
+/// 文件按分块流式上传，续传时需要定位到断点
+async fn upload_file_internal(
     app: &tauri::AppHandle,
     state: &State<'_, DbState>,
-    peer_state: Option<&State<'_, PeerState>>,
     peer_id: String,
     peer_addr: String,
     file_name: String,
     file_size: usize,
     file_path_for_db: String,
-    mut file: R,
+    file: tokio::fs::File,
 ) -> Result<serde_json::Value, String> {
-    // 立即创建数据库记录（状态为 uploading）
-    let message_id = match crate::db::save_file_message(
+    // 立即创建数据库记录（状态为 uploading），传输 ID 同时作为跨节点消息 ID，接收方按它记录进度
+    let transfer_id = match crate::db::save_file_message(
         &state.pool,
         peer_id.clone(),
         file_name.clone(),
@@ -91,180 +46,108 @@ async fn upload_file_internal<R: tokio::io::AsyncRead + Unpin>(
     )
     .await
     {
-        Ok(id) => {
-            println!("[Command] ✓ 已创建上传中记录，ID: {}", id);
-            Some(id)
+        Ok((id, transfer_id)) => {
+            log::info!("[Command] ✓ 已创建上传中记录，ID: {}, 传输 ID: {}", id, transfer_id);
+            transfer_id
         }
         Err(e) => {
-            eprintln!("[Command] ✗ 创建上传记录失败: {}", e);
-            None
+            log::error!("[Command] ✗ 创建上传记录失败: {}", e);
+            return Err(e);
         }
     };
 
-    // 获取自己的 ID（发送者 ID）
-    let my_id = crate::db::get_user_id(&state.pool).await?;
-
-    // 获取接收方的可用内存
-    let receiver_memory_mb = if let Some(ps) = peer_state {
-        let peers = ps.manager.get_all_peers();
-        peers
-            .iter()
-            .find(|p| {
-                p.addr
-                    .starts_with(&peer_addr.split(':').next().unwrap_or(""))
-            })
-            .map(|p| p.available_memory_mb)
-            .unwrap_or(1024)
-    } else {
-        1024
-    };
-
-    println!("[Command] 接收方可用内存: {} MB", receiver_memory_mb);
-
-    // 分块上传
-    let chunk_size = calculate_optimal_chunk_size(file_size);
-
-    // 根据接收方内存调整分块大小（取发送方和接收方的最小值）
-    let max_chunk_for_receiver = std::cmp::max(
-        50 * 1024 * 1024,
-        receiver_memory_mb as usize * 1024 * 1024 / 4,
-    );
-    let adjusted_chunk_size = std::cmp::min(chunk_size, max_chunk_for_receiver);
-
-    println!(
-        "[Command] 原始分块大小: {} MB, 调整后: {} MB",
-        chunk_size / (1024 * 1024),
-        adjusted_chunk_size / (1024 * 1024)
-    );
-
-    let total_chunks = (file_size + adjusted_chunk_size - 1) / adjusted_chunk_size;
-
-    println!(
-        "[Command] 开始分块上传: 文件大小={}, 分块大小={}, 总分块数={}",
-        file_size, adjusted_chunk_size, total_chunks
-    );
-
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(300))
-        .build()
-        .map_err(|e| format!("创建客户端失败: {}", e))?;
-
-    let upload_url = format!("http://{}/api/upload", peer_addr);
-
-    let mut offset = 0;
-    let mut chunk_index = 0;
-    let start_time = std::time::Instant::now();
-
-    loop {
-        // 读取分块
-        let mut buf = vec![0u8; adjusted_chunk_size];
-        let mut bytes_read = 0;
-
-        while bytes_read < adjusted_chunk_size {
-            let n = tokio::io::AsyncReadExt::read(&mut file, &mut buf[bytes_read..])
-                .await
-                .map_err(|e| format!("读取文件失败: {}", e))?;
-
-            if n == 0 {
-                break;
-            }
-
-            bytes_read += n;
-        }
+    // 失败时记录标记为中断，可以续传
+    crate::network::transfer::run_upload(
+        &state.pool,
+        &transfer_id,
+        &peer_addr,
+        file,
+        upload_progress(app, transfer_id.clone(), file_name.clone()),
+    )
+    .await?;
 
-        if bytes_read == 0 {
-            break;
-        }
+    Ok(serde_json::json!({
+        "success": true,
+        "transfer_id": transfer_id,
+        "file_name": file_name,
+        "file_size": file_size,
+    }))
+}
 
-        buf.truncate(bytes_read);
-        let n = bytes_read;
-
-        // 构造 multipart 请求
-        let form = reqwest::multipart::Form::new()
-            .text("peer_id", my_id.clone())
-            .text("file_name", file_name.clone())
-            .text("file_size", file_size.to_string())
-            .text("chunk_index", chunk_index.to_string())
-            .text("chunk_total", total_chunks.to_string())
-            .part(
-                "chunk",
-                reqwest::multipart::Part::bytes(buf.clone())
-                    .mime_str("application/octet-stream")
-                    .map_err(|e| format!("设置 MIME 类型失败: {}", e))?,
-            );
-
-        println!(
-            "[Command] 上传分块 {}/{}, 大小: {} 字节",
-            chunk_index + 1,
-            total_chunks,
-            n
+/// 上传进度转发给前端（桌面前端和浏览器）
+fn upload_progress(
+    app: &tauri::AppHandle,
+    transfer_id: String,
+    file_name: String,
+) -> impl Fn(crate::network::transfer::UploadProgress) {
+    use tauri::Manager;
+    let events = app.state::<EventState>().events.clone();
+    move |progress| {
+        events.emit(
+            "upload_progress",
+            serde_json::json!({
+                "transfer_id": transfer_id,
+                "file_name": file_name,
+                "uploaded": progress.uploaded,
+                "total": progress.total,
+                "speed_mb_s": progress.speed_mb_s
+            }),
         );
+    }
+}
 
-        let response = client.post(&upload_url).multipart(form).send().await.map_err(|e| {
-            if let Some(id) = message_id {
-                let pool = state.pool.clone();
-                tokio::spawn(async move {
-                    let _ = crate::db::delete_message_by_id(&pool, id).await;
-                });
-            }
-            format!("上传分块失败: {}", e)
-        })?;
-
-        if !response.status().is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "无法读取错误信息".to_string());
-            eprintln!("[Command] ✗ 上传分块失败: {}", error_text);
-
-            if let Some(id) = message_id {
-                let _ = crate::db::delete_message_by_id(&state.pool, id).await;
-            }
+/// 继续一次中断的上传（网络中断或程序重启后）
+#[tauri::command]
+pub async fn resume_file_transfer(
+    app: tauri::AppHandle,
+    state: State<'_, DbState>,
+    peer_state: State<'_, PeerState>,
+    transfer_id: String,
+) -> Result<serde_json::Value, String> {
+    log::info!("[Command] 继续上传: {}", transfer_id);
 
-            return Err(format!("上传分块失败: {}", error_text));
-        }
+    let transfer = crate::db::get_transfer(&state.pool, &transfer_id)
+        .await?
+        .filter(|t| t.sender_id == "me")
+        .ok_or("找不到这次传输")?;
 
-        offset += n;
-        chunk_index += 1;
-
-        // 打印进度
-        let elapsed = start_time.elapsed().as_secs_f64();
-        if elapsed > 0.0 {
-            let speed = offset as f64 / (1024.0 * 1024.0) / elapsed;
-            println!(
-                "[Command] 已上传: {} MB, 速度: {:.2} MB/s",
-                offset / (1024 * 1024),
-                speed
-            );
-            let _ = app.emit(
-                "upload_progress",
-                serde_json::json!({
-                    "file_name": file_name.clone(),
-                    "speed_mb_s": speed
-                }),
-            );
-        }
+    if transfer.status != "interrupted" {
+        return Err(format!("当前状态 ({}) 不能续传", transfer.status));
+    }
+    // Android 的 content URI 和 FD 在重启后失效，只有普通文件路径可以重新打开
+    let file_path = transfer.file_path;
+    if file_path.starts_with("content://") || file_path.starts_with("fd:") {
+        return Err("该文件无法续传，请重新发送".to_string());
     }
 
-    let total_time = start_time.elapsed().as_secs_f64();
-    let avg_speed = file_size as f64 / (1024.0 * 1024.0) / total_time;
-    println!(
-        "[Command] ✓ 文件上传完成，耗时: {:.2}s, 平均速度: {:.2} MB/s",
-        total_time, avg_speed
-    );
+    let peer = peer_state
+        .manager
+        .get_peer(&transfer.receiver_id)
+        .filter(|p| !p.is_offline)
+        .ok_or("对方不在线，无法续传")?;
 
-    // 更新数据库状态为 "sent"
-    if let Some(id) = message_id {
-        if let Err(e) = crate::db::update_file_status_by_id(&state.pool, id, "sent").await {
-            eprintln!("[Command] ⚠ 更新数据库状态失败: {}", e);
-        } else {
-            println!("[Command] ✓ 文件状态已更新为 sent");
-        }
-    }
+    let file_size = std::fs::metadata(&file_path)
+        .map_err(|e| format!("读取文件信息失败: {}", e))?
+        .len() as usize;
+    let file = tokio::fs::File::open(&file_path)
+        .await
+        .map_err(|e| format!("打开文件失败: {}", e))?;
+
+    crate::db::update_transfer_status(&state.pool, &transfer_id, "uploading").await?;
+
+    crate::network::transfer::run_upload(
+        &state.pool,
+        &transfer_id,
+        &peer.addr,
+        file,
+        upload_progress(&app, transfer_id.clone(), transfer.file_name.clone()),
+    )
+    .await?;
 
     Ok(serde_json::json!({
         "success": true,
-        "file_name": file_name,
+        "transfer_id": transfer_id,
+        "file_name": transfer.file_name,
         "file_size": file_size,
     }))
 }
@@ -306,7 +189,7 @@ pub async fn update_my_name(state: State<'_, DbState>, new_name: String) -> Resu
     crate::db::update_username(&state.pool, new_name.clone()).await?;
 
     // 数据库更新后，定时广播线程会自动使用新名称
-    println!("[Command] 用户名已更新，广播线程将使用新名称");
+    log::info!("[Command] 用户名已更新，广播线程将使用新名称");
 
     // 返回更新后的名字
     Ok(new_name)
@@ -324,22 +207,68 @@ pub async fn send_message(
     peer_addr: String,
     content: String,
 ) -> Result<(), String> {
-    println!("[Command] 收到发送消息请求: 发送给 {}", peer_id);
+    log::info!("[Command] 收到发送消息请求: 发送给 {}", peer_id);
+
+    // 锁定状态下消息无法保存，不要先发出去
+    crate::encryption::ensure_unlocked()?;
 
     // 获取自己的信息
     let my_id = crate::db::get_user_id(&state.pool).await?;
     let my_name = crate::db::get_username(&state.pool).await?;
 
     // 发送消息
-    crate::network::messaging::send_text_message(&peer_addr, my_id, my_name, content.clone())
-        .await?;
+    let msg_uuid = uuid::Uuid::new_v4().to_string();
+    crate::network::messaging::send_text_message(
+        &peer_addr,
+        &peer_id,
+        my_id,
+        my_name,
+        msg_uuid.clone(),
+        content.clone(),
+    )
+    .await?;
 
     // 保存到数据库(标记为自己发送的)
-    crate::db::save_text_message(&state.pool, peer_id, content).await?;
+    crate::db::save_text_message(&state.pool, peer_id, msg_uuid, content).await?;
 
     Ok(())
 }
 
+#[tauri::command]
+pub async fn react_to_message(
+    state: State<'_, DbState>,
+    peer_id: String,
+    peer_addr: String,
+    message_id: String,
+    emoji: String,
+    remove: Option<bool>,
+) -> Result<(), String> {
+    log::info!("[Command] 收到表情回应请求: {} {}", message_id, emoji);
+
+    crate::db::validate_emoji(&emoji)?;
+
+    let my_id = crate::db::get_user_id(&state.pool).await?;
+    let remove = remove.unwrap_or(false);
+
+    // 先同步给对方，再保存到本地
+    crate::network::messaging::send_reaction(
+        &peer_addr,
+        &peer_id,
+        my_id.clone(),
+        message_id.clone(),
+        emoji.clone(),
+        remove,
+    )
+    .await?;
+
+    if remove {
+        crate::db::remove_reaction(&state.pool, &message_id, &my_id, &emoji).await
+    } else {
+        let timestamp = chrono::Utc::now().timestamp();
+        crate::db::add_reaction(&state.pool, &message_id, &my_id, &emoji, timestamp).await
+    }
+}
+
 #[tauri::command]
 pub async fn get_chat_history(
     state: State<'_, DbState>,
@@ -358,6 +287,282 @@ pub async fn get_chat_history_with_offset(
     crate::network::messaging::get_chat_history_with_offset(&state.pool, &peer_id, limit, offset).await
 }
 
+#[tauri::command]
+pub async fn search_messages(
+    state: State<'_, DbState>,
+    query: crate::models::SearchQuery,
+) -> Result<Vec<crate::models::SearchResult>, String> {
+    crate::db::search_messages(&state.pool, &query).await
+}
+
+#[tauri::command]
+pub async fn get_conversations(
+    state: State<'_, DbState>,
+    peer_state: State<'_, PeerState>,
+) -> Result<Vec<crate::models::Conversation>, String> {
+    let mut conversations = crate::db::get_conversations(&state.pool).await?;
+
+    // 补充对方的名称和在线状态
+    peer_state.manager.mark_stale_as_offline();
+    for conversation in conversations.iter_mut() {
+        if let Some(peer) = peer_state.manager.get_peer(&conversation.peer_id) {
+            conversation.peer_name = Some(peer.name);
+            conversation.is_online = !peer.is_offline;
+        }
+    }
+
+    Ok(conversations)
+}
+
+#[tauri::command]
+pub async fn mark_conversation_read(
+    state: State<'_, DbState>,
+    peer_id: String,
+) -> Result<(), String> {
+    crate::db::mark_conversation_read(&state.pool, &peer_id).await
+}
+
+#[tauri::command]
+pub async fn get_retention_policy(
+    state: State<'_, DbState>,
+) -> Result<crate::models::RetentionPolicy, String> {
+    crate::retention::get_policy(&state.pool).await
+}
+
+#[tauri::command]
+pub async fn update_retention_policy(
+    state: State<'_, DbState>,
+    policy: crate::models::RetentionPolicy,
+) -> Result<crate::models::PruneSummary, String> {
+    crate::retention::update_policy(&state.pool, &policy).await?;
+    // 新策略立即生效
+    crate::retention::apply_policy(&state.pool).await
+}
+
+#[tauri::command]
+pub async fn get_file_accept_policy(
+    state: State<'_, DbState>,
+) -> Result<crate::models::FileAcceptPolicy, String> {
+    crate::offers::get_policy(&state.pool).await
+}
+
+#[tauri::command]
+pub async fn update_file_accept_policy(
+    state: State<'_, DbState>,
+    policy: crate::models::FileAcceptPolicy,
+) -> Result<(), String> {
+    crate::offers::update_policy(&state.pool, &policy).await
+}
+
+/// 接受对方发来的文件（等待确认的文件），可以指定保存目录
+#[tauri::command]
+pub async fn accept_file(
+    state: State<'_, DbState>,
+    transfer_id: String,
+    save_path: Option<String>,
+) -> Result<(), String> {
+    crate::offers::accept(&state.pool, &transfer_id, save_path).await
+}
+
+/// 拒绝对方发来的文件
+#[tauri::command]
+pub async fn reject_file(state: State<'_, DbState>, transfer_id: String) -> Result<(), String> {
+    crate::offers::reject(&state.pool, &transfer_id).await
+}
+
+#[tauri::command]
+pub async fn clear_conversation(
+    state: State<'_, DbState>,
+    peer_id: String,
+    delete_files: Option<bool>,
+) -> Result<crate::models::PruneSummary, String> {
+    crate::retention::clear_conversation(&state.pool, &peer_id, delete_files.unwrap_or(false)).await
+}
+
+/// 立即备份数据库，返回备份文件路径（默认保存到定时备份目录或下载目录）
+#[tauri::command]
+pub async fn backup_database(
+    state: State<'_, DbState>,
+    dest: Option<String>,
+) -> Result<String, String> {
+    let dest = match dest {
+        Some(dest) => dest,
+        None => match crate::backup::get_schedule(&state.pool).await?.dir {
+            Some(dir) => dir,
+            None => crate::db::get_download_path(&state.pool).await?,
+        },
+    };
+    let path = crate::backup::backup_database(&state.pool, std::path::Path::new(&dest)).await?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// 校验备份文件并暂存，重启应用后换入
+#[tauri::command]
+pub async fn restore_database(
+    state: State<'_, DbState>,
+    backup_path: String,
+) -> Result<(), String> {
+    let db_path = crate::backup::database_file(&state.pool).await?;
+    crate::backup::stage_restore(&db_path, std::path::Path::new(&backup_path)).await
+}
+
+#[tauri::command]
+pub async fn get_backup_schedule(
+    state: State<'_, DbState>,
+) -> Result<crate::models::BackupSchedule, String> {
+    crate::backup::get_schedule(&state.pool).await
+}
+
+#[tauri::command]
+pub async fn update_backup_schedule(
+    state: State<'_, DbState>,
+    schedule: crate::models::BackupSchedule,
+) -> Result<(), String> {
+    crate::backup::update_schedule(&state.pool, &schedule).await
+}
+
+#[tauri::command]
+pub fn get_encryption_status() -> crate::encryption::EncryptionStatus {
+    crate::encryption::status()
+}
+
+#[tauri::command]
+pub async fn enable_encryption(state: State<'_, DbState>, passphrase: String) -> Result<(), String> {
+    crate::encryption::enable(&state.pool, &passphrase).await
+}
+
+#[tauri::command]
+pub async fn unlock_database(state: State<'_, DbState>, passphrase: String) -> Result<(), String> {
+    crate::encryption::unlock(&state.pool, &passphrase).await
+}
+
+#[tauri::command]
+pub fn lock_database() -> Result<(), String> {
+    crate::encryption::lock()
+}
+
+#[tauri::command]
+pub async fn disable_encryption(
+    state: State<'_, DbState>,
+    passphrase: String,
+) -> Result<(), String> {
+    crate::encryption::disable(&state.pool, &passphrase).await
+}
+
+/// 访问密码与局域网密钥的状态（桌面端本身不需要登录）
+#[tauri::command]
+pub fn get_auth_status() -> crate::auth::AuthStatus {
+    crate::auth::status(true)
+}
+
+/// 设置浏览器访问密码，new_password 为空时取消密码
+#[tauri::command]
+pub async fn set_web_password(
+    state: State<'_, DbState>,
+    current_password: String,
+    new_password: String,
+) -> Result<(), String> {
+    crate::auth::set_password(&state.pool, &current_password, &new_password).await
+}
+
+/// 设置局域网密钥，key 为空时不再校验节点签名
+#[tauri::command]
+pub async fn set_peer_key(state: State<'_, DbState>, key: String) -> Result<(), String> {
+    crate::auth::set_peer_key(&state.pool, &key).await
+}
+
+/// 本机证书指纹和明文连接设置
+#[tauri::command]
+pub fn get_tls_status() -> crate::tls::TlsStatus {
+    crate::tls::status()
+}
+
+/// 是否允许与不支持加密连接的节点明文通信
+#[tauri::command]
+pub async fn set_allow_plaintext(state: State<'_, DbState>, allow: bool) -> Result<(), String> {
+    crate::tls::set_allow_plaintext(&state.pool, allow).await
+}
+
+/// 删除某个节点记录的证书，下次连接时重新记录
+#[tauri::command]
+pub async fn forget_peer_certificate(state: State<'_, DbState>, peer_id: String) -> Result<(), String> {
+    crate::tls::forget_peer(&state.pool, &peer_id).await
+}
+
+/// 和某个节点的安全码（当面核对身份）
+#[tauri::command]
+pub async fn get_safety_number(
+    state: State<'_, DbState>,
+    peer_id: String,
+) -> Result<crate::e2e::SafetyNumber, String> {
+    crate::e2e::safety_number(&state.pool, &peer_id).await
+}
+
+/// 核对安全码后标记对方为已验证（或取消标记）
+#[tauri::command]
+pub async fn set_peer_verified(
+    state: State<'_, DbState>,
+    peer_id: String,
+    verified: bool,
+) -> Result<(), String> {
+    crate::e2e::set_verified(&state.pool, &peer_id, verified).await
+}
+
+/// 信任对方变化后的身份密钥
+#[tauri::command]
+pub async fn accept_peer_key_change(state: State<'_, DbState>, peer_id: String) -> Result<(), String> {
+    crate::e2e::accept_key_change(&state.pool, &peer_id).await
+}
+
+/// 导出聊天记录到文件，返回写入的路径（默认保存到下载目录）
+#[tauri::command]
+pub async fn export_history(
+    state: State<'_, DbState>,
+    peer_id: Option<String>,
+    format: String,
+    bundle_files: Option<bool>,
+    save_dir: Option<String>,
+) -> Result<String, String> {
+    let archive = crate::db::export_history(
+        &state.pool,
+        peer_id.as_deref(),
+        &format,
+        bundle_files.unwrap_or(false),
+    )
+    .await?;
+
+    let dir = match save_dir {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => std::path::PathBuf::from(crate::db::get_download_path(&state.pool).await?),
+    };
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| format!("创建目录失败: {}", e))?;
+
+    let path = dir.join(&archive.file_name);
+    tokio::fs::write(&path, &archive.data)
+        .await
+        .map_err(|e| format!("写入导出文件失败: {}", e))?;
+
+    log::info!("[Command] 聊天记录已导出到: {:?}", path);
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// 从 LANChat 导出的 JSON 文件导入聊天记录
+#[tauri::command]
+pub async fn import_history(
+    state: State<'_, DbState>,
+    file_path: String,
+) -> Result<crate::db::ImportSummary, String> {
+    log::info!("[Command] 导入聊天记录: {}", file_path);
+
+    let data = tokio::fs::read(&file_path)
+        .await
+        .map_err(|e| format!("读取导入文件失败: {}", e))?;
+
+    crate::db::import_history(&state.pool, &data).await
+}
+
 #[tauri::command]
 pub async fn send_file(
     app: tauri::AppHandle,
@@ -366,7 +571,7 @@ pub async fn send_file(
     peer_addr: String,
     file_path: String,
 ) -> Result<serde_json::Value, String> {
-    println!(
+    log::info!(
         "[Command] 收到发送文件请求: {} -> {} ({})",
         file_path, peer_addr, peer_id
     );
@@ -382,13 +587,13 @@ pub async fn send_file(
         file_path.clone()
     };
 
-    println!("[Command] 实际文件路径: {}", actual_path);
+    log::info!("[Command] 实际文件路径: {}", actual_path);
 
     // 检测是否是 Android content URI
     if actual_path.starts_with("content://") {
         #[cfg(target_os = "android")]
         {
-            println!("[Command] 检测到 Android content URI，使用 FD 方式");
+            log::info!("[Command] 检测到 Android content URI，使用 FD 方式");
             
             // 使用 JNI 调用 Android ContentResolver 获取 FD
             use crate::android_fd::AndroidFile;
@@ -418,19 +623,17 @@ pub async fn send_file(
                 Ok(metadata) => metadata.len() as usize,
                 Err(_) => {
                     // 如果无法通过 metadata 获取，尝试通过 stat 系统调用
-                    println!("[Command] 无法通过 metadata 获取文件大小，使用默认值");
+                    log::info!("[Command] 无法通过 metadata 获取文件大小，使用默认值");
                     0
                 }
             };
             
-            println!("[Command] 文件名: {}, 大小: {} 字节", file_name, file_size);
+            log::info!("[Command] 文件名: {}, 大小: {} 字节", file_name, file_size);
             
             // 使用统一的上传函数
-            let peer_state = app.try_state::<PeerState>();
             return upload_file_internal(
                 &app,
                 &state,
-                peer_state.as_ref(),
                 peer_id,
                 peer_addr,
                 file_name,
@@ -458,7 +661,7 @@ pub async fn send_file(
         std::fs::metadata(&actual_path).map_err(|e| format!("读取文件信息失败: {}", e))?;
     let file_size = file_metadata.len() as usize;
 
-    println!("[Command] 文件: {}, 大小: {} 字节", file_name, file_size);
+    log::info!("[Command] 文件: {}, 大小: {} 字节", file_name, file_size);
 
     // 打开文件
     let file = tokio::fs::File::open(&actual_path)
@@ -466,11 +669,9 @@ pub async fn send_file(
         .map_err(|e| format!("打开文件失败: {}", e))?;
 
     // 使用统一的上传函数
-    let peer_state = app.try_state::<PeerState>();
     upload_file_internal(
         &app,
         &state,
-        peer_state.as_ref(),
         peer_id,
         peer_addr,
         file_name,
@@ -523,7 +724,7 @@ pub async fn get_theme_list() -> Result<Vec<serde_json::Value>, String> {
         }
     }
 
-    println!("[Command] 找到 {} 个主题", themes.len());
+    log::info!("[Command] 找到 {} 个主题", themes.len());
     Ok(themes)
 }
 
@@ -537,7 +738,7 @@ pub async fn get_theme_css(theme_name: String) -> Result<String, String> {
     if theme_name == "vscode" {
         // 从嵌入的资源中读取 vscode.css
         let css_content = include_str!("../../src/css/vscode.css");
-        println!(
+        log::info!(
             "[Command] 加载内置主题: vscode ({} 字节)",
             css_content.len()
         );
@@ -558,7 +759,7 @@ pub async fn get_theme_css(theme_name: String) -> Result<String, String> {
     let css_content =
         std::fs::read_to_string(&theme_path).map_err(|e| format!("读取主题文件失败: {}", e))?;
 
-    println!(
+    log::info!(
         "[Command] 成功读取主题文件: {} ({} 字节)",
         theme_path.display(),
         css_content.len()
@@ -574,7 +775,7 @@ pub async fn save_current_theme(
     // 保存当前主题到数据库
     crate::db::save_current_theme(&state.pool, theme_name.clone()).await?;
 
-    println!("[Command] 主题设置已保存: {}", theme_name);
+    log::info!("[Command] 主题设置已保存: {}", theme_name);
     Ok(())
 }
 
@@ -583,7 +784,7 @@ pub async fn get_current_theme(state: State<'_, DbState>) -> Result<String, Stri
     let result = crate::db::get_current_theme(&state.pool).await?;
 
     let theme = result.unwrap_or_else(|| "default".to_string());
-    println!("[Command] 当前主题: {}", theme);
+    log::info!("[Command] 当前主题: {}", theme);
     Ok(theme)
 }
 
@@ -592,13 +793,13 @@ pub async fn get_default_download_path() -> Result<String, String> {
     if cfg!(target_os = "android") {
         // Android 的公共下载目录
         let download_path = "/storage/emulated/0/Download/LANChat";
-        println!("[Command] Android 默认下载路径: {}", download_path);
+        log::info!("[Command] Android 默认下载路径: {}", download_path);
         Ok(download_path.to_string())
     } else {
         // 桌面端和 Web 端返回用户下载目录
         let home_dir = dirs::home_dir().ok_or("无法获取用户主目录")?;
         let download_path = home_dir.join("Downloads").join("LANChat");
-        println!("[Command] 默认下载路径: {}", download_path.display());
+        log::info!("[Command] 默认下载路径: {}", download_path.display());
         Ok(download_path.to_string_lossy().to_string())
     }
 }
@@ -610,7 +811,7 @@ pub async fn request_storage_permission() -> Result<bool, String> {
         // Android 上需要请求存储权限
         // 注意：这个功能需要 Tauri 的 Android 插件支持
         // 目前先返回 true，假设权限已授予
-        println!("[Command] Android 存储权限检查（假设已授予）");
+        log::info!("[Command] Android 存储权限检查（假设已授予）");
         return Ok(true);
     }
 
@@ -630,7 +831,7 @@ pub async fn save_file_message(
     file_path: String,
     status: String,
 ) -> Result<i64, String> {
-    println!(
+    log::info!(
         "[Command] 文件: {}, 大小: {}, 状态: {}",
         file_name, file_size, status
     );
@@ -645,20 +846,21 @@ pub async fn save_file_message(
         status,
     )
     .await
+    .map(|(id, _)| id)
 }
 #[cfg(feature = "desktop")]
 #[tauri::command]
 pub async fn open_file_location(app: tauri::AppHandle, file_path: String) -> Result<(), String> {
     use tauri_plugin_opener::OpenerExt;
     
-    println!("[Command] 打开文件位置: {}", file_path);
+    log::info!("[Command] 打开文件位置: {}", file_path);
     
     // 使用 opener 插件打开文件所在目录
     app.opener()
         .reveal_item_in_dir(&file_path)
         .map_err(|e| format!("打开文件位置失败: {}", e))?;
     
-    println!("[Command] ✓ 文件位置已打开");
+    log::info!("[Command] ✓ 文件位置已打开");
     Ok(())
 }
 
@@ -668,7 +870,7 @@ pub async fn set_android_shared_files(
     app: tauri::AppHandle,
     files: Vec<serde_json::Value>,
 ) -> Result<(), String> {
-    println!("[Command] set_android_shared_files 被调用，文件数: {}", files.len());
+    log::info!("[Command] set_android_shared_files 被调用，文件数: {}", files.len());
     
     #[cfg(target_os = "android")]
     {
@@ -676,11 +878,11 @@ pub async fn set_android_shared_files(
         
         if let Some(share_state) = app.try_state::<AndroidShareState>() {
             share_state.set_files(files);
-            println!("[Command] 文件已保存到状态");
+            log::info!("[Command] 文件已保存到状态");
             return Ok(());
         }
         
-        println!("[Command] 没有找到分享状态");
+        log::info!("[Command] 没有找到分享状态");
         Err("分享状态未初始化".to_string())
     }
 
@@ -693,7 +895,7 @@ pub async fn set_android_shared_files(
 
 #[tauri::command]
 pub async fn get_android_shared_files(app: tauri::AppHandle) -> Result<Vec<serde_json::Value>, String> {
-    println!("[Command] get_android_shared_files 被调用");
+    log::info!("[Command] get_android_shared_files 被调用");
     
     #[cfg(target_os = "android")]
     {
@@ -706,11 +908,11 @@ pub async fn get_android_shared_files(app: tauri::AppHandle) -> Result<Vec<serde
         // 尝试从应用状态获取分享文件
         if let Some(share_state) = app.try_state::<AndroidShareState>() {
             let files = share_state.get_files();
-            println!("[Command] 从状态获取到 {} 个文件", files.len());
+            log::info!("[Command] 从状态获取到 {} 个文件", files.len());
             return Ok(files);
         }
         
-        println!("[Command] 没有找到分享状态");
+        log::info!("[Command] 没有找到分享状态");
         Ok(vec![])
     }
 
@@ -723,7 +925,7 @@ pub async fn get_android_shared_files(app: tauri::AppHandle) -> Result<Vec<serde
 
 #[tauri::command]
 pub async fn clear_android_shared_files(app: tauri::AppHandle) -> Result<(), String> {
-    println!("[Command] clear_android_shared_files 被调用");
+    log::info!("[Command] clear_android_shared_files 被调用");
     
     #[cfg(target_os = "android")]
     {
@@ -731,7 +933,7 @@ pub async fn clear_android_shared_files(app: tauri::AppHandle) -> Result<(), Str
         
         if let Some(share_state) = app.try_state::<AndroidShareState>() {
             share_state.clear_files();
-            println!("[Command] 已清除分享文件");
+            log::info!("[Command] 已清除分享文件");
         }
         
         Ok(())
@@ -761,13 +963,13 @@ impl AndroidShareState {
     pub fn set_files(&self, files: Vec<serde_json::Value>) {
         if let Ok(mut f) = self.files.lock() {
             *f = files;
-            println!("[AndroidShareState] 已设置 {} 个文件", f.len());
+            log::info!("[AndroidShareState] 已设置 {} 个文件", f.len());
         }
     }
     
     pub fn get_files(&self) -> Vec<serde_json::Value> {
         if let Ok(f) = self.files.lock() {
-            println!("[AndroidShareState] 获取 {} 个文件", f.len());
+            log::info!("[AndroidShareState] 获取 {} 个文件", f.len());
             f.clone()
         } else {
             Vec::new()
@@ -777,7 +979,7 @@ impl AndroidShareState {
     pub fn clear_files(&self) {
         if let Ok(mut f) = self.files.lock() {
             f.clear();
-            println!("[AndroidShareState] 已清除文件");
+            log::info!("[AndroidShareState] 已清除文件");
         }
     }
 }
@@ -808,7 +1010,7 @@ pub async fn send_file_from_fd(
     #[allow(non_snake_case)]
     originalUri: Option<String>,
 ) -> Result<serde_json::Value, String> {
-    println!(
+    log::info!(
         "[Command] 收到从 FD 发送文件请求: fd={}, name={}, size={}, uri={:?}, to={}",
         fd, fileName, fileSize, originalUri, peerAddr
     );
@@ -826,11 +1028,9 @@ pub async fn send_file_from_fd(
         let file_path = originalUri.unwrap_or_else(|| format!("fd:{}", fd));
 
         // 使用统一的上传函数
-        let peer_state = app.try_state::<PeerState>();
         upload_file_internal(
             &app,
             &state,
-            peer_state.as_ref(),
             peerId,
             peerAddr,
             fileName.clone(),
@@ -855,7 +1055,7 @@ pub async fn share_file_to_other_app(
     #[allow(non_snake_case)]
     filePath: String,
 ) -> Result<(), String> {
-    println!("[Command] 准备分享文件到其他应用: {}", filePath);
+    log::info!("[Command] 准备分享文件到其他应用: {}", filePath);
     
     use jni::objects::JValue;
     
@@ -878,7 +1078,7 @@ pub async fn share_file_to_other_app(
         &[JValue::Object(&file_path_jstring)]
     ).map_err(|e| format!("调用 shareFile 失败: {}", e))?;
     
-    println!("[Command] 分享文件命令已发送到 Android");
+    log::info!("[Command] 分享文件命令已发送到 Android");
     Ok(())
 }
 
@@ -897,7 +1097,7 @@ pub async fn share_file_to_other_app(
 #[cfg(feature = "desktop")]
 #[tauri::command]
 pub async fn read_clipboard_files() -> Result<Vec<String>, String> {
-    println!("[Command] 读取剪贴板文件");
+    log::info!("[Command] 读取剪贴板文件");
     
     #[cfg(not(target_os = "android"))]
     {
@@ -906,14 +1106,14 @@ pub async fn read_clipboard_files() -> Result<Vec<String>, String> {
         {
             if let Ok(files) = try_read_wayland_clipboard().await {
                 if !files.is_empty() {
-                    println!("[Command] ✓ 通过 Wayland 读取到 {} 个文件", files.len());
+                    log::info!("[Command] ✓ 通过 Wayland 读取到 {} 个文件", files.len());
                     return Ok(files);
                 }
             }
         }
         
         // 2. Fallback: 使用 clipboard-rs
-        println!("[Command] 尝试使用 clipboard-rs");
+        log::info!("[Command] 尝试使用 clipboard-rs");
         use clipboard_rs::{Clipboard, ClipboardContext};
         
         let ctx = ClipboardContext::new()
@@ -922,7 +1122,7 @@ pub async fn read_clipboard_files() -> Result<Vec<String>, String> {
         let files = ctx.get_files()
             .map_err(|e| format!("读取剪贴板文件失败: {}", e))?;
         
-        println!("[Command] ✓ 通过 clipboard-rs 读取到 {} 个文件", files.len());
+        log::info!("[Command] ✓ 通过 clipboard-rs 读取到 {} 个文件", files.len());
         Ok(files)
     }
     
@@ -938,7 +1138,7 @@ async fn try_read_wayland_clipboard() -> Result<Vec<String>, String> {
     use wl_clipboard_rs::paste::{get_contents, ClipboardType, MimeType, Seat};
     use std::io::Read;
     
-    println!("[Command] 尝试通过 Wayland 读取剪贴板");
+    log::info!("[Command] 尝试通过 Wayland 读取剪贴板");
     
     // 尝试读取 text/uri-list MIME 类型（文件列表）
     let result = get_contents(
@@ -961,27 +1161,27 @@ async fn try_read_wayland_clipboard() -> Result<Vec<String>, String> {
                 .collect();
             
             if files.is_empty() {
-                println!("[Command] Wayland 剪贴板中没有文件");
+                log::info!("[Command] Wayland 剪贴板中没有文件");
                 Err("剪贴板中没有文件".to_string())
             } else {
-                println!("[Command] Wayland 剪贴板中的文件: {:?}", files);
+                log::info!("[Command] Wayland 剪贴板中的文件: {:?}", files);
                 Ok(files)
             }
         }
         Err(wl_clipboard_rs::paste::Error::NoSeats) => {
-            println!("[Command] Wayland: 没有可用的 seat");
+            log::info!("[Command] Wayland: 没有可用的 seat");
             Err("没有可用的 seat".to_string())
         }
         Err(wl_clipboard_rs::paste::Error::ClipboardEmpty) => {
-            println!("[Command] Wayland: 剪贴板为空");
+            log::info!("[Command] Wayland: 剪贴板为空");
             Err("剪贴板为空".to_string())
         }
         Err(wl_clipboard_rs::paste::Error::NoMimeType) => {
-            println!("[Command] Wayland: 剪贴板中没有 text/uri-list 类型");
+            log::info!("[Command] Wayland: 剪贴板中没有 text/uri-list 类型");
             Err("剪贴板中没有文件".to_string())
         }
         Err(e) => {
-            println!("[Command] Wayland 读取失败: {:?}", e);
+            log::info!("[Command] Wayland 读取失败: {:?}", e);
             Err(format!("Wayland 读取失败: {:?}", e))
         }
     }
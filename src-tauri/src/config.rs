@@ -0,0 +1,544 @@
+// 配置文件（TOML）
+// lanchat-web 通过 --config 指定，没有指定时读取默认位置（~/.config/lanchat/lanchat.toml），桌面端读取同一个文件
+// 优先级：命令行参数 > 配置文件 > 数据库中保存的设置 > 默认值
+// 用户名、下载目录、接收规则和明文连接设置启动时写入数据库，之后在界面中修改的设置会保存到数据库，
+// 但下次启动时仍以配置文件为准
+use crate::models::PeerAcceptRule;
+use crate::network::discovery::DiscoveryOptions;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const DEFAULT_PORT: u16 = 8888;
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_DISCOVERY_INTERVAL: u64 = 5;
+
+const POLICIES: &[&str] = &["auto", "ask", "reject"];
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+/// 配置文件的内容（没有写的项为 None，使用数据库中的设置或默认值）
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// 节点端口（TCP 消息和文件接口、UDP 广播）
+    pub port: Option<u16>,
+    /// 节点接口监听的 IP 地址（UDP 广播总是监听所有网卡）
+    pub bind: Option<IpAddr>,
+    /// 页面和控制接口的监听地址
+    pub control_addr: Option<SocketAddr>,
+    /// 数据目录（只对 lanchat-web 有效）
+    pub db_path: Option<PathBuf>,
+    pub username: Option<String>,
+    pub download_dir: Option<PathBuf>,
+    /// 日志级别（LANChat 自己的日志和依赖库的诊断日志）
+    pub log_level: Option<String>,
+    pub accept: AcceptConfig,
+    pub discovery: DiscoveryConfig,
+    pub tls: TlsConfig,
+    /// [aliases] 命令行客户端中联系人的别名（别名 = 节点 ID 或用户名）
+    pub aliases: Option<BTreeMap<String, String>>,
+}
+
+/// [accept] 接收文件的规则
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcceptConfig {
+    pub default_policy: Option<String>,
+    pub max_auto_accept_size: Option<i64>,
+    /// 按联系人覆盖默认规则（写了就整体替换数据库中的联系人规则）
+    pub peers: Option<Vec<PeerAcceptRule>>,
+}
+
+/// [discovery] 局域网发现
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// 是否发送广播和组播（关闭后只向 peers 中的地址发送心跳）
+    pub broadcast: Option<bool>,
+    pub interval_secs: Option<u64>,
+    /// 额外发送心跳的地址（"IP" 或 "IP:端口"），用于广播不通的网络
+    pub peers: Option<Vec<String>>,
+}
+
+/// [tls] 节点之间的加密连接
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM 格式的证书链和私钥，不设置时使用自动生成的自签名证书
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub allow_plaintext: Option<bool>,
+}
+
+/// 默认的配置文件位置
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("lanchat").join("lanchat.toml"))
+}
+
+/// 读取配置文件
+pub fn load(path: &Path) -> Result<Config, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("无法读取配置文件 {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("配置文件 {} 格式错误: {}", path.display(), e))
+}
+
+/// 读取默认位置的配置文件，文件不存在时返回空配置
+pub fn load_default() -> Result<Config, String> {
+    match default_path() {
+        Some(path) if path.exists() => {
+            // 读取配置时还没有开始输出日志，直接打印到标准错误
+            eprintln!("[Config] 读取配置文件: {}", path.display());
+            load(&path)
+        }
+        _ => Ok(Config::default()),
+    }
+}
+
+impl Config {
+    /// 用 overrides 中设置了的项覆盖当前配置（命令行参数覆盖配置文件）
+    pub fn merge(self, overrides: Config) -> Config {
+        Config {
+            port: overrides.port.or(self.port),
+            bind: overrides.bind.or(self.bind),
+            control_addr: overrides.control_addr.or(self.control_addr),
+            db_path: overrides.db_path.or(self.db_path),
+            username: overrides.username.or(self.username),
+            download_dir: overrides.download_dir.or(self.download_dir),
+            log_level: overrides.log_level.or(self.log_level),
+            accept: AcceptConfig {
+                default_policy: overrides
+                    .accept
+                    .default_policy
+                    .or(self.accept.default_policy),
+                max_auto_accept_size: overrides
+                    .accept
+                    .max_auto_accept_size
+                    .or(self.accept.max_auto_accept_size),
+                peers: overrides.accept.peers.or(self.accept.peers),
+            },
+            discovery: DiscoveryConfig {
+                broadcast: overrides.discovery.broadcast.or(self.discovery.broadcast),
+                interval_secs: overrides
+                    .discovery
+                    .interval_secs
+                    .or(self.discovery.interval_secs),
+                peers: overrides.discovery.peers.or(self.discovery.peers),
+            },
+            tls: TlsConfig {
+                cert: overrides.tls.cert.or(self.tls.cert),
+                key: overrides.tls.key.or(self.tls.key),
+                allow_plaintext: overrides.tls.allow_plaintext.or(self.tls.allow_plaintext),
+            },
+            aliases: overrides.aliases.or(self.aliases),
+        }
+    }
+
+    /// 检查所有配置项，返回全部错误（每行一个）
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        if self.port == Some(0) {
+            errors.push("port: 端口不能为 0".to_string());
+        }
+        if let Some(addr) = self.control_addr {
+            let peer_addr = self.peer_addr();
+            if addr.port() == self.port() && addr != peer_addr {
+                errors.push(format!(
+                    "control_addr: 端口 {} 已被节点接口使用（要和节点接口共用端口，请设置为 {}）",
+                    addr.port(),
+                    peer_addr
+                ));
+            }
+        }
+        if let Some(name) = &self.username {
+            if name.trim().is_empty() {
+                errors.push("username: 用户名不能为空".to_string());
+            } else if name.len() > 50 {
+                errors.push("username: 用户名过长（最多50个字符）".to_string());
+            }
+        }
+        if let Some(dir) = &self.download_dir {
+            if dir.as_os_str().is_empty() {
+                errors.push("download_dir: 路径不能为空".to_string());
+            } else if dir.is_file() {
+                errors.push(format!("download_dir: {} 是一个文件", dir.display()));
+            }
+        }
+        if let Some(level) = &self.log_level {
+            if !LOG_LEVELS.contains(&level.as_str()) {
+                errors.push(format!(
+                    "log_level: 无效的日志级别 {:?}（可选 {}）",
+                    level,
+                    LOG_LEVELS.join("、")
+                ));
+            }
+        }
+
+        if let Some(policy) = &self.accept.default_policy {
+            if !POLICIES.contains(&policy.as_str()) {
+                errors.push(format!(
+                    "accept.default_policy: 无效的规则 {:?}（可选 auto、ask、reject）",
+                    policy
+                ));
+            }
+        }
+        if self
+            .accept
+            .max_auto_accept_size
+            .is_some_and(|size| size < 0)
+        {
+            errors.push("accept.max_auto_accept_size: 不能为负数".to_string());
+        }
+        for rule in self.accept.peers.iter().flatten() {
+            if rule.peer_id.trim().is_empty() {
+                errors.push("accept.peers: peer_id 不能为空".to_string());
+            }
+            if !POLICIES.contains(&rule.policy.as_str()) {
+                errors.push(format!(
+                    "accept.peers: {} 的规则 {:?} 无效（可选 auto、ask、reject）",
+                    rule.peer_id, rule.policy
+                ));
+            }
+        }
+
+        if let Some(interval) = self.discovery.interval_secs {
+            if !(1..=3600).contains(&interval) {
+                errors.push("discovery.interval_secs: 心跳间隔需要在 1 到 3600 秒之间".to_string());
+            }
+        }
+        for peer in self.discovery.peers.iter().flatten() {
+            if parse_peer_addr(peer, self.port()).is_none() {
+                errors.push(format!(
+                    "discovery.peers: 无效的地址 {:?}（格式为 IP 或 IP:端口）",
+                    peer
+                ));
+            }
+        }
+
+        for (alias, target) in self.aliases.iter().flatten() {
+            if alias.trim().is_empty() || target.trim().is_empty() {
+                errors.push(format!(
+                    "aliases: 别名 {:?} 和它指向的节点都不能为空",
+                    alias
+                ));
+            }
+        }
+
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(_), None) | (None, Some(_)) => {
+                errors.push("tls: cert 和 key 需要同时设置".to_string());
+            }
+            (Some(cert), Some(key)) => {
+                for (name, path) in [("tls.cert", cert), ("tls.key", key)] {
+                    if !path.is_file() {
+                        errors.push(format!("{}: 文件 {} 不存在", name, path.display()));
+                    }
+                }
+            }
+            (None, None) => {}
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+
+    /// 节点接口的监听地址
+    pub fn peer_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind.unwrap_or(IpAddr::from([0, 0, 0, 0])), self.port())
+    }
+
+    /// 控制接口的监听地址：配置文件优先，其次是数据库中的设置，最后是默认地址
+    pub async fn control_addr(&self, pool: &Pool<Sqlite>) -> SocketAddr {
+        match self.control_addr {
+            Some(addr) => addr,
+            None => crate::web_server::resolve_control_addr(pool, self.port()).await,
+        }
+    }
+
+    pub fn discovery_options(&self) -> DiscoveryOptions {
+        let port = self.port();
+        DiscoveryOptions {
+            broadcast: self.discovery.broadcast.unwrap_or(true),
+            interval: Duration::from_secs(
+                self.discovery
+                    .interval_secs
+                    .unwrap_or(DEFAULT_DISCOVERY_INTERVAL),
+            ),
+            static_peers: self
+                .discovery
+                .peers
+                .iter()
+                .flatten()
+                .filter_map(|peer| parse_peer_addr(peer, port))
+                .map(|addr| addr.to_string())
+                .collect(),
+        }
+    }
+
+    pub fn log_level(&self) -> log::LevelFilter {
+        self.log_level
+            .as_deref()
+            .unwrap_or(DEFAULT_LOG_LEVEL)
+            .parse()
+            .unwrap_or(log::LevelFilter::Info)
+    }
+
+    /// 把保存在数据库中的设置（用户名、下载目录、接收规则、明文连接、证书）更新为配置中的值
+    pub async fn apply(&self, pool: &Pool<Sqlite>) -> Result<(), String> {
+        if let Some(name) = &self.username {
+            if crate::db::get_username(pool).await.ok().as_deref() != Some(name.trim()) {
+                crate::db::update_username(pool, name.clone())
+                    .await
+                    .map_err(|e| format!("username: {}", e))?;
+            }
+        }
+        if let Some(dir) = &self.download_dir {
+            let dir = dir.to_string_lossy().to_string();
+            if crate::db::get_download_path(pool).await.ok().as_deref() != Some(dir.trim()) {
+                crate::db::update_download_path(pool, dir)
+                    .await
+                    .map_err(|e| format!("download_dir: {}", e))?;
+            }
+        }
+
+        let accept = &self.accept;
+        if accept.default_policy.is_some()
+            || accept.max_auto_accept_size.is_some()
+            || accept.peers.is_some()
+        {
+            let mut policy = crate::offers::get_policy(pool).await?;
+            if let Some(default_policy) = &accept.default_policy {
+                policy.default_policy = default_policy.clone();
+            }
+            if let Some(size) = accept.max_auto_accept_size {
+                policy.max_auto_accept_size = size;
+            }
+            if let Some(peers) = &accept.peers {
+                policy.peer_rules = peers.clone();
+            }
+            crate::offers::update_policy(pool, &policy)
+                .await
+                .map_err(|e| format!("accept: {}", e))?;
+        }
+
+        if let Some(allow) = self.tls.allow_plaintext {
+            if crate::tls::allow_plaintext() != allow {
+                crate::tls::set_allow_plaintext(pool, allow).await?;
+            }
+        }
+        if let (Some(cert), Some(key)) = (&self.tls.cert, &self.tls.key) {
+            crate::tls::use_certificate_files(cert, key).map_err(|e| format!("tls: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// 实际生效的配置：没有写的项填入数据库中的设置或默认值
+    pub async fn effective(&self, pool: &Pool<Sqlite>) -> Config {
+        let policy = crate::offers::get_policy(pool).await.unwrap_or_default();
+        let discovery = self.discovery_options();
+        Config {
+            port: Some(self.port()),
+            bind: Some(self.peer_addr().ip()),
+            control_addr: Some(self.control_addr(pool).await),
+            db_path: self.db_path.clone(),
+            username: crate::db::get_username(pool).await.ok(),
+            download_dir: crate::db::get_download_path(pool)
+                .await
+                .ok()
+                .map(PathBuf::from),
+            log_level: Some(self.log_level().to_string().to_lowercase()),
+            accept: AcceptConfig {
+                default_policy: Some(policy.default_policy),
+                max_auto_accept_size: Some(policy.max_auto_accept_size),
+                peers: Some(policy.peer_rules),
+            },
+            discovery: DiscoveryConfig {
+                broadcast: Some(discovery.broadcast),
+                interval_secs: Some(discovery.interval.as_secs()),
+                peers: Some(discovery.static_peers),
+            },
+            tls: TlsConfig {
+                cert: self.tls.cert.clone(),
+                key: self.tls.key.clone(),
+                allow_plaintext: Some(crate::tls::allow_plaintext()),
+            },
+            aliases: self.aliases.clone(),
+        }
+    }
+
+    /// 打印实际生效的配置（TOML 格式，可以直接复制为配置文件）
+    pub async fn print_effective(&self, pool: &Pool<Sqlite>) {
+        let effective = self.effective(pool).await;
+        match toml::to_string_pretty(&effective) {
+            Ok(text) => {
+                log::info!("[Config] 生效的配置:");
+                for line in text.lines().filter(|line| !line.is_empty()) {
+                    log::info!("[Config]   {}", line);
+                }
+            }
+            Err(e) => log::error!("[Config] 无法显示配置: {}", e),
+        }
+    }
+}
+
+// "IP" 使用本机的节点端口，"IP:端口" 原样使用
+fn parse_peer_addr(peer: &str, port: u16) -> Option<SocketAddr> {
+    peer.parse::<SocketAddr>().ok().or_else(|| {
+        peer.parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, port))
+    })
+}
+
+// 所有日志按 log_level 过滤，依赖库的日志带上级别和来源
+// 默认输出到标准错误，终端界面运行时写入日志文件
+struct Logger;
+
+static LOGGER: Logger = Logger;
+static LEVEL: AtomicUsize = AtomicUsize::new(log::LevelFilter::Info as usize);
+static LOG_FILE: Mutex<Option<std::fs::File>> = Mutex::new(None);
+
+fn is_own(target: &str) -> bool {
+    target.starts_with("lanchat")
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() as usize <= LEVEL.load(Ordering::Relaxed)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = if is_own(record.target()) {
+            record.args().to_string()
+        } else {
+            format!(
+                "[{}] {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        };
+        match LOG_FILE.lock().unwrap().as_mut() {
+            Some(file) => {
+                let _ = writeln!(file, "{}", line);
+            }
+            None => eprintln!("{}", line),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// 开始输出日志（可以多次调用，只更新级别）
+pub fn init_logging(level: log::LevelFilter) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
+
+/// 日志写入文件而不是标准错误（终端界面占用了终端）
+pub fn log_to_file(file: std::fs::File) {
+    *LOG_FILE.lock().unwrap() = Some(file);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_full_config() {
+        let config: Config = toml::from_str(
+            r#"
+            port = 9000
+            bind = "192.168.1.10"
+            username = "build-box"
+            log_level = "info"
+
+            [accept]
+            default_policy = "ask"
+            peers = [{ peer_id = "abc", policy = "auto" }]
+
+            [discovery]
+            broadcast = false
+            peers = ["10.0.0.5", "10.0.0.6:9100"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.peer_addr().to_string(), "192.168.1.10:9000");
+        assert_eq!(
+            config.discovery_options().static_peers,
+            vec!["10.0.0.5:9000", "10.0.0.6:9100"]
+        );
+        assert_eq!(config.log_level(), log::LevelFilter::Info);
+    }
+
+    #[test]
+    fn log_level_applies_to_own_logs() {
+        use log::Log;
+        let record = |target: &'static str, level| {
+            log::MetadataBuilder::new()
+                .target(target)
+                .level(level)
+                .build()
+        };
+        init_logging(log::LevelFilter::Warn);
+        assert!(!LOGGER.enabled(&record("lanchat::network", log::Level::Info)));
+        assert!(LOGGER.enabled(&record("lanchat::network", log::Level::Warn)));
+        assert!(!LOGGER.enabled(&record("sqlx::query", log::Level::Info)));
+        init_logging(log::LevelFilter::Info);
+        assert!(LOGGER.enabled(&record("lanchat::network", log::Level::Info)));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let error = toml::from_str::<Config>("prot = 9000").unwrap_err();
+        assert!(error.to_string().contains("prot"));
+    }
+
+    #[test]
+    fn reports_every_invalid_value() {
+        let config: Config = toml::from_str(
+            r#"
+            log_level = "loud"
+            [accept]
+            default_policy = "maybe"
+            [tls]
+            cert = "/nonexistent/cert.pem"
+            "#,
+        )
+        .unwrap();
+        let errors = config.validate().unwrap_err();
+        assert!(errors.contains("log_level"));
+        assert!(errors.contains("accept.default_policy"));
+        assert!(errors.contains("tls: cert 和 key 需要同时设置"));
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let file: Config = toml::from_str("port = 9000\nusername = \"file\"").unwrap();
+        let overrides = Config {
+            username: Some("cli".to_string()),
+            ..Default::default()
+        };
+        let merged = file.merge(overrides);
+        assert_eq!(merged.port(), 9000);
+        assert_eq!(merged.username.as_deref(), Some("cli"));
+    }
+}
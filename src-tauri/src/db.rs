@@ -1,6 +1,8 @@
 use crate::utils::generate_random_name;
-use sqlx::{sqlite::SqlitePool, Pool, Sqlite};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use sqlx::{Pool, Sqlite};
 use std::path::PathBuf;
+use std::str::FromStr;
 
 #[cfg(feature = "desktop")]
 use tauri::AppHandle;
@@ -16,7 +18,7 @@ pub async fn get_username(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<String, Str
         .fetch_one(pool)
         .await
         .map_err(|e| {
-            eprintln!("[DB] 读取用户名失败: {}", e);
+            log::error!("[DB] 读取用户名失败: {}", e);
             e.to_string()
         })?;
     Ok(res.0)
@@ -33,7 +35,7 @@ pub async fn get_user_id(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<String, Stri
         Err(_) => {
             // 如果没有 user_id,生成一个并保存
             let user_id = uuid::Uuid::new_v4().to_string();
-            println!("[DB] 生成并保存新的用户 ID: {}", user_id);
+            log::info!("[DB] 生成并保存新的用户 ID: {}", user_id);
 
             sqlx::query("INSERT INTO settings (key, value) VALUES ('user_id', ?)")
                 .bind(&user_id)
@@ -50,7 +52,7 @@ pub async fn update_username(
     pool: &sqlx::Pool<sqlx::Sqlite>,
     new_name: String,
 ) -> Result<(), String> {
-    println!("[DB] 正在更新用户名为: {}", new_name);
+    log::info!("[DB] 正在更新用户名为: {}", new_name);
 
     // 验证用户名不为空
     if new_name.trim().is_empty() {
@@ -67,11 +69,11 @@ pub async fn update_username(
         .execute(pool)
         .await
         .map_err(|e| {
-            println!("[DB] 更新失败: {}", e);
+            log::info!("[DB] 更新失败: {}", e);
             e.to_string()
         })?;
 
-    println!("[DB] 用户名更新成功");
+    log::info!("[DB] 用户名更新成功");
     Ok(())
 }
 
@@ -102,7 +104,7 @@ pub async fn update_download_path(
     pool: &sqlx::Pool<sqlx::Sqlite>,
     new_path: String,
 ) -> Result<(), String> {
-    println!("[DB] 正在更新下载路径为: {}", new_path);
+    log::info!("[DB] 正在更新下载路径为: {}", new_path);
 
     // 验证路径不为空
     if new_path.trim().is_empty() {
@@ -120,7 +122,7 @@ pub async fn update_download_path(
         .await
         .map_err(|e| e.to_string())?;
 
-    println!("[DB] 下载路径更新成功");
+    log::info!("[DB] 下载路径更新成功");
     Ok(())
 }
 
@@ -133,20 +135,22 @@ pub async fn init_db(app_handle: &AppHandle) -> Result<Pool<Sqlite>, sqlx::Error
 
 // 为 Web 端初始化数据库（使用自定义路径）
 pub async fn init_db_standalone(custom_path: Option<PathBuf>) -> Result<Pool<Sqlite>, sqlx::Error> {
-    let app_dir = if let Some(path) = custom_path {
+    init_db_with_path(standalone_data_dir(custom_path)).await
+}
+
+// Web 端的数据目录（未指定时与桌面端相同: ~/.local/share/com.lanchat.app/）
+pub fn standalone_data_dir(custom_path: Option<PathBuf>) -> PathBuf {
+    if let Some(path) = custom_path {
         path
     } else {
-        // 默认使用与桌面端相同的路径: ~/.local/share/com.lanchat.app/
         let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
         PathBuf::from(home).join(".local/share/com.lanchat.app")
-    };
-
-    init_db_with_path(app_dir).await
+    }
 }
 
 // 通用的数据库初始化逻辑
 async fn init_db_with_path(app_dir: PathBuf) -> Result<Pool<Sqlite>, sqlx::Error> {
-    println!("[DB] 数据库路径: {:?}", app_dir);
+    log::info!("[DB] 数据库路径: {:?}", app_dir);
 
     // 确保目录一定存在
     if !app_dir.exists() {
@@ -156,42 +160,33 @@ async fn init_db_with_path(app_dir: PathBuf) -> Result<Pool<Sqlite>, sqlx::Error
     let db_path = app_dir.join("lanchat.db");
     let db_url = format!("sqlite:{}", db_path.to_str().unwrap());
 
+    // 上次运行时选择了从备份恢复，在打开数据库之前换入
+    crate::backup::apply_pending_restore(&db_path)
+        .map_err(|e| sqlx::Error::Configuration(e.into()))?;
+
     // 检查文件是否存在，如果不存在，手动创建空文件
     if !db_path.exists() {
         std::fs::File::create(&db_path).unwrap();
     }
 
-    let pool = SqlitePool::connect(&db_url).await?;
+    // 删除和改写的内容在文件中清零（开启聊天内容加密后，旧的明文不会留在空闲页里）
+    let options = SqliteConnectOptions::from_str(&db_url)?.pragma("secure_delete", "on");
+    let pool = SqlitePool::connect_with(options).await?;
 
-    // 创建表结构
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS messages (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            sender_id TEXT,
-            receiver_id TEXT,
-            content TEXT,
-            msg_type TEXT,
-            timestamp INTEGER,
-            file_path TEXT,
-            file_status TEXT
-        )",
-    )
-    .execute(&pool)
-    .await?;
+    // 按版本执行数据库迁移（建表、加字段、索引等都在 migrations.rs 中维护）
+    crate::migrations::run(&pool).await?;
 
-    // 数据库迁移：为现有的messages表添加receiver_id字段（如果不存在）
-    let _ = sqlx::query("ALTER TABLE messages ADD COLUMN receiver_id TEXT")
-        .execute(&pool)
-        .await; // 忽略错误，因为字段可能已经存在
+    // 读取加密设置（开启了加密则进入锁定状态）
+    crate::encryption::load_state(&pool).await?;
 
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS settings (
-            key TEXT PRIMARY KEY,
-            value TEXT
-        )",
-    )
-    .execute(&pool)
-    .await?;
+    // 读取访问密码和局域网密钥设置
+    crate::auth::load_state(&pool).await?;
+
+    // 读取（第一次启动时生成）本机证书和已记录的节点证书
+    crate::tls::load_state(&pool).await?;
+
+    // 读取（第一次启动时生成）本机身份密钥
+    crate::e2e::load_state(&pool).await?;
 
     // 初始化配置 (如果没有用户名则生成一个)
     let user_exists = sqlx::query("SELECT value FROM settings WHERE key = 'username'")
@@ -200,11 +195,11 @@ async fn init_db_with_path(app_dir: PathBuf) -> Result<Pool<Sqlite>, sqlx::Error
 
     if user_exists.is_none() {
         let random_name = generate_random_name();
-        println!("[DB] 生成随机用户名: {}", random_name);
+        log::info!("[DB] 生成随机用户名: {}", random_name);
 
         // 生成唯一的 UUID
         let user_id = uuid::Uuid::new_v4().to_string();
-        println!("[DB] 生成用户 ID: {}", user_id);
+        log::info!("[DB] 生成用户 ID: {}", user_id);
 
         sqlx::query("INSERT INTO settings (key, value) VALUES ('username', ?)")
             .bind(random_name)
@@ -228,7 +223,7 @@ async fn init_db_with_path(app_dir: PathBuf) -> Result<Pool<Sqlite>, sqlx::Error
                 .to_string()
         };
 
-        println!("[DB] 设置默认下载路径: {}", download_dir);
+        log::info!("[DB] 设置默认下载路径: {}", download_dir);
 
         sqlx::query("INSERT INTO settings (key, value) VALUES ('download_path', ?)")
             .bind(download_dir)
@@ -241,207 +236,279 @@ async fn init_db_with_path(app_dir: PathBuf) -> Result<Pool<Sqlite>, sqlx::Error
 
 // ==================== 文件相关的数据库函数 ====================
 
-/// 保存文件消息到数据库
-/// 如果存在相同文件名和状态为 uploading 的消息，则更新；否则新建
-pub async fn save_file_message(
+// 当前时间（秒）
+fn now_secs() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// 创建文件传输：同时插入文件消息和传输记录，消息的 msg_uuid 即传输 ID
+/// 返回消息 ID
+pub async fn create_transfer(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    peer_id: String,
-    file_name: String,
-    file_size: usize,
-    file_path: String,
-    status: String,
+    transfer: &crate::models::Transfer,
 ) -> Result<i64, String> {
-    println!(
-        "[DB] 保存文件消息: 文件={}, 大小={}, 状态={}",
-        file_name, file_size, status
+    log::info!(
+        "[DB] 创建文件传输: ID={}, 文件={}, 大小={}, 状态={}",
+        transfer.id, transfer.file_name, transfer.file_size, transfer.status
     );
 
-    // 检查是否存在相同文件名和状态为 uploading 的消息
-    let existing = sqlx::query_as::<_, (i64,)>(
-        "SELECT id FROM messages WHERE receiver_id = ? AND content = ? AND msg_type = 'file' AND file_status = 'uploading' ORDER BY id DESC LIMIT 1"
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| format!("开始事务失败: {}", e))?;
+
+    let result = sqlx::query(
+        "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status, msg_uuid) VALUES (?, ?, ?, 'file', ?, ?, ?, ?)"
     )
-    .bind(&peer_id)
-    .bind(&file_name)
-    .fetch_optional(pool)
+    .bind(&transfer.sender_id)
+    .bind(&transfer.receiver_id)
+    .bind(&transfer.file_name)
+    .bind(transfer.created_at)
+    .bind(&transfer.file_path)
+    .bind(&transfer.status)
+    .bind(&transfer.id)
+    .execute(&mut *tx)
     .await
-    .map_err(|e| format!("查询消息失败: {}", e))?;
-
-    if let Some((msg_id,)) = existing {
-        // 更新现有的 uploading 消息
-        println!(
-            "[DB] 更新现有消息 ID: {}, 状态: {} -> {}",
-            msg_id, "uploading", status
-        );
-        sqlx::query("UPDATE messages SET file_path = ?, file_status = ? WHERE id = ?")
-            .bind(&file_path)
-            .bind(&status)
-            .bind(msg_id)
-            .execute(pool)
-            .await
-            .map_err(|e| format!("更新消息失败: {}", e))?;
+    .map_err(|e| format!("保存消息失败: {}", e))?;
 
-        println!("[DB] 消息已更新");
-        Ok(msg_id)
-    } else {
-        // 插入新消息
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+    sqlx::query(
+        "INSERT INTO transfers (id, sender_id, receiver_id, file_name, file_size, file_path, file_hash, mime_type, status, save_dir, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&transfer.id)
+    .bind(&transfer.sender_id)
+    .bind(&transfer.receiver_id)
+    .bind(&transfer.file_name)
+    .bind(transfer.file_size)
+    .bind(&transfer.file_path)
+    .bind(&transfer.file_hash)
+    .bind(&transfer.mime_type)
+    .bind(&transfer.status)
+    .bind(&transfer.save_dir)
+    .bind(transfer.created_at)
+    .bind(transfer.updated_at)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| format!("创建传输记录失败: {}", e))?;
 
-        let result = sqlx::query(
-            "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status) VALUES ('me', ?, ?, 'file', ?, ?, ?)"
-        )
-        .bind(&peer_id)
-        .bind(&file_name)
-        .bind(timestamp)
-        .bind(&file_path)
-        .bind(&status)
-        .execute(pool)
+    tx.commit()
         .await
-        .map_err(|e| format!("保存消息失败: {}", e))?;
+        .map_err(|e| format!("提交事务失败: {}", e))?;
 
-        let msg_id = result.last_insert_rowid();
-        println!("[DB] 新消息已保存，ID: {}", msg_id);
-        Ok(msg_id)
-    }
+    let msg_id = result.last_insert_rowid();
+    log::info!("[DB] 文件传输已创建，消息 ID: {}", msg_id);
+    Ok(msg_id)
 }
 
-/// 获取下载中的文件（根据发送者ID）
-pub async fn get_downloading_file(
+/// 保存自己发送的文件消息，返回 (消息 ID, 传输 ID)
+pub async fn save_file_message(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    sender_id: &str,
-) -> Result<Option<String>, String> {
-    let row = sqlx::query("SELECT content FROM messages WHERE sender_id = ? AND msg_type = 'file' AND file_status = 'downloading' ORDER BY id DESC LIMIT 1")
-        .bind(sender_id)
+    peer_id: String,
+    file_name: String,
+    file_size: usize,
+    file_path: String,
+    status: String,
+) -> Result<(i64, String), String> {
+    let now = now_secs();
+    let transfer = crate::models::Transfer {
+        id: uuid::Uuid::new_v4().to_string(),
+        sender_id: "me".to_string(),
+        receiver_id: peer_id,
+        file_name,
+        file_size: file_size as i64,
+        file_path,
+        file_hash: None,
+        mime_type: None,
+        status,
+        save_dir: None,
+        created_at: now,
+        updated_at: now,
+    };
+
+    let msg_id = create_transfer(pool, &transfer).await?;
+    Ok((msg_id, transfer.id))
+}
+
+/// 按传输 ID 查找传输记录
+pub async fn get_transfer(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    transfer_id: &str,
+) -> Result<Option<crate::models::Transfer>, String> {
+    sqlx::query_as::<_, crate::models::Transfer>("SELECT * FROM transfers WHERE id = ?")
+        .bind(transfer_id)
         .fetch_optional(pool)
         .await
-        .map_err(|e| format!("查询文件失败: {}", e))?;
+        .map_err(|e| format!("查询传输记录失败: {}", e))
+}
 
-    if let Some(row) = row {
-        use sqlx::Row;
-        let file_name: String = row.get("content");
-        Ok(Some(file_name))
-    } else {
-        Ok(None)
-    }
+/// 获取某个发送者最近一次正在接收的传输（旧版本发送方的后续分块不带传输 ID）
+pub async fn get_downloading_transfer(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    sender_id: &str,
+) -> Result<Option<crate::models::Transfer>, String> {
+    sqlx::query_as::<_, crate::models::Transfer>(
+        "SELECT * FROM transfers WHERE sender_id = ? AND status = 'downloading' ORDER BY created_at DESC, rowid DESC LIMIT 1",
+    )
+    .bind(sender_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| format!("查询传输记录失败: {}", e))
+}
+
+/// 获取最近更新的传输记录（收发双向），按更新时间倒序
+pub async fn get_recent_transfers(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    limit: i64,
+) -> Result<Vec<crate::models::Transfer>, String> {
+    sqlx::query_as::<_, crate::models::Transfer>(
+        "SELECT * FROM transfers ORDER BY updated_at DESC, rowid DESC LIMIT ?",
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("查询传输记录失败: {}", e))
 }
 
-/// 更新文件状态（从 downloading 到 accepted）
-pub async fn update_file_status(
+/// 更新传输状态（文件消息的状态由触发器同步）
+pub async fn update_transfer_status(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    file_name: &str,
+    transfer_id: &str,
     new_status: &str,
 ) -> Result<(), String> {
-    println!("[DB] 更新文件状态: {} -> {}", file_name, new_status);
+    log::info!("[DB] 更新传输状态: {} -> {}", transfer_id, new_status);
 
-    sqlx::query(
-        "UPDATE messages SET file_status = ? WHERE content = ? AND msg_type = 'file' AND file_status = 'downloading'"
-    )
-    .bind(new_status)
-    .bind(file_name)
-    .execute(pool)
-    .await
-    .map_err(|e| format!("更新文件状态失败: {}", e))?;
+    let result = sqlx::query("UPDATE transfers SET status = ?, updated_at = ? WHERE id = ?")
+        .bind(new_status)
+        .bind(now_secs())
+        .bind(transfer_id)
+        .execute(pool)
+        .await
+        .map_err(|e| format!("更新传输状态失败: {}", e))?;
 
-    println!("[DB] 文件状态已更新");
+    if result.rows_affected() == 0 {
+        return Err(format!("传输不存在: {}", transfer_id));
+    }
     Ok(())
 }
 
-/// 创建文件接收记录（Web端发送文件时）
-pub async fn create_upload_record(
+/// 接受一个等待确认的传输（可以指定保存目录），返回是否有记录被更新
+pub async fn accept_transfer(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    receiver_id: String,
-    file_name: String,
-    timestamp: i64,
-) -> Result<i64, String> {
-    println!(
-        "[DB] 创建上传记录: 接收者={}, 文件={}",
-        receiver_id, file_name
-    );
-
+    transfer_id: &str,
+    save_dir: Option<&str>,
+) -> Result<bool, String> {
     let result = sqlx::query(
-        "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status) VALUES ('me', ?, ?, 'file', ?, '', 'uploading')"
+        "UPDATE transfers SET status = 'downloading', save_dir = ?, updated_at = ?
+         WHERE id = ? AND status = 'pending' AND sender_id != 'me'",
     )
-    .bind(&receiver_id)
-    .bind(&file_name)
-    .bind(timestamp)
+    .bind(save_dir)
+    .bind(now_secs())
+    .bind(transfer_id)
     .execute(pool)
     .await
-    .map_err(|e| format!("创建记录失败: {}", e))?;
-
-    let msg_id = result.last_insert_rowid();
-    println!("[DB] 上传记录已创建，ID: {}", msg_id);
-    Ok(msg_id)
+    .map_err(|e| format!("更新传输状态失败: {}", e))?;
+    Ok(result.rows_affected() > 0)
 }
 
-/// 更新上传状态
-pub async fn update_upload_status(
+/// 更新传输的文件路径和状态（接收文件后移动到保存位置时）
+pub async fn update_transfer_path(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    file_name: String,
-    status: String,
+    transfer_id: &str,
+    new_path: &str,
+    new_status: &str,
 ) -> Result<(), String> {
-    println!("[DB] 更新上传状态: {} -> {}", file_name, status);
-
-    sqlx::query(
-        "UPDATE messages SET file_status = ? WHERE sender_id = 'me' AND content = ? AND file_status = 'uploading'"
-    )
-    .bind(&status)
-    .bind(&file_name)
-    .execute(pool)
-    .await
-    .map_err(|e| format!("更新状态失败: {}", e))?;
+    log::info!(
+        "[DB] 更新传输路径: {} -> {}, 状态: {}",
+        transfer_id, new_path, new_status
+    );
 
-    println!("[DB] 上传状态已更新");
+    sqlx::query("UPDATE transfers SET file_path = ?, status = ?, updated_at = ? WHERE id = ?")
+        .bind(new_path)
+        .bind(new_status)
+        .bind(now_secs())
+        .bind(transfer_id)
+        .execute(pool)
+        .await
+        .map_err(|e| format!("更新传输路径失败: {}", e))?;
     Ok(())
 }
 
-/// 删除上传记录（上传失败时）
-pub async fn delete_upload_record(
+/// 记录文件的 SHA-256（发送完成或接收方校验通过后）
+pub async fn set_transfer_hash(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    file_name: String,
-    timestamp: i64,
+    transfer_id: &str,
+    file_hash: &str,
 ) -> Result<(), String> {
-    println!("[DB] 删除上传记录: {}", file_name);
-
-    sqlx::query(
-        "DELETE FROM messages WHERE sender_id = 'me' AND content = ? AND timestamp = ? AND file_status = 'uploading'"
-    )
-    .bind(&file_name)
-    .bind(timestamp)
-    .execute(pool)
-    .await
-    .map_err(|e| format!("删除记录失败: {}", e))?;
-
-    println!("[DB] 上传记录已删除");
+    sqlx::query("UPDATE transfers SET file_hash = ?, updated_at = ? WHERE id = ?")
+        .bind(file_hash)
+        .bind(now_secs())
+        .bind(transfer_id)
+        .execute(pool)
+        .await
+        .map_err(|e| format!("更新文件校验值失败: {}", e))?;
     Ok(())
 }
 
-/// 更新文件状态（通过消息ID）
-pub async fn update_file_status_by_id(
+/// 删除传输（Web 端上传失败时），传输记录随文件消息由触发器删除
+pub async fn delete_transfer(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    msg_id: i64,
-    new_status: &str,
+    transfer_id: &str,
 ) -> Result<(), String> {
-    println!("[DB] 更新文件状态（ID: {}）: -> {}", msg_id, new_status);
+    log::info!("[DB] 删除传输: {}", transfer_id);
 
-    sqlx::query("UPDATE messages SET file_status = ? WHERE id = ?")
-        .bind(new_status)
-        .bind(msg_id)
+    sqlx::query("DELETE FROM messages WHERE msg_uuid = ? AND msg_type = 'file'")
+        .bind(transfer_id)
         .execute(pool)
         .await
-        .map_err(|e| format!("更新文件状态失败: {}", e))?;
-
-    println!("[DB] 文件状态已更新");
+        .map_err(|e| format!("删除记录失败: {}", e))?;
     Ok(())
 }
 
+/// 发送方下线时，正在接收的它的文件标记为 interrupted（对方续传时恢复为 downloading）
+pub async fn mark_interrupted_downloads(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    sender_id: &str,
+) -> Result<u64, String> {
+    let result = sqlx::query(
+        "UPDATE transfers SET status = 'interrupted', updated_at = ? WHERE status = 'downloading' AND sender_id = ?",
+    )
+    .bind(now_secs())
+    .bind(sender_id)
+    .execute(pool)
+    .await
+    .map_err(|e| format!("更新接收状态失败: {}", e))?;
+
+    if result.rows_affected() > 0 {
+        log::info!("[DB] {} 个未接收完的文件已标记为中断", result.rows_affected());
+    }
+    Ok(result.rows_affected())
+}
+
+/// 节点启动和退出时，没有完成的上传都标记为 interrupted（可以手动续传）
+/// 不在打开数据库时调用：命令行客户端可能和正在运行的节点共用数据库
+pub async fn mark_interrupted_uploads(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<u64, String> {
+    let result = sqlx::query(
+        "UPDATE transfers SET status = 'interrupted', updated_at = ? WHERE status IN ('uploading', 'waiting')",
+    )
+    .bind(now_secs())
+    .execute(pool)
+    .await
+    .map_err(|e| format!("更新上传状态失败: {}", e))?;
+
+    if result.rows_affected() > 0 {
+        log::info!("[DB] {} 个未完成的上传已标记为中断", result.rows_affected());
+    }
+    Ok(result.rows_affected())
+}
+
 /// 删除消息（通过消息ID）
 pub async fn delete_message_by_id(
     pool: &sqlx::Pool<sqlx::Sqlite>,
     msg_id: i64,
 ) -> Result<(), String> {
-    println!("[DB] 删除消息: ID {}", msg_id);
+    log::info!("[DB] 删除消息: ID {}", msg_id);
 
     sqlx::query("DELETE FROM messages WHERE id = ?")
         .bind(msg_id)
@@ -449,17 +516,19 @@ pub async fn delete_message_by_id(
         .await
         .map_err(|e| format!("删除消息失败: {}", e))?;
 
-    println!("[DB] 消息已删除");
+    log::info!("[DB] 消息已删除");
     Ok(())
 }
 
 /// 保存文本消息
+/// msg_uuid 必须与发给对方的帧中的 msg_id 一致，双方才能对齐同一条消息
 pub async fn save_text_message(
     pool: &sqlx::Pool<sqlx::Sqlite>,
     receiver_id: String,
+    msg_uuid: String,
     content: String,
 ) -> Result<(), String> {
-    println!(
+    log::info!(
         "[DB] 保存文本消息: 接收者={}, 内容长度={}",
         receiver_id,
         content.len()
@@ -470,17 +539,20 @@ pub async fn save_text_message(
         .unwrap()
         .as_secs() as i64;
 
+    let stored = crate::encryption::encrypt_content(&content)?;
+
     sqlx::query(
-        "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp) VALUES ('me', ?, ?, 'text', ?)"
+        "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, msg_uuid) VALUES ('me', ?, ?, 'text', ?, ?)"
     )
     .bind(&receiver_id)
-    .bind(&content)
+    .bind(&stored)
     .bind(timestamp)
+    .bind(&msg_uuid)
     .execute(pool)
     .await
     .map_err(|e| format!("保存消息失败: {}", e))?;
 
-    println!("[DB] 文本消息已保存");
+    log::info!("[DB] 文本消息已保存");
     Ok(())
 }
 
@@ -489,7 +561,7 @@ pub async fn save_current_theme(
     pool: &sqlx::Pool<sqlx::Sqlite>,
     theme_name: String,
 ) -> Result<(), String> {
-    println!("[DB] 保存当前主题: {}", theme_name);
+    log::info!("[DB] 保存当前主题: {}", theme_name);
 
     sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES ('current_theme', ?)")
         .bind(&theme_name)
@@ -497,7 +569,7 @@ pub async fn save_current_theme(
         .await
         .map_err(|e| format!("保存主题失败: {}", e))?;
 
-    println!("[DB] 主题已保存");
+    log::info!("[DB] 主题已保存");
     Ok(())
 }
 
@@ -512,129 +584,1557 @@ pub async fn get_current_theme(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<Option
     Ok(result)
 }
 
+/// 获取控制接口的监听地址（settings 表中的 control_addr，没有设置时返回 None）
+pub async fn get_control_addr(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<Option<String>, String> {
+    sqlx::query_scalar::<_, String>("SELECT value FROM settings WHERE key = 'control_addr'")
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| format!("查询控制接口地址失败: {}", e))
+}
+
 /// 保存接收到的文本消息（来自其他对等体）
 pub async fn save_received_text_message(
     pool: &sqlx::Pool<sqlx::Sqlite>,
     sender_id: String,
+    msg_uuid: Option<String>,
     content: String,
     msg_type: String,
     timestamp: i64,
 ) -> Result<(), String> {
-    println!(
+    log::info!(
         "[DB] 保存接收到的文本消息: 发送者={}, 内容长度={}",
         sender_id,
         content.len()
     );
 
+    let stored = crate::encryption::encrypt_content(&content)?;
+
     sqlx::query(
-        "INSERT INTO messages (sender_id, content, msg_type, timestamp) VALUES (?, ?, ?, ?)",
+        "INSERT OR IGNORE INTO messages (sender_id, content, msg_type, timestamp, msg_uuid) VALUES (?, ?, ?, ?, ?)",
     )
     .bind(&sender_id)
-    .bind(&content)
+    .bind(&stored)
     .bind(&msg_type)
     .bind(timestamp)
+    // 旧版本的对方不会发送 msg_id，此时本地生成一个
+    .bind(msg_uuid.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()))
     .execute(pool)
     .await
     .map_err(|e| format!("保存消息失败: {}", e))?;
 
-    println!("[DB] 接收到的消息已保存");
+    log::info!("[DB] 接收到的消息已保存");
     Ok(())
 }
 
-/// 更新文件状态（通过文件路径）
-pub async fn update_file_status_by_path(
+/// 最新一条消息的本地 ID（没有消息时为 0）
+pub async fn get_last_message_id(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<i64, String> {
+    sqlx::query_scalar::<_, i64>("SELECT COALESCE(MAX(id), 0) FROM messages")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| format!("查询消息失败: {}", e))
+}
+
+/// 本地 ID 大于 after_id 的收到的消息（按 ID 顺序），命令行客户端用来轮询新消息
+pub async fn get_received_messages_after(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    old_path: &str,
-    new_path: &str,
-    new_status: &str,
+    after_id: i64,
+) -> Result<Vec<crate::models::Message>, String> {
+    let my_id = get_user_id(pool).await?;
+    let mut messages = sqlx::query_as::<_, crate::models::Message>(
+        "SELECT id, sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status, msg_uuid
+         FROM messages
+         WHERE id > ? AND sender_id != 'me' AND sender_id != ?
+         ORDER BY id
+         LIMIT 100",
+    )
+    .bind(after_id)
+    .bind(&my_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("查询消息失败: {}", e))?;
+
+    for message in messages.iter_mut() {
+        message.content = crate::encryption::decrypt_content(&message.content)?;
+    }
+    Ok(messages)
+}
+
+/// 通过本地消息 ID 获取跨节点消息 UUID
+pub async fn get_message_uuid(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    msg_id: i64,
+) -> Result<Option<String>, String> {
+    sqlx::query_scalar::<_, Option<String>>("SELECT msg_uuid FROM messages WHERE id = ?")
+        .bind(msg_id)
+        .fetch_optional(pool)
+        .await
+        .map(|row| row.flatten())
+        .map_err(|e| format!("查询消息失败: {}", e))
+}
+
+// ==================== 会话列表相关的数据库函数 ====================
+
+/// 获取会话列表，按最后一条消息时间倒序
+/// conversations 表由 messages 上的触发器维护，这里只负责读取
+pub async fn get_conversations(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<Vec<crate::models::Conversation>, String> {
+    let mut conversations = sqlx::query_as::<_, crate::models::Conversation>(
+        "SELECT peer_id, last_message, last_msg_type, last_sender_id, last_timestamp, unread_count
+         FROM conversations
+         ORDER BY last_timestamp DESC",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("查询会话列表失败: {}", e))?;
+
+    for conversation in conversations.iter_mut() {
+        if let Some(message) = &conversation.last_message {
+            conversation.last_message = Some(crate::encryption::decrypt_content(message)?);
+        }
+    }
+    Ok(conversations)
+}
+
+/// 将与某个用户的会话标记为已读
+pub async fn mark_conversation_read(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    peer_id: &str,
 ) -> Result<(), String> {
-    println!(
-        "[DB] 更新文件状态（路径）: {} -> {}, 状态: {}",
-        old_path, new_path, new_status
-    );
+    log::info!("[DB] 标记会话已读: {}", peer_id);
 
-    sqlx::query("UPDATE messages SET file_status = ?, file_path = ? WHERE file_path = ?")
-        .bind(new_status)
-        .bind(new_path)
-        .bind(old_path)
+    sqlx::query("UPDATE conversations SET unread_count = 0 WHERE peer_id = ?")
+        .bind(peer_id)
         .execute(pool)
         .await
-        .map_err(|e| format!("更新文件状态失败: {}", e))?;
+        .map_err(|e| format!("标记已读失败: {}", e))?;
 
-    println!("[DB] 文件状态已更新");
     Ok(())
 }
 
-/// 获取所有文件消息（用于调试）
-pub async fn get_all_file_messages(
-    pool: &sqlx::Pool<sqlx::Sqlite>,
-    limit: i64,
-) -> Result<Vec<(i64, String, String, String, String)>, String> {
-    println!("[DB] 获取所有文件消息（限制: {}）", limit);
+// ==================== 表情回应相关的数据库函数 ====================
+
+/// 校验表情回应内容（只允许单个短表情，防止被当成消息通道）
+pub fn validate_emoji(emoji: &str) -> Result<(), String> {
+    let emoji = emoji.trim();
+    if emoji.is_empty() {
+        return Err("表情不能为空".to_string());
+    }
+    if emoji.chars().count() > 16 || !is_emoji_sequence(emoji) {
+        return Err("无效的表情".to_string());
+    }
+    Ok(())
+}
+
+// 表情序列：由表情字符（包括肤色）和连接用的组件（零宽连接符、变体选择符、标签）组成，
+// 数字和 # * 只能作为键帽（1️⃣ #️⃣）出现
+fn is_emoji_sequence(text: &str) -> bool {
+    let mut chars = text.chars().peekable();
+    let mut has_emoji = false;
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '#' || c == '*' {
+            chars.next_if_eq(&'\u{FE0F}');
+            if chars.next_if_eq(&'\u{20E3}').is_none() {
+                return false;
+            }
+            has_emoji = true;
+        } else if is_emoji_char(c) {
+            has_emoji = true;
+        } else if !is_emoji_component(c) {
+            return false;
+        }
+    }
+    has_emoji
+}
 
-    let rows = sqlx::query_as::<_, (i64, String, String, String, String)>(
-        "SELECT id, sender_id, content, file_path, file_status FROM messages WHERE msg_type = 'file' ORDER BY id DESC LIMIT ?"
+fn is_emoji_char(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x00A9 | 0x00AE | 0x203C | 0x2049 | 0x2122 | 0x2139 | 0x24C2 | 0x2934 | 0x2935
+            | 0x3030 | 0x303D | 0x3297 | 0x3299
+            | 0x2194..=0x21AA
+            | 0x2300..=0x23FF
+            | 0x25AA..=0x25FE
+            | 0x2600..=0x27BF
+            | 0x2B00..=0x2BFF
+            | 0x1F000..=0x1FAFF
     )
-    .bind(limit)
-    .fetch_all(pool)
-    .await
-    .map_err(|e| format!("查询文件消息失败: {}", e))?;
+}
 
-    println!("[DB] 找到 {} 条文件消息", rows.len());
-    Ok(rows)
+fn is_emoji_component(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x200D | 0xFE0E | 0xFE0F | 0xE0020..=0xE007F
+    )
 }
 
-/// 查询待接收的文件（通过文件路径模糊匹配）
-pub async fn get_pending_file_by_path(
+// 其他节点只能回应和自己之间的消息（对方发来的或发给对方的），本机用户可以回应任何消息
+async fn check_reactor(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    path_pattern: &str,
-) -> Result<Option<(String, String)>, String> {
-    println!("[DB] 查询待接收文件: 路径模式={}", path_pattern);
-
-    let row = sqlx::query_as::<_, (String, String)>(
-        "SELECT file_path, content FROM messages WHERE file_path LIKE ? AND file_status = 'pending'"
+    message_id: &str,
+    reactor_id: &str,
+) -> Result<(), String> {
+    if reactor_id == get_user_id(pool).await? {
+        return Ok(());
+    }
+    let involved = sqlx::query(
+        "SELECT 1 FROM messages WHERE msg_uuid = ? AND (sender_id = ? OR receiver_id = ?)",
     )
-    .bind(path_pattern)
+    .bind(message_id)
+    .bind(reactor_id)
+    .bind(reactor_id)
     .fetch_optional(pool)
     .await
-    .map_err(|e| format!("查询文件失败: {}", e))?;
-
-    if let Some((path, name)) = &row {
-        println!("[DB] 找到待接收文件: {} ({})", name, path);
-    } else {
-        println!("[DB] 未找到待接收文件");
+    .map_err(|e| format!("查询消息失败: {}", e))?
+    .is_some();
+    if !involved {
+        return Err(format!("节点 {} 不能回应消息 {}", reactor_id, message_id));
     }
-
-    Ok(row)
+    Ok(())
 }
 
-/// 创建接收文件记录（接收来自其他对等体的文件）
-pub async fn create_received_file_record(
+/// 添加表情回应（重复添加会被忽略）
+pub async fn add_reaction(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    sender_id: String,
-    file_name: String,
-    file_path: String,
+    message_id: &str,
+    reactor_id: &str,
+    emoji: &str,
     timestamp: i64,
-) -> Result<i64, String> {
-    // 获取当前用户ID作为接收者
-    let my_id = get_user_id(pool).await?;
+) -> Result<(), String> {
+    log::info!(
+        "[DB] 添加表情回应: 消息={}, 用户={}, 表情={}",
+        message_id, reactor_id, emoji
+    );
+    check_reactor(pool, message_id, reactor_id).await?;
 
-    let result = sqlx::query(
-        "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status) VALUES (?, ?, ?, 'file', ?, ?, 'downloading')"
+    sqlx::query(
+        "INSERT OR IGNORE INTO reactions (message_id, reactor_id, emoji, timestamp) VALUES (?, ?, ?, ?)",
     )
-    .bind(&sender_id)
-    .bind(&my_id)
-    .bind(&file_name)
+    .bind(message_id)
+    .bind(reactor_id)
+    .bind(emoji)
     .bind(timestamp)
-    .bind(&file_path)
     .execute(pool)
     .await
-    .map_err(|e| format!("创建记录失败: {}", e))?;
+    .map_err(|e| format!("保存表情回应失败: {}", e))?;
 
-    let msg_id = result.last_insert_rowid();
-    println!(
-        "[DB] ✓ 接收文件记录已创建，ID: {}, 文件: {}, 状态: downloading",
-        msg_id, file_name
+    Ok(())
+}
+
+/// 取消表情回应
+pub async fn remove_reaction(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    message_id: &str,
+    reactor_id: &str,
+    emoji: &str,
+) -> Result<(), String> {
+    log::info!(
+        "[DB] 取消表情回应: 消息={}, 用户={}, 表情={}",
+        message_id, reactor_id, emoji
     );
-    Ok(msg_id)
+    check_reactor(pool, message_id, reactor_id).await?;
+
+    sqlx::query("DELETE FROM reactions WHERE message_id = ? AND reactor_id = ? AND emoji = ?")
+        .bind(message_id)
+        .bind(reactor_id)
+        .bind(emoji)
+        .execute(pool)
+        .await
+        .map_err(|e| format!("删除表情回应失败: {}", e))?;
+
+    Ok(())
+}
+
+/// 批量获取消息的表情回应，按 (消息, 表情) 聚合
+pub async fn get_reactions_for_messages(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    message_ids: &[String],
+) -> Result<std::collections::HashMap<String, Vec<crate::models::ReactionSummary>>, String> {
+    let mut result: std::collections::HashMap<String, Vec<crate::models::ReactionSummary>> =
+        std::collections::HashMap::new();
+
+    if message_ids.is_empty() {
+        return Ok(result);
+    }
+
+    let placeholders = vec!["?"; message_ids.len()].join(", ");
+    let sql = format!(
+        "SELECT message_id, emoji, COUNT(*), GROUP_CONCAT(reactor_id) FROM reactions
+         WHERE message_id IN ({})
+         GROUP BY message_id, emoji
+         ORDER BY MIN(timestamp) ASC",
+        placeholders
+    );
+
+    let mut query = sqlx::query_as::<_, (String, String, i64, String)>(&sql);
+    for id in message_ids {
+        query = query.bind(id);
+    }
+
+    let rows = query
+        .fetch_all(pool)
+        .await
+        .map_err(|e| format!("查询表情回应失败: {}", e))?;
+
+    for (message_id, emoji, count, reactors) in rows {
+        result
+            .entry(message_id)
+            .or_default()
+            .push(crate::models::ReactionSummary {
+                emoji,
+                count,
+                reactors: reactors.split(',').map(|s| s.to_string()).collect(),
+            });
+    }
+
+    Ok(result)
+}
+
+// ==================== 全文搜索相关的数据库函数 ====================
+
+// 高亮标记先用私有区字符占位，HTML 转义之后再替换成 <mark>
+const HIGHLIGHT_START: char = '\u{E000}';
+const HIGHLIGHT_END: char = '\u{E001}';
+// 加密数据库搜索时每批解密的消息数
+const ENCRYPTED_SEARCH_BATCH: i64 = 500;
+
+/// 搜索聊天记录（文本内容和文件名）
+pub async fn search_messages(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    query: &crate::models::SearchQuery,
+) -> Result<Vec<crate::models::SearchResult>, String> {
+    let terms: Vec<&str> = query.q.split_whitespace().collect();
+    if terms.is_empty() {
+        return Err("搜索关键词不能为空".to_string());
+    }
+
+    log::info!("[DB] 搜索消息: {:?}", terms);
+
+    let my_id = get_user_id(pool).await?;
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    // 开启加密后全文索引里只有密文，只能解密后逐条匹配
+    if crate::encryption::is_enabled() {
+        return search_encrypted(pool, query, &terms, &my_id, limit, offset).await;
+    }
+
+    // trigram 索引只能匹配 3 个字符及以上的关键词，更短的关键词退回 LIKE 扫描
+    let use_fts = terms.iter().all(|t| t.chars().count() >= 3);
+
+    let mut sql = String::from(
+        "SELECT m.msg_uuid, m.sender_id,
+                CASE WHEN m.sender_id IN (?, 'me') THEN m.receiver_id ELSE m.sender_id END AS peer_id,
+                m.msg_type, m.timestamp, m.content, ",
+    );
+
+    if use_fts {
+        sql.push_str(&format!(
+            "snippet(messages_fts, 0, '{}', '{}', '…', 40)
+             FROM messages_fts JOIN messages m ON m.id = messages_fts.rowid
+             WHERE messages_fts MATCH ?",
+            HIGHLIGHT_START, HIGHLIGHT_END
+        ));
+    } else {
+        sql.push_str("NULL FROM messages m WHERE 1 = 1");
+        for _ in &terms {
+            sql.push_str(" AND m.content LIKE ? ESCAPE '\\'");
+        }
+    }
+
+    if query.peer_id.is_some() {
+        sql.push_str(" AND ((m.sender_id IN (?, 'me') AND m.receiver_id = ?) OR m.sender_id = ?)");
+    }
+    if query.since.is_some() {
+        sql.push_str(" AND m.timestamp >= ?");
+    }
+    if query.until.is_some() {
+        sql.push_str(" AND m.timestamp <= ?");
+    }
+    if query.msg_type.is_some() {
+        sql.push_str(" AND m.msg_type = ?");
+    }
+    sql.push_str(" ORDER BY m.timestamp DESC LIMIT ? OFFSET ?");
+
+    let mut q = sqlx::query_as::<
+        _,
+        (
+            Option<String>,
+            String,
+            Option<String>,
+            String,
+            i64,
+            Option<String>,
+            Option<String>,
+        ),
+    >(&sql)
+    .bind(&my_id);
+
+    if use_fts {
+        // 每个关键词单独加引号，避免用户输入被当成 FTS 语法
+        let match_expr = terms
+            .iter()
+            .map(|t| format!("\"{}\"", t.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        q = q.bind(match_expr);
+    } else {
+        for t in &terms {
+            let escaped = t
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            q = q.bind(format!("%{}%", escaped));
+        }
+    }
+
+    if let Some(peer_id) = &query.peer_id {
+        q = q.bind(&my_id).bind(peer_id).bind(peer_id);
+    }
+    if let Some(since) = query.since {
+        q = q.bind(since);
+    }
+    if let Some(until) = query.until {
+        q = q.bind(until);
+    }
+    if let Some(msg_type) = &query.msg_type {
+        q = q.bind(msg_type);
+    }
+
+    let rows = q
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| format!("搜索失败: {}", e))?;
+
+    let results: Vec<crate::models::SearchResult> = rows
+        .into_iter()
+        .map(
+            |(message_id, sender_id, peer_id, msg_type, timestamp, content, snippet)| {
+                let content = content.unwrap_or_default();
+                let raw_snippet = snippet.unwrap_or_else(|| mark_terms(&content, &terms));
+                crate::models::SearchResult {
+                    message_id,
+                    from_id: if sender_id == my_id {
+                        "me".to_string()
+                    } else {
+                        sender_id
+                    },
+                    peer_id,
+                    file_name: if msg_type == "file" {
+                        Some(content.clone())
+                    } else {
+                        None
+                    },
+                    msg_type,
+                    timestamp,
+                    content,
+                    snippet: highlight_to_html(&raw_snippet),
+                }
+            },
+        )
+        .collect();
+
+    log::info!("[DB] 找到 {} 条匹配的消息", results.len());
+    Ok(results)
+}
+
+// 加密数据库的搜索：按条件分批取出候选消息（从新到旧），解密后在内存中匹配关键词，找够一页就停止
+async fn search_encrypted(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    query: &crate::models::SearchQuery,
+    terms: &[&str],
+    my_id: &str,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<crate::models::SearchResult>, String> {
+    let mut sql = String::from(
+        "SELECT m.msg_uuid, m.sender_id,
+                CASE WHEN m.sender_id IN (?, 'me') THEN m.receiver_id ELSE m.sender_id END AS peer_id,
+                m.msg_type, m.timestamp, m.content
+         FROM messages m WHERE 1 = 1",
+    );
+    if query.peer_id.is_some() {
+        sql.push_str(" AND ((m.sender_id IN (?, 'me') AND m.receiver_id = ?) OR m.sender_id = ?)");
+    }
+    if query.since.is_some() {
+        sql.push_str(" AND m.timestamp >= ?");
+    }
+    if query.until.is_some() {
+        sql.push_str(" AND m.timestamp <= ?");
+    }
+    if query.msg_type.is_some() {
+        sql.push_str(" AND m.msg_type = ?");
+    }
+    sql.push_str(" ORDER BY m.timestamp DESC, m.id DESC LIMIT ? OFFSET ?");
+
+    let lower_terms: Vec<String> = terms.iter().map(|t| t.to_lowercase()).collect();
+    let mut results = Vec::new();
+    let mut skipped = 0;
+    let mut scanned = 0;
+
+    loop {
+        type Row = (
+            Option<String>,
+            String,
+            Option<String>,
+            String,
+            i64,
+            Option<String>,
+        );
+        let mut q = sqlx::query_as::<_, Row>(&sql).bind(my_id);
+        if let Some(peer_id) = &query.peer_id {
+            q = q.bind(my_id).bind(peer_id).bind(peer_id);
+        }
+        if let Some(since) = query.since {
+            q = q.bind(since);
+        }
+        if let Some(until) = query.until {
+            q = q.bind(until);
+        }
+        if let Some(msg_type) = &query.msg_type {
+            q = q.bind(msg_type);
+        }
+        let rows = q
+            .bind(ENCRYPTED_SEARCH_BATCH)
+            .bind(scanned)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| format!("搜索失败: {}", e))?;
+        let last_batch = (rows.len() as i64) < ENCRYPTED_SEARCH_BATCH;
+        scanned += rows.len() as i64;
+
+        for (message_id, sender_id, peer_id, msg_type, timestamp, content) in rows {
+            let content = crate::encryption::decrypt_content(&content.unwrap_or_default())?;
+            let lower = content.to_lowercase();
+            if !lower_terms.iter().all(|t| lower.contains(t.as_str())) {
+                continue;
+            }
+            if skipped < offset {
+                skipped += 1;
+                continue;
+            }
+
+            results.push(crate::models::SearchResult {
+                message_id,
+                from_id: if sender_id == my_id {
+                    "me".to_string()
+                } else {
+                    sender_id
+                },
+                peer_id,
+                file_name: if msg_type == "file" {
+                    Some(content.clone())
+                } else {
+                    None
+                },
+                msg_type,
+                timestamp,
+                snippet: highlight_to_html(&mark_terms(&content, terms)),
+                content,
+            });
+            if results.len() as i64 >= limit {
+                break;
+            }
+        }
+        if last_batch || results.len() as i64 >= limit {
+            break;
+        }
+    }
+
+    log::info!("[DB] 找到 {} 条匹配的消息", results.len());
+    Ok(results)
+}
+
+// LIKE 回退路径没有 snippet()，手动标记第一个命中的关键词并截取上下文
+fn mark_terms(content: &str, terms: &[&str]) -> String {
+    let lower = content.to_lowercase();
+    let hit = terms
+        .iter()
+        .filter_map(|t| lower.find(&t.to_lowercase()).map(|pos| (pos, t.len())))
+        .min_by_key(|(pos, _)| *pos);
+
+    let Some((pos, len)) = hit else {
+        return content.chars().take(64).collect();
+    };
+    // 小写转换可能改变字节长度，对不上时直接返回原文开头
+    if lower.len() != content.len()
+        || !content.is_char_boundary(pos)
+        || !content.is_char_boundary(pos + len)
+    {
+        return content.chars().take(64).collect();
+    }
+
+    let before: String = content[..pos]
+        .chars()
+        .rev()
+        .take(24)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    let after: String = content[pos + len..].chars().take(40).collect();
+    format!(
+        "{}{}{}{}{}{}{}",
+        if before.len() < pos { "…" } else { "" },
+        before,
+        HIGHLIGHT_START,
+        &content[pos..pos + len],
+        HIGHLIGHT_END,
+        after,
+        if pos + len + after.len() < content.len() {
+            "…"
+        } else {
+            ""
+        }
+    )
+}
+
+// 转义 HTML 后把占位符替换为 <mark> 标签，前端可以直接用 innerHTML 渲染
+fn highlight_to_html(snippet: &str) -> String {
+    let mut out = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            HIGHLIGHT_START => out.push_str("<mark>"),
+            HIGHLIGHT_END => out.push_str("</mark>"),
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// ==================== 聊天记录导出相关的数据库函数 ====================
+
+/// 导出文件的格式版本，导入时用来判断兼容性
+pub const EXPORT_FORMAT_VERSION: i64 = 1;
+
+/// 导出结果（文件名、MIME 类型和内容）
+pub struct ExportArchive {
+    pub file_name: String,
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// 导出聊天记录
+/// peer_id 为 None 时导出全部记录；format 支持 json / markdown / html；
+/// bundle_files 为 true 时打包成 zip，并附带已接收的文件
+pub async fn export_history(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    peer_id: Option<&str>,
+    format: &str,
+    bundle_files: bool,
+) -> Result<ExportArchive, String> {
+    let (extension, content_type) = match format {
+        "json" => ("json", "application/json"),
+        "markdown" | "md" => ("md", "text/markdown; charset=utf-8"),
+        "html" => ("html", "text/html; charset=utf-8"),
+        _ => return Err(format!("不支持的导出格式: {}", format)),
+    };
+
+    let my_id = get_user_id(pool).await?;
+    let my_name = get_username(pool).await?;
+
+    log::info!(
+        "[DB] 导出聊天记录: 对象={}, 格式={}, 打包文件={}",
+        peer_id.unwrap_or("全部"),
+        extension,
+        bundle_files
+    );
+
+    let rows = match peer_id {
+        Some(peer_id) => sqlx::query_as::<_, crate::models::Message>(
+            "SELECT id, sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status, msg_uuid
+             FROM messages
+             WHERE
+                (sender_id = ? AND receiver_id = ?) OR
+                (sender_id = ? AND (receiver_id = ? OR receiver_id IS NULL)) OR
+                (sender_id = 'me' AND receiver_id = ?)
+             ORDER BY timestamp ASC, id ASC",
+        )
+        .bind(&my_id)
+        .bind(peer_id)
+        .bind(peer_id)
+        .bind(&my_id)
+        .bind(peer_id),
+        None => sqlx::query_as::<_, crate::models::Message>(
+            "SELECT id, sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status, msg_uuid
+             FROM messages
+             ORDER BY timestamp ASC, id ASC",
+        ),
+    }
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("查询聊天记录失败: {}", e))?;
+
+    let mut messages = Vec::with_capacity(rows.len());
+    for mut msg in rows {
+        msg.content = crate::encryption::decrypt_content(&msg.content)?;
+        messages.push(crate::models::ExportedMessage::from_message(msg, &my_id));
+    }
+
+    // 需要打包的文件：只打包别人发来的、本地仍然存在的文件
+    let mut attachments: Vec<(String, PathBuf)> = Vec::new();
+    if bundle_files {
+        for msg in messages.iter_mut() {
+            if msg.from_id == my_id {
+                continue;
+            }
+            let Some(file) = msg.file.as_mut() else {
+                continue;
+            };
+            let path = PathBuf::from(&file.path);
+            if file.path.is_empty() || !path.is_file() {
+                continue;
+            }
+            let archived = format!(
+                "files/{}_{}",
+                crate::utils::sanitize_file_name(msg.message_id.as_deref().unwrap_or("unknown")),
+                crate::utils::sanitize_file_name(&file.name)
+            );
+            file.archived_path = Some(archived.clone());
+            attachments.push((archived, path));
+        }
+    }
+
+    let export = crate::models::ChatExport {
+        format: "lanchat-export".to_string(),
+        version: EXPORT_FORMAT_VERSION,
+        exported_at: chrono::Utc::now().timestamp(),
+        my_id,
+        my_name,
+        peer_id: peer_id.map(|s| s.to_string()),
+        messages,
+    };
+
+    let document = match extension {
+        "json" => serde_json::to_vec_pretty(&export).map_err(|e| format!("序列化失败: {}", e))?,
+        "md" => render_export_markdown(&export).into_bytes(),
+        _ => render_export_html(&export).into_bytes(),
+    };
+
+    let base_name = format!(
+        "lanchat-{}-{}",
+        peer_id.unwrap_or("all"),
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    );
+
+    if !bundle_files {
+        return Ok(ExportArchive {
+            file_name: format!("{}.{}", base_name, extension),
+            content_type,
+            data: document,
+        });
+    }
+
+    // 打包 zip（文件读取是阻塞操作，放到 blocking 线程里做）
+    let document_name = format!("history.{}", extension);
+    let data = tokio::task::spawn_blocking(move || {
+        build_export_zip(&document_name, &document, &attachments)
+    })
+    .await
+    .map_err(|e| format!("打包任务失败: {}", e))??;
+
+    Ok(ExportArchive {
+        file_name: format!("{}.zip", base_name),
+        content_type: "application/zip",
+        data,
+    })
+}
+
+fn build_export_zip(
+    document_name: &str,
+    document: &[u8],
+    attachments: &[(String, PathBuf)],
+) -> Result<Vec<u8>, String> {
+    use std::io::Write;
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .large_file(true);
+
+    zip.start_file(document_name, options)
+        .map_err(|e| format!("写入压缩包失败: {}", e))?;
+    zip.write_all(document)
+        .map_err(|e| format!("写入压缩包失败: {}", e))?;
+
+    for (name, path) in attachments {
+        let mut file = match std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                log::error!("[DB] 跳过无法读取的文件 {:?}: {}", path, e);
+                continue;
+            }
+        };
+        zip.start_file(name.as_str(), options)
+            .map_err(|e| format!("写入压缩包失败: {}", e))?;
+        std::io::copy(&mut file, &mut zip).map_err(|e| format!("写入压缩包失败: {}", e))?;
+    }
+
+    let cursor = zip.finish().map_err(|e| format!("写入压缩包失败: {}", e))?;
+    Ok(cursor.into_inner())
+}
+
+fn format_export_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|t| {
+            t.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn export_sender_name(export: &crate::models::ChatExport, from_id: &str) -> String {
+    if from_id == export.my_id {
+        export.my_name.clone()
+    } else {
+        from_id.to_string()
+    }
+}
+
+fn render_export_markdown(export: &crate::models::ChatExport) -> String {
+    let mut out = String::new();
+    out.push_str("# LANChat 聊天记录\n\n");
+    out.push_str(&format!(
+        "- 导出者: {} ({})\n",
+        export.my_name, export.my_id
+    ));
+    out.push_str(&format!(
+        "- 对话对象: {}\n",
+        export.peer_id.as_deref().unwrap_or("全部")
+    ));
+    out.push_str(&format!(
+        "- 导出时间: {}\n",
+        format_export_time(export.exported_at)
+    ));
+    out.push_str(&format!("- 消息数: {}\n\n---\n\n", export.messages.len()));
+
+    for msg in &export.messages {
+        out.push_str(&format!(
+            "**{}** · {}\n\n",
+            export_sender_name(export, &msg.from_id),
+            format_export_time(msg.timestamp)
+        ));
+        match &msg.file {
+            Some(file) => {
+                out.push_str(&format!("> 📎 {}", file.name));
+                if let Some(size) = file.size {
+                    out.push_str(&format!(" ({} 字节)", size));
+                }
+                if let Some(status) = &file.status {
+                    out.push_str(&format!(" [{}]", status));
+                }
+                if let Some(archived) = &file.archived_path {
+                    out.push_str(&format!(" → `{}`", archived));
+                }
+                out.push_str("\n\n");
+            }
+            None => {
+                // 每行前加引用符号，避免消息里的 Markdown 语法破坏整体结构
+                for line in msg.content.lines() {
+                    out.push_str("> ");
+                    out.push_str(line);
+                    out.push('\n');
+                }
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn render_export_html(export: &crate::models::ChatExport) -> String {
+    let mut body = String::new();
+    for msg in &export.messages {
+        let class = if msg.from_id == export.my_id { "msg sent" } else { "msg received" };
+        let content = match &msg.file {
+            Some(file) => {
+                let mut text = format!("📎 {}", escape_html(&file.name));
+                if let Some(size) = file.size {
+                    text.push_str(&format!(" ({} 字节)", size));
+                }
+                if let Some(status) = &file.status {
+                    text.push_str(&format!(" [{}]", escape_html(status)));
+                }
+                match &file.archived_path {
+                    Some(archived) => format!(
+                        "<a href=\"{}\">{}</a>",
+                        escape_html(archived),
+                        text
+                    ),
+                    None => text,
+                }
+            }
+            None => escape_html(&msg.content).replace('\n', "<br>"),
+        };
+        body.push_str(&format!(
+            "<div class=\"{}\"><div class=\"meta\">{} · {}</div><div class=\"content\">{}</div></div>\n",
+            class,
+            escape_html(&export_sender_name(export, &msg.from_id)),
+            format_export_time(msg.timestamp),
+            content
+        ));
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="zh-CN">
+<head>
+<meta charset="utf-8">
+<title>LANChat 聊天记录</title>
+<style>
+body {{ font-family: -apple-system, "Segoe UI", "PingFang SC", "Microsoft YaHei", sans-serif; background: #f5f5f5; margin: 0; padding: 24px; }}
+.header {{ max-width: 800px; margin: 0 auto 16px; color: #555; font-size: 14px; }}
+.chat {{ max-width: 800px; margin: 0 auto; display: flex; flex-direction: column; gap: 8px; }}
+.msg {{ max-width: 70%; padding: 8px 12px; border-radius: 8px; background: #fff; box-shadow: 0 1px 2px rgba(0,0,0,.08); word-wrap: break-word; }}
+.msg.sent {{ align-self: flex-end; background: #d9f2d0; }}
+.msg.received {{ align-self: flex-start; }}
+.meta {{ font-size: 12px; color: #888; margin-bottom: 4px; }}
+</style>
+</head>
+<body>
+<div class="header">
+<h2>LANChat 聊天记录</h2>
+<div>导出者: {} ({})</div>
+<div>对话对象: {}</div>
+<div>导出时间: {}</div>
+<div>消息数: {}</div>
+</div>
+<div class="chat">
+{}</div>
+</body>
+</html>
+"#,
+        escape_html(&export.my_name),
+        escape_html(&export.my_id),
+        escape_html(export.peer_id.as_deref().unwrap_or("全部")),
+        format_export_time(export.exported_at),
+        export.messages.len(),
+        body
+    )
+}
+
+// ==================== 聊天记录导入相关的数据库函数 ====================
+
+// 导入的文件都是已经结束的传输：未完成的状态（接收中、等待确认、已中断等）改为 imported，
+// 不会被当成正在进行的传输继续接收或续传
+fn imported_file_status(status: Option<&str>, from_me: bool) -> &'static str {
+    match (status, from_me) {
+        (None | Some("sent"), true) => "sent",
+        (None | Some("accepted"), false) => "accepted",
+        (Some("rejected"), _) => "rejected",
+        (Some("corrupted"), false) => "corrupted",
+        _ => "imported",
+    }
+}
+
+// 只保留指向本机下载目录中已收到文件的路径，其他路径（另一台设备上的、导入文件里伪造的）清空，
+// 下载接口不会读取下载目录以外的文件
+fn imported_file_path(path: &str, status: &str, download_dir: &str) -> String {
+    if path.is_empty() || !matches!(status, "sent" | "accepted") {
+        return String::new();
+    }
+    let download_dir = std::fs::canonicalize(download_dir).unwrap_or_else(|_| download_dir.into());
+    match std::fs::canonicalize(path) {
+        Ok(path) if path.starts_with(&download_dir) => path.to_string_lossy().to_string(),
+        _ => String::new(),
+    }
+}
+
+/// 导入结果统计
+#[derive(Debug, serde::Serialize)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+/// 导入 LANChat 导出的 JSON 聊天记录，合并到当前数据库
+/// 按消息 UUID 去重；没有 UUID 或 UUID 未命中时再按 (发送者, 时间戳, 内容) 去重
+/// 导出者本人的消息会映射为当前用户的消息，方便换设备后继续使用
+pub async fn import_history(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    data: &[u8],
+) -> Result<ImportSummary, String> {
+    let export: crate::models::ChatExport =
+        serde_json::from_slice(data).map_err(|e| format!("无法解析导入文件: {}", e))?;
+
+    if export.format != "lanchat-export" {
+        return Err("不是 LANChat 导出的聊天记录".to_string());
+    }
+    if export.version > EXPORT_FORMAT_VERSION {
+        return Err(format!(
+            "导入文件版本 ({}) 高于当前程序支持的版本 ({})，请升级 LANChat",
+            export.version, EXPORT_FORMAT_VERSION
+        ));
+    }
+
+    let my_id = get_user_id(pool).await?;
+    let download_dir = get_download_path(pool).await?;
+    log::info!(
+        "[DB] 开始导入聊天记录: 来源用户={}, 消息数={}",
+        export.my_id,
+        export.messages.len()
+    );
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| format!("开启事务失败: {}", e))?;
+
+    // 导入的是历史消息，不应该产生未读数：先记下当前的未读数，导入后恢复
+    sqlx::query("CREATE TEMP TABLE import_unread AS SELECT peer_id, unread_count FROM conversations")
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| format!("导入失败: {}", e))?;
+
+    let mut summary = ImportSummary {
+        imported: 0,
+        skipped: 0,
+    };
+
+    for msg in &export.messages {
+        // 自己发的消息统一存成 sender_id = 'me'，与本地发送的消息保持一致
+        let from_me = msg.from_id == export.my_id || msg.from_id == my_id || msg.from_id == "me";
+        let (sender_id, receiver_id) = if from_me {
+            ("me".to_string(), msg.to_id.clone())
+        } else {
+            let receiver = match msg.to_id.as_deref() {
+                Some(to) if to == export.my_id => Some(my_id.clone()),
+                Some(to) => Some(to.to_string()),
+                None => Some(my_id.clone()),
+            };
+            (msg.from_id.clone(), receiver)
+        };
+
+        if let Some(uuid) = &msg.message_id {
+            let exists = sqlx::query("SELECT 1 FROM messages WHERE msg_uuid = ?")
+                .bind(uuid)
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(|e| format!("导入失败: {}", e))?
+                .is_some();
+            if exists {
+                summary.skipped += 1;
+                continue;
+            }
+        }
+
+        // 加密后的内容每次都不同，只能取出同一发送者同一时间的消息解密后再比较
+        let candidates: Vec<String> = sqlx::query_scalar(
+            "SELECT content FROM messages
+             WHERE (sender_id = ? OR (? = 'me' AND sender_id = ?)) AND timestamp = ?",
+        )
+        .bind(&sender_id)
+        .bind(&sender_id)
+        .bind(&my_id)
+        .bind(msg.timestamp)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| format!("导入失败: {}", e))?;
+        let mut duplicate = false;
+        for candidate in &candidates {
+            if crate::encryption::decrypt_content(candidate)? == msg.content {
+                duplicate = true;
+                break;
+            }
+        }
+        if duplicate {
+            summary.skipped += 1;
+            continue;
+        }
+
+        let (file_path, file_status) = match &msg.file {
+            Some(file) => {
+                let status = imported_file_status(file.status.as_deref(), from_me);
+                (
+                    Some(imported_file_path(&file.path, status, &download_dir)),
+                    Some(status.to_string()),
+                )
+            }
+            None => (None, None),
+        };
+        let msg_uuid = msg
+            .message_id
+            .clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        // 文件消息的内容是文件名，不加密
+        let content = if msg.msg_type == "file" {
+            msg.content.clone()
+        } else {
+            crate::encryption::encrypt_content(&msg.content)?
+        };
+
+        sqlx::query(
+            "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status, msg_uuid) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&sender_id)
+        .bind(&receiver_id)
+        .bind(&content)
+        .bind(&msg.msg_type)
+        .bind(msg.timestamp)
+        .bind(&file_path)
+        .bind(&file_status)
+        .bind(&msg_uuid)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| format!("导入失败: {}", e))?;
+
+        if msg.msg_type == "file" {
+            sqlx::query(
+                "INSERT OR IGNORE INTO transfers (id, sender_id, receiver_id, file_name, file_size, file_path, status, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(&msg_uuid)
+            .bind(&sender_id)
+            .bind(receiver_id.as_deref().unwrap_or(""))
+            .bind(&content)
+            .bind(msg.file.as_ref().and_then(|f| f.size).unwrap_or(0) as i64)
+            .bind(file_path.as_deref().unwrap_or(""))
+            .bind(file_status.as_deref().unwrap_or("imported"))
+            .bind(msg.timestamp)
+            .bind(msg.timestamp)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| format!("导入失败: {}", e))?;
+        }
+
+        summary.imported += 1;
+    }
+
+    sqlx::query(
+        "UPDATE conversations SET unread_count = IFNULL(
+            (SELECT unread_count FROM import_unread u WHERE u.peer_id = conversations.peer_id), 0)",
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| format!("导入失败: {}", e))?;
+
+    sqlx::query("DROP TABLE import_unread")
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| format!("导入失败: {}", e))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| format!("提交导入失败: {}", e))?;
+
+    log::info!(
+        "[DB] ✓ 导入完成: 新增 {} 条, 跳过重复 {} 条",
+        summary.imported, summary.skipped
+    );
+    Ok(summary)
+}
+
+/// 测试用的内存数据库：执行全部迁移，本机 ID 为 my-id，下载目录为 download_dir
+#[cfg(test)]
+pub async fn test_pool(download_dir: &std::path::Path) -> Pool<Sqlite> {
+    // 内存数据库每个连接是独立的，只能使用一个连接
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    crate::migrations::run(&pool).await.unwrap();
+    sqlx::query(
+        "INSERT INTO settings (key, value) VALUES ('user_id', 'my-id'), ('username', 'Me'), ('download_path', ?)",
+    )
+        .bind(download_dir.to_string_lossy().to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(id: &str, sender_id: &str, status: &str) -> crate::models::Transfer {
+        crate::models::Transfer {
+            id: id.to_string(),
+            sender_id: sender_id.to_string(),
+            receiver_id: "my-id".to_string(),
+            file_name: format!("{}.bin", id),
+            file_size: 10,
+            file_path: format!("/tmp/{}.bin", id),
+            file_hash: None,
+            mime_type: None,
+            status: status.to_string(),
+            save_dir: None,
+            created_at: 1,
+            updated_at: 1,
+        }
+    }
+
+    async fn status_of(pool: &Pool<Sqlite>, id: &str) -> String {
+        get_transfer(pool, id).await.unwrap().unwrap().status
+    }
+
+    #[test]
+    fn emoji_accepts_keycaps_flags_and_sequences() {
+        for emoji in ["👍", "❤️", "1️⃣", "#️⃣", "*⃣", "🇨🇳", "👍🏽", "👨‍👩‍👧", "🏴‍☠️", "™️"]
+        {
+            assert!(validate_emoji(emoji).is_ok(), "{}", emoji);
+        }
+    }
+
+    #[test]
+    fn emoji_rejects_text() {
+        for text in [
+            "",
+            " ",
+            "a",
+            "1",
+            "#",
+            "ok",
+            "👍a",
+            "1️",
+            "中",
+            "\u{200D}\u{FE0F}",
+        ] {
+            assert!(validate_emoji(text).is_err(), "{:?}", text);
+        }
+        assert!(validate_emoji(&"👍".repeat(17)).is_err());
+    }
+
+    #[tokio::test]
+    async fn reaction_toggles_per_reactor() {
+        let pool = test_pool(&std::env::temp_dir()).await;
+        save_text_message(&pool, "peer-a".into(), "m1".into(), "hi".into())
+            .await
+            .unwrap();
+        let ids = vec!["m1".to_string()];
+
+        add_reaction(&pool, "m1", "my-id", "👍", 1).await.unwrap();
+        add_reaction(&pool, "m1", "my-id", "👍", 2).await.unwrap();
+        add_reaction(&pool, "m1", "peer-a", "👍", 3).await.unwrap();
+        let reactions = get_reactions_for_messages(&pool, &ids).await.unwrap();
+        assert_eq!(reactions["m1"].len(), 1);
+        assert_eq!(reactions["m1"][0].count, 2);
+
+        remove_reaction(&pool, "m1", "my-id", "👍").await.unwrap();
+        let reactions = get_reactions_for_messages(&pool, &ids).await.unwrap();
+        assert_eq!(reactions["m1"][0].count, 1);
+        assert_eq!(reactions["m1"][0].reactors, vec!["peer-a".to_string()]);
+
+        remove_reaction(&pool, "m1", "peer-a", "👍").await.unwrap();
+        let reactions = get_reactions_for_messages(&pool, &ids).await.unwrap();
+        assert!(!reactions.contains_key("m1"));
+    }
+
+    #[tokio::test]
+    async fn encrypted_search_pages_through_candidates() {
+        let pool = test_pool(&std::env::temp_dir()).await;
+        // 1200 条消息，每 100 条有一条包含关键词，分布在多批候选中
+        sqlx::query(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1200)
+             INSERT INTO messages (sender_id, content, msg_type, timestamp, msg_uuid)
+             SELECT 'peer-a', CASE WHEN i % 100 = 0 THEN 'Needle ' || i ELSE 'hay ' || i END,
+                    'text', i, 'm' || i FROM n",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let query = crate::models::SearchQuery::default();
+
+        let all = search_encrypted(&pool, &query, &["needle"], "my-id", 50, 0)
+            .await
+            .unwrap();
+        let timestamps: Vec<i64> = all.iter().map(|r| r.timestamp).collect();
+        assert_eq!(
+            timestamps,
+            (1..=12).rev().map(|i| i * 100).collect::<Vec<_>>()
+        );
+
+        let page = search_encrypted(&pool, &query, &["needle"], "my-id", 2, 10)
+            .await
+            .unwrap();
+        let timestamps: Vec<i64> = page.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, vec![200, 100]);
+        assert_eq!(page[0].from_id, "peer-a");
+    }
+
+    #[tokio::test]
+    async fn peers_only_react_to_their_own_conversation() {
+        let pool = chat_pool().await;
+
+        // m1 是和 peer-a 的消息，m3 是和 peer-b 的消息
+        add_reaction(&pool, "m1", "peer-a", "👍", 1).await.unwrap();
+        assert!(add_reaction(&pool, "m3", "peer-a", "👍", 2).await.is_err());
+        assert!(add_reaction(&pool, "missing", "peer-a", "👍", 3)
+            .await
+            .is_err());
+        add_reaction(&pool, "m3", "peer-b", "👍", 4).await.unwrap();
+        add_reaction(&pool, "m3", "my-id", "👍", 5).await.unwrap();
+        assert!(remove_reaction(&pool, "m3", "peer-a", "👍").await.is_err());
+
+        let ids = vec!["m1".to_string(), "m3".to_string()];
+        let reactions = get_reactions_for_messages(&pool, &ids).await.unwrap();
+        assert_eq!(reactions["m1"][0].reactors, vec!["peer-a".to_string()]);
+        assert_eq!(reactions["m3"][0].count, 2);
+    }
+
+    // 和 peer-a 的两条消息，和 peer-b 的一条消息
+    async fn chat_pool() -> Pool<Sqlite> {
+        let pool = test_pool(&std::env::temp_dir()).await;
+        let text = "<b>hi</b>\nline two".to_string();
+        save_text_message(&pool, "peer-a".into(), "m1".into(), text)
+            .await
+            .unwrap();
+        let reply = "reply".to_string();
+        save_received_text_message(
+            &pool,
+            "peer-a".into(),
+            Some("m2".into()),
+            reply,
+            "text".into(),
+            2,
+        )
+        .await
+        .unwrap();
+        save_text_message(&pool, "peer-b".into(), "m3".into(), "other chat".into())
+            .await
+            .unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn export_json_holds_one_conversation() {
+        let pool = chat_pool().await;
+
+        let archive = export_history(&pool, Some("peer-a"), "json", false)
+            .await
+            .unwrap();
+        assert!(archive.file_name.starts_with("lanchat-peer-a-"));
+        assert!(archive.file_name.ends_with(".json"));
+
+        let export: crate::models::ChatExport = serde_json::from_slice(&archive.data).unwrap();
+        assert_eq!(export.format, "lanchat-export");
+        assert_eq!(export.my_name, "Me");
+        let ids: Vec<_> = export
+            .messages
+            .iter()
+            .map(|m| m.message_id.as_deref())
+            .collect();
+        assert_eq!(ids, vec![Some("m2"), Some("m1")]);
+        let sent = &export.messages[1];
+        assert_eq!(sent.from_id, "my-id");
+        assert_eq!(sent.to_id.as_deref(), Some("peer-a"));
+    }
+
+    #[tokio::test]
+    async fn export_markdown_and_html_keep_message_text_inert() {
+        let pool = chat_pool().await;
+
+        let markdown = export_history(&pool, Some("peer-a"), "markdown", false)
+            .await
+            .unwrap();
+        let markdown = String::from_utf8(markdown.data).unwrap();
+        assert!(markdown.contains("> <b>hi</b>\n> line two\n"));
+
+        let html = export_history(&pool, None, "html", false).await.unwrap();
+        let html = String::from_utf8(html.data).unwrap();
+        assert!(html.contains("&lt;b&gt;hi&lt;/b&gt;<br>line two"));
+        assert!(html.contains("other chat"));
+
+        assert!(export_history(&pool, None, "pdf", false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn import_from_another_device_merges_once() {
+        let source = chat_pool().await;
+        let export = export_history(&source, None, "json", false).await.unwrap();
+
+        let pool = test_pool(&std::env::temp_dir()).await;
+        sqlx::query("UPDATE settings SET value = 'new-device' WHERE key = 'user_id'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let summary = import_history(&pool, &export.data).await.unwrap();
+        assert_eq!((summary.imported, summary.skipped), (3, 0));
+        let summary = import_history(&pool, &export.data).await.unwrap();
+        assert_eq!((summary.imported, summary.skipped), (0, 3));
+
+        let sender: String =
+            sqlx::query_scalar("SELECT sender_id FROM messages WHERE msg_uuid = 'm1'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(sender, "me");
+        let conversations = get_conversations(&pool).await.unwrap();
+        assert_eq!(conversations.len(), 2);
+        assert!(conversations.iter().all(|c| c.unread_count == 0));
+    }
+
+    #[tokio::test]
+    async fn import_skips_messages_without_id_already_present() {
+        let pool = chat_pool().await;
+        let data = serde_json::json!({
+            "format": "lanchat-export",
+            "version": EXPORT_FORMAT_VERSION,
+            "exported_at": 0,
+            "my_id": "old-id",
+            "my_name": "Old",
+            "peer_id": null,
+            "messages": [
+                { "from_id": "peer-a", "msg_type": "text", "timestamp": 2, "content": "reply" },
+                { "from_id": "peer-a", "msg_type": "text", "timestamp": 3, "content": "new" },
+            ],
+        });
+
+        let summary = import_history(&pool, data.to_string().as_bytes())
+            .await
+            .unwrap();
+        assert_eq!((summary.imported, summary.skipped), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn imported_transfers_are_finished_and_stay_in_the_download_dir() {
+        let dir = std::env::temp_dir().join(format!("lanchat-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let received = dir.join("photo.png");
+        std::fs::write(&received, b"png").unwrap();
+        let outside = std::env::temp_dir().join(format!("lanchat-test-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&outside, b"secret").unwrap();
+
+        let pool = test_pool(&dir).await;
+        let file = |name: &str, path: &std::path::Path, status: &str| {
+            serde_json::json!({
+                "name": name,
+                "path": path.to_string_lossy(),
+                "status": status,
+            })
+        };
+        let data = serde_json::json!({
+            "format": "lanchat-export",
+            "version": EXPORT_FORMAT_VERSION,
+            "exported_at": 0,
+            "my_id": "old-id",
+            "my_name": "Old",
+            "peer_id": null,
+            "messages": [
+                { "from_id": "peer-a", "msg_type": "file", "timestamp": 1, "content": "photo.png",
+                  "message_id": "f-received", "file": file("photo.png", &received, "accepted") },
+                { "from_id": "peer-a", "msg_type": "file", "timestamp": 2, "content": "big.iso",
+                  "message_id": "f-downloading", "file": file("big.iso", &received, "downloading") },
+                { "from_id": "peer-a", "msg_type": "file", "timestamp": 3, "content": "passwd",
+                  "message_id": "f-outside", "file": file("passwd", &outside, "accepted") },
+                { "from_id": "old-id", "to_id": "peer-a", "msg_type": "file", "timestamp": 4,
+                  "content": "notes.txt", "message_id": "f-interrupted",
+                  "file": file("notes.txt", &outside, "interrupted") },
+            ],
+        });
+        let summary = import_history(&pool, data.to_string().as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(summary.imported, 4);
+
+        let transfer = |id: &'static str| {
+            let pool = pool.clone();
+            async move { get_transfer(&pool, id).await.unwrap().unwrap() }
+        };
+        let kept = transfer("f-received").await;
+        assert_eq!(kept.status, "accepted");
+        assert_eq!(
+            std::path::PathBuf::from(kept.file_path),
+            std::fs::canonicalize(&received).unwrap()
+        );
+        let unfinished = transfer("f-downloading").await;
+        assert_eq!(
+            (unfinished.status.as_str(), unfinished.file_path.as_str()),
+            ("imported", "")
+        );
+        assert!(get_downloading_transfer(&pool, "peer-a")
+            .await
+            .unwrap()
+            .is_none());
+        let outside_path = transfer("f-outside").await;
+        assert_eq!(
+            (
+                outside_path.status.as_str(),
+                outside_path.file_path.as_str()
+            ),
+            ("accepted", "")
+        );
+        let sent = transfer("f-interrupted").await;
+        assert_eq!(
+            (sent.status.as_str(), sent.file_path.as_str()),
+            ("imported", "")
+        );
+
+        let message: (Option<String>, Option<String>) = sqlx::query_as(
+            "SELECT file_path, file_status FROM messages WHERE msg_uuid = 'f-outside'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(message, (Some(String::new()), Some("accepted".to_string())));
+
+        let _ = std::fs::remove_file(&outside);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn import_rejects_other_files() {
+        let pool = test_pool(&std::env::temp_dir()).await;
+        let newer = serde_json::json!({
+            "format": "lanchat-export",
+            "version": EXPORT_FORMAT_VERSION + 1,
+            "exported_at": 0,
+            "my_id": "x",
+            "my_name": "x",
+            "messages": [],
+        });
+        assert!(import_history(&pool, newer.to_string().as_bytes())
+            .await
+            .is_err());
+        assert!(import_history(&pool, b"{}").await.is_err());
+        assert!(import_history(&pool, b"not json").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn offline_sender_interrupts_only_its_downloads() {
+        let pool = test_pool(&std::env::temp_dir()).await;
+        for (id, sender, status) in [
+            ("from-a", "peer-a", "downloading"),
+            ("from-a-done", "peer-a", "accepted"),
+            ("from-b", "peer-b", "downloading"),
+            ("to-a", "me", "uploading"),
+        ] {
+            create_transfer(&pool, &transfer(id, sender, status))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            mark_interrupted_downloads(&pool, "peer-a").await.unwrap(),
+            1
+        );
+        assert_eq!(status_of(&pool, "from-a").await, "interrupted");
+        assert_eq!(status_of(&pool, "from-a-done").await, "accepted");
+        assert_eq!(status_of(&pool, "from-b").await, "downloading");
+        assert_eq!(status_of(&pool, "to-a").await, "uploading");
+    }
 }
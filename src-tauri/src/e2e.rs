@@ -0,0 +1,804 @@
+// 端到端加密
+// 每个节点第一次启动时生成长期身份密钥（X25519，保存在 settings 表中），通过 /api/identity 交换公钥
+// 第一次收到某个节点的公钥时记录（TOFU），之后公钥变化就拒绝收发，等用户核对安全码后确认
+// 双方的身份密钥经 X25519 + HKDF-SHA256 派生出会话密钥，用 ChaCha20-Poly1305 加密消息内容和文件数据
+// 文件分块按 64 KiB 分段加密，接收方边接收边解密，内存占用只有一个分段
+// 表情回应、文件名、大小等元数据不加密（节点之间的连接本身有 TLS）
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use sqlx::{Pool, Sqlite};
+use std::sync::{OnceLock, RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::events::Events;
+use crate::tls::PeerClient;
+
+/// 文件数据的加密分段大小（明文）
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+// 每个分段附带的认证标签
+const TAG_LEN: usize = 16;
+// 分段 nonce = 每个分块随机的 8 字节前缀 + 4 字节分段序号
+const NONCE_PREFIX_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+
+const SESSION_INFO: &[u8] = b"lanchat-e2e-v1";
+const AUTH_INFO: &[u8] = b"lanchat-peer-auth-v1";
+const SAFETY_NUMBER_CONTEXT: &[u8] = b"lanchat-safety-number-v1";
+
+pub const DECRYPT_ERROR: &str = "分块解密失败";
+
+// 同一进程只使用一个数据库，和证书一样放在全局
+static IDENTITY: RwLock<Option<StaticSecret>> = RwLock::new(None);
+// 记录和查询其他节点的身份公钥
+static STORE: OnceLock<Pool<Sqlite>> = OnceLock::new();
+
+/// 节点的身份公钥（/api/identity 的响应）
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Identity {
+    pub peer_id: String,
+    pub public_key: String,
+}
+
+/// 加密后的消息内容（base64）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub sender_key: String, // 发送者的身份公钥，接收方据此核对对方身份
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// 和某个节点的安全码
+#[derive(Debug, Serialize)]
+pub struct SafetyNumber {
+    pub peer_id: String,
+    /// 还没有收到对方的身份公钥时为 None；公钥变化后按新的公钥计算
+    pub safety_number: Option<String>,
+    pub verified: bool,
+    /// 对方的公钥变化了，等待用户核对后确认
+    pub key_changed: bool,
+}
+
+/// 和某个节点之间的会话密钥
+#[derive(Clone)]
+pub struct SessionKey(Key);
+
+/// 启动时读取（第一次启动时生成）本机身份密钥
+pub async fn load_state(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let stored: Option<String> =
+        sqlx::query_scalar("SELECT value FROM settings WHERE key = 'identity_key'")
+            .fetch_optional(pool)
+            .await?;
+    let secret = match stored.and_then(|value| decode_key(&value).ok()) {
+        Some(bytes) => StaticSecret::from(bytes),
+        None => {
+            let mut bytes = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES ('identity_key', ?)")
+                .bind(BASE64.encode(bytes))
+                .execute(pool)
+                .await?;
+            log::info!("[E2E] 已生成本机身份密钥");
+            StaticSecret::from(bytes)
+        }
+    };
+    *IDENTITY.write().unwrap() = Some(secret);
+
+    let known: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM peer_identities")
+        .fetch_one(pool)
+        .await?;
+    log::info!(
+        "[E2E] 本机身份公钥: {}，已记录 {} 个节点的身份公钥",
+        public_key(),
+        known
+    );
+    let _ = STORE.set(pool.clone());
+    Ok(())
+}
+
+/// 本机身份公钥（base64），load_state 之前为空字符串
+pub fn public_key() -> String {
+    IDENTITY
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|secret| BASE64.encode(PublicKey::from(secret).as_bytes()))
+        .unwrap_or_default()
+}
+
+pub fn key_changed_error(peer_id: &str) -> String {
+    format!(
+        "节点 {} 的身份密钥和之前记录的不一致，可能有人在冒充对方；请当面核对安全码，确认后再信任新的密钥",
+        peer_id
+    )
+}
+
+/// 和某个节点之间的会话密钥（双方算出的结果相同）
+pub fn session_key(peer_key: &PublicKey) -> Result<SessionKey, String> {
+    let identity = IDENTITY.read().unwrap();
+    let secret = identity.as_ref().ok_or("身份密钥还没有加载")?;
+    derive_session_key(secret, peer_key)
+}
+
+/// 和某个节点之间的请求签名密钥（与会话密钥分开派生），只有双方的身份密钥能算出
+pub fn auth_key(peer_key: &PublicKey) -> Result<[u8; 32], String> {
+    let identity = IDENTITY.read().unwrap();
+    let secret = identity.as_ref().ok_or("身份密钥还没有加载")?;
+    derive_key(secret, peer_key, AUTH_INFO)
+}
+
+/// 校验某个节点的请求时使用的签名密钥：请求附带了身份公钥时按 trust_sender_key 核对（第一次见到时记录，
+/// 变化时通知前端并返回错误）；没有附带时使用记录的公钥，没有记录过时为 None
+pub async fn sender_auth_key(
+    peer_id: &str,
+    claimed_key: Option<&str>,
+    events: &Events,
+) -> Result<Option<[u8; 32]>, String> {
+    let peer_key = match claimed_key {
+        Some(claimed_key) => Some(trust_sender_key(peer_id, claimed_key, events).await?),
+        None => peer_key(peer_id).await?,
+    };
+    peer_key.map(|key| auth_key(&key)).transpose()
+}
+
+/// 用记录的公钥算出和某个节点之间的请求签名密钥；没有记录或公钥变化后还没有确认时为 None
+pub async fn known_auth_key(peer_id: &str) -> Option<[u8; 32]> {
+    let peer_key = peer_key(peer_id).await.ok()??;
+    auth_key(&peer_key).ok()
+}
+
+/// 加密消息内容；aad 把密文绑定到发送者和消息 ID
+pub fn seal_message(key: &SessionKey, aad: &str, plaintext: &str) -> Result<Envelope, String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(&key.0)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| "加密消息失败".to_string())?;
+    Ok(Envelope {
+        sender_key: public_key(),
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    })
+}
+
+/// 解密消息内容
+pub fn open_message(key: &SessionKey, envelope: &Envelope, aad: &str) -> Result<String, String> {
+    let nonce = BASE64
+        .decode(&envelope.nonce)
+        .ok()
+        .filter(|n| n.len() == NONCE_LEN)
+        .ok_or("消息的 nonce 无效")?;
+    let ciphertext = BASE64
+        .decode(&envelope.ciphertext)
+        .map_err(|_| "消息的密文无效".to_string())?;
+    let plaintext = ChaCha20Poly1305::new(&key.0)
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| "解密消息失败（密钥不匹配或消息被篡改）".to_string())?;
+    String::from_utf8(plaintext).map_err(|_| "消息内容不是有效的 UTF-8".to_string())
+}
+
+/// 文件分块的附加数据：把分段绑定到这次传输和分块位置
+pub fn chunk_aad(transfer_id: &str, offset: u64) -> String {
+    format!("{}:{}", transfer_id, offset)
+}
+
+/// 每个分块使用新的随机 nonce 前缀
+pub fn new_nonce_prefix() -> [u8; NONCE_PREFIX_LEN] {
+    let mut prefix = [0u8; NONCE_PREFIX_LEN];
+    rand::thread_rng().fill_bytes(&mut prefix);
+    prefix
+}
+
+/// 加密后的分块长度
+pub fn sealed_len(len: u64) -> u64 {
+    len + len.div_ceil(SEGMENT_SIZE as u64) * TAG_LEN as u64
+}
+
+/// 把明文流按分段加密（发送方上传分块时使用）
+pub fn seal_stream<R>(
+    key: &SessionKey,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    aad: String,
+    reader: R,
+) -> impl futures_util::Stream<Item = std::io::Result<Vec<u8>>> + Send + 'static
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let cipher = ChaCha20Poly1305::new(&key.0);
+    futures_util::stream::try_unfold(
+        (reader, 0u32, cipher, prefix, aad),
+        |(mut reader, counter, cipher, prefix, aad)| async move {
+            let mut segment = Vec::with_capacity(SEGMENT_SIZE);
+            (&mut reader)
+                .take(SEGMENT_SIZE as u64)
+                .read_to_end(&mut segment)
+                .await?;
+            if segment.is_empty() {
+                return Ok(None);
+            }
+            let sealed = cipher
+                .encrypt(
+                    &segment_nonce(&prefix, counter),
+                    Payload {
+                        msg: &segment,
+                        aad: aad.as_bytes(),
+                    },
+                )
+                .map_err(|_| std::io::Error::other("加密分块失败"))?;
+            Ok(Some((sealed, (reader, counter + 1, cipher, prefix, aad))))
+        },
+    )
+}
+
+/// 边接收边解密分块（接收方使用），只缓存一个分段
+pub struct SegmentOpener {
+    cipher: ChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    aad: String,
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl SegmentOpener {
+    /// prefix 为发送方随分块发送的 e2e_nonce 字段（base64）
+    pub fn new(key: &SessionKey, prefix: &str, aad: String) -> Result<Self, String> {
+        let prefix: [u8; NONCE_PREFIX_LEN] = BASE64
+            .decode(prefix)
+            .ok()
+            .and_then(|p| p.try_into().ok())
+            .ok_or("分块的 e2e_nonce 无效")?;
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(&key.0),
+            prefix,
+            aad,
+            counter: 0,
+            buffer: Vec::with_capacity(SEGMENT_SIZE + TAG_LEN),
+        })
+    }
+
+    /// 收到一段密文，返回已经可以解密的明文
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        self.buffer.extend_from_slice(data);
+        let mut plaintext = Vec::new();
+        while self.buffer.len() >= SEGMENT_SIZE + TAG_LEN {
+            let segment: Vec<u8> = self.buffer.drain(..SEGMENT_SIZE + TAG_LEN).collect();
+            plaintext.extend(self.open_segment(&segment)?);
+        }
+        Ok(plaintext)
+    }
+
+    /// 分块接收完毕，解密最后一个不满的分段
+    pub fn finish(mut self) -> Result<Vec<u8>, String> {
+        if self.buffer.is_empty() {
+            return Ok(Vec::new());
+        }
+        let segment = std::mem::take(&mut self.buffer);
+        self.open_segment(&segment)
+    }
+
+    fn open_segment(&mut self, segment: &[u8]) -> Result<Vec<u8>, String> {
+        let plaintext = self
+            .cipher
+            .decrypt(
+                &segment_nonce(&self.prefix, self.counter),
+                Payload {
+                    msg: segment,
+                    aad: self.aad.as_bytes(),
+                },
+            )
+            .map_err(|_| DECRYPT_ERROR.to_string())?;
+        self.counter += 1;
+        Ok(plaintext)
+    }
+}
+
+/// 从对方的 /api/identity 取得身份公钥并和记录的比较（发送消息和文件之前调用）
+/// 对方是没有端到端加密的旧版本时返回 None；公钥变化时返回错误
+pub async fn fetch_peer_key(
+    client: &PeerClient,
+    peer_id: &str,
+) -> Result<Option<PublicKey>, String> {
+    let response = client
+        .get("/api/identity")
+        .send()
+        .await
+        .map_err(|e| format!("获取对方的身份密钥失败: {}", e))?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::METHOD_NOT_ALLOWED
+    {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(format!(
+            "获取对方的身份密钥失败: HTTP {} {}",
+            status, error_text
+        ));
+    }
+
+    let identity = response
+        .json::<Identity>()
+        .await
+        .map_err(|e| format!("解析对方的身份密钥失败: {}", e))?;
+    if identity.peer_id != peer_id {
+        return Err(format!(
+            "对方的节点 ID 不一致（期望 {}，实际 {}）",
+            peer_id, identity.peer_id
+        ));
+    }
+    match remember_key(peer_id, &identity.public_key).await? {
+        KeyStatus::Trusted(key) => {
+            client.set_identity(auth_key(&key)?);
+            Ok(Some(key))
+        }
+        KeyStatus::Changed => Err(key_changed_error(peer_id)),
+    }
+}
+
+/// 核对消息或文件提议里发送者的身份公钥（第一次收到时记录）
+/// 公钥变化时通知前端（peer-key-changed）并返回错误
+pub async fn trust_sender_key(
+    peer_id: &str,
+    public_key: &str,
+    events: &Events,
+) -> Result<PublicKey, String> {
+    match remember_key(peer_id, public_key).await? {
+        KeyStatus::Trusted(key) => Ok(key),
+        KeyStatus::Changed => {
+            events.emit(
+                "peer-key-changed",
+                serde_json::json!({ "peer_id": peer_id }),
+            );
+            Err(key_changed_error(peer_id))
+        }
+    }
+}
+
+/// 没有端到端加密的消息和文件只有允许明文连接、并且没有记录过对方的身份公钥时才收发
+/// 记录过公钥的节点支持端到端加密，收到或要发送未加密的内容说明可能有人在中间降级
+/// refused 是不允许明文连接时的提示
+pub async fn check_plaintext(peer_id: &str, refused: &str) -> Result<(), String> {
+    if !crate::tls::allow_plaintext() {
+        return Err(refused.to_string());
+    }
+    let known: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM peer_identities WHERE peer_id = ?")
+        .bind(peer_id)
+        .fetch_one(store()?)
+        .await
+        .map_err(|e| format!("查询身份密钥失败: {}", e))?;
+    if known > 0 {
+        log::error!(
+            "[E2E] ✗ 节点 {} 之前使用端到端加密，拒绝未加密的内容",
+            peer_id
+        );
+        return Err(format!(
+            "节点 {} 之前使用端到端加密，拒绝收发未加密的消息和文件",
+            peer_id
+        ));
+    }
+    Ok(())
+}
+
+/// 记录的某个节点的身份公钥；公钥变化后还没有确认时返回错误
+pub async fn peer_key(peer_id: &str) -> Result<Option<PublicKey>, String> {
+    let row: Option<(String, Option<String>)> =
+        sqlx::query_as("SELECT public_key, changed_key FROM peer_identities WHERE peer_id = ?")
+            .bind(peer_id)
+            .fetch_optional(store()?)
+            .await
+            .map_err(|e| format!("查询身份密钥失败: {}", e))?;
+    match row {
+        None => Ok(None),
+        Some((_, Some(_))) => Err(key_changed_error(peer_id)),
+        Some((key, None)) => decode_key(&key).map(|bytes| Some(PublicKey::from(bytes))),
+    }
+}
+
+/// 和某个节点的安全码（双方看到的相同），用于当面核对身份
+pub async fn safety_number(pool: &Pool<Sqlite>, peer_id: &str) -> Result<SafetyNumber, String> {
+    let row: Option<(String, bool, Option<String>)> = sqlx::query_as(
+        "SELECT public_key, verified, changed_key FROM peer_identities WHERE peer_id = ?",
+    )
+    .bind(peer_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| format!("查询身份密钥失败: {}", e))?;
+
+    let Some((key, verified, changed_key)) = row else {
+        return Ok(SafetyNumber {
+            peer_id: peer_id.to_string(),
+            safety_number: None,
+            verified: false,
+            key_changed: false,
+        });
+    };
+
+    let my_id = crate::db::get_user_id(pool).await?;
+    let my_key = decode_key(&public_key())?;
+    let peer_key = decode_key(changed_key.as_deref().unwrap_or(&key))?;
+    Ok(SafetyNumber {
+        peer_id: peer_id.to_string(),
+        safety_number: Some(compute_safety_number(&my_id, &my_key, peer_id, &peer_key)),
+        verified,
+        key_changed: changed_key.is_some(),
+    })
+}
+
+/// 当面核对安全码后标记为已验证（或取消标记）
+pub async fn set_verified(
+    pool: &Pool<Sqlite>,
+    peer_id: &str,
+    verified: bool,
+) -> Result<(), String> {
+    let result = sqlx::query(
+        "UPDATE peer_identities SET verified = ? WHERE peer_id = ? AND changed_key IS NULL",
+    )
+    .bind(verified)
+    .bind(peer_id)
+    .execute(pool)
+    .await
+    .map_err(|e| format!("保存验证状态失败: {}", e))?;
+    if result.rows_affected() == 0 {
+        return Err("没有对方的身份密钥，或者对方的密钥变化了还没有确认".to_string());
+    }
+    log::info!(
+        "[E2E] 节点 {} {}",
+        peer_id,
+        if verified {
+            "已验证"
+        } else {
+            "取消验证"
+        }
+    );
+    Ok(())
+}
+
+/// 信任对方变化后的身份公钥（对方重新安装过），需要重新核对安全码
+pub async fn accept_key_change(pool: &Pool<Sqlite>, peer_id: &str) -> Result<(), String> {
+    let result = sqlx::query(
+        "UPDATE peer_identities SET public_key = changed_key, changed_key = NULL, verified = 0
+         WHERE peer_id = ? AND changed_key IS NOT NULL",
+    )
+    .bind(peer_id)
+    .execute(pool)
+    .await
+    .map_err(|e| format!("保存身份密钥失败: {}", e))?;
+    if result.rows_affected() == 0 {
+        return Err("对方的身份密钥没有变化".to_string());
+    }
+    log::info!("[E2E] 已信任节点 {} 的新身份密钥", peer_id);
+    Ok(())
+}
+
+enum KeyStatus {
+    Trusted(PublicKey),
+    Changed,
+}
+
+// 第一次见到的公钥直接记录；和记录的不同时保存到 changed_key 等待确认
+// 等待确认期间无论对方使用哪个公钥都不信任（与 peer_key 一致），直到用户确认
+async fn remember_key(peer_id: &str, public_key: &str) -> Result<KeyStatus, String> {
+    let bytes = decode_key(public_key)?;
+    let encoded = BASE64.encode(bytes);
+    let pool = store()?;
+
+    let known: Option<(String, Option<String>)> =
+        sqlx::query_as("SELECT public_key, changed_key FROM peer_identities WHERE peer_id = ?")
+            .bind(peer_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| format!("查询身份密钥失败: {}", e))?;
+
+    match known {
+        Some((_, Some(_))) => Ok(KeyStatus::Changed),
+        Some((known, None)) if known == encoded => Ok(KeyStatus::Trusted(PublicKey::from(bytes))),
+        Some(_) => {
+            log::warn!("[E2E] ⚠ 节点 {} 的身份密钥变化了，拒绝收发", peer_id);
+            sqlx::query("UPDATE peer_identities SET changed_key = ? WHERE peer_id = ?")
+                .bind(&encoded)
+                .bind(peer_id)
+                .execute(pool)
+                .await
+                .map_err(|e| format!("保存身份密钥失败: {}", e))?;
+            Ok(KeyStatus::Changed)
+        }
+        None => {
+            sqlx::query(
+                "INSERT OR IGNORE INTO peer_identities (peer_id, public_key, verified, first_seen) VALUES (?, ?, 0, ?)",
+            )
+            .bind(peer_id)
+            .bind(&encoded)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(pool)
+            .await
+            .map_err(|e| format!("保存身份密钥失败: {}", e))?;
+            log::info!("[E2E] 记录节点 {} 的身份公钥", peer_id);
+            Ok(KeyStatus::Trusted(PublicKey::from(bytes)))
+        }
+    }
+}
+
+fn store() -> Result<&'static Pool<Sqlite>, String> {
+    STORE.get().ok_or_else(|| "身份密钥还没有加载".to_string())
+}
+
+/// 测试用：STORE 在整个进程只能设置一次，需要身份密钥的测试共用一个库
+/// 使用文件数据库，创建它的测试结束后其他测试仍然可以使用
+#[cfg(test)]
+pub(crate) async fn test_store() -> Pool<Sqlite> {
+    static SHARED: tokio::sync::OnceCell<Pool<Sqlite>> = tokio::sync::OnceCell::const_new();
+    SHARED
+        .get_or_init(|| async {
+            let dir = std::env::temp_dir().join(format!("lanchat-test-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let options = sqlx::sqlite::SqliteConnectOptions::new()
+                .filename(dir.join("e2e.db"))
+                .create_if_missing(true);
+            let pool = sqlx::sqlite::SqlitePoolOptions::new()
+                .connect_with(options)
+                .await
+                .unwrap();
+            crate::migrations::run(&pool).await.unwrap();
+            load_state(&pool).await.unwrap();
+            pool
+        })
+        .await
+        .clone()
+}
+
+fn decode_key(value: &str) -> Result<[u8; 32], String> {
+    BASE64
+        .decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| "身份密钥格式无效".to_string())
+}
+
+fn derive_session_key(secret: &StaticSecret, peer_key: &PublicKey) -> Result<SessionKey, String> {
+    derive_key(secret, peer_key, SESSION_INFO).map(|okm| SessionKey(Key::from(okm)))
+}
+
+fn derive_key(
+    secret: &StaticSecret,
+    peer_key: &PublicKey,
+    info: &[u8],
+) -> Result<[u8; 32], String> {
+    let shared = secret.diffie_hellman(peer_key);
+    if !shared.was_contributory() {
+        return Err("对方的身份密钥无效".to_string());
+    }
+
+    // 盐为排序后的双方公钥，双方得到相同的会话密钥
+    let my_key = PublicKey::from(secret);
+    let mut keys = [my_key.as_bytes(), peer_key.as_bytes()];
+    keys.sort();
+    let salt = Sha256::new()
+        .chain_update(keys[0])
+        .chain_update(keys[1])
+        .finalize();
+
+    let mut okm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(info, &mut okm)
+        .map_err(|_| "派生会话密钥失败".to_string())?;
+    Ok(okm)
+}
+
+fn segment_nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u32) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::from(nonce)
+}
+
+// 安全码：排序后的（节点 ID，公钥）做 SHA-512，取 60 字节显示为 12 组 5 位数字
+fn compute_safety_number(my_id: &str, my_key: &[u8], peer_id: &str, peer_key: &[u8]) -> String {
+    let mut parties = [(my_id.as_bytes(), my_key), (peer_id.as_bytes(), peer_key)];
+    parties.sort();
+
+    let mut hasher = Sha512::new();
+    hasher.update(SAFETY_NUMBER_CONTEXT);
+    for (id, key) in parties {
+        hasher.update((id.len() as u32).to_be_bytes());
+        hasher.update(id);
+        hasher.update(key);
+    }
+    let digest = hasher.finalize();
+
+    digest
+        .chunks(5)
+        .take(12)
+        .map(|group| {
+            let value = group.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::TryStreamExt;
+
+    fn keypair() -> (StaticSecret, PublicKey) {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret);
+        (secret, public)
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_session_key() {
+        let (alice, alice_public) = keypair();
+        let (bob, bob_public) = keypair();
+
+        let alice_key = derive_session_key(&alice, &bob_public).unwrap();
+        let bob_key = derive_session_key(&bob, &alice_public).unwrap();
+
+        let envelope = seal_message(&alice_key, "alice:msg-1", "你好").unwrap();
+        assert_eq!(
+            open_message(&bob_key, &envelope, "alice:msg-1").unwrap(),
+            "你好"
+        );
+        // 换一条消息的附加数据就无法解密
+        assert!(open_message(&bob_key, &envelope, "alice:msg-2").is_err());
+
+        let (mallory, _) = keypair();
+        let wrong_key = derive_session_key(&mallory, &alice_public).unwrap();
+        assert!(open_message(&wrong_key, &envelope, "alice:msg-1").is_err());
+    }
+
+    #[test]
+    fn auth_key_is_shared_and_separate_from_the_session_key() {
+        let (alice, alice_public) = keypair();
+        let (bob, bob_public) = keypair();
+        let (mallory, _) = keypair();
+
+        let alice_auth = derive_key(&alice, &bob_public, AUTH_INFO).unwrap();
+        assert_eq!(
+            alice_auth,
+            derive_key(&bob, &alice_public, AUTH_INFO).unwrap()
+        );
+        assert_ne!(
+            alice_auth,
+            derive_key(&alice, &bob_public, SESSION_INFO).unwrap()
+        );
+        assert_ne!(
+            alice_auth,
+            derive_key(&mallory, &alice_public, AUTH_INFO).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn sealed_chunk_roundtrip_in_uneven_pieces() {
+        let (alice, _) = keypair();
+        let (_, bob_public) = keypair();
+        let key = derive_session_key(&alice, &bob_public).unwrap();
+
+        let data: Vec<u8> = (0..SEGMENT_SIZE * 2 + 1234).map(|i| i as u8).collect();
+        let prefix = new_nonce_prefix();
+        let sealed: Vec<u8> = seal_stream(
+            &key,
+            prefix,
+            chunk_aad("t", 0),
+            std::io::Cursor::new(data.clone()),
+        )
+        .try_concat()
+        .await
+        .unwrap();
+        assert_eq!(sealed.len() as u64, sealed_len(data.len() as u64));
+
+        // 网络上收到的数据块大小和分段无关
+        let mut opener =
+            SegmentOpener::new(&key, &BASE64.encode(prefix), chunk_aad("t", 0)).unwrap();
+        let mut plaintext = Vec::new();
+        for piece in sealed.chunks(10_000) {
+            plaintext.extend(opener.update(piece).unwrap());
+        }
+        plaintext.extend(opener.finish().unwrap());
+        assert_eq!(plaintext, data);
+
+        // 分块位置不同时拒绝
+        let mut opener =
+            SegmentOpener::new(&key, &BASE64.encode(prefix), chunk_aad("t", 1)).unwrap();
+        assert_eq!(opener.update(&sealed).err().as_deref(), Some(DECRYPT_ERROR));
+    }
+
+    #[tokio::test]
+    async fn key_change_stays_pending_until_accepted() {
+        let pool = test_store().await;
+        let old_key = BASE64.encode(keypair().1.as_bytes());
+        let new_key = BASE64.encode(keypair().1.as_bytes());
+
+        assert!(matches!(
+            remember_key("peer-x", &old_key).await,
+            Ok(KeyStatus::Trusted(_))
+        ));
+        assert!(matches!(
+            remember_key("peer-x", &new_key).await,
+            Ok(KeyStatus::Changed)
+        ));
+
+        // 等待确认期间旧公钥也不再信任，和 peer_key 的结果一致
+        assert!(matches!(
+            remember_key("peer-x", &old_key).await,
+            Ok(KeyStatus::Changed)
+        ));
+        assert!(peer_key("peer-x").await.is_err());
+
+        accept_key_change(&pool, "peer-x").await.unwrap();
+        assert!(matches!(
+            remember_key("peer-x", &new_key).await,
+            Ok(KeyStatus::Trusted(_))
+        ));
+        assert_eq!(
+            peer_key("peer-x")
+                .await
+                .unwrap()
+                .map(|k| BASE64.encode(k.as_bytes())),
+            Some(new_key)
+        );
+    }
+
+    #[tokio::test]
+    async fn peers_with_a_known_key_must_encrypt() {
+        test_store().await;
+        let key = BASE64.encode(keypair().1.as_bytes());
+        assert!(matches!(
+            remember_key("peer-encrypting", &key).await,
+            Ok(KeyStatus::Trusted(_))
+        ));
+
+        {
+            let _setting = crate::tls::PLAINTEXT_SETTING.lock().await;
+            assert_eq!(
+                check_plaintext("peer-legacy", "不允许明文").await,
+                Err("不允许明文".to_string())
+            );
+        }
+
+        let _plaintext = crate::tls::PlaintextAllowed::acquire().await;
+        assert!(check_plaintext("peer-legacy", "不允许明文").await.is_ok());
+        let error = check_plaintext("peer-encrypting", "不允许明文")
+            .await
+            .unwrap_err();
+        assert!(error.contains("之前使用端到端加密"));
+    }
+
+    #[test]
+    fn safety_number_is_the_same_on_both_sides() {
+        let (_, alice) = keypair();
+        let (_, bob) = keypair();
+
+        let from_alice = compute_safety_number("alice", alice.as_bytes(), "bob", bob.as_bytes());
+        let from_bob = compute_safety_number("bob", bob.as_bytes(), "alice", alice.as_bytes());
+        assert_eq!(from_alice, from_bob);
+        assert_eq!(from_alice.split(' ').count(), 12);
+
+        let (_, other) = keypair();
+        assert_ne!(
+            from_alice,
+            compute_safety_number("alice", alice.as_bytes(), "bob", other.as_bytes())
+        );
+    }
+}
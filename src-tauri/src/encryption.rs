@@ -0,0 +1,531 @@
+// 聊天内容静态加密
+// 开启后文本消息的内容在写入数据库前用 ChaCha20-Poly1305 加密，读取时解密
+// 密钥由用户口令经 Argon2id 派生，只保存在内存中；程序启动后处于锁定状态，解锁前无法读写消息内容
+// 文件名、时间戳、发送者等元数据不加密
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use sqlx::{Pool, Sqlite};
+use std::sync::RwLock;
+
+// 加密后内容的前缀，没有前缀的内容视为明文（开启加密前的旧数据）
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+// 用于校验口令是否正确的固定明文
+const VERIFIER_PLAINTEXT: &str = "lanchat-encryption-verifier";
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+
+pub const LOCKED_ERROR: &str = "数据库已锁定，请先输入口令解锁";
+
+enum KeyState {
+    Disabled,
+    Locked,
+    Unlocked(Key),
+}
+
+// 同一进程只使用一个数据库，密钥状态放在全局，db.rs 的读写函数不需要额外传参
+static STATE: RwLock<KeyState> = RwLock::new(KeyState::Disabled);
+
+/// 加密状态
+#[derive(Debug, serde::Serialize)]
+pub struct EncryptionStatus {
+    pub enabled: bool,
+    pub locked: bool,
+}
+
+pub fn status() -> EncryptionStatus {
+    match &*STATE.read().unwrap() {
+        KeyState::Disabled => EncryptionStatus {
+            enabled: false,
+            locked: false,
+        },
+        KeyState::Locked => EncryptionStatus {
+            enabled: true,
+            locked: true,
+        },
+        KeyState::Unlocked(_) => EncryptionStatus {
+            enabled: true,
+            locked: false,
+        },
+    }
+}
+
+/// 锁定状态下返回错误（用于在发送消息等操作之前提前检查）
+pub fn ensure_unlocked() -> Result<(), String> {
+    match &*STATE.read().unwrap() {
+        KeyState::Locked => Err(LOCKED_ERROR.to_string()),
+        _ => Ok(()),
+    }
+}
+
+pub fn is_enabled() -> bool {
+    !matches!(&*STATE.read().unwrap(), KeyState::Disabled)
+}
+
+/// 初始化数据库时读取加密设置，开启了加密则进入锁定状态
+pub async fn load_state(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let enabled = sqlx::query("SELECT value FROM settings WHERE key = 'encryption_verifier'")
+        .fetch_optional(pool)
+        .await?
+        .is_some();
+
+    *STATE.write().unwrap() = if enabled {
+        log::info!("[Encryption] 聊天内容已加密，等待解锁");
+        KeyState::Locked
+    } else {
+        KeyState::Disabled
+    };
+    Ok(())
+}
+
+/// 加密要写入数据库的内容（未开启加密时原样返回）
+pub fn encrypt_content(plaintext: &str) -> Result<String, String> {
+    match &*STATE.read().unwrap() {
+        KeyState::Disabled => Ok(plaintext.to_string()),
+        KeyState::Locked => Err(LOCKED_ERROR.to_string()),
+        KeyState::Unlocked(key) => encrypt_with(key, plaintext),
+    }
+}
+
+/// 解密从数据库读取的内容（明文内容原样返回）
+pub fn decrypt_content(stored: &str) -> Result<String, String> {
+    if !stored.starts_with(ENCRYPTED_PREFIX) {
+        return Ok(stored.to_string());
+    }
+    match &*STATE.read().unwrap() {
+        KeyState::Unlocked(key) => decrypt_with(key, stored),
+        _ => Err(LOCKED_ERROR.to_string()),
+    }
+}
+
+/// 开启加密：生成盐和校验值，并加密已有的文本消息
+pub async fn enable(pool: &Pool<Sqlite>, passphrase: &str) -> Result<(), String> {
+    if is_enabled() {
+        return Err("已经开启了加密".to_string());
+    }
+    validate_passphrase(passphrase)?;
+
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let key = derive_key(passphrase, &salt)?;
+    let verifier = encrypt_with(&key, VERIFIER_PLAINTEXT)?;
+
+    log::info!("[Encryption] 正在开启加密...");
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| format!("开启事务失败: {}", e))?;
+
+    let count = rewrite_contents(&mut tx, |content| {
+        if content.starts_with(ENCRYPTED_PREFIX) {
+            Ok(None)
+        } else {
+            encrypt_with(&key, content).map(Some)
+        }
+    })
+    .await?;
+    rebuild_search_index(&mut tx).await?;
+
+    for (k, v) in [
+        ("encryption_salt", BASE64.encode(salt)),
+        ("encryption_verifier", verifier),
+    ] {
+        sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)")
+            .bind(k)
+            .bind(v)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| format!("保存加密设置失败: {}", e))?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| format!("提交失败: {}", e))?;
+
+    *STATE.write().unwrap() = KeyState::Unlocked(key);
+    log::info!("[Encryption] ✓ 加密已开启，已加密 {} 条消息", count);
+    compact(pool)
+        .await
+        .map_err(|e| format!("加密已开启，但{}", e))
+}
+
+/// 用口令解锁
+pub async fn unlock(pool: &Pool<Sqlite>, passphrase: &str) -> Result<(), String> {
+    let key = verify_passphrase(pool, passphrase).await?;
+    *STATE.write().unwrap() = KeyState::Unlocked(key);
+    log::info!("[Encryption] ✓ 已解锁");
+    Ok(())
+}
+
+/// 锁定（清除内存中的密钥）
+pub fn lock() -> Result<(), String> {
+    let mut state = STATE.write().unwrap();
+    if matches!(*state, KeyState::Disabled) {
+        return Err("未开启加密".to_string());
+    }
+    *state = KeyState::Locked;
+    log::info!("[Encryption] 已锁定");
+    Ok(())
+}
+
+/// 关闭加密：解密所有消息并删除加密设置
+pub async fn disable(pool: &Pool<Sqlite>, passphrase: &str) -> Result<(), String> {
+    let key = verify_passphrase(pool, passphrase).await?;
+
+    log::info!("[Encryption] 正在关闭加密...");
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| format!("开启事务失败: {}", e))?;
+
+    let count = rewrite_contents(&mut tx, |content| {
+        if content.starts_with(ENCRYPTED_PREFIX) {
+            decrypt_with(&key, content).map(Some)
+        } else {
+            Ok(None)
+        }
+    })
+    .await?;
+    rebuild_search_index(&mut tx).await?;
+
+    sqlx::query("DELETE FROM settings WHERE key IN ('encryption_salt', 'encryption_verifier')")
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| format!("删除加密设置失败: {}", e))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| format!("提交失败: {}", e))?;
+
+    *STATE.write().unwrap() = KeyState::Disabled;
+    log::info!("[Encryption] ✓ 加密已关闭，已解密 {} 条消息", count);
+    compact(pool)
+        .await
+        .map_err(|e| format!("加密已关闭，但{}", e))
+}
+
+// 全文索引只包含没有加密的内容（触发器不索引密文）
+// 改写内容后清空重建，旧的索引数据（其中有明文）随之删除
+async fn rebuild_search_index(tx: &mut sqlx::SqliteConnection) -> Result<(), String> {
+    for sql in [
+        crate::migrations::REINDEX_CLEAR,
+        crate::migrations::REINDEX_PLAINTEXT,
+    ] {
+        sqlx::query(sql)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| format!("重建搜索索引失败: {}", e))?;
+    }
+    Ok(())
+}
+
+// 改写后旧的明文还留在数据库文件的空闲页里：secure_delete（打开数据库时设置）把删除的内容清零，
+// VACUUM 重写整个文件，不留下空闲页
+async fn compact(pool: &Pool<Sqlite>) -> Result<(), String> {
+    sqlx::query("VACUUM")
+        .execute(pool)
+        .await
+        .map_err(|e| format!("清理数据库中的旧内容失败: {}", e))?;
+    log::info!("[Encryption] ✓ 已清理数据库中的旧内容");
+    Ok(())
+}
+
+fn validate_passphrase(passphrase: &str) -> Result<(), String> {
+    if passphrase.chars().count() < 8 {
+        return Err("口令至少需要 8 个字符".to_string());
+    }
+    Ok(())
+}
+
+async fn verify_passphrase(pool: &Pool<Sqlite>, passphrase: &str) -> Result<Key, String> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        "SELECT key, value FROM settings WHERE key IN ('encryption_salt', 'encryption_verifier')",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("读取加密设置失败: {}", e))?;
+
+    let mut salt = None;
+    let mut verifier = None;
+    for (k, v) in rows {
+        match k.as_str() {
+            "encryption_salt" => salt = Some(v),
+            "encryption_verifier" => verifier = Some(v),
+            _ => {}
+        }
+    }
+    let (Some(salt), Some(verifier)) = (salt, verifier) else {
+        return Err("未开启加密".to_string());
+    };
+
+    let salt = BASE64
+        .decode(salt)
+        .map_err(|_| "加密设置已损坏".to_string())?;
+    let key = derive_key(passphrase, &salt)?;
+
+    match decrypt_with(&key, &verifier) {
+        Ok(text) if text == VERIFIER_PLAINTEXT => Ok(key),
+        _ => Err("口令错误".to_string()),
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, String> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("派生密钥失败: {}", e))?;
+    Ok(key)
+}
+
+fn encrypt_with(key: &Key, plaintext: &str) -> Result<String, String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let ciphertext = ChaCha20Poly1305::new(key)
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| "加密失败".to_string())?;
+
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(&ciphertext);
+    Ok(format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(data)))
+}
+
+fn decrypt_with(key: &Key, stored: &str) -> Result<String, String> {
+    let encoded = stored
+        .strip_prefix(ENCRYPTED_PREFIX)
+        .ok_or_else(|| "内容未加密".to_string())?;
+    let data = BASE64
+        .decode(encoded)
+        .map_err(|_| "密文格式错误".to_string())?;
+    if data.len() < NONCE_LEN {
+        return Err("密文格式错误".to_string());
+    }
+
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let plaintext = ChaCha20Poly1305::new(key)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "解密失败".to_string())?;
+    String::from_utf8(plaintext).map_err(|_| "解密失败".to_string())
+}
+
+// 改写所有文本消息的内容（以及会话列表里的最后一条消息）
+// transform 返回 None 表示不需要修改
+async fn rewrite_contents(
+    tx: &mut sqlx::SqliteConnection,
+    transform: impl Fn(&str) -> Result<Option<String>, String>,
+) -> Result<usize, String> {
+    let rows: Vec<(i64, String)> =
+        sqlx::query_as("SELECT id, content FROM messages WHERE msg_type != 'file' AND content IS NOT NULL")
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(|e| format!("读取消息失败: {}", e))?;
+
+    let mut count = 0;
+    for (id, content) in rows {
+        if let Some(new_content) = transform(&content)? {
+            sqlx::query("UPDATE messages SET content = ? WHERE id = ?")
+                .bind(new_content)
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| format!("更新消息失败: {}", e))?;
+            count += 1;
+        }
+    }
+
+    let conversations: Vec<(String, String)> = sqlx::query_as(
+        "SELECT peer_id, last_message FROM conversations WHERE last_msg_type != 'file' AND last_message IS NOT NULL",
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| format!("读取会话失败: {}", e))?;
+
+    for (peer_id, content) in conversations {
+        if let Some(new_content) = transform(&content)? {
+            sqlx::query("UPDATE conversations SET last_message = ? WHERE peer_id = ?")
+                .bind(new_content)
+                .bind(peer_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| format!("更新会话失败: {}", e))?;
+        }
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 这些测试不修改全局的密钥状态，其他测试可能同时在读写消息
+
+    #[test]
+    fn content_round_trips_only_with_the_same_key() {
+        let key = derive_key("correct horse", b"0123456789abcdef").unwrap();
+        let other = derive_key("wrong horse", b"0123456789abcdef").unwrap();
+
+        let first = encrypt_with(&key, "你好").unwrap();
+        let second = encrypt_with(&key, "你好").unwrap();
+        assert!(first.starts_with(ENCRYPTED_PREFIX));
+        assert_ne!(first, second);
+        assert_eq!(decrypt_with(&key, &first).unwrap(), "你好");
+        assert!(decrypt_with(&other, &first).is_err());
+
+        let mut tampered = first.into_bytes();
+        let last = tampered.len() - 2;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        assert!(decrypt_with(&key, &String::from_utf8(tampered).unwrap()).is_err());
+        assert!(decrypt_with(&key, "plain text").is_err());
+    }
+
+    #[tokio::test]
+    async fn rewrite_encrypts_text_and_leaves_file_names() {
+        let pool = crate::db::test_pool(&std::env::temp_dir()).await;
+        for sql in [
+            "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, msg_uuid)
+             VALUES ('peer-a', 'my-id', 'secret', 'text', 1, 'm1')",
+            "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, msg_uuid)
+             VALUES ('peer-b', 'my-id', 'photo.jpg', 'file', 1, 'm2')",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+        let key = derive_key("correct horse", b"0123456789abcdef").unwrap();
+
+        let mut conn = pool.acquire().await.unwrap();
+        let count = rewrite_contents(&mut conn, |content| encrypt_with(&key, content).map(Some))
+            .await
+            .unwrap();
+        drop(conn);
+        assert_eq!(count, 1);
+
+        let contents: Vec<String> =
+            sqlx::query_scalar("SELECT content FROM messages ORDER BY msg_uuid")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(decrypt_with(&key, &contents[0]).unwrap(), "secret");
+        assert_eq!(contents[1], "photo.jpg");
+        let last: String =
+            sqlx::query_scalar("SELECT last_message FROM conversations WHERE peer_id = 'peer-a'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!(last.starts_with(ENCRYPTED_PREFIX));
+    }
+
+    #[tokio::test]
+    async fn search_index_only_holds_plaintext() {
+        let pool = crate::db::test_pool(&std::env::temp_dir()).await;
+        sqlx::query(
+            "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, msg_uuid)
+             VALUES ('peer-a', 'my-id', 'secret plans', 'text', 1, 'm1')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let key = derive_key("correct horse", b"0123456789abcdef").unwrap();
+        let matches = |term: &'static str| {
+            let pool = pool.clone();
+            async move {
+                let count: i64 = sqlx::query_scalar(
+                    "SELECT COUNT(*) FROM messages_fts WHERE messages_fts MATCH ?",
+                )
+                .bind(term)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+                count
+            }
+        };
+        let indexed_plaintext = || {
+            let pool = pool.clone();
+            async move {
+                let count: i64 = sqlx::query_scalar(
+                    "SELECT COUNT(*) FROM messages_fts_data WHERE instr(block, CAST('secret' AS BLOB)) > 0",
+                )
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+                count
+            }
+        };
+        assert_eq!(matches("secret").await, 1);
+
+        // 开启加密：索引里不留下明文，也不索引密文
+        let mut tx = pool.begin().await.unwrap();
+        rewrite_contents(&mut tx, |content| encrypt_with(&key, content).map(Some))
+            .await
+            .unwrap();
+        rebuild_search_index(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(matches("secret").await, 0);
+        assert_eq!(indexed_plaintext().await, 0);
+        assert_eq!(matches("enc").await, 0);
+
+        // 加密期间收到的消息也不进入索引
+        sqlx::query(
+            "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, msg_uuid)
+             VALUES ('peer-a', 'my-id', ?, 'text', 2, 'm2')",
+        )
+        .bind(encrypt_with(&key, "secret again").unwrap())
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert_eq!(matches("enc").await, 0);
+        compact(&pool).await.unwrap();
+
+        // 关闭加密后重新索引
+        let mut tx = pool.begin().await.unwrap();
+        rewrite_contents(&mut tx, |content| decrypt_with(&key, content).map(Some))
+            .await
+            .unwrap();
+        rebuild_search_index(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(matches("secret").await, 2);
+
+        sqlx::query("DELETE FROM messages WHERE msg_uuid = 'm1'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(matches("secret").await, 1);
+    }
+
+    #[tokio::test]
+    async fn passphrase_is_checked_against_the_verifier() {
+        let pool = crate::db::test_pool(&std::env::temp_dir()).await;
+        assert!(verify_passphrase(&pool, "correct horse").await.is_err());
+
+        let salt = b"0123456789abcdef";
+        let key = derive_key("correct horse", salt).unwrap();
+        for (k, v) in [
+            ("encryption_salt", BASE64.encode(salt)),
+            (
+                "encryption_verifier",
+                encrypt_with(&key, VERIFIER_PLAINTEXT).unwrap(),
+            ),
+        ] {
+            sqlx::query("INSERT INTO settings (key, value) VALUES (?, ?)")
+                .bind(k)
+                .bind(v)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            verify_passphrase(&pool, "correct horse").await.unwrap(),
+            key
+        );
+        assert_eq!(
+            verify_passphrase(&pool, "wrong horse").await.unwrap_err(),
+            "口令错误"
+        );
+        assert!(validate_passphrase("short").is_err());
+    }
+}
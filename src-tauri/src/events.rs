@@ -0,0 +1,156 @@
+// 事件总线：网络核心代码通过 EventSink 通知前端，不直接依赖 Tauri
+// 桌面端转发为 Tauri 事件，浏览器通过 /api/events（SSE）订阅广播，测试时用 RecordingSink 记录
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+// 广播缓冲区大小；浏览器跟不上时会收到 resync 事件，需要重新加载
+const BROADCAST_CAPACITY: usize = 256;
+
+/// 推送给前端的事件：name 与前端监听的事件名相同，payload 是 JSON 数据
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub name: &'static str,
+    pub payload: serde_json::Value,
+}
+
+/// 事件的接收方
+pub trait EventSink: Send + Sync {
+    fn emit(&self, name: &'static str, payload: serde_json::Value);
+}
+
+/// 各模块共享的事件出口
+pub type Events = Arc<dyn EventSink>;
+
+/// 广播给所有订阅者（浏览器的 SSE 连接），没有订阅者时直接丢弃
+pub struct BroadcastSink {
+    sender: broadcast::Sender<Event>,
+}
+
+impl BroadcastSink {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for BroadcastSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventSink for BroadcastSink {
+    fn emit(&self, name: &'static str, payload: serde_json::Value) {
+        let _ = self.sender.send(Event { name, payload });
+    }
+}
+
+/// 转发为 Tauri 事件（桌面端前端）
+#[cfg(feature = "desktop")]
+pub struct TauriSink {
+    app: tauri::AppHandle,
+}
+
+#[cfg(feature = "desktop")]
+impl TauriSink {
+    pub fn new(app: tauri::AppHandle) -> Self {
+        Self { app }
+    }
+}
+
+#[cfg(feature = "desktop")]
+impl EventSink for TauriSink {
+    fn emit(&self, name: &'static str, payload: serde_json::Value) {
+        use tauri::Emitter;
+        let _ = self.app.emit(name, payload);
+    }
+}
+
+/// 同时发送给多个接收方（桌面端：Tauri 前端 + 浏览器）
+pub struct Fanout {
+    sinks: Vec<Events>,
+}
+
+impl Fanout {
+    pub fn new(sinks: Vec<Events>) -> Self {
+        Self { sinks }
+    }
+}
+
+impl EventSink for Fanout {
+    fn emit(&self, name: &'static str, payload: serde_json::Value) {
+        for sink in &self.sinks {
+            sink.emit(name, payload.clone());
+        }
+    }
+}
+
+/// 记录收到的所有事件（测试用）
+#[derive(Default)]
+pub struct RecordingSink {
+    events: Mutex<Vec<Event>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 已记录的事件（按发送顺序）
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// 已记录的事件名（按发送顺序）
+    pub fn names(&self) -> Vec<&'static str> {
+        self.events.lock().unwrap().iter().map(|e| e.name).collect()
+    }
+}
+
+impl EventSink for RecordingSink {
+    fn emit(&self, name: &'static str, payload: serde_json::Value) {
+        self.events.lock().unwrap().push(Event { name, payload });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn broadcast_reaches_every_subscriber() {
+        let bus = BroadcastSink::new();
+        let mut first = bus.subscribe();
+        let mut second = bus.subscribe();
+
+        bus.emit("new-message", serde_json::json!({ "content": "hi" }));
+
+        for receiver in [&mut first, &mut second] {
+            let event = receiver.recv().await.unwrap();
+            assert_eq!(event.name, "new-message");
+            assert_eq!(event.payload["content"], "hi");
+        }
+    }
+
+    #[test]
+    fn broadcast_without_subscribers_is_dropped() {
+        BroadcastSink::new().emit("new-peer", serde_json::json!({}));
+    }
+
+    #[test]
+    fn fanout_forwards_to_all_sinks() {
+        let a = Arc::new(RecordingSink::new());
+        let b = Arc::new(RecordingSink::new());
+        let fanout = Fanout::new(vec![a.clone(), b.clone()]);
+
+        fanout.emit("new-peer", serde_json::json!({ "id": "p1" }));
+        fanout.emit("peers-updated", serde_json::json!([]));
+
+        assert_eq!(a.names(), vec!["new-peer", "peers-updated"]);
+        assert_eq!(b.events()[0].payload["id"], "p1");
+    }
+}
@@ -3,10 +3,21 @@
 pub mod commands;
 
 pub mod android_fd;
+pub mod auth;
+pub mod backup;
+pub mod cli;
+pub mod config;
 pub mod db;
+pub mod encryption;
+pub mod events;
+pub mod migrations;
 pub mod models;
 pub mod network;
+pub mod offers;
 pub mod peers;
+pub mod retention;
+pub mod tls;
+pub mod e2e;
 pub mod utils;
 pub mod web_server;
 
@@ -30,7 +41,39 @@ pub fn run() {
             commands::send_message,
             commands::get_chat_history,
             commands::get_chat_history_with_offset,
+            commands::react_to_message,
+            commands::search_messages,
+            commands::get_conversations,
+            commands::mark_conversation_read,
+            commands::export_history,
+            commands::import_history,
+            commands::get_retention_policy,
+            commands::update_retention_policy,
+            commands::get_file_accept_policy,
+            commands::update_file_accept_policy,
+            commands::accept_file,
+            commands::reject_file,
+            commands::clear_conversation,
+            commands::backup_database,
+            commands::restore_database,
+            commands::get_backup_schedule,
+            commands::update_backup_schedule,
+            commands::get_encryption_status,
+            commands::enable_encryption,
+            commands::unlock_database,
+            commands::lock_database,
+            commands::disable_encryption,
+            commands::get_auth_status,
+            commands::set_web_password,
+            commands::set_peer_key,
+            commands::get_tls_status,
+            commands::set_allow_plaintext,
+            commands::forget_peer_certificate,
+            commands::get_safety_number,
+            commands::set_peer_verified,
+            commands::accept_peer_key_change,
             commands::send_file,
+            commands::resume_file_transfer,
             commands::get_settings,
             commands::update_settings,
             commands::get_theme_list,
@@ -49,11 +92,27 @@ pub fn run() {
         ])
         .setup(|app| {
             let handle = app.handle().clone();
-            let port = 8888;
+            // 和 lanchat-web 读取同一个配置文件（db_path 只对 lanchat-web 有效）
+            let config = config::load_default()
+                .and_then(|config| config.validate().map(|_| config))
+                .unwrap_or_else(|e| {
+                    eprintln!("[Config] 配置无效，使用默认设置: {}", e);
+                    config::Config::default()
+                });
+            config::init_logging(config.log_level());
+            let port = config.port();
 
             tauri::async_runtime::block_on(async move {
-                println!("[Lib] 正在初始化数据库...");
+                log::info!("[Lib] 正在初始化数据库...");
                 let pool = db::init_db(&handle).await.expect("DB error");
+                // 上次运行时没有完成的上传标记为中断，可以从断点续传
+                if let Err(e) = db::mark_interrupted_uploads(&pool).await {
+                    log::error!("[DB] {}", e);
+                }
+                if let Err(e) = config.apply(&pool).await {
+                    log::error!("[Config] 配置无效: {}", e);
+                }
+                config.print_effective(&pool).await;
                 let my_name = db::get_username(&pool)
                     .await
                     .unwrap_or_else(|_| "Unknown".into());
@@ -61,8 +120,8 @@ pub fn run() {
                 let my_id = db::get_user_id(&pool).await.expect("无法获取或生成用户 ID");
 
                 handle.manage(db::DbState { pool: pool.clone() });
-                println!("[Lib] 我的用户名: {}", my_name);
-                println!("[Lib] 我的 ID: {}", my_id);
+                log::info!("[Lib] 我的用户名: {}", my_name);
+                log::info!("[Lib] 我的 ID: {}", my_id);
 
                 // 创建全局用户管理器
                 let peer_manager = Arc::new(peers::PeerManager::new());
@@ -75,17 +134,29 @@ pub fn run() {
                 // 注册 Android 分享状态
                 handle.manage(commands::AndroidShareState::new());
 
-                let h1 = handle.clone();
+                // 事件同时发给桌面前端（Tauri 事件）和浏览器（SSE）
+                let bus = Arc::new(events::BroadcastSink::new());
+                let events: events::Events = Arc::new(events::Fanout::new(vec![
+                    bus.clone(),
+                    Arc::new(events::TauriSink::new(handle.clone())),
+                ]));
+                handle.manage(commands::EventState {
+                    events: events.clone(),
+                });
+
+                let events1 = events.clone();
                 let id1 = my_id.clone();
                 let name1 = my_name.clone();
+                let pool1 = pool.clone();
                 let peer_manager_clone = peer_manager.clone();
                 tokio::spawn(async move {
-                    println!("[Lib] 开启监听线程...");
+                    log::info!("[Lib] 开启监听线程...");
                     network::discovery::start_listening(
                         port,
                         id1,
                         name1,
-                        Some(h1),
+                        pool1,
+                        events1,
                         peer_manager_clone,
                     )
                     .await;
@@ -93,25 +164,45 @@ pub fn run() {
 
                 let id2 = my_id.clone();
                 let pool2 = pool.clone();
+                let discovery = config.discovery_options();
                 tokio::spawn(async move {
-                    println!("[Lib] 开启广播线程...");
-                    network::discovery::start_announcing(port, id2, pool2).await;
+                    log::info!("[Lib] 开启广播线程...");
+                    network::discovery::start_announcing(port, id2, pool2, discovery).await;
+                });
+
+                let pool3 = pool.clone();
+                tokio::spawn(async move {
+                    log::info!("[Lib] 开启聊天记录清理任务...");
+                    retention::start_retention_job(pool3).await;
+                });
+
+                let pool4 = pool.clone();
+                tokio::spawn(async move {
+                    log::info!("[Lib] 开启定时备份任务...");
+                    backup::start_backup_job(pool4).await;
                 });
 
                 // 启动 HTTP 服务器（用于接收文件和 WebSocket 消息）
                 let pool_clone = pool.clone();
                 let peer_manager_clone = peer_manager.clone();
-                let handle_clone = handle.clone();
+                let peer_addr = config.peer_addr();
+                let control_addr = config.control_addr(&pool).await;
                 tokio::spawn(async move {
-                    println!("[Lib] 启动 HTTP 服务器在端口 {}...", port);
-                    web_server::start_server(
-                        port,
-                        port,
+                    log::info!("[Lib] 启动 HTTP 服务器在 {}...", peer_addr);
+                    if let Err(e) = web_server::start_server(
+                        peer_addr,
+                        control_addr,
                         pool_clone,
                         peer_manager_clone,
-                        Some(handle_clone),
+                        bus,
+                        events,
+                        // 桌面端随进程退出，不需要等待连接关闭
+                        tokio_util::sync::CancellationToken::new(),
                     )
-                    .await;
+                    .await
+                    {
+                        log::error!("[Lib] ✗ HTTP 服务器无法启动: {}", e);
+                    }
                 });
             });
             Ok(())
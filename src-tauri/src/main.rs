@@ -2,6 +2,7 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use lanchat::db;
+use lanchat::events::{BroadcastSink, Events, Fanout, TauriSink};
 use lanchat::peers::PeerManager;
 use std::sync::Arc;
 use tauri::{
@@ -11,6 +12,11 @@ use tauri::{
 };
 
 fn main() {
+    // 带子命令启动时作为命令行客户端运行，例如 lanchat peers
+    if let Some(code) = lanchat::cli::run_from_args() {
+        std::process::exit(code);
+    }
+
     tauri::Builder::default()
         .plugin(tauri_plugin_single_instance::init(|app, _args, _cwd| {
             // 当尝试启动第二个实例时，显示已存在的窗口
@@ -34,7 +40,39 @@ fn main() {
             lanchat::commands::send_message,
             lanchat::commands::get_chat_history,
             lanchat::commands::get_chat_history_with_offset,
+            lanchat::commands::react_to_message,
+            lanchat::commands::search_messages,
+            lanchat::commands::get_conversations,
+            lanchat::commands::mark_conversation_read,
+            lanchat::commands::export_history,
+            lanchat::commands::import_history,
+            lanchat::commands::get_retention_policy,
+            lanchat::commands::update_retention_policy,
+            lanchat::commands::get_file_accept_policy,
+            lanchat::commands::update_file_accept_policy,
+            lanchat::commands::accept_file,
+            lanchat::commands::reject_file,
+            lanchat::commands::clear_conversation,
+            lanchat::commands::backup_database,
+            lanchat::commands::restore_database,
+            lanchat::commands::get_backup_schedule,
+            lanchat::commands::update_backup_schedule,
+            lanchat::commands::get_encryption_status,
+            lanchat::commands::enable_encryption,
+            lanchat::commands::unlock_database,
+            lanchat::commands::lock_database,
+            lanchat::commands::disable_encryption,
+            lanchat::commands::get_auth_status,
+            lanchat::commands::set_web_password,
+            lanchat::commands::set_peer_key,
+            lanchat::commands::get_tls_status,
+            lanchat::commands::set_allow_plaintext,
+            lanchat::commands::forget_peer_certificate,
+            lanchat::commands::get_safety_number,
+            lanchat::commands::set_peer_verified,
+            lanchat::commands::accept_peer_key_change,
             lanchat::commands::send_file,
+            lanchat::commands::resume_file_transfer,
             lanchat::commands::get_settings,
             lanchat::commands::update_settings,
             lanchat::commands::get_theme_list,
@@ -55,7 +93,15 @@ fn main() {
         // --------------------------
         .setup(|app| {
             let handle = app.handle().clone();
-            let port = 8888;
+            // 和 lanchat-web 读取同一个配置文件（db_path 只对 lanchat-web 有效）
+            let config = lanchat::config::load_default()
+                .and_then(|config| config.validate().map(|_| config))
+                .unwrap_or_else(|e| {
+                    eprintln!("[Config] 配置无效，使用默认设置: {}", e);
+                    lanchat::config::Config::default()
+                });
+            lanchat::config::init_logging(config.log_level());
+            let port = config.port();
 
             // 获取主窗口并设置关闭事件处理
             if let Some(window) = app.get_webview_window("main") {
@@ -115,6 +161,14 @@ fn main() {
             tauri::async_runtime::block_on(async move {
                 println!("[Main] 正在初始化数据库...");
                 let pool = db::init_db(&handle).await.expect("DB error");
+                // 上次运行时没有完成的上传标记为中断，可以从断点续传
+                if let Err(e) = db::mark_interrupted_uploads(&pool).await {
+                    eprintln!("[DB] {}", e);
+                }
+                if let Err(e) = config.apply(&pool).await {
+                    eprintln!("[Config] 配置无效: {}", e);
+                }
+                config.print_effective(&pool).await;
                 let my_name = db::get_username(&pool)
                     .await
                     .unwrap_or_else(|_| "Unknown".into());
@@ -136,9 +190,20 @@ fn main() {
                 // 注册 Android 分享状态
                 handle.manage(lanchat::commands::AndroidShareState::new());
 
-                let h1 = handle.clone();
+                // 事件同时发给桌面前端（Tauri 事件）和浏览器（SSE）
+                let bus = Arc::new(BroadcastSink::new());
+                let events: Events = Arc::new(Fanout::new(vec![
+                    bus.clone(),
+                    Arc::new(TauriSink::new(handle.clone())),
+                ]));
+                handle.manage(lanchat::commands::EventState {
+                    events: events.clone(),
+                });
+
+                let events1 = events.clone();
                 let id1 = my_id.clone();
                 let name1 = my_name.clone();
+                let pool1 = pool.clone();
                 let peer_manager_clone = peer_manager.clone();
                 tokio::spawn(async move {
                     println!("[Main] 开启监听线程...");
@@ -146,7 +211,8 @@ fn main() {
                         port,
                         id1,
                         name1,
-                        Some(h1),
+                        pool1,
+                        events1,
                         peer_manager_clone,
                     )
                     .await;
@@ -154,25 +220,45 @@ fn main() {
 
                 let id2 = my_id.clone();
                 let pool2 = pool.clone();
+                let discovery = config.discovery_options();
                 tokio::spawn(async move {
                     println!("[Main] 开启广播线程...");
-                    lanchat::network::discovery::start_announcing(port, id2, pool2).await;
+                    lanchat::network::discovery::start_announcing(port, id2, pool2, discovery).await;
+                });
+
+                let pool3 = pool.clone();
+                tokio::spawn(async move {
+                    println!("[Main] 开启聊天记录清理任务...");
+                    lanchat::retention::start_retention_job(pool3).await;
+                });
+
+                let pool4 = pool.clone();
+                tokio::spawn(async move {
+                    println!("[Main] 开启定时备份任务...");
+                    lanchat::backup::start_backup_job(pool4).await;
                 });
 
                 // 桌面端也启动 HTTP 服务器（用于接收文件和 WebSocket 消息）
                 let pool_clone = pool.clone();
                 let peer_manager_clone = peer_manager.clone();
-                let handle_clone = handle.clone();
+                let peer_addr = config.peer_addr();
+                let control_addr = config.control_addr(&pool).await;
                 tokio::spawn(async move {
-                    println!("[Main] 启动 HTTP 服务器在端口 {}...", port);
-                    lanchat::web_server::start_server(
-                        port,
-                        port,
+                    println!("[Main] 启动 HTTP 服务器在 {}...", peer_addr);
+                    if let Err(e) = lanchat::web_server::start_server(
+                        peer_addr,
+                        control_addr,
                         pool_clone,
                         peer_manager_clone,
-                        Some(handle_clone),
+                        bus,
+                        events,
+                        // 桌面端随进程退出，不需要等待连接关闭
+                        tokio_util::sync::CancellationToken::new(),
                     )
-                    .await;
+                    .await
+                    {
+                        eprintln!("[Main] ✗ HTTP 服务器无法启动: {}", e);
+                    }
                 });
             });
             Ok(())
@@ -0,0 +1,517 @@
+// 数据库版本迁移
+// 每个迁移只追加、不修改：已发布的迁移改了会导致不同用户的库结构不一致
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+// 单个迁移步骤
+enum Step {
+    Sql(&'static str),
+    // 旧版本用 "ALTER TABLE ... 忽略错误" 的方式加过列，这里先检查再添加
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+}
+
+struct Migration {
+    version: i64,
+    description: &'static str,
+    steps: &'static [Step],
+}
+
+// 按版本号递增排列，新的迁移只能加在末尾
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "初始表结构",
+        steps: &[
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS messages (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    sender_id TEXT,
+                    receiver_id TEXT,
+                    content TEXT,
+                    msg_type TEXT,
+                    timestamp INTEGER,
+                    file_path TEXT,
+                    file_status TEXT
+                )",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS settings (
+                    key TEXT PRIMARY KEY,
+                    value TEXT
+                )",
+            ),
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "messages 表增加接收者字段",
+        steps: &[Step::AddColumn {
+            table: "messages",
+            column: "receiver_id",
+            definition: "TEXT",
+        }],
+    },
+    Migration {
+        version: 3,
+        description: "跨节点消息 ID 与表情回应",
+        steps: &[
+            Step::AddColumn {
+                table: "messages",
+                column: "msg_uuid",
+                definition: "TEXT",
+            },
+            // 旧消息没有 UUID，补一个本地唯一的（对方那边的记录无法对齐，只能本地使用）
+            Step::Sql(
+                "UPDATE messages SET msg_uuid = lower(hex(randomblob(16))) WHERE msg_uuid IS NULL",
+            ),
+            Step::Sql(
+                "CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_msg_uuid ON messages(msg_uuid)",
+            ),
+            // 同一个人对同一条消息的同一个表情只记一次
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS reactions (
+                    message_id TEXT NOT NULL,
+                    reactor_id TEXT NOT NULL,
+                    emoji TEXT NOT NULL,
+                    timestamp INTEGER,
+                    PRIMARY KEY (message_id, reactor_id, emoji)
+                )",
+            ),
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "消息全文索引",
+        steps: &[
+            // 外部内容表，由触发器与 messages 保持同步
+            // 使用 trigram 分词器，中文等没有空格分词的语言也能做子串匹配
+            Step::Sql(
+                "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+                    content,
+                    content='messages',
+                    content_rowid='id',
+                    tokenize='trigram'
+                )",
+            ),
+            Step::Sql(
+                "CREATE TRIGGER IF NOT EXISTS messages_fts_ai AFTER INSERT ON messages BEGIN
+                    INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+                END",
+            ),
+            Step::Sql(
+                "CREATE TRIGGER IF NOT EXISTS messages_fts_ad AFTER DELETE ON messages BEGIN
+                    INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+                END",
+            ),
+            Step::Sql(
+                "CREATE TRIGGER IF NOT EXISTS messages_fts_au AFTER UPDATE OF content ON messages BEGIN
+                    INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+                    INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+                END",
+            ),
+            // 为已有的消息建立索引
+            Step::Sql("INSERT INTO messages_fts(messages_fts) VALUES ('rebuild')"),
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "会话列表（最后一条消息与未读数）",
+        steps: &[
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS conversations (
+                    peer_id TEXT PRIMARY KEY,
+                    last_message TEXT,
+                    last_msg_type TEXT,
+                    last_sender_id TEXT,
+                    last_timestamp INTEGER,
+                    unread_count INTEGER NOT NULL DEFAULT 0
+                )",
+            ),
+            // 插入消息时维护会话：自己发的消息对方是 receiver_id，收到的消息对方是 sender_id
+            // 收到消息未读数加一；自己发消息说明已经看过这个会话，未读清零
+            // 时间戳较旧的消息（如离线补发）只影响未读数，不覆盖最后一条消息
+            Step::Sql(
+                "CREATE TRIGGER IF NOT EXISTS conversations_ai AFTER INSERT ON messages
+                WHEN (CASE WHEN new.sender_id IN ('me', (SELECT value FROM settings WHERE key = 'user_id'))
+                      THEN new.receiver_id ELSE new.sender_id END) IS NOT NULL
+                BEGIN
+                    INSERT INTO conversations (peer_id, last_message, last_msg_type, last_sender_id, last_timestamp, unread_count)
+                    VALUES (
+                        CASE WHEN new.sender_id IN ('me', (SELECT value FROM settings WHERE key = 'user_id'))
+                             THEN new.receiver_id ELSE new.sender_id END,
+                        new.content, new.msg_type, new.sender_id, new.timestamp,
+                        CASE WHEN new.sender_id IN ('me', (SELECT value FROM settings WHERE key = 'user_id'))
+                             THEN 0 ELSE 1 END
+                    )
+                    ON CONFLICT(peer_id) DO UPDATE SET
+                        last_message = CASE WHEN excluded.last_timestamp >= IFNULL(last_timestamp, 0)
+                                            THEN excluded.last_message ELSE last_message END,
+                        last_msg_type = CASE WHEN excluded.last_timestamp >= IFNULL(last_timestamp, 0)
+                                             THEN excluded.last_msg_type ELSE last_msg_type END,
+                        last_sender_id = CASE WHEN excluded.last_timestamp >= IFNULL(last_timestamp, 0)
+                                              THEN excluded.last_sender_id ELSE last_sender_id END,
+                        last_timestamp = MAX(IFNULL(last_timestamp, 0), excluded.last_timestamp),
+                        unread_count = CASE WHEN excluded.unread_count = 0
+                                            THEN 0 ELSE unread_count + 1 END;
+                END",
+            ),
+            // 已有的历史消息视为已读，只生成最后一条消息摘要
+            Step::Sql(
+                "INSERT OR REPLACE INTO conversations (peer_id, last_message, last_msg_type, last_sender_id, last_timestamp, unread_count)
+                SELECT peer_id, content, msg_type, sender_id, timestamp, 0 FROM (
+                    SELECT
+                        CASE WHEN sender_id IN ('me', (SELECT value FROM settings WHERE key = 'user_id'))
+                             THEN receiver_id ELSE sender_id END AS peer_id,
+                        content, msg_type, sender_id, timestamp,
+                        ROW_NUMBER() OVER (
+                            PARTITION BY CASE WHEN sender_id IN ('me', (SELECT value FROM settings WHERE key = 'user_id'))
+                                              THEN receiver_id ELSE sender_id END
+                            ORDER BY timestamp DESC, id DESC
+                        ) AS rn
+                    FROM messages
+                )
+                WHERE rn = 1 AND peer_id IS NOT NULL",
+            ),
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "文件传输表（按传输 ID 记录收发双方、大小、路径、校验值和状态）",
+        steps: &[
+            // id 与文件消息的 msg_uuid 相同；自己发送的传输 sender_id 为 'me'，与 messages 一致
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS transfers (
+                    id TEXT PRIMARY KEY,
+                    sender_id TEXT NOT NULL,
+                    receiver_id TEXT NOT NULL,
+                    file_name TEXT NOT NULL,
+                    file_size INTEGER NOT NULL DEFAULT 0,
+                    file_path TEXT NOT NULL DEFAULT '',
+                    file_hash TEXT,
+                    status TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL
+                )",
+            ),
+            Step::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_transfers_sender_status ON transfers(sender_id, status)",
+            ),
+            // 聊天记录仍然从 messages 读取文件状态和路径，传输表变化时同步过去
+            Step::Sql(
+                "CREATE TRIGGER IF NOT EXISTS transfers_au AFTER UPDATE OF status, file_path ON transfers BEGIN
+                    UPDATE messages SET file_status = new.status, file_path = new.file_path
+                    WHERE msg_uuid = new.id AND msg_type = 'file';
+                END",
+            ),
+            // 删除文件消息（清空会话、保留策略等）时一并删除传输记录
+            Step::Sql(
+                "CREATE TRIGGER IF NOT EXISTS transfers_message_ad AFTER DELETE ON messages
+                WHEN old.msg_type = 'file' BEGIN
+                    DELETE FROM transfers WHERE id = old.msg_uuid;
+                END",
+            ),
+            // 已有的文件消息补建传输记录（旧记录没有保存大小和校验值）
+            Step::Sql(
+                "INSERT OR IGNORE INTO transfers (id, sender_id, receiver_id, file_name, file_size, file_path, status, created_at, updated_at)
+                SELECT msg_uuid, sender_id, IFNULL(receiver_id, ''), IFNULL(content, ''), 0,
+                       IFNULL(file_path, ''), IFNULL(file_status, 'sent'), timestamp, timestamp
+                FROM messages WHERE msg_type = 'file' AND msg_uuid IS NOT NULL",
+            ),
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "文件提议（接收前确认）与按联系人的接收规则",
+        steps: &[
+            Step::AddColumn {
+                table: "transfers",
+                column: "mime_type",
+                definition: "TEXT",
+            },
+            // 手动接收时选择的保存目录，传输完成后文件从暂存目录移到这里
+            Step::AddColumn {
+                table: "transfers",
+                column: "save_dir",
+                definition: "TEXT",
+            },
+            // policy: auto（自动接收）、ask（每次确认）、reject（总是拒绝）
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS file_accept_rules (
+                    peer_id TEXT PRIMARY KEY,
+                    policy TEXT NOT NULL
+                )",
+            ),
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "节点证书指纹（第一次连接时记录，之后证书变化就拒绝连接）",
+        steps: &[Step::Sql(
+            "CREATE TABLE IF NOT EXISTS peer_certificates (
+                peer_id TEXT PRIMARY KEY,
+                fingerprint TEXT NOT NULL,
+                first_seen INTEGER NOT NULL
+            )",
+        )],
+    },
+    Migration {
+        version: 9,
+        description: "节点身份公钥（端到端加密，第一次收到时记录，密钥变化时记在 changed_key 等待确认）",
+        steps: &[Step::Sql(
+            "CREATE TABLE IF NOT EXISTS peer_identities (
+                peer_id TEXT PRIMARY KEY,
+                public_key TEXT NOT NULL,
+                verified INTEGER NOT NULL DEFAULT 0,
+                first_seen INTEGER NOT NULL,
+                changed_key TEXT
+            )",
+        )],
+    },
+    Migration {
+        version: 10,
+        description: "加密的消息内容不写入全文索引",
+        steps: &[
+            // 'enc:v1:' 是 encryption.rs 中加密内容的前缀
+            // 外部内容表删除索引时需要和写入时相同的内容，所以加密的内容在写入和删除时都跳过
+            Step::Sql("DROP TRIGGER IF EXISTS messages_fts_ai"),
+            Step::Sql("DROP TRIGGER IF EXISTS messages_fts_ad"),
+            Step::Sql("DROP TRIGGER IF EXISTS messages_fts_au"),
+            Step::Sql(
+                "CREATE TRIGGER messages_fts_ai AFTER INSERT ON messages
+                WHEN new.content NOT LIKE 'enc:v1:%' BEGIN
+                    INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+                END",
+            ),
+            Step::Sql(
+                "CREATE TRIGGER messages_fts_ad AFTER DELETE ON messages
+                WHEN old.content NOT LIKE 'enc:v1:%' BEGIN
+                    INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+                END",
+            ),
+            Step::Sql(
+                "CREATE TRIGGER messages_fts_au AFTER UPDATE OF content ON messages BEGIN
+                    INSERT INTO messages_fts(messages_fts, rowid, content)
+                        SELECT 'delete', old.id, old.content WHERE old.content NOT LIKE 'enc:v1:%';
+                    INSERT INTO messages_fts(rowid, content)
+                        SELECT new.id, new.content WHERE new.content NOT LIKE 'enc:v1:%';
+                END",
+            ),
+            // 已经开启加密的数据库里索引过密文，重新建立只包含明文的索引
+            Step::Sql(REINDEX_CLEAR),
+            Step::Sql(REINDEX_PLAINTEXT),
+        ],
+    },
+];
+
+/// 清空全文索引（连同索引里保存的内容）
+pub const REINDEX_CLEAR: &str = "INSERT INTO messages_fts(messages_fts) VALUES ('delete-all')";
+/// 为没有加密的消息重新建立全文索引
+pub const REINDEX_PLAINTEXT: &str =
+    "INSERT INTO messages_fts(rowid, content) SELECT id, content FROM messages WHERE content NOT LIKE 'enc:v1:%'";
+
+/// 当前程序支持的最新数据库版本
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// 读取数据库当前的版本（没有版本表的旧数据库视为 0）
+pub async fn current_version(pool: &Pool<Sqlite>) -> Result<i64, sqlx::Error> {
+    ensure_version_table(pool).await?;
+
+    let version: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+        .fetch_one(pool)
+        .await?;
+
+    Ok(version.unwrap_or(0))
+}
+
+/// 按顺序执行所有未应用的迁移，每个迁移在独立的事务中完成
+pub async fn run(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let current = current_version(pool).await?;
+    let latest = latest_version();
+
+    // 数据库由更新版本的程序创建，继续运行可能会损坏数据
+    if current > latest {
+        return Err(sqlx::Error::Configuration(
+            format!(
+                "数据库版本 ({}) 高于当前程序支持的版本 ({})，请升级 LANChat 后再使用此数据库",
+                current, latest
+            )
+            .into(),
+        ));
+    }
+
+    if current == latest {
+        log::info!("[Migrations] 数据库已是最新版本: {}", current);
+        return Ok(());
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        log::info!(
+            "[Migrations] 正在应用迁移 {}: {}",
+            migration.version,
+            migration.description
+        );
+
+        let mut tx = pool.begin().await?;
+
+        for step in migration.steps {
+            apply_step(&mut tx, step).await?;
+        }
+
+        sqlx::query(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)",
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        log::info!("[Migrations] ✓ 迁移 {} 已完成", migration.version);
+    }
+
+    Ok(())
+}
+
+async fn ensure_version_table(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT,
+            applied_at INTEGER
+        )",
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn apply_step(conn: &mut SqliteConnection, step: &Step) -> Result<(), sqlx::Error> {
+    match step {
+        Step::Sql(sql) => {
+            sqlx::query(sql).execute(&mut *conn).await?;
+        }
+        Step::AddColumn {
+            table,
+            column,
+            definition,
+        } => {
+            let exists: i64 =
+                sqlx::query_scalar("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+                    .bind(table)
+                    .bind(column)
+                    .fetch_one(&mut *conn)
+                    .await?;
+
+            if exists == 0 {
+                sqlx::query(&format!(
+                    "ALTER TABLE {} ADD COLUMN {} {}",
+                    table, column, definition
+                ))
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn memory_pool() -> Pool<Sqlite> {
+        // 内存数据库每个连接是独立的，只能使用一个连接
+        sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn pre_series_database_migrates_to_latest() {
+        let pool = memory_pool().await;
+        // 加版本表之前的库结构和数据
+        for sql in [
+            "CREATE TABLE messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sender_id TEXT,
+                receiver_id TEXT,
+                content TEXT,
+                msg_type TEXT,
+                timestamp INTEGER,
+                file_path TEXT,
+                file_status TEXT
+            )",
+            "CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT)",
+            "INSERT INTO settings (key, value) VALUES ('user_id', 'my-id')",
+            "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp)
+             VALUES ('peer-a', 'my-id', 'hello from the old version', 'text', 1)",
+            "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status)
+             VALUES ('me', 'peer-a', 'report.pdf', 'file', 2, '/tmp/report.pdf', 'sent')",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+
+        run(&pool).await.unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+        assert_eq!(latest_version(), 10);
+
+        let missing_uuid: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE msg_uuid IS NULL")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(missing_uuid, 0);
+
+        let (last_message, unread): (String, i64) = sqlx::query_as(
+            "SELECT last_message, unread_count FROM conversations WHERE peer_id = 'peer-a'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(last_message, "report.pdf");
+        assert_eq!(unread, 0);
+
+        let (file_name, status): (String, String) =
+            sqlx::query_as("SELECT file_name, status FROM transfers WHERE receiver_id = 'peer-a'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(file_name, "report.pdf");
+        assert_eq!(status, "sent");
+
+        let found: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM messages_fts WHERE messages_fts MATCH 'old version'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(found, 1);
+
+        // 再次运行不做任何事
+        run(&pool).await.unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+    }
+
+    #[tokio::test]
+    async fn newer_database_is_refused() {
+        let pool = memory_pool().await;
+        ensure_version_table(&pool).await.unwrap();
+        sqlx::query(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?, '', 0)",
+        )
+        .bind(latest_version() + 1)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert!(run(&pool).await.is_err());
+    }
+}
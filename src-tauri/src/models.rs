@@ -11,11 +11,22 @@ pub struct Message {
     pub timestamp: i64,
     pub file_path: Option<String>,
     pub file_status: Option<String>,
+    pub msg_uuid: Option<String>, // 跨节点稳定的消息 ID
+}
+
+// 表情回应聚合结果（同一条消息的同一个表情）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: i64,
+    pub reactors: Vec<String>, // 回应者的用户 ID
 }
 
 // API 响应用的消息结构体（字段名适配前端）
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MessageResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
     pub from_id: String,
     pub content: String,
     pub timestamp: i64,
@@ -30,11 +41,14 @@ pub struct MessageResponse {
     pub file_status: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<ReactionSummary>,
 }
 
 impl From<Message> for MessageResponse {
     fn from(msg: Message) -> Self {
         let mut response = MessageResponse {
+            message_id: msg.msg_uuid,
             from_id: msg.sender_id,
             content: msg.content.clone(),
             timestamp: msg.timestamp,
@@ -44,18 +58,14 @@ impl From<Message> for MessageResponse {
             file_path: None,
             file_status: None,
             file_size: None,
+            reactions: Vec::new(),
         };
 
         // 如果是文件消息，添加文件信息
         if msg.msg_type == "file" {
             if let Some(ref path) = msg.file_path {
-                // 从路径提取文件名
-                let filename = std::path::Path::new(path)
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("");
-
-                response.file_id = Some(filename.to_string()); // 直接使用文件名作为 file_id
+                // 传输 ID 与消息 ID 相同，下载和接收文件都按它查找
+                response.file_id = response.message_id.clone();
                 response.file_name = Some(msg.content.clone()); // content 存储的是文件名
                 response.file_path = Some(path.clone());
                 response.file_status = msg.file_status.clone();
@@ -71,6 +81,215 @@ impl From<Message> for MessageResponse {
     }
 }
 
+// 搜索条件（HTTP 查询参数和 Tauri 命令共用）
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub peer_id: Option<String>,
+    pub since: Option<i64>, // 起始时间（Unix 时间戳，含）
+    pub until: Option<i64>, // 截止时间（Unix 时间戳，含）
+    pub msg_type: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+// 搜索结果
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub message_id: Option<String>,
+    pub peer_id: Option<String>, // 对话的另一方
+    pub from_id: String,
+    pub msg_type: String,
+    pub timestamp: i64,
+    pub content: String,
+    pub snippet: String, // 已转义的 HTML，命中部分用 <mark> 包裹
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+}
+
+// 会话列表项 - 对应 conversations 表，名称和在线状态来自在线用户列表
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Conversation {
+    pub peer_id: String,
+    #[sqlx(skip)]
+    pub peer_name: Option<String>,
+    #[sqlx(skip)]
+    pub is_online: bool,
+    pub last_message: Option<String>,
+    pub last_msg_type: Option<String>,
+    pub last_sender_id: Option<String>,
+    pub last_timestamp: Option<i64>,
+    pub unread_count: i64,
+}
+
+// 文件传输 - 对应 transfers 表，id 与文件消息的 msg_uuid 相同
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Transfer {
+    pub id: String,
+    pub sender_id: String, // 自己发送的传输为 'me'
+    pub receiver_id: String,
+    pub file_name: String,
+    pub file_size: i64,
+    pub file_path: String,
+    pub file_hash: Option<String>,
+    pub mime_type: Option<String>,
+    pub status: String,
+    pub save_dir: Option<String>, // 手动接收时选择的保存目录
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+// 聊天记录导出文件（JSON 格式直接序列化此结构，导入时反序列化）
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatExport {
+    pub format: String, // 固定为 "lanchat-export"
+    pub version: i64,
+    pub exported_at: i64,
+    pub my_id: String,
+    pub my_name: String,
+    #[serde(default)]
+    pub peer_id: Option<String>, // None 表示导出了全部记录
+    pub messages: Vec<ExportedMessage>,
+}
+
+// 导出的单条消息
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedMessage {
+    #[serde(default)]
+    pub message_id: Option<String>,
+    pub from_id: String, // 自己发的消息也写成自己的用户 ID，而不是 'me'
+    #[serde(default)]
+    pub to_id: Option<String>,
+    pub msg_type: String,
+    pub timestamp: i64,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<ExportedFile>,
+}
+
+// 导出的文件消息元数据
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedFile {
+    pub name: String,
+    #[serde(default)]
+    pub path: String,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived_path: Option<String>, // 打包导出时文件在 zip 中的路径
+}
+
+impl ExportedMessage {
+    pub fn from_message(msg: Message, my_id: &str) -> Self {
+        let from_me = msg.sender_id == "me" || msg.sender_id == my_id;
+        let from_id = if from_me {
+            my_id.to_string()
+        } else {
+            msg.sender_id
+        };
+        // 旧数据收到的消息没有 receiver_id，接收者就是自己
+        let to_id = if from_me {
+            msg.receiver_id
+        } else {
+            msg.receiver_id.or_else(|| Some(my_id.to_string()))
+        };
+
+        let file = if msg.msg_type == "file" {
+            let path = msg.file_path.unwrap_or_default();
+            let size = std::fs::metadata(&path).ok().map(|m| m.len());
+            Some(ExportedFile {
+                name: msg.content.clone(),
+                path,
+                status: msg.file_status,
+                size,
+                archived_path: None,
+            })
+        } else {
+            None
+        };
+
+        ExportedMessage {
+            message_id: msg.msg_uuid,
+            from_id,
+            to_id,
+            msg_type: msg.msg_type,
+            timestamp: msg.timestamp,
+            content: msg.content,
+            file,
+        }
+    }
+}
+
+// 聊天记录保留策略（0 表示不限制）
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    #[serde(default)]
+    pub max_age_days: i64,
+    #[serde(default)]
+    pub max_messages_per_conversation: i64,
+    #[serde(default)]
+    pub delete_files: bool, // 删除消息时是否一并删除收到的文件
+}
+
+// 接收文件的规则：对方发来文件提议时决定自动接收、询问还是拒绝
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileAcceptPolicy {
+    #[serde(default = "default_accept_policy")]
+    pub default_policy: String, // auto / ask / reject
+    #[serde(default)]
+    pub max_auto_accept_size: i64, // 超过该大小的文件即使自动接收也要确认（0 表示不限制）
+    #[serde(default)]
+    pub peer_rules: Vec<PeerAcceptRule>, // 按联系人覆盖默认规则
+}
+
+fn default_accept_policy() -> String {
+    "auto".to_string()
+}
+
+impl Default for FileAcceptPolicy {
+    fn default() -> Self {
+        FileAcceptPolicy {
+            default_policy: default_accept_policy(),
+            max_auto_accept_size: 1024 * 1024 * 1024,
+            peer_rules: Vec::new(),
+        }
+    }
+}
+
+// 某个联系人的接收规则 - 对应 file_accept_rules 表
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PeerAcceptRule {
+    pub peer_id: String,
+    pub policy: String,
+}
+
+// 清理结果统计
+#[derive(Debug, Default, Serialize)]
+pub struct PruneSummary {
+    pub deleted_messages: usize,
+    pub deleted_files: usize,
+}
+
+impl PruneSummary {
+    pub fn merge(&mut self, other: PruneSummary) {
+        self.deleted_messages += other.deleted_messages;
+        self.deleted_files += other.deleted_files;
+    }
+}
+
+// 定时备份设置（interval_hours 为 0 表示关闭，keep 为 0 表示不清理旧备份）
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct BackupSchedule {
+    #[serde(default)]
+    pub dir: Option<String>,
+    #[serde(default)]
+    pub interval_hours: i64,
+    #[serde(default)]
+    pub keep: i64,
+}
+
 // 设置结构体 - 对应 settings 表
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Setting {
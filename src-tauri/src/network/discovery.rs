@@ -3,9 +3,7 @@ use std::net::{Ipv4Addr, UdpSocket};
 use std::sync::Arc;
 use std::time::Duration;
 
-#[cfg(feature = "desktop")]
-use tauri::{AppHandle, Emitter};
-
+use crate::events::Events;
 use crate::peers::PeerManager;
 
 const MULTICAST_IP: &str = "224.0.0.167";
@@ -67,20 +65,60 @@ fn get_smart_broadcast_addresses(port: u16) -> Vec<String> {
     addrs
 }
 
-pub async fn start_announcing(port: u16, user_id: String, pool: sqlx::Pool<sqlx::Sqlite>) {
+/// 发送心跳的方式（来自配置文件的 [discovery]）
+#[derive(Debug, Clone)]
+pub struct DiscoveryOptions {
+    /// 是否向广播和组播地址发送心跳
+    pub broadcast: bool,
+    pub interval: Duration,
+    /// 额外单播心跳的地址（"IP:端口"）
+    pub static_peers: Vec<String>,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self {
+            broadcast: true,
+            interval: Duration::from_secs(5),
+            static_peers: Vec::new(),
+        }
+    }
+}
+
+// 心跳发送的目标：广播和组播地址（开启广播时）加上配置的节点地址
+fn announce_targets(port: u16, options: &DiscoveryOptions) -> Vec<String> {
+    let mut targets = if options.broadcast {
+        get_smart_broadcast_addresses(port)
+    } else {
+        Vec::new()
+    };
+    targets.extend(options.static_peers.iter().cloned());
+    targets
+}
+
+pub async fn start_announcing(
+    port: u16,
+    user_id: String,
+    pool: sqlx::Pool<sqlx::Sqlite>,
+    options: DiscoveryOptions,
+) {
     let socket = match create_discovery_socket("0.0.0.0:0", false) {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("[UDP] 创建发送 socket 失败: {}", e);
+            log::error!("[UDP] 创建发送 socket 失败: {}", e);
             return;
         }
     };
 
-    println!("[UDP] 开始通过智能路由遍历发送心跳...");
+    log::info!("[UDP] 开始通过智能路由遍历发送心跳...");
 
     use sysinfo::System;
     let mut sys = System::new();
-    let target_addrs = get_smart_broadcast_addresses(port);
+    let target_addrs = announce_targets(port, &options);
+    if target_addrs.is_empty() {
+        log::info!("[UDP] 广播已关闭且没有配置节点地址，不发送心跳");
+        return;
+    }
 
     loop {
         let username = match crate::db::get_username(&pool).await {
@@ -102,38 +140,78 @@ pub async fn start_announcing(port: u16, user_id: String, pool: sqlx::Pool<sqlx:
         }
 
         // 成功数量通常是 2~4 个（组播 + 全局 + 刚好撞中的你的 84 热点网段等）
-        // println!("[UDP] 心跳发送成功，激活了 {} 个真实路由网段", success_count);
+        // log::info!("[UDP] 心跳发送成功，激活了 {} 个真实路由网段", success_count);
+
+        tokio::time::sleep(options.interval).await;
+    }
+}
 
-        tokio::time::sleep(Duration::from_secs(5)).await;
+/// 退出前通知邻居本机下线，对方立即把本机显示为离线，不用等心跳超时
+/// 只有 4 段，旧版本按字段数忽略这条消息
+pub fn announce_offline(port: u16, user_id: &str, options: &DiscoveryOptions) {
+    let socket = match create_discovery_socket("0.0.0.0:0", false) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("[UDP] 创建发送 socket 失败: {}", e);
+            return;
+        }
+    };
+    let msg = format!("LANChat|OFFLINE|{}|{}", user_id, port);
+    for addr in announce_targets(port, options) {
+        let _ = socket.send_to(msg.as_bytes(), &addr);
     }
+    log::info!("[UDP] 已发送下线通知");
 }
 
-// 桌面端版本 - 带 AppHandle
-#[cfg(all(feature = "desktop", not(feature = "web")))]
+// 监听邻居的广播，发现新用户、用户信息更新或用户下线时发出 new-peer 事件
 pub async fn start_listening(
     port: u16,
     my_id: String,
     _my_name: String,
-    app: Option<AppHandle>,
+    pool: sqlx::Pool<sqlx::Sqlite>,
+    events: Events,
     peer_manager: Arc<PeerManager>,
 ) {
     let bind_addr = format!("0.0.0.0:{}", port);
-    let socket = match create_discovery_socket(&bind_addr, true) {
+    // 异步接收：阻塞的 recv_from 会一直占住运行时的工作线程，由它唤醒的任务（例如 SSE 推送）得不到执行
+    let socket = match create_discovery_socket(&bind_addr, true).and_then(|s| {
+        s.set_nonblocking(true)?;
+        tokio::net::UdpSocket::from_std(s)
+    }) {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("[UDP] 创建监听 socket 失败: {}", e);
+            log::error!("[UDP] 创建监听 socket 失败: {}", e);
             return;
         }
     };
 
     let mut buf = [0u8; 1024];
-    println!("[UDP] 正在端口 {} 监听邻居...", port);
+    log::info!("[UDP] 正在端口 {} 监听邻居...", port);
 
     loop {
-        if let Ok((size, addr)) = socket.recv_from(&mut buf) {
+        if let Ok((size, addr)) = socket.recv_from(&mut buf).await {
             let msg = String::from_utf8_lossy(&buf[..size]);
             let parts: Vec<&str> = msg.split('|').collect();
 
+            if parts.len() >= 3 && parts[0] == "LANChat" && parts[1] == "OFFLINE" {
+                if parts[2] == my_id {
+                    continue;
+                }
+                if let Some(peer) = peer_manager.mark_offline(parts[2], addr.ip()) {
+                    // 对方不会再发来剩下的分块，正在接收的文件标记为中断，等对方续传
+                    if let Err(e) = crate::db::mark_interrupted_downloads(&pool, &peer.id).await {
+                        log::error!("[DB] {}", e);
+                    }
+                    events.emit(
+                        "new-peer",
+                        serde_json::json!({
+                            "id": peer.id, "name": peer.name, "addr": peer.addr, "available_memory_mb": peer.available_memory_mb, "is_offline": true
+                        }),
+                    );
+                }
+                continue;
+            }
+
             if parts.len() >= 6 && parts[0] == "LANChat" {
                 let peer_id = parts[2].to_string();
                 let name = parts[3].to_string();
@@ -155,60 +233,17 @@ pub async fn start_listening(
 
                 // 只在新用户或重新上线时打印日志
                 if is_new_or_reconnected {
-                    println!(
+                    log::info!(
                         "[UDP] 发现用户: {} ({}) at {} (可用内存: {} MB)",
                         name, peer_id, peer_addr, available_memory_mb
                     );
                 }
 
-                if let Some(app_handle) = &app {
-                    let _ = app_handle.emit("new-peer", serde_json::json!({
-                        "id": peer_id, "name": name, "addr": peer_addr, "available_memory_mb": available_memory_mb
-                    }));
-                }
-            }
-        }
-    }
-}
-
-// Web 端版本 - 不带 AppHandle
-#[cfg(all(feature = "web", not(feature = "desktop")))]
-pub async fn start_listening(
-    port: u16,
-    my_id: String,
-    _my_name: String,
-    peer_manager: Arc<PeerManager>,
-) {
-    let bind_addr = format!("0.0.0.0:{}", port);
-    let socket = match create_discovery_socket(&bind_addr, true) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("[UDP] Web端创建监听 socket 失败: {}", e);
-            return;
-        }
-    };
-
-    let mut buf = [0u8; 1024];
-    loop {
-        if let Ok((size, addr)) = socket.recv_from(&mut buf) {
-            let msg = String::from_utf8_lossy(&buf[..size]);
-            let parts: Vec<&str> = msg.split('|').collect();
-
-            if parts.len() >= 6 && parts[0] == "LANChat" {
-                let peer_id = parts[2].to_string();
-                let name = parts[3].to_string();
-                let peer_port = parts[4];
-                let available_memory_mb: u64 = parts[5].parse().unwrap_or(0);
-
-                if peer_id == my_id {
-                    continue;
-                }
-                let peer_addr = format!("{}:{}", addr.ip(), peer_port);
-                peer_manager.add_or_update_with_memory(
-                    peer_id,
-                    name,
-                    peer_addr,
-                    available_memory_mb,
+                events.emit(
+                    "new-peer",
+                    serde_json::json!({
+                        "id": peer_id, "name": name, "addr": peer_addr, "available_memory_mb": available_memory_mb, "is_offline": false
+                    }),
                 );
             }
         }
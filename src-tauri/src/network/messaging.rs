@@ -2,8 +2,7 @@
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-#[cfg(feature = "desktop")]
-use tauri::Emitter;
+use crate::events::Events;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TextMessage {
@@ -12,38 +11,86 @@ pub struct TextMessage {
     pub from_name: String, // 发送者名字
     pub content: String,   // 消息内容
     pub timestamp: u64,    // Unix 时间戳
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<String>, // 跨节点稳定的消息 ID（旧版本不发送）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e2e: Option<crate::e2e::Envelope>, // 端到端加密的内容（此时 content 为空）
+}
+
+// 表情回应帧 - 在双方之间同步对某条消息的回应（不加密）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionMessage {
+    pub msg_type: String,   // "reaction"
+    pub from_id: String,    // 回应者 UUID
+    pub message_id: String, // 被回应消息的 msg_id
+    pub emoji: String,
+    pub action: String,     // "add" 或 "remove"
+    pub timestamp: u64,
 }
 
 // 发送文本消息
 pub async fn send_text_message(
     peer_addr: &str,
+    peer_id: &str,
     from_id: String,
     from_name: String,
+    msg_id: String,
     content: String,
 ) -> Result<(), String> {
-    println!("[Messaging] 正在连接到 {}...", peer_addr);
+    log::info!("[Messaging] 正在连接到 {}...", peer_addr);
+
+    // 先取得对方的身份公钥，用会话密钥加密内容
+    let client = crate::tls::PeerClient::connect(peer_addr, peer_id, &from_id).await?;
+    let peer_key = crate::e2e::fetch_peer_key(&client, peer_id).await?;
 
     // 构造消息
-    let message = TextMessage {
+    let mut message = TextMessage {
         msg_type: "text".to_string(),
         from_id,
         from_name,
+        msg_id: Some(msg_id),
         content,
         timestamp: std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs(),
+        e2e: None,
     };
 
+    match peer_key {
+        Some(peer_key) => {
+            let key = crate::e2e::session_key(&peer_key)?;
+            let aad = message_aad(&message);
+            message.e2e = Some(crate::e2e::seal_message(&key, &aad, &message.content)?);
+            message.content = String::new();
+        }
+        // 对方是没有端到端加密的旧版本
+        None => {
+            crate::e2e::check_plaintext(
+                peer_id,
+                "对方不支持端到端加密，可能是旧版本；如果信任当前网络，可以在设置中允许明文连接",
+            )
+            .await?;
+            log::info!("[Messaging] 对方不支持端到端加密，发送未加密的消息");
+        }
+    }
+
     // 序列化为 JSON
     let json = serde_json::to_string(&message).map_err(|e| format!("序列化失败: {}", e))?;
 
-    // 尝试通过 WebSocket 发送
-    let ws_url = format!("ws://{}/ws", peer_addr);
-
-    match tokio_tungstenite::connect_async(&ws_url).await {
+    // 尝试通过 WebSocket 发送（优先使用 TLS）
+    let connection = crate::tls::connect(peer_addr, peer_id).await?;
+    let identity = crate::e2e::known_auth_key(peer_id).await;
+    let request = signed_ws_request(
+        peer_addr,
+        connection.encrypted,
+        &message.from_id,
+        identity.as_ref(),
+    )?;
+
+    match tokio_tungstenite::client_async(request, connection.stream).await {
         Ok((mut ws_stream, _)) => {
-            println!("[Messaging] WebSocket 连接成功");
+            log::info!("[Messaging] WebSocket 连接成功");
 
             use futures_util::SinkExt;
             use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
@@ -56,14 +103,151 @@ pub async fn send_text_message(
             // 优雅地关闭连接
             let _ = ws_stream.close(None).await;
 
-            println!("[Messaging] 消息发送成功");
+            log::info!("[Messaging] 消息发送成功");
             Ok(())
         }
-        Err(e) => {
-            eprintln!("[Messaging] WebSocket 连接失败: {}, 尝试 TCP", e);
-            // 回退到 TCP
+        // 对方要求节点认证且签名不匹配，回退到 TCP 也不会被接受
+        Err(tokio_tungstenite::tungstenite::Error::Http(response))
+            if response.status() == reqwest::StatusCode::UNAUTHORIZED =>
+        {
+            Err("对方拒绝了连接，请确认双方设置了相同的局域网密钥".to_string())
+        }
+        // 回退到 TCP（明文），需要用户允许明文连接
+        Err(e) if crate::tls::allow_plaintext() => {
+            log::error!("[Messaging] WebSocket 连接失败: {}, 尝试 TCP", e);
             send_via_tcp(peer_addr, message).await
         }
+        Err(e) => Err(format!("WebSocket 连接失败: {}", e)),
+    }
+}
+
+// WebSocket 握手请求，附带节点签名（设置了局域网密钥时）和身份签名（知道对方的身份公钥时）
+fn signed_ws_request(
+    peer_addr: &str,
+    encrypted: bool,
+    my_id: &str,
+    identity: Option<&[u8; 32]>,
+) -> Result<tokio_tungstenite::tungstenite::handshake::client::Request, String> {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let scheme = if encrypted { "wss" } else { "ws" };
+    let mut request = format!("{}://{}/ws", scheme, peer_addr)
+        .into_client_request()
+        .map_err(|e| format!("WebSocket 地址无效: {}", e))?;
+    for (name, value) in crate::auth::peer_headers(
+        my_id,
+        identity,
+        "GET",
+        "/ws",
+        &crate::auth::content_hash(&[]),
+    ) {
+        let value = value.parse().map_err(|_| "节点签名无效".to_string())?;
+        request.headers_mut().insert(name, value);
+    }
+    Ok(request)
+}
+
+// 发送表情回应（仅支持 WebSocket，旧版本对方会忽略无法解析的帧）
+pub async fn send_reaction(
+    peer_addr: &str,
+    peer_id: &str,
+    from_id: String,
+    message_id: String,
+    emoji: String,
+    remove: bool,
+) -> Result<(), String> {
+    let reaction = ReactionMessage {
+        msg_type: "reaction".to_string(),
+        from_id,
+        message_id,
+        emoji,
+        action: if remove { "remove" } else { "add" }.to_string(),
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    };
+
+    let json = serde_json::to_string(&reaction).map_err(|e| format!("序列化失败: {}", e))?;
+    let connection = crate::tls::connect(peer_addr, peer_id).await?;
+    let identity = crate::e2e::known_auth_key(peer_id).await;
+    let request = signed_ws_request(
+        peer_addr,
+        connection.encrypted,
+        &reaction.from_id,
+        identity.as_ref(),
+    )?;
+    let (mut ws_stream, _) = tokio_tungstenite::client_async(request, connection.stream)
+        .await
+        .map_err(|e| format!("WebSocket 连接失败: {}", e))?;
+
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
+
+    ws_stream
+        .send(WsMessage::Text(json))
+        .await
+        .map_err(|e| format!("发送失败: {}", e))?;
+
+    let _ = ws_stream.close(None).await;
+
+    log::info!("[Messaging] 表情回应发送成功");
+    Ok(())
+}
+
+// 加密内容绑定到发送者和消息 ID，不能挪用到其他消息
+fn message_aad(message: &TextMessage) -> String {
+    format!(
+        "{}:{}",
+        message.from_id,
+        message.msg_id.as_deref().unwrap_or_default()
+    )
+}
+
+/// 解密收到的消息（核对发送者的身份公钥）；没有加密的消息只有允许明文连接、并且对方不支持端到端加密时才接收
+pub async fn open_message(message: &mut TextMessage, events: &Events) -> Result<(), String> {
+    match message.e2e.take() {
+        Some(envelope) => {
+            let sender_key =
+                crate::e2e::trust_sender_key(&message.from_id, &envelope.sender_key, events)
+                    .await?;
+            let key = crate::e2e::session_key(&sender_key)?;
+            message.content = crate::e2e::open_message(&key, &envelope, &message_aad(message))?;
+            Ok(())
+        }
+        None => {
+            crate::e2e::check_plaintext(
+                &message.from_id,
+                "拒绝没有端到端加密的消息（可以在设置中允许明文连接）",
+            )
+            .await
+        }
+    }
+}
+
+// 保存收到的表情回应
+pub async fn apply_reaction(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    reaction: &ReactionMessage,
+) -> Result<(), String> {
+    crate::db::validate_emoji(&reaction.emoji)?;
+
+    match reaction.action.as_str() {
+        "add" => {
+            crate::db::add_reaction(
+                pool,
+                &reaction.message_id,
+                &reaction.from_id,
+                &reaction.emoji,
+                reaction.timestamp as i64,
+            )
+            .await
+        }
+        "remove" => {
+            crate::db::remove_reaction(pool, &reaction.message_id, &reaction.from_id, &reaction.emoji)
+                .await
+        }
+        other => Err(format!("未知的回应操作: {}", other)),
     }
 }
 
@@ -90,113 +274,50 @@ async fn send_via_tcp(peer_addr: &str, message: TextMessage) -> Result<(), Strin
         .await
         .map_err(|e| format!("发送消息失败: {}", e))?;
 
-    println!("[Messaging] TCP 消息发送成功");
+    log::info!("[Messaging] TCP 消息发送成功");
     Ok(())
 }
 
 // 启动消息接收服务器
-pub async fn start_message_server(
-    port: u16,
-    db_pool: sqlx::Pool<sqlx::Sqlite>,
-    #[cfg(feature = "desktop")] app_handle: Option<tauri::AppHandle>,
-) {
+pub async fn start_message_server(port: u16, db_pool: sqlx::Pool<sqlx::Sqlite>, events: Events) {
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
         .await
         .expect("无法绑定消息服务器端口");
 
-    println!("[Messaging] 消息服务器启动在端口 {}", port);
+    log::info!("[Messaging] 消息服务器启动在端口 {}", port);
 
     loop {
         match listener.accept().await {
             Ok((stream, addr)) => {
-                println!("[Messaging] 收到来自 {} 的连接", addr);
+                log::info!("[Messaging] 收到来自 {} 的连接", addr);
 
                 let pool = db_pool.clone();
-                #[cfg(feature = "desktop")]
-                let app = app_handle.clone();
+                let events = events.clone();
 
                 tokio::spawn(async move {
-                    #[cfg(feature = "desktop")]
-                    let result = handle_message_connection(stream, pool, app).await;
-
-                    #[cfg(feature = "web")]
-                    let result = handle_message_connection(stream, pool).await;
-
-                    if let Err(e) = result {
-                        eprintln!("[Messaging] 处理消息失败: {}", e);
+                    if let Err(e) = handle_message_connection(stream, pool, events).await {
+                        log::error!("[Messaging] 处理消息失败: {}", e);
                     }
                 });
             }
             Err(e) => {
-                eprintln!("[Messaging] 接受连接失败: {}", e);
+                log::error!("[Messaging] 接受连接失败: {}", e);
             }
         }
     }
 }
 
-// 处理单个消息连接 - 桌面端版本
-#[cfg(all(feature = "desktop", not(feature = "web")))]
+// 处理单个消息连接
 async fn handle_message_connection(
     mut stream: tokio::net::TcpStream,
     db_pool: sqlx::Pool<sqlx::Sqlite>,
-    app_handle: Option<tauri::AppHandle>,
+    events: Events,
 ) -> Result<(), String> {
-    // 读取消息长度(4字节)
-    let mut len_bytes = [0u8; 4];
-    stream
-        .read_exact(&mut len_bytes)
-        .await
-        .map_err(|e| format!("读取长度失败: {}", e))?;
-
-    let len = u32::from_be_bytes(len_bytes) as usize;
-
-    if len > 1024 * 1024 {
-        return Err("消息过大".to_string());
+    // 旧版 TCP 协议无法携带节点签名，设置了局域网密钥时不接收
+    if crate::auth::peer_key_enabled() {
+        return Err("已设置局域网密钥，拒绝未签名的 TCP 消息".to_string());
     }
 
-    // 读取消息内容
-    let mut buffer = vec![0u8; len];
-    stream
-        .read_exact(&mut buffer)
-        .await
-        .map_err(|e| format!("读取消息失败: {}", e))?;
-
-    // 解析 JSON
-    let json_str = String::from_utf8(buffer).map_err(|e| format!("UTF-8 解析失败: {}", e))?;
-
-    let message: TextMessage =
-        serde_json::from_str(&json_str).map_err(|e| format!("JSON 解析失败: {}", e))?;
-
-    println!(
-        "[Messaging] 收到消息: {} 说: {}",
-        message.from_name, message.content
-    );
-
-    // 保存到数据库
-    save_message_to_db(&db_pool, &message).await?;
-
-    // 发送事件通知前端
-    if let Some(app) = app_handle {
-        let _ = app.emit(
-            "new-message",
-            serde_json::json!({
-                "from_id": message.from_id,
-                "from_name": message.from_name,
-                "content": message.content,
-                "timestamp": message.timestamp,
-            }),
-        );
-    }
-
-    Ok(())
-}
-
-// Web 端的消息处理 - 不带 AppHandle
-#[cfg(all(feature = "web", not(feature = "desktop")))]
-async fn handle_message_connection(
-    mut stream: tokio::net::TcpStream,
-    db_pool: sqlx::Pool<sqlx::Sqlite>,
-) -> Result<(), String> {
     // 读取消息长度(4字节)
     let mut len_bytes = [0u8; 4];
     stream
@@ -220,18 +341,28 @@ async fn handle_message_connection(
     // 解析 JSON
     let json_str = String::from_utf8(buffer).map_err(|e| format!("UTF-8 解析失败: {}", e))?;
 
-    let message: TextMessage =
+    let mut message: TextMessage =
         serde_json::from_str(&json_str).map_err(|e| format!("JSON 解析失败: {}", e))?;
+    open_message(&mut message, &events).await?;
 
-    println!(
-        "[Messaging] 收到消息: {} 说: {}",
-        message.from_name, message.content
-    );
+    // 日志里不写消息内容
+    log::info!("[Messaging] 收到来自 {} 的消息", message.from_name);
 
     // 保存到数据库
     save_message_to_db(&db_pool, &message).await?;
 
-    // Web 端暂时只保存,不通知前端(前端会轮询)
+    // 通知前端
+    events.emit(
+        "new-message",
+        serde_json::json!({
+            "message_id": message.msg_id,
+            "from_id": message.from_id,
+            "from_name": message.from_name,
+            "content": message.content,
+            "timestamp": message.timestamp,
+            "msg_type": message.msg_type,
+        }),
+    );
 
     Ok(())
 }
@@ -244,19 +375,23 @@ async fn save_message_to_db(
     // 获取当前用户ID作为接收者
     let my_id = crate::db::get_user_id(pool).await?;
 
+    // 开启了加密时加密内容（锁定状态下无法保存，消息会被拒收）
+    let content = crate::encryption::encrypt_content(&message.content)?;
+
     sqlx::query(
-        "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp) VALUES (?, ?, ?, ?, ?)"
+        "INSERT OR IGNORE INTO messages (sender_id, receiver_id, content, msg_type, timestamp, msg_uuid) VALUES (?, ?, ?, ?, ?, ?)"
     )
     .bind(&message.from_id)  // 发送者ID
     .bind(&my_id)            // 接收者ID（当前用户）
-    .bind(&message.content)
+    .bind(&content)
     .bind(&message.msg_type)
     .bind(message.timestamp as i64)
+    .bind(message.msg_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string()))
     .execute(pool)
     .await
     .map_err(|e| format!("保存消息失败: {}", e))?;
 
-    println!("[Messaging] 消息已保存到数据库");
+    log::info!("[Messaging] 消息已保存到数据库");
     Ok(())
 }
 
@@ -284,10 +419,10 @@ pub async fn get_chat_history_with_offset(
     // 2. 对方发送给我的消息 (sender_id = peer_id AND (receiver_id = my_id OR receiver_id IS NULL))
     // 3. 兼容旧数据：sender_id = 'me' 的消息
     // 使用子查询先排序再分页，确保获取最新的消息
-    let messages = sqlx::query_as::<_, crate::models::Message>(
-        "SELECT id, sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status 
+    let mut messages = sqlx::query_as::<_, crate::models::Message>(
+        "SELECT id, sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status, msg_uuid 
          FROM (
-            SELECT id, sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status 
+            SELECT id, sender_id, receiver_id, content, msg_type, timestamp, file_path, file_status, msg_uuid 
             FROM messages 
             WHERE 
                 (sender_id = ? AND receiver_id = ?) OR 
@@ -309,11 +444,22 @@ pub async fn get_chat_history_with_offset(
     .await
     .map_err(|e| format!("查询历史失败: {}", e))?;
 
+    for msg in messages.iter_mut() {
+        msg.content = crate::encryption::decrypt_content(&msg.content)?;
+    }
+
+    // 聚合这一页消息的表情回应
+    let message_ids: Vec<String> = messages.iter().filter_map(|m| m.msg_uuid.clone()).collect();
+    let mut reactions = crate::db::get_reactions_for_messages(pool, &message_ids).await?;
+
     // 转换为 MessageResponse 并序列化为 JSON
     let responses: Vec<serde_json::Value> = messages
         .into_iter()
         .map(|msg| {
-            let response = crate::models::MessageResponse::from(msg);
+            let mut response = crate::models::MessageResponse::from(msg);
+            if let Some(id) = &response.message_id {
+                response.reactions = reactions.remove(id).unwrap_or_default();
+            }
             serde_json::to_value(response).unwrap_or(serde_json::json!({}))
         })
         .collect();
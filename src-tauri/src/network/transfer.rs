@@ -0,0 +1,641 @@
+// 文件分块上传（发送方）
+// 每次传输有一个稳定的 transfer_id（即文件消息的 msg_uuid），接收方按 transfer_id 记录已收到的字节数
+// 网络中断或程序重启后，发送方先询问接收方已有多少字节，再从该位置继续上传
+// 每个分块带 SHA-256 校验值，整个文件的 SHA-256 随每个分块发送，接收方收齐后校验
+// 分块数据从文件流式读出直接写入请求体，内存占用与分块大小无关
+// 上传之前先发送文件提议（名称、大小、校验值、类型），接收方接受后才开始传输数据
+// 对方支持端到端加密时，分块数据用双方的会话密钥分段加密（校验值仍按明文计算）
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::tls::PeerClient;
+
+/// 分块大小：只决定续传的粒度和单个请求的耗时，不再受内存限制
+/// 千兆局域网下一块大约 0.2 秒，中断后最多重传这么多数据
+pub const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+// 读取文件时的缓冲区大小
+const READ_BUFFER: usize = 256 * 1024;
+
+// 等待接收方确认文件提议时的查询间隔和最长等待时间
+const DECISION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const DECISION_TIMEOUT: Duration = Duration::from_secs(600);
+
+// 单个分块连续失败的最大重试次数
+const MAX_RETRIES: u32 = 5;
+// 第一次重试前的等待时间，之后每次翻倍
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// 一次文件上传的参数
+pub struct UploadTask {
+    pub transfer_id: String,
+    pub peer_addr: String,
+    /// 接收方的节点 ID（按它校验接收方的证书）
+    pub peer_id: String,
+    pub sender_id: String,
+    pub file_name: String,
+    pub file_size: u64,
+    pub file_hash: String,
+    pub mime_type: String,
+}
+
+/// 文件提议（发送方在传输数据之前发给接收方）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOffer {
+    pub transfer_id: String,
+    pub sender_id: String,
+    pub file_name: String,
+    pub file_size: u64,
+    #[serde(default)]
+    pub file_hash: Option<String>,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    /// 发送方的身份公钥（端到端加密，旧版本不发送）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_key: Option<String>,
+}
+
+/// 上传进度（每个分块完成后报告一次）
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UploadProgress {
+    /// 接收方已收到的字节数
+    pub uploaded: u64,
+    pub total: u64,
+    /// 本次上传的平均速度（MB/s）
+    pub speed_mb_s: f64,
+}
+
+/// 接收方对文件提议的答复
+/// decision: accepted（可以开始传输）、pending（等待用户确认）、rejected（拒绝）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfferReply {
+    pub transfer_id: String,
+    pub decision: String,
+    #[serde(default)]
+    pub received_bytes: u64,
+}
+
+// 接收方返回的传输进度
+#[derive(Deserialize)]
+struct TransferProgress {
+    received_bytes: u64,
+    #[serde(default)]
+    status: Option<String>,
+}
+
+// 单个分块上传的结果
+enum ChunkError {
+    // 接收方已有的字节数与本次上传的偏移不一致，需要从接收方的位置继续
+    OffsetMismatch(u64),
+    // 接收方拒绝了这个分块（请求本身有问题），重试也不会成功
+    Rejected(String),
+    // 网络错误或接收方出错，可以重试
+    Failed(String),
+}
+
+/// 查询接收方已收到的字节数（接收方没有这次传输的记录时返回 0）
+pub async fn query_received_bytes(client: &PeerClient, task: &UploadTask) -> Result<u64, String> {
+    let response = client
+        .get(&format!("/api/upload/{}", task.transfer_id))
+        .send()
+        .await
+        .map_err(|e| format!("查询传输进度失败: {}", e))?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(0);
+    }
+    if !response.status().is_success() {
+        return Err(format!("查询传输进度失败: HTTP {}", response.status()));
+    }
+
+    response
+        .json::<TransferProgress>()
+        .await
+        .map(|p| p.received_bytes)
+        .map_err(|e| format!("解析传输进度失败: {}", e))
+}
+
+// 连接接收方（按接收方的节点 ID 校验证书）
+async fn connect(task: &UploadTask) -> Result<PeerClient, String> {
+    PeerClient::connect(&task.peer_addr, &task.peer_id, &task.sender_id).await
+}
+
+// 取得接收方的身份公钥；对方不支持端到端加密时，只有允许明文连接才继续
+async fn peer_session_key(
+    client: &PeerClient,
+    task: &UploadTask,
+) -> Result<Option<crate::e2e::SessionKey>, String> {
+    match crate::e2e::fetch_peer_key(client, &task.peer_id).await? {
+        Some(peer_key) => crate::e2e::session_key(&peer_key).map(Some),
+        None => {
+            crate::e2e::check_plaintext(
+                &task.peer_id,
+                "对方不支持端到端加密，可能是旧版本；如果信任当前网络，可以在设置中允许明文连接",
+            )
+            .await?;
+            log::info!("[Transfer] 接收方不支持端到端加密，发送未加密的文件");
+            Ok(None)
+        }
+    }
+}
+
+/// 向接收方发送文件提议，返回接收方的决定（accepted / pending / rejected）
+/// 旧版本接收方没有提议接口，直接视为接受
+pub async fn offer(task: &UploadTask) -> Result<String, String> {
+    let client = connect(task).await?;
+    let session = peer_session_key(&client, task).await?;
+    let offer = FileOffer {
+        transfer_id: task.transfer_id.clone(),
+        sender_id: task.sender_id.clone(),
+        file_name: task.file_name.clone(),
+        file_size: task.file_size,
+        file_hash: Some(task.file_hash.clone()),
+        mime_type: Some(task.mime_type.clone()),
+        sender_key: session.is_some().then(crate::e2e::public_key),
+    };
+
+    let response = client
+        .post_json("/api/offer", &offer)?
+        .send()
+        .await
+        .map_err(|e| format!("发送文件提议失败: {}", e))?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::METHOD_NOT_ALLOWED
+    {
+        log::info!("[Transfer] 接收方不支持文件提议，直接上传");
+        return Ok("accepted".to_string());
+    }
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(format!("发送文件提议失败: HTTP {} {}", status, error_text));
+    }
+
+    let reply = response
+        .json::<OfferReply>()
+        .await
+        .map_err(|e| format!("解析文件提议答复失败: {}", e))?;
+    log::info!(
+        "[Transfer] 文件提议的答复: {} ({})",
+        reply.decision,
+        task.file_name
+    );
+    Ok(reply.decision)
+}
+
+/// 等待接收方确认文件提议，接受时返回 true，拒绝时返回 false，超时返回错误
+pub async fn wait_for_decision(task: &UploadTask) -> Result<bool, String> {
+    let client = connect(task).await?;
+    let path = format!("/api/upload/{}", task.transfer_id);
+    let start = std::time::Instant::now();
+
+    loop {
+        tokio::time::sleep(DECISION_POLL_INTERVAL).await;
+        if start.elapsed() > DECISION_TIMEOUT {
+            return Err("等待对方接收超时".to_string());
+        }
+
+        // 网络暂时不通时继续等待
+        let Ok(response) = client.get(&path).send().await else {
+            continue;
+        };
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err("对方没有这次传输的记录".to_string());
+        }
+        let Ok(progress) = response.json::<TransferProgress>().await else {
+            continue;
+        };
+        match progress.status.as_deref() {
+            Some("pending") => continue,
+            Some("rejected") => return Ok(false),
+            _ => return Ok(true),
+        }
+    }
+}
+
+/// 上传文件，从接收方已有的位置开始；网络中断时自动重试并续传
+pub async fn upload(
+    task: &UploadTask,
+    file: &mut File,
+    on_progress: impl Fn(UploadProgress),
+) -> Result<(), String> {
+    let client = connect(task).await?;
+    let session = peer_session_key(&client, task).await?;
+
+    let file_hash = &task.file_hash;
+    let chunk_total = task.file_size.div_ceil(CHUNK_SIZE);
+
+    let mut offset = query_received_bytes(&client, task).await?;
+    if offset > 0 {
+        log::info!(
+            "[Transfer] 接收方已有 {} 字节，从断点继续上传: {}",
+            offset,
+            task.file_name
+        );
+    }
+
+    let start_offset = offset;
+    let start_time = std::time::Instant::now();
+    let mut retries = 0;
+    let mut mismatches = 0;
+
+    while offset < task.file_size {
+        let len = CHUNK_SIZE.min(task.file_size - offset);
+        let chunk_index = offset / CHUNK_SIZE;
+
+        log::info!(
+            "[Transfer] 上传分块 {}/{}, 偏移: {}, 大小: {} 字节",
+            chunk_index + 1,
+            chunk_total,
+            offset,
+            len
+        );
+
+        // 先读一遍计算分块校验值（校验值要放在分块数据之前发送），再流式上传
+        let chunk_info = ChunkInfo {
+            offset,
+            len,
+            index: chunk_index,
+            total: chunk_total,
+            chunk_hash: hash_range(file, offset, len).await?,
+            file_hash,
+            e2e_nonce: session.as_ref().map(|_| crate::e2e::new_nonce_prefix()),
+        };
+        let body = chunk_body(file, task, &chunk_info, session.as_ref()).await?;
+
+        match send_chunk(&client, task, &chunk_info, body).await {
+            Ok(received) => {
+                offset = received;
+                retries = 0;
+            }
+            Err(ChunkError::OffsetMismatch(received)) => {
+                log::info!(
+                    "[Transfer] 接收方进度为 {} 字节，与本地偏移 {} 不一致，从接收方的位置继续",
+                    received,
+                    offset
+                );
+                // 不随成功的分块清零：接收方整个文件校验失败时会反复要求从头重传
+                mismatches += 1;
+                if mismatches > MAX_RETRIES {
+                    return Err("无法与接收方同步传输进度".to_string());
+                }
+                offset = received;
+            }
+            Err(ChunkError::Rejected(e)) => {
+                return Err(format!("接收方拒绝了上传: {}", e));
+            }
+            Err(ChunkError::Failed(e)) => {
+                retries += 1;
+                if retries > MAX_RETRIES {
+                    return Err(format!("上传分块失败: {}", e));
+                }
+                let delay = RETRY_DELAY * 2u32.pow(retries - 1);
+                log::error!(
+                    "[Transfer] ✗ 上传分块失败: {}，{} 秒后第 {} 次重试",
+                    e,
+                    delay.as_secs(),
+                    retries
+                );
+                tokio::time::sleep(delay).await;
+
+                // 失败的分块可能已经部分写入，重新询问接收方的进度
+                if let Ok(received) = query_received_bytes(&client, task).await {
+                    offset = received;
+                }
+                continue;
+            }
+        }
+
+        let elapsed = start_time.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            let speed = offset.saturating_sub(start_offset) as f64 / (1024.0 * 1024.0) / elapsed;
+            log::info!(
+                "[Transfer] 已上传: {} MB, 速度: {:.2} MB/s",
+                offset / (1024 * 1024),
+                speed
+            );
+            on_progress(UploadProgress {
+                uploaded: offset,
+                total: task.file_size,
+                speed_mb_s: speed,
+            });
+        }
+    }
+
+    let total_time = start_time.elapsed().as_secs_f64();
+    let avg_speed = (task.file_size - start_offset) as f64 / (1024.0 * 1024.0) / total_time;
+    log::info!(
+        "[Transfer] ✓ 文件上传完成，耗时: {:.2}s, 平均速度: {:.2} MB/s",
+        total_time,
+        avg_speed
+    );
+    Ok(())
+}
+
+/// 发送一个文件：创建发送记录（状态为 uploading），然后提议并上传，返回传输 ID
+/// file_path 只用于记录（续传时重新打开），Android 上可以是 content URI
+pub async fn send_file(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    peer_id: &str,
+    peer_addr: &str,
+    file_name: &str,
+    file_path: &str,
+    file: File,
+    on_progress: impl Fn(UploadProgress),
+) -> Result<String, String> {
+    let file_size = file
+        .metadata()
+        .await
+        .map_err(|e| format!("读取文件信息失败: {}", e))?
+        .len();
+
+    // 传输 ID 同时作为跨节点消息 ID，接收方按它记录进度
+    let (id, transfer_id) = crate::db::save_file_message(
+        pool,
+        peer_id.to_string(),
+        file_name.to_string(),
+        file_size as usize,
+        file_path.to_string(),
+        "uploading".to_string(),
+    )
+    .await?;
+    log::info!(
+        "[Transfer] ✓ 已创建上传中记录，ID: {}, 传输 ID: {}",
+        id,
+        transfer_id
+    );
+
+    run_upload(pool, &transfer_id, peer_addr, file, on_progress).await?;
+    Ok(transfer_id)
+}
+
+/// 发送文件提议并上传（新的发送和续传共用），维护数据库中的状态：
+/// 成功为 sent，对方拒绝为 rejected，失败为 interrupted（可以续传）
+pub async fn run_upload(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    transfer_id: &str,
+    peer_addr: &str,
+    mut file: File,
+    on_progress: impl Fn(UploadProgress),
+) -> Result<(), String> {
+    let my_id = crate::db::get_user_id(pool).await?;
+    // 接收方的节点 ID，按它校验对方的证书
+    let transfer = crate::db::get_transfer(pool, transfer_id)
+        .await?
+        .ok_or("找不到这次传输")?;
+    let file_size = file
+        .metadata()
+        .await
+        .map_err(|e| format!("读取文件信息失败: {}", e))?
+        .len();
+
+    // 文件提议里带上整个文件的校验值和类型
+    let file_hash = match hash_file(&mut file).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            let _ = crate::db::update_transfer_status(pool, transfer_id, "interrupted").await;
+            return Err(e);
+        }
+    };
+    log::info!("[Transfer] 文件 SHA-256: {}", file_hash);
+    if let Err(e) = crate::db::set_transfer_hash(pool, transfer_id, &file_hash).await {
+        log::warn!("[Transfer] ⚠ {}", e);
+    }
+
+    let task = UploadTask {
+        transfer_id: transfer_id.to_string(),
+        peer_addr: peer_addr.to_string(),
+        peer_id: transfer.receiver_id,
+        sender_id: my_id,
+        mime_type: mime_guess::from_path(&transfer.file_name)
+            .first_or_octet_stream()
+            .to_string(),
+        file_name: transfer.file_name,
+        file_size,
+        file_hash,
+    };
+
+    let result = offer_and_upload(pool, &task, &mut file, on_progress).await;
+
+    // 失败时保留记录并标记为中断，之后可以从断点续传
+    let status = match result {
+        Ok(true) => "sent",
+        Ok(false) => "rejected",
+        Err(_) => "interrupted",
+    };
+    if let Err(e) = crate::db::update_transfer_status(pool, transfer_id, status).await {
+        log::warn!("[Transfer] ⚠ 更新数据库状态失败: {}", e);
+    }
+    if !result? {
+        return Err("对方拒绝了这个文件".to_string());
+    }
+    Ok(())
+}
+
+// 先发送文件提议，对方接受后再上传数据；对方拒绝时返回 false
+async fn offer_and_upload(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    task: &UploadTask,
+    file: &mut File,
+    on_progress: impl Fn(UploadProgress),
+) -> Result<bool, String> {
+    let accepted = match offer(task).await?.as_str() {
+        "accepted" => true,
+        "rejected" => false,
+        _ => {
+            log::info!("[Transfer] 等待对方确认接收: {}", task.file_name);
+            let _ = crate::db::update_transfer_status(pool, &task.transfer_id, "waiting").await;
+            let accepted = wait_for_decision(task).await?;
+            if accepted {
+                let _ =
+                    crate::db::update_transfer_status(pool, &task.transfer_id, "uploading").await;
+            }
+            accepted
+        }
+    };
+    if !accepted {
+        log::info!("[Transfer] 对方拒绝了文件: {}", task.file_name);
+        return Ok(false);
+    }
+
+    upload(task, file, on_progress).await?;
+    Ok(true)
+}
+
+/// 计算整个文件的 SHA-256
+pub async fn hash_file(file: &mut File) -> Result<String, String> {
+    use sha2::{Digest, Sha256};
+
+    file.seek(std::io::SeekFrom::Start(0))
+        .await
+        .map_err(|e| format!("定位文件失败: {}", e))?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; READ_BUFFER];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .map_err(|e| format!("读取文件失败: {}", e))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// 计算文件中一段数据的 SHA-256
+async fn hash_range(file: &mut File, offset: u64, len: u64) -> Result<String, String> {
+    use sha2::{Digest, Sha256};
+
+    file.seek(std::io::SeekFrom::Start(offset))
+        .await
+        .map_err(|e| format!("定位文件失败: {}", e))?;
+
+    let mut reader = (&mut *file).take(len);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; READ_BUFFER];
+    let mut total = 0;
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .await
+            .map_err(|e| format!("读取文件失败: {}", e))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        total += n as u64;
+    }
+
+    if total < len {
+        return Err(format!(
+            "文件在上传过程中变小了（期望 {} 字节，只读到 {} 字节）",
+            offset + len,
+            offset + total
+        ));
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// 把文件中的一段数据包装成流式请求体（复制一个文件句柄，读取时才从磁盘加载）
+// 端到端加密时边读边分段加密
+async fn chunk_body(
+    file: &File,
+    task: &UploadTask,
+    info: &ChunkInfo<'_>,
+    session: Option<&crate::e2e::SessionKey>,
+) -> Result<reqwest::Body, String> {
+    let (offset, len) = (info.offset, info.len);
+    let mut handle = file
+        .try_clone()
+        .await
+        .map_err(|e| format!("打开文件失败: {}", e))?;
+    handle
+        .seek(std::io::SeekFrom::Start(offset))
+        .await
+        .map_err(|e| format!("定位文件失败: {}", e))?;
+
+    let reader = handle.take(len);
+    Ok(match (session, info.e2e_nonce) {
+        (Some(key), Some(prefix)) => reqwest::Body::wrap_stream(crate::e2e::seal_stream(
+            key,
+            prefix,
+            crate::e2e::chunk_aad(&task.transfer_id, offset),
+            reader,
+        )),
+        _ => reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::with_capacity(
+            reader,
+            READ_BUFFER,
+        )),
+    })
+}
+
+// 分块在文件中的位置和校验信息
+struct ChunkInfo<'a> {
+    offset: u64,
+    len: u64,
+    index: u64,
+    total: u64,
+    chunk_hash: String,
+    file_hash: &'a str,
+    e2e_nonce: Option<[u8; 8]>, // 端到端加密时分段 nonce 的前缀
+}
+
+// 上传一个分块，成功时返回接收方已收到的字节数
+async fn send_chunk(
+    client: &PeerClient,
+    task: &UploadTask,
+    info: &ChunkInfo<'_>,
+    body: reqwest::Body,
+) -> Result<u64, ChunkError> {
+    let part_len = match info.e2e_nonce {
+        Some(_) => crate::e2e::sealed_len(info.len),
+        None => info.len,
+    };
+    let part = reqwest::multipart::Part::stream_with_length(body, part_len)
+        .mime_str("application/octet-stream")
+        .map_err(|e| ChunkError::Failed(format!("设置 MIME 类型失败: {}", e)))?;
+
+    let mut form = reqwest::multipart::Form::new()
+        .text("transfer_id", task.transfer_id.clone())
+        .text("msg_id", task.transfer_id.clone())
+        .text("peer_id", task.sender_id.clone())
+        .text("file_name", task.file_name.clone())
+        .text("file_size", task.file_size.to_string())
+        .text("offset", info.offset.to_string())
+        .text("chunk_index", info.index.to_string())
+        .text("chunk_total", info.total.to_string())
+        .text("chunk_hash", info.chunk_hash.clone())
+        .text("file_hash", info.file_hash.to_string());
+    // chunk 必须是最后一个字段
+    if let Some(prefix) = info.e2e_nonce {
+        use base64::Engine;
+        form = form.text(
+            "e2e_nonce",
+            base64::engine::general_purpose::STANDARD.encode(prefix),
+        );
+    }
+    let form = form.part("chunk", part);
+
+    let response = client
+        .post_unsigned_payload("/api/upload")
+        .multipart(form)
+        .send()
+        .await
+        .map_err(|e| ChunkError::Failed(e.to_string()))?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::CONFLICT {
+        let progress = response
+            .json::<TransferProgress>()
+            .await
+            .map_err(|e| ChunkError::Failed(format!("解析传输进度失败: {}", e)))?;
+        return Err(ChunkError::OffsetMismatch(progress.received_bytes));
+    }
+    // 分块在传输中损坏，接收方没有写入，重传这一块即可
+    if status == reqwest::StatusCode::UNPROCESSABLE_ENTITY {
+        return Err(ChunkError::Failed("分块校验失败".to_string()));
+    }
+    if !status.is_success() {
+        let error_text = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "无法读取错误信息".to_string());
+        return Err(if status.is_client_error() {
+            ChunkError::Rejected(error_text)
+        } else {
+            ChunkError::Failed(error_text)
+        });
+    }
+
+    // 旧版本接收方不返回 received_bytes，按本块长度推算
+    let received = response
+        .json::<TransferProgress>()
+        .await
+        .map(|p| p.received_bytes)
+        .unwrap_or(info.offset + info.len);
+    Ok(received)
+}
@@ -0,0 +1,456 @@
+// 文件提议与接收规则
+// 发送方先发送文件提议，接收方按联系人规则和大小限制决定自动接收、等待确认还是拒绝
+// 接受之后数据写入暂存目录，收齐并校验通过后才移动到下载目录（或手动接收时选择的目录）
+use crate::models::{FileAcceptPolicy, PeerAcceptRule, Transfer};
+use crate::network::transfer::{FileOffer, OfferReply};
+use sqlx::{Pool, Sqlite};
+use std::path::{Path, PathBuf};
+
+// 暂存目录（在下载目录下，移动到下载目录时只需要重命名）
+const STAGING_DIR: &str = ".lanchat-staging";
+
+const POLICIES: &[&str] = &["auto", "ask", "reject"];
+
+/// 读取接收规则（未设置时自动接收 1GB 以内的文件）
+pub async fn get_policy(pool: &Pool<Sqlite>) -> Result<FileAcceptPolicy, String> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        "SELECT key, value FROM settings WHERE key IN ('file_accept_default', 'file_accept_max_auto_size')",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("读取接收规则失败: {}", e))?;
+
+    let mut policy = FileAcceptPolicy::default();
+    for (key, value) in rows {
+        match key.as_str() {
+            "file_accept_default" => policy.default_policy = value,
+            "file_accept_max_auto_size" => policy.max_auto_accept_size = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+
+    policy.peer_rules = sqlx::query_as::<_, PeerAcceptRule>(
+        "SELECT peer_id, policy FROM file_accept_rules ORDER BY peer_id",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("读取接收规则失败: {}", e))?;
+
+    Ok(policy)
+}
+
+/// 保存接收规则（联系人规则整体替换）
+pub async fn update_policy(pool: &Pool<Sqlite>, policy: &FileAcceptPolicy) -> Result<(), String> {
+    let valid = |p: &str| POLICIES.contains(&p);
+    if !valid(&policy.default_policy) || !policy.peer_rules.iter().all(|r| valid(&r.policy)) {
+        return Err("接收规则只能是 auto、ask 或 reject".to_string());
+    }
+    if policy.max_auto_accept_size < 0 {
+        return Err("自动接收的大小上限不能为负数".to_string());
+    }
+
+    log::info!(
+        "[Offers] 更新接收规则: 默认={}, 自动接收上限={} 字节, 联系人规则 {} 条",
+        policy.default_policy,
+        policy.max_auto_accept_size,
+        policy.peer_rules.len()
+    );
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| format!("保存接收规则失败: {}", e))?;
+
+    let values = [
+        ("file_accept_default", policy.default_policy.clone()),
+        (
+            "file_accept_max_auto_size",
+            policy.max_auto_accept_size.to_string(),
+        ),
+    ];
+    for (key, value) in values {
+        sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)")
+            .bind(key)
+            .bind(value)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| format!("保存接收规则失败: {}", e))?;
+    }
+
+    sqlx::query("DELETE FROM file_accept_rules")
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| format!("保存接收规则失败: {}", e))?;
+    for rule in &policy.peer_rules {
+        sqlx::query("INSERT OR REPLACE INTO file_accept_rules (peer_id, policy) VALUES (?, ?)")
+            .bind(&rule.peer_id)
+            .bind(&rule.policy)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| format!("保存接收规则失败: {}", e))?;
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| format!("保存接收规则失败: {}", e))
+}
+
+/// 按规则决定如何处理某个联系人发来的文件：accepted、pending 或 rejected
+pub async fn decide(
+    pool: &Pool<Sqlite>,
+    peer_id: &str,
+    file_size: u64,
+) -> Result<&'static str, String> {
+    let policy = get_policy(pool).await?;
+    let rule = policy
+        .peer_rules
+        .iter()
+        .find(|r| r.peer_id == peer_id)
+        .map(|r| r.policy.as_str())
+        .unwrap_or(&policy.default_policy);
+
+    let decision = match rule {
+        "reject" => "rejected",
+        "ask" => "pending",
+        // 自动接收也受大小上限约束，超过上限需要确认
+        _ if policy.max_auto_accept_size > 0 && file_size > policy.max_auto_accept_size as u64 => {
+            "pending"
+        }
+        _ => "accepted",
+    };
+    Ok(decision)
+}
+
+/// 处理对方发来的文件提议，创建传输记录并返回答复
+/// 同一个传输 ID 再次提议（发送方续传）时按已有记录的状态答复
+pub async fn handle_offer(
+    pool: &Pool<Sqlite>,
+    download_dir: &Path,
+    offer: FileOffer,
+) -> Result<OfferReply, String> {
+    if let Some(transfer) = crate::db::get_transfer(pool, &offer.transfer_id).await? {
+        if transfer.sender_id == "me" {
+            return Err("传输 ID 与自己发送的文件冲突".to_string());
+        }
+        return Ok(reply_for(&transfer).await);
+    }
+
+    let file_name = crate::utils::sanitize_file_name(&offer.file_name);
+    let decision = decide(pool, &offer.sender_id, offer.file_size).await?;
+    log::info!(
+        "[Offers] 收到文件提议: {} ({} 字节) 来自 {}，决定: {}",
+        file_name,
+        offer.file_size,
+        offer.sender_id,
+        decision
+    );
+
+    // 暂存文件名由本地生成，不使用对方提供的任何内容
+    let staging_dir = download_dir.join(STAGING_DIR);
+    std::fs::create_dir_all(&staging_dir).map_err(|e| format!("创建暂存目录失败: {}", e))?;
+    let staging_path = staging_dir.join(format!("{}.part", uuid::Uuid::new_v4()));
+
+    let status = match decision {
+        "accepted" => "downloading",
+        other => other,
+    };
+    let my_id = crate::db::get_user_id(pool).await?;
+    let now = chrono::Utc::now().timestamp();
+    let transfer = Transfer {
+        id: offer.transfer_id,
+        sender_id: offer.sender_id,
+        receiver_id: my_id,
+        file_name,
+        file_size: offer.file_size as i64,
+        file_path: staging_path.to_string_lossy().to_string(),
+        file_hash: offer.file_hash.map(|h| h.to_lowercase()),
+        mime_type: offer.mime_type,
+        status: status.to_string(),
+        save_dir: None,
+        created_at: now,
+        updated_at: now,
+    };
+    crate::db::create_transfer(pool, &transfer).await?;
+    if status == "downloading" {
+        finish_if_empty(pool, &transfer, download_dir).await?;
+    }
+
+    Ok(OfferReply {
+        transfer_id: transfer.id,
+        decision: decision.to_string(),
+        received_bytes: 0,
+    })
+}
+
+// 按已有传输记录的状态答复（校验失败的文件需要从头重传）
+async fn reply_for(transfer: &Transfer) -> OfferReply {
+    let decision = match transfer.status.as_str() {
+        "pending" => "pending",
+        "rejected" => "rejected",
+        _ => "accepted",
+    };
+    let received_bytes = if decision != "accepted" || transfer.status == "corrupted" {
+        0
+    } else {
+        tokio::fs::metadata(&transfer.file_path)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0)
+    };
+
+    OfferReply {
+        transfer_id: transfer.id.clone(),
+        decision: decision.to_string(),
+        received_bytes,
+    }
+}
+
+/// 手动接受一个等待确认的文件，可以指定保存目录（必须是绝对路径，且不能包含 `..`）
+pub async fn accept(
+    pool: &Pool<Sqlite>,
+    transfer_id: &str,
+    save_dir: Option<String>,
+) -> Result<(), String> {
+    if let Some(dir) = &save_dir {
+        let dir = Path::new(dir);
+        let has_parent_dir = dir
+            .components()
+            .any(|c| matches!(c, std::path::Component::ParentDir));
+        if !dir.is_absolute() || has_parent_dir {
+            return Err("保存路径必须是绝对路径，且不能包含 ..".to_string());
+        }
+    }
+
+    log::info!(
+        "[Offers] 接受文件: {} (保存目录: {:?})",
+        transfer_id,
+        save_dir
+    );
+    if !crate::db::accept_transfer(pool, transfer_id, save_dir.as_deref()).await? {
+        return Err("文件不存在或已处理".to_string());
+    }
+
+    if let Some(transfer) = crate::db::get_transfer(pool, transfer_id).await? {
+        let download_dir = PathBuf::from(crate::db::get_download_path(pool).await?);
+        finish_if_empty(pool, &transfer, &download_dir).await?;
+    }
+    Ok(())
+}
+
+// 空文件没有数据分块，接受时直接完成（否则一直停在下载中）
+async fn finish_if_empty(
+    pool: &Pool<Sqlite>,
+    transfer: &Transfer,
+    download_dir: &Path,
+) -> Result<(), String> {
+    if transfer.file_size != 0 {
+        return Ok(());
+    }
+    std::fs::File::create(&transfer.file_path).map_err(|e| format!("创建文件失败: {}", e))?;
+    finish(pool, transfer, download_dir).await?;
+    Ok(())
+}
+
+/// 拒绝一个等待确认或正在接收的文件，删除已收到的数据
+pub async fn reject(pool: &Pool<Sqlite>, transfer_id: &str) -> Result<(), String> {
+    let transfer = crate::db::get_transfer(pool, transfer_id)
+        .await?
+        .filter(|t| t.sender_id != "me" && matches!(t.status.as_str(), "pending" | "downloading"))
+        .ok_or("文件不存在或已处理")?;
+
+    log::info!(
+        "[Offers] 拒绝文件: {} ({})",
+        transfer.file_name,
+        transfer_id
+    );
+    crate::db::update_transfer_status(pool, transfer_id, "rejected").await?;
+    let _ = tokio::fs::remove_file(&transfer.file_path).await;
+    Ok(())
+}
+
+/// 文件收齐并校验通过后，从暂存目录移动到保存目录（同名时自动改名），状态改为 accepted
+pub async fn finish(
+    pool: &Pool<Sqlite>,
+    transfer: &Transfer,
+    download_dir: &Path,
+) -> Result<PathBuf, String> {
+    let staging_path = PathBuf::from(&transfer.file_path);
+    let target_dir = transfer
+        .save_dir
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| download_dir.to_path_buf());
+
+    // 旧版本直接写在下载目录里的文件不需要移动
+    let final_path = if staging_path.parent() == Some(target_dir.as_path()) {
+        staging_path
+    } else {
+        move_into(&staging_path, &target_dir, &transfer.file_name)?
+    };
+
+    crate::db::update_transfer_path(
+        pool,
+        &transfer.id,
+        &final_path.to_string_lossy(),
+        "accepted",
+    )
+    .await?;
+    log::info!("[Offers] ✓ 文件已保存到: {:?}", final_path);
+    Ok(final_path)
+}
+
+// 把文件移动到目标目录，rename 失败（跨文件系统）时复制后删除
+fn move_into(from: &Path, dir: &Path, file_name: &str) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("创建目录失败: {}", e))?;
+    let to = crate::utils::reserve_unique_path(dir, &crate::utils::sanitize_file_name(file_name))
+        .map_err(|e| format!("创建文件失败: {}", e))?;
+
+    if let Err(e) = std::fs::rename(from, &to) {
+        log::info!("[Offers] rename 失败 ({}), 尝试复制+删除", e);
+        if let Err(e) = std::fs::copy(from, &to) {
+            let _ = std::fs::remove_file(&to);
+            return Err(format!("复制文件失败: {}", e));
+        }
+        if let Err(e) = std::fs::remove_file(from) {
+            log::info!("[Offers] ⚠ 删除暂存文件失败: {}", e);
+        }
+    }
+    Ok(to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(transfer_id: &str, file_size: u64) -> FileOffer {
+        FileOffer {
+            transfer_id: transfer_id.to_string(),
+            sender_id: "peer-a".to_string(),
+            file_name: "empty.txt".to_string(),
+            file_size,
+            file_hash: None,
+            mime_type: None,
+            sender_key: None,
+        }
+    }
+
+    fn temp_download_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("lanchat-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn empty_file_completes_when_auto_accepted() {
+        let dir = temp_download_dir();
+        let pool = crate::db::test_pool(&dir).await;
+
+        let reply = handle_offer(&pool, &dir, offer("t-empty", 0))
+            .await
+            .unwrap();
+        assert_eq!(reply.decision, "accepted");
+
+        let transfer = crate::db::get_transfer(&pool, "t-empty")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(transfer.status, "accepted");
+        assert_eq!(PathBuf::from(&transfer.file_path), dir.join("empty.txt"));
+        assert_eq!(std::fs::metadata(&transfer.file_path).unwrap().len(), 0);
+        assert_eq!(std::fs::read_dir(dir.join(STAGING_DIR)).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_file_completes_when_accepted_manually() {
+        let dir = temp_download_dir();
+        let pool = crate::db::test_pool(&dir).await;
+        sqlx::query(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES ('file_accept_default', 'ask')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let reply = handle_offer(&pool, &dir, offer("t-ask", 0)).await.unwrap();
+        assert_eq!(reply.decision, "pending");
+
+        accept(&pool, "t-ask", None).await.unwrap();
+        let transfer = crate::db::get_transfer(&pool, "t-ask")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(transfer.status, "accepted");
+        assert!(Path::new(&transfer.file_path).starts_with(&dir));
+        assert!(!transfer.file_path.ends_with(".part"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn auto_accept_is_limited_by_size_and_peer_rules() {
+        let dir = temp_download_dir();
+        let pool = crate::db::test_pool(&dir).await;
+        let policy = FileAcceptPolicy {
+            default_policy: "auto".to_string(),
+            max_auto_accept_size: 1000,
+            peer_rules: vec![
+                PeerAcceptRule {
+                    peer_id: "peer-b".to_string(),
+                    policy: "ask".to_string(),
+                },
+                PeerAcceptRule {
+                    peer_id: "peer-c".to_string(),
+                    policy: "reject".to_string(),
+                },
+            ],
+        };
+        update_policy(&pool, &policy).await.unwrap();
+
+        assert_eq!(decide(&pool, "peer-a", 1000).await.unwrap(), "accepted");
+        assert_eq!(decide(&pool, "peer-a", 1001).await.unwrap(), "pending");
+        assert_eq!(decide(&pool, "peer-b", 1).await.unwrap(), "pending");
+        assert_eq!(decide(&pool, "peer-c", 1).await.unwrap(), "rejected");
+
+        // 超过上限的提议等待确认，不写入任何数据
+        let reply = handle_offer(&pool, &dir, offer("t-large", 5000))
+            .await
+            .unwrap();
+        assert_eq!(reply.decision, "pending");
+        let transfer = crate::db::get_transfer(&pool, "t-large")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(transfer.status, "pending");
+        assert_eq!(transfer.file_size, 5000);
+
+        // 上限为 0 时不限制大小
+        let unlimited = FileAcceptPolicy {
+            max_auto_accept_size: 0,
+            ..policy
+        };
+        update_policy(&pool, &unlimited).await.unwrap();
+        assert_eq!(decide(&pool, "peer-a", u64::MAX).await.unwrap(), "accepted");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn invalid_policies_are_refused() {
+        let dir = temp_download_dir();
+        let pool = crate::db::test_pool(&dir).await;
+
+        let negative = FileAcceptPolicy {
+            max_auto_accept_size: -1,
+            ..FileAcceptPolicy::default()
+        };
+        assert!(update_policy(&pool, &negative).await.is_err());
+        let unknown = FileAcceptPolicy {
+            default_policy: "always".to_string(),
+            ..FileAcceptPolicy::default()
+        };
+        assert!(update_policy(&pool, &unknown).await.is_err());
+
+        // 保存失败时原来的规则不变
+        assert_eq!(get_policy(&pool).await.unwrap().default_policy, "auto");
+    }
+}
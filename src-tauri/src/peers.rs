@@ -1,6 +1,7 @@
 // 在线用户管理模块
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -58,13 +59,13 @@ impl PeerManager {
 
             // 只在用户重新上线时打印日志
             if was_offline {
-                println!(
+                log::info!(
                     "[PeerManager] 用户重新上线: {} ({}) - 可用内存: {} MB",
                     peer.name, peer.id, available_memory_mb
                 );
                 return true; // 重新上线，返回 true
             }
-            return false; // 只是更新，返回 false
+            false // 只是更新，返回 false
         } else {
             // 新用户
             let peer = Peer {
@@ -75,12 +76,12 @@ impl PeerManager {
                 is_offline: false,
                 available_memory_mb,
             };
-            println!(
+            log::info!(
                 "[PeerManager] 添加新用户: {} ({}) - 可用内存: {} MB",
                 name, id, available_memory_mb
             );
             peers.insert(id, peer);
-            return true; // 新用户，返回 true
+            true // 新用户，返回 true
         }
     }
 
@@ -97,7 +98,7 @@ impl PeerManager {
         for peer in peers.values_mut() {
             let time_since_seen = now - peer.last_seen;
             if time_since_seen > 6 && !peer.is_offline {
-                println!(
+                log::info!(
                     "[PeerManager] 用户离线: {} ({}) - {}秒未见",
                     peer.name, peer.id, time_since_seen
                 );
@@ -109,12 +110,30 @@ impl PeerManager {
         peers.retain(|id, peer| {
             let keep = now - peer.last_seen < 60;
             if !keep {
-                println!("[PeerManager] 移除用户: {} ({})", peer.name, id);
+                log::info!("[PeerManager] 移除用户: {} ({})", peer.name, id);
             }
             keep
         });
     }
 
+    // 对方主动通知下线，返回刚下线的用户（已经离线或不认识时返回 None）
+    // 下线通知没有签名，只接受来自该用户当前地址的通知，其他机器不能冒充它下线
+    pub fn mark_offline(&self, id: &str, from: IpAddr) -> Option<Peer> {
+        let mut peers = self.peers.write().unwrap();
+        let peer = peers.get_mut(id).filter(|peer| !peer.is_offline)?;
+        let known_ip = peer.addr.parse::<SocketAddr>().ok().map(|addr| addr.ip());
+        if known_ip != Some(from) {
+            log::warn!(
+                "[PeerManager] 忽略下线通知: {} 的地址是 {}，通知来自 {}",
+                peer.id, peer.addr, from
+            );
+            return None;
+        }
+        log::info!("[PeerManager] 用户下线: {} ({})", peer.name, peer.id);
+        peer.is_offline = true;
+        Some(peer.clone())
+    }
+
     // 获取所有用户（包括离线的）
     pub fn get_all_peers(&self) -> Vec<Peer> {
         // 先标记离线用户
@@ -124,6 +143,12 @@ impl PeerManager {
         peers.values().cloned().collect()
     }
 
+    // 按 ID 获取单个用户
+    pub fn get_peer(&self, id: &str) -> Option<Peer> {
+        let peers = self.peers.read().unwrap();
+        peers.get(id).cloned()
+    }
+
     // 获取所有在线用户（过滤掉离线的）
     pub fn get_active_peers(&self) -> Vec<Peer> {
         let peers = self.peers.read().unwrap();
@@ -136,3 +161,30 @@ impl Default for PeerManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offline_notice_reports_the_peer_once() {
+        let peers = PeerManager::new();
+        peers.add_or_update_with_memory(
+            "peer-a".to_string(),
+            "A".to_string(),
+            "127.0.0.1:8888".to_string(),
+            0,
+        );
+
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "192.168.1.66".parse().unwrap();
+        assert!(peers.mark_offline("peer-a", other).is_none());
+        assert!(!peers.get_peer("peer-a").unwrap().is_offline);
+
+        let peer = peers.mark_offline("peer-a", local).unwrap();
+        assert!(peer.is_offline);
+        assert_eq!(peer.addr, "127.0.0.1:8888");
+        assert!(peers.mark_offline("peer-a", local).is_none());
+        assert!(peers.mark_offline("unknown", local).is_none());
+    }
+}
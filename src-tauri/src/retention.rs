@@ -0,0 +1,376 @@
+// 聊天记录保留策略与自动清理
+use crate::models::{PruneSummary, RetentionPolicy};
+use sqlx::{Pool, Sqlite};
+use std::time::Duration;
+
+// 后台清理任务的执行间隔
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+// 计算消息所属会话的对方 ID（与 conversations 触发器的规则一致）
+const PEER_ID_EXPR: &str = "CASE WHEN sender_id IN ('me', (SELECT value FROM settings WHERE key = 'user_id')) THEN receiver_id ELSE sender_id END";
+
+/// 读取保留策略（未设置的项视为不限制）
+pub async fn get_policy(pool: &Pool<Sqlite>) -> Result<RetentionPolicy, String> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        "SELECT key, value FROM settings WHERE key IN ('retention_max_age_days', 'retention_max_messages', 'retention_delete_files')",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("读取保留策略失败: {}", e))?;
+
+    let mut policy = RetentionPolicy::default();
+    for (key, value) in rows {
+        match key.as_str() {
+            "retention_max_age_days" => policy.max_age_days = value.parse().unwrap_or(0),
+            "retention_max_messages" => {
+                policy.max_messages_per_conversation = value.parse().unwrap_or(0)
+            }
+            "retention_delete_files" => policy.delete_files = value == "true",
+            _ => {}
+        }
+    }
+    Ok(policy)
+}
+
+/// 保存保留策略
+pub async fn update_policy(pool: &Pool<Sqlite>, policy: &RetentionPolicy) -> Result<(), String> {
+    if policy.max_age_days < 0 || policy.max_messages_per_conversation < 0 {
+        return Err("保留天数和消息数不能为负数".to_string());
+    }
+
+    log::info!(
+        "[Retention] 更新保留策略: 天数={}, 每个会话消息数={}, 删除文件={}",
+        policy.max_age_days, policy.max_messages_per_conversation, policy.delete_files
+    );
+
+    let values = [
+        ("retention_max_age_days", policy.max_age_days.to_string()),
+        (
+            "retention_max_messages",
+            policy.max_messages_per_conversation.to_string(),
+        ),
+        ("retention_delete_files", policy.delete_files.to_string()),
+    ];
+    for (key, value) in values {
+        sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)")
+            .bind(key)
+            .bind(value)
+            .execute(pool)
+            .await
+            .map_err(|e| format!("保存保留策略失败: {}", e))?;
+    }
+    Ok(())
+}
+
+/// 按保留策略清理过期消息
+pub async fn apply_policy(pool: &Pool<Sqlite>) -> Result<PruneSummary, String> {
+    let policy = get_policy(pool).await?;
+    let mut summary = PruneSummary::default();
+
+    if policy.max_age_days > 0 {
+        let cutoff = chrono::Utc::now().timestamp() - policy.max_age_days * 86400;
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM messages WHERE timestamp < ?")
+            .bind(cutoff)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| format!("查询过期消息失败: {}", e))?;
+        summary.merge(delete_messages(pool, &ids, policy.delete_files).await?);
+    }
+
+    if policy.max_messages_per_conversation > 0 {
+        let ids: Vec<i64> = sqlx::query_scalar(&format!(
+            "SELECT id FROM (
+                SELECT id, ROW_NUMBER() OVER (
+                    PARTITION BY {} ORDER BY timestamp DESC, id DESC
+                ) AS rn
+                FROM messages
+            ) WHERE rn > ?",
+            PEER_ID_EXPR
+        ))
+        .bind(policy.max_messages_per_conversation)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| format!("查询超出数量的消息失败: {}", e))?;
+        summary.merge(delete_messages(pool, &ids, policy.delete_files).await?);
+    }
+
+    if summary.deleted_messages > 0 {
+        log::info!(
+            "[Retention] 已清理 {} 条消息, {} 个文件",
+            summary.deleted_messages, summary.deleted_files
+        );
+    }
+    Ok(summary)
+}
+
+/// 清空与某个用户的会话（可选同时删除收到的文件）
+pub async fn clear_conversation(
+    pool: &Pool<Sqlite>,
+    peer_id: &str,
+    delete_files: bool,
+) -> Result<PruneSummary, String> {
+    log::info!(
+        "[Retention] 清空会话: {}, 删除文件={}",
+        peer_id, delete_files
+    );
+
+    let ids: Vec<i64> = sqlx::query_scalar(&format!(
+        "SELECT id FROM messages WHERE {} = ?",
+        PEER_ID_EXPR
+    ))
+    .bind(peer_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("查询会话消息失败: {}", e))?;
+
+    delete_messages(pool, &ids, delete_files).await
+}
+
+/// 启动后台清理任务（启动时执行一次，之后每小时执行一次）
+pub async fn start_retention_job(pool: Pool<Sqlite>) {
+    loop {
+        if let Err(e) = apply_policy(&pool).await {
+            log::error!("[Retention] 自动清理失败: {}", e);
+        }
+        tokio::time::sleep(RETENTION_INTERVAL).await;
+    }
+}
+
+// (对方 ID, 是否自己发送, 文件路径, 消息 UUID)
+type MessageRow = (Option<String>, bool, Option<String>, Option<String>);
+
+// 删除一批消息及其表情回应，并刷新受影响的会话
+async fn delete_messages(
+    pool: &Pool<Sqlite>,
+    ids: &[i64],
+    delete_files: bool,
+) -> Result<PruneSummary, String> {
+    let mut summary = PruneSummary::default();
+    if ids.is_empty() {
+        return Ok(summary);
+    }
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| format!("开启事务失败: {}", e))?;
+
+    let mut files = Vec::new();
+    let mut peers = std::collections::HashSet::new();
+
+    for id in ids {
+        let row: Option<MessageRow> = sqlx::query_as(&format!(
+            "SELECT {}, sender_id IN ('me', (SELECT value FROM settings WHERE key = 'user_id')), file_path, msg_uuid
+             FROM messages WHERE id = ?",
+            PEER_ID_EXPR
+        ))
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| format!("查询消息失败: {}", e))?;
+
+        let Some((peer_id, from_me, file_path, msg_uuid)) = row else {
+            continue;
+        };
+
+        if let Some(uuid) = msg_uuid {
+            sqlx::query("DELETE FROM reactions WHERE message_id = ?")
+                .bind(uuid)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| format!("删除表情回应失败: {}", e))?;
+        }
+
+        sqlx::query("DELETE FROM messages WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| format!("删除消息失败: {}", e))?;
+        summary.deleted_messages += 1;
+
+        // 只删除别人发来的文件；自己发送的文件路径指向的是原始文件，不能动
+        if let (Some(path), false) = (file_path, from_me) {
+            if !path.is_empty() {
+                files.push(path);
+            }
+        }
+        if let Some(peer_id) = peer_id {
+            peers.insert(peer_id);
+        }
+    }
+
+    // 刷新会话的最后一条消息；没有剩余消息的会话直接删除
+    for peer_id in &peers {
+        let latest: Option<(String, String, String, i64)> = sqlx::query_as(&format!(
+            "SELECT content, msg_type, sender_id, timestamp FROM messages
+             WHERE {} = ? ORDER BY timestamp DESC, id DESC LIMIT 1",
+            PEER_ID_EXPR
+        ))
+        .bind(peer_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| format!("刷新会话失败: {}", e))?;
+
+        match latest {
+            Some((content, msg_type, sender_id, timestamp)) => {
+                sqlx::query(
+                    "UPDATE conversations SET last_message = ?, last_msg_type = ?, last_sender_id = ?, last_timestamp = ? WHERE peer_id = ?",
+                )
+                .bind(content)
+                .bind(msg_type)
+                .bind(sender_id)
+                .bind(timestamp)
+                .bind(peer_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| format!("刷新会话失败: {}", e))?;
+            }
+            None => {
+                sqlx::query("DELETE FROM conversations WHERE peer_id = ?")
+                    .bind(peer_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(|e| format!("刷新会话失败: {}", e))?;
+            }
+        }
+    }
+
+    tx.commit()
+        .await
+        .map_err(|e| format!("提交删除失败: {}", e))?;
+
+    // 数据库提交成功后再删文件，避免回滚后文件已经没了
+    // 只删除下载目录里的文件，导入的记录可能指向其他设备上的路径
+    if delete_files {
+        let download_dir = crate::db::get_download_path(pool).await?;
+        let download_dir = std::fs::canonicalize(&download_dir).unwrap_or(download_dir.into());
+        for path in files {
+            let in_download_dir = std::fs::canonicalize(&path)
+                .map(|p| p.starts_with(&download_dir))
+                .unwrap_or(false);
+            if !in_download_dir {
+                continue;
+            }
+            match tokio::fs::remove_file(&path).await {
+                Ok(_) => summary.deleted_files += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log::error!("[Retention] 删除文件失败 {}: {}", path, e),
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn receive(pool: &Pool<Sqlite>, peer_id: &str, uuid: &str, timestamp: i64) {
+        crate::db::save_received_text_message(
+            pool,
+            peer_id.to_string(),
+            Some(uuid.to_string()),
+            format!("message {}", uuid),
+            "text".to_string(),
+            timestamp,
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn remaining(pool: &Pool<Sqlite>) -> Vec<String> {
+        sqlx::query_scalar("SELECT msg_uuid FROM messages ORDER BY timestamp, id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn old_messages_and_their_reactions_are_pruned() {
+        let pool = crate::db::test_pool(&std::env::temp_dir()).await;
+        let now = chrono::Utc::now().timestamp();
+        receive(&pool, "peer-a", "old", now - 10 * 86400).await;
+        receive(&pool, "peer-a", "new", now).await;
+        crate::db::add_reaction(&pool, "old", "my-id", "👍", now)
+            .await
+            .unwrap();
+
+        update_policy(
+            &pool,
+            &RetentionPolicy {
+                max_age_days: 7,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let summary = apply_policy(&pool).await.unwrap();
+
+        assert_eq!(summary.deleted_messages, 1);
+        assert_eq!(remaining(&pool).await, vec!["new"]);
+        let reactions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reactions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(reactions, 0);
+    }
+
+    #[tokio::test]
+    async fn each_conversation_keeps_its_latest_messages() {
+        let pool = crate::db::test_pool(&std::env::temp_dir()).await;
+        for (i, uuid) in ["a1", "a2", "a3"].iter().enumerate() {
+            receive(&pool, "peer-a", uuid, i as i64 + 1).await;
+        }
+        receive(&pool, "peer-b", "b1", 1).await;
+
+        update_policy(
+            &pool,
+            &RetentionPolicy {
+                max_messages_per_conversation: 2,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        apply_policy(&pool).await.unwrap();
+
+        assert_eq!(remaining(&pool).await, vec!["b1", "a2", "a3"]);
+    }
+
+    #[tokio::test]
+    async fn clearing_a_conversation_deletes_only_downloaded_files() {
+        let dir = std::env::temp_dir().join(format!("lanchat-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let outside = dir.with_extension("outside");
+        let pool = crate::db::test_pool(&dir).await;
+        let downloaded = dir.join("a.txt");
+        std::fs::write(&downloaded, b"a").unwrap();
+        std::fs::write(&outside, b"b").unwrap();
+        for (uuid, path) in [("f1", &downloaded), ("f2", &outside)] {
+            sqlx::query(
+                "INSERT INTO messages (sender_id, receiver_id, content, msg_type, timestamp, file_path, msg_uuid)
+                 VALUES ('peer-a', 'my-id', 'file', 'file', 1, ?, ?)",
+            )
+            .bind(path.to_string_lossy().to_string())
+            .bind(uuid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        receive(&pool, "peer-b", "b1", 1).await;
+
+        let summary = clear_conversation(&pool, "peer-a", true).await.unwrap();
+
+        assert_eq!(summary.deleted_messages, 2);
+        assert_eq!(summary.deleted_files, 1);
+        assert!(!downloaded.exists());
+        assert!(outside.exists());
+        assert_eq!(remaining(&pool).await, vec!["b1"]);
+        let conversations = crate::db::get_conversations(&pool).await.unwrap();
+        assert_eq!(conversations.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&outside).unwrap();
+    }
+}
@@ -1,31 +1,267 @@
 use clap::Parser;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio;
 
+use lanchat::config::{AcceptConfig, Config, DiscoveryConfig, TlsConfig};
+use lanchat::events::{BroadcastSink, Events};
 use lanchat::peers::PeerManager;
+use tokio_util::sync::CancellationToken;
+
+// 退出时等待正在进行的请求（例如上传中的分块）完成的最长时间
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
 
 #[derive(Parser, Debug)]
 struct Args {
-    #[arg(short, long, default_value_t = 8888)]
-    port: u16, // 这个端口同时用于 HTTP(TCP) 和 广播(UDP)
+    /// 配置文件（TOML），默认读取 ~/.config/lanchat/lanchat.toml（存在时）
+    /// 命令行参数优先于配置文件
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
+
+    /// 端口，同时用于 HTTP(TCP) 和 广播(UDP)，默认 8888
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// 节点接口监听的 IP 地址（默认 0.0.0.0）
+    #[arg(long, value_name = "IP")]
+    bind: Option<std::net::IpAddr>,
+
+    #[arg(long)]
+    db_path: Option<std::path::PathBuf>, // 可选的数据库路径
 
+    /// 页面和控制接口的监听地址（默认 127.0.0.1:<port+1>，只有本机可以访问）
+    /// 需要从其他设备打开页面时设置为 0.0.0.0:<端口>，建议同时设置访问密码
+    #[arg(long, value_name = "ADDR")]
+    control_addr: Option<std::net::SocketAddr>,
+
+    /// 用户名
     #[arg(long)]
-    db_path: Option<String>, // 可选的数据库路径
+    username: Option<String>,
+
+    /// 接收文件的保存目录
+    #[arg(long, value_name = "DIR")]
+    download_dir: Option<std::path::PathBuf>,
+
+    /// 接收文件的默认规则：auto、ask 或 reject
+    #[arg(long, value_name = "POLICY")]
+    accept_policy: Option<String>,
+
+    /// 自动接收的最大文件大小（字节），0 表示不限制
+    #[arg(long, value_name = "BYTES")]
+    max_auto_accept_size: Option<i64>,
+
+    /// 是否发送局域网广播（关闭后只向 --discovery-peer 发送心跳）
+    #[arg(long, value_name = "true|false")]
+    broadcast: Option<bool>,
+
+    /// 心跳间隔（秒）
+    #[arg(long, value_name = "SECS")]
+    discovery_interval: Option<u64>,
+
+    /// 额外发送心跳的节点地址（IP 或 IP:端口），可以重复指定
+    #[arg(long, value_name = "ADDR")]
+    discovery_peer: Vec<String>,
+
+    /// TLS 证书链（PEM），需要和 --tls-key 一起使用
+    #[arg(long, value_name = "FILE")]
+    tls_cert: Option<std::path::PathBuf>,
+
+    /// TLS 私钥（PEM）
+    #[arg(long, value_name = "FILE")]
+    tls_key: Option<std::path::PathBuf>,
+
+    /// 日志级别：off、error、warn、info、debug、trace
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<String>,
+
+    /// 备份数据库到指定文件或目录后退出
+    #[arg(long, value_name = "PATH")]
+    backup: Option<String>,
+
+    /// 校验备份文件并恢复为当前数据库（原数据库会保留一份）
+    #[arg(long, value_name = "FILE")]
+    restore: Option<String>,
+
+    /// 定时备份的目录
+    #[arg(long, value_name = "DIR")]
+    backup_dir: Option<String>,
+
+    /// 定时备份间隔（小时），0 表示关闭
+    #[arg(long, value_name = "HOURS")]
+    backup_interval: Option<i64>,
+
+    /// 定时备份保留的份数，0 表示不清理
+    #[arg(long, value_name = "N")]
+    backup_keep: Option<i64>,
+
+    /// 重设浏览器访问密码（空字符串表示取消密码）
+    #[arg(long, value_name = "PASSWORD")]
+    web_password: Option<String>,
+
+    /// 设置局域网密钥（空字符串表示取消），所有节点需要使用相同的密钥
+    #[arg(long, value_name = "KEY")]
+    peer_key: Option<String>,
+
+    /// 是否允许与不支持加密连接的节点（旧版本、浏览器页面直接发送文件）明文通信
+    #[arg(long, value_name = "true|false")]
+    allow_plaintext: Option<bool>,
+
+    /// 作为命令行客户端运行（不启动节点），例如 lanchat-web send alice "你好"
+    #[command(subcommand)]
+    command: Option<lanchat::cli::Command>,
+}
+
+impl Args {
+    // 命令行中指定了的配置项
+    fn config_overrides(&self) -> Config {
+        Config {
+            port: self.port,
+            bind: self.bind,
+            control_addr: self.control_addr,
+            db_path: self.db_path.clone(),
+            username: self.username.clone(),
+            download_dir: self.download_dir.clone(),
+            log_level: self.log_level.clone(),
+            accept: AcceptConfig {
+                default_policy: self.accept_policy.clone(),
+                max_auto_accept_size: self.max_auto_accept_size,
+                peers: None,
+            },
+            discovery: DiscoveryConfig {
+                broadcast: self.broadcast,
+                interval_secs: self.discovery_interval,
+                peers: (!self.discovery_peer.is_empty()).then(|| self.discovery_peer.clone()),
+            },
+            tls: TlsConfig {
+                cert: self.tls_cert.clone(),
+                key: self.tls_key.clone(),
+                allow_plaintext: self.allow_plaintext,
+            },
+            aliases: None,
+        }
+    }
+}
+
+// 读取配置文件并合并命令行参数，配置无效时退出
+fn load_config(args: &Args) -> Config {
+    let file = match &args.config {
+        Some(path) => lanchat::config::load(path),
+        None => lanchat::config::load_default(),
+    };
+    let config = match file {
+        Ok(config) => config.merge(args.config_overrides()),
+        Err(e) => {
+            eprintln!("[Config] {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(errors) = config.validate() {
+        eprintln!("[Config] 配置无效:");
+        for line in errors.lines() {
+            eprintln!("[Config]   {}", line);
+        }
+        std::process::exit(1);
+    }
+    config
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let port = args.port;
+
+    // 命令行客户端：标准输出只留给命令的结果
+    if let Some(command) = args.command.clone() {
+        let output = lanchat::cli::Output::stdout();
+        let config = load_config(&args);
+        lanchat::config::init_logging(config.log_level());
+        std::process::exit(lanchat::cli::run(command, config, None, output).await);
+    }
+
+    let config = load_config(&args);
+    lanchat::config::init_logging(config.log_level());
+    let port = config.port();
 
     // 初始化数据库
     println!("[Server Main] 正在初始化数据库...");
-    let db_path = args.db_path.map(|p| std::path::PathBuf::from(p));
+    let db_path = config.db_path.clone();
+
+    // 从备份恢复：先校验并暂存，初始化数据库时换入
+    if let Some(restore) = &args.restore {
+        let data_dir = lanchat::db::standalone_data_dir(db_path.clone());
+        std::fs::create_dir_all(&data_dir).expect("无法创建数据目录");
+        if let Err(e) = lanchat::backup::stage_restore(
+            &data_dir.join("lanchat.db"),
+            std::path::Path::new(restore),
+        )
+        .await
+        {
+            eprintln!("[Server Main] 恢复失败: {}", e);
+            std::process::exit(1);
+        }
+    }
+
     let pool = lanchat::db::init_db_standalone(db_path)
         .await
         .expect("数据库初始化失败");
 
+    // 只备份不启动服务
+    if let Some(dest) = &args.backup {
+        match lanchat::backup::backup_database(&pool, std::path::Path::new(dest)).await {
+            Ok(path) => {
+                println!("[Server Main] 备份已保存到: {:?}", path);
+                return;
+            }
+            Err(e) => {
+                eprintln!("[Server Main] 备份失败: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // 命令行指定了定时备份参数时更新设置
+    if args.backup_dir.is_some() || args.backup_interval.is_some() || args.backup_keep.is_some() {
+        let mut schedule = lanchat::backup::get_schedule(&pool)
+            .await
+            .expect("读取备份设置失败");
+        if let Some(dir) = args.backup_dir {
+            schedule.dir = Some(dir);
+        }
+        if let Some(interval) = args.backup_interval {
+            schedule.interval_hours = interval;
+        }
+        if let Some(keep) = args.backup_keep {
+            schedule.keep = keep;
+        }
+        if let Err(e) = lanchat::backup::update_schedule(&pool, &schedule).await {
+            eprintln!("[Server Main] 定时备份设置无效: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(password) = &args.web_password {
+        if let Err(e) = lanchat::auth::reset_password(&pool, password).await {
+            eprintln!("[Server Main] 访问密码无效: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(key) = &args.peer_key {
+        if let Err(e) = lanchat::auth::set_peer_key(&pool, key).await {
+            eprintln!("[Server Main] 局域网密钥无效: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // 上次运行时没有完成的上传标记为中断，可以从断点续传
+    if let Err(e) = lanchat::db::mark_interrupted_uploads(&pool).await {
+        eprintln!("[DB] {}", e);
+    }
+
+    // 配置文件和命令行中的设置写入数据库
+    if let Err(e) = config.apply(&pool).await {
+        eprintln!("[Config] 配置无效: {}", e);
+        std::process::exit(1);
+    }
+    config.print_effective(&pool).await;
+
     // 从数据库读取用户名和 ID
     let my_name = lanchat::db::get_username(&pool)
         .await
@@ -41,22 +277,47 @@ async fn main() {
     // 创建全局用户管理器
     let peer_manager = Arc::new(PeerManager::new());
 
+    // 事件推送给浏览器（SSE）
+    let bus = Arc::new(BroadcastSink::new());
+    let events: Events = bus.clone();
+
     // 1. 启动 Web 服务 (TCP)
     let pool_clone = pool.clone();
     let peer_manager_clone = peer_manager.clone();
-    tokio::spawn(async move {
-        lanchat::web_server::start_server(port, port, pool_clone, peer_manager_clone).await;
+    let server_events = events.clone();
+    let peer_addr = config.peer_addr();
+    let control_addr = config.control_addr(&pool).await;
+    let shutdown = CancellationToken::new();
+    let server_shutdown = shutdown.clone();
+    let mut server = tokio::spawn(async move {
+        if let Err(e) = lanchat::web_server::start_server(
+            peer_addr,
+            control_addr,
+            pool_clone,
+            peer_manager_clone,
+            bus,
+            server_events,
+            server_shutdown,
+        )
+        .await
+        {
+            eprintln!("[Server Main] ✗ {}", e);
+            std::process::exit(1);
+        }
     });
 
     // 2. 启动 UDP 监听
     let listen_id = my_id.clone();
     let listen_name = my_name.clone();
+    let listen_pool = pool.clone();
     let peer_manager_clone = peer_manager.clone();
-    tokio::spawn(async move {
+    let listener = tokio::spawn(async move {
         lanchat::network::discovery::start_listening(
             port,
             listen_id,
             listen_name,
+            listen_pool,
+            events,
             peer_manager_clone,
         )
         .await;
@@ -65,18 +326,90 @@ async fn main() {
     // 3. 启动 UDP 广播
     let announce_id = my_id.clone();
     let announce_pool = pool.clone();
-    tokio::spawn(async move {
-        lanchat::network::discovery::start_announcing(port, announce_id, announce_pool).await;
+    let discovery = config.discovery_options();
+    let announce_options = discovery.clone();
+    let announcer = tokio::spawn(async move {
+        lanchat::network::discovery::start_announcing(
+            port,
+            announce_id,
+            announce_pool,
+            announce_options,
+        )
+        .await;
+    });
+
+    // 4. 启动聊天记录清理任务
+    let retention_pool = pool.clone();
+    let retention = tokio::spawn(async move {
+        lanchat::retention::start_retention_job(retention_pool).await;
+    });
+
+    // 5. 启动定时备份任务
+    let backup_pool = pool.clone();
+    let backup = tokio::spawn(async move {
+        lanchat::backup::start_backup_job(backup_pool).await;
     });
 
     println!("[Server Main] ========================================");
-    println!("[Server Main] Web 服务器: http://localhost:{}", port);
-    println!("[Server Main] WebSocket: ws://localhost:{}/ws", port);
+    println!("[Server Main] Web 页面: http://{}", control_addr);
+    println!("[Server Main] 节点接口: {}", peer_addr);
     println!("[Server Main] UDP 广播端口: {}", port);
     println!("[Server Main] ========================================");
 
-    // 防止主线程退出
-    loop {
-        tokio::time::sleep(Duration::from_secs(3600)).await;
+    // 等待 Ctrl+C 或 SIGTERM，然后按顺序退出
+    wait_for_signal().await;
+    println!("[Server Main] 收到退出信号，正在关闭（再按一次 Ctrl+C 立即退出）...");
+    tokio::spawn(async {
+        wait_for_signal().await;
+        eprintln!("[Server Main] 立即退出");
+        std::process::exit(130);
+    });
+
+    // 1. 停止心跳，不再接受新连接，等正在进行的上传和请求完成
+    announcer.abort();
+    shutdown.cancel();
+    if tokio::time::timeout(SHUTDOWN_GRACE, &mut server)
+        .await
+        .is_err()
+    {
+        server.abort();
+        eprintln!(
+            "[Server Main] {} 秒内仍有连接没有完成，强制关闭（未完成的接收可以由发送方续传）",
+            SHUTDOWN_GRACE.as_secs()
+        );
+    }
+
+    // 2. 通知邻居本机下线
+    lanchat::network::discovery::announce_offline(port, &my_id, &discovery);
+
+    // 3. 停止后台任务
+    for task in [listener, retention, backup] {
+        task.abort();
+    }
+
+    // 4. 浏览器发起、还没有完成的上传标记为中断，之后可以续传
+    if let Err(e) = lanchat::db::mark_interrupted_uploads(&pool).await {
+        eprintln!("[Server Main] {}", e);
+    }
+
+    // 5. 关闭数据库
+    pool.close().await;
+    println!("[Server Main] 已退出");
+}
+
+// 等待 Ctrl+C（SIGINT）或 SIGTERM
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("无法监听 SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
@@ -0,0 +1,693 @@
+// 节点之间的加密连接
+// 每个节点第一次启动时生成自签名证书（保存在 settings 表中），节点接口同一个端口上同时支持 TLS 和明文
+// 连接对方时按节点 ID 固定证书指纹：第一次连接时记录（TOFU），之后证书变化就拒绝连接
+// 对方不支持 TLS（旧版本）时，只有用户允许明文连接才会回退到明文
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+// TLS 记录的第一个字节（握手），节点接口据此区分 TLS 和明文连接
+pub const TLS_HANDSHAKE_BYTE: u8 = 0x16;
+
+// 证书里的名称；节点之间按指纹校验，不检查名称
+const CERT_NAME: &str = "lanchat.local";
+
+// 同一进程只使用一个数据库，和认证状态一样放在全局
+static ALLOW_PLAINTEXT: AtomicBool = AtomicBool::new(false);
+static SERVER_CONFIG: RwLock<Option<Arc<ServerConfig>>> = RwLock::new(None);
+static FINGERPRINT: RwLock<String> = RwLock::new(String::new());
+// 节点 ID -> 记录的证书指纹
+static PINS: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+// 第一次连接新节点时用来保存证书指纹
+static PIN_STORE: OnceLock<Pool<Sqlite>> = OnceLock::new();
+// 节点 ID -> 访问该节点 HTTP 接口的客户端（复用连接，不用每个请求都重新握手）
+static CLIENTS: Mutex<Option<HashMap<String, CachedClient>>> = Mutex::new(None);
+
+/// 加密连接的设置
+#[derive(Debug, serde::Serialize)]
+pub struct TlsStatus {
+    /// 本机证书的 SHA-256 指纹
+    pub fingerprint: String,
+    pub allow_plaintext: bool,
+}
+
+pub fn status() -> TlsStatus {
+    TlsStatus {
+        fingerprint: FINGERPRINT.read().unwrap().clone(),
+        allow_plaintext: allow_plaintext(),
+    }
+}
+
+pub fn allow_plaintext() -> bool {
+    ALLOW_PLAINTEXT.load(Ordering::Relaxed)
+}
+
+// 测试并行运行，依赖明文设置的测试先取得这个锁
+#[cfg(test)]
+pub(crate) static PLAINTEXT_SETTING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// 测试用：守卫存在期间允许明文连接，丢弃时恢复默认设置
+#[cfg(test)]
+pub(crate) struct PlaintextAllowed {
+    _lock: tokio::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl PlaintextAllowed {
+    pub(crate) async fn acquire() -> Self {
+        let lock = PLAINTEXT_SETTING.lock().await;
+        ALLOW_PLAINTEXT.store(true, Ordering::Relaxed);
+        Self { _lock: lock }
+    }
+}
+
+#[cfg(test)]
+impl Drop for PlaintextAllowed {
+    fn drop(&mut self) {
+        ALLOW_PLAINTEXT.store(false, Ordering::Relaxed);
+    }
+}
+
+/// 启动时读取（第一次启动时生成）本机证书、已记录的节点证书和明文设置
+pub async fn load_state(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        "SELECT key, value FROM settings WHERE key IN ('tls_cert', 'tls_key', 'tls_allow_plaintext')",
+    )
+    .fetch_all(pool)
+    .await?;
+    let setting = |name: &str| {
+        rows.iter()
+            .find(|(key, _)| key == name)
+            .and_then(|(_, value)| BASE64.decode(value).ok())
+    };
+
+    let (cert, key) = match (setting("tls_cert"), setting("tls_key")) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => {
+            let (cert, key) =
+                generate_certificate().map_err(|e| sqlx::Error::Configuration(e.into()))?;
+            sqlx::query(
+                "INSERT OR REPLACE INTO settings (key, value) VALUES ('tls_cert', ?), ('tls_key', ?)",
+            )
+            .bind(BASE64.encode(&cert))
+            .bind(BASE64.encode(&key))
+            .execute(pool)
+            .await?;
+            log::info!("[TLS] 已生成本机证书");
+            (cert, key)
+        }
+    };
+
+    let config =
+        server_config(cert.clone(), key).map_err(|e| sqlx::Error::Configuration(e.into()))?;
+    *SERVER_CONFIG.write().unwrap() = Some(Arc::new(config));
+    *FINGERPRINT.write().unwrap() = fingerprint(&cert);
+
+    let allow = rows
+        .iter()
+        .any(|(key, value)| key == "tls_allow_plaintext" && value == "1");
+    ALLOW_PLAINTEXT.store(allow, Ordering::Relaxed);
+
+    let pins: Vec<(String, String)> =
+        sqlx::query_as("SELECT peer_id, fingerprint FROM peer_certificates")
+            .fetch_all(pool)
+            .await?;
+    log::info!(
+        "[TLS] 本机证书指纹: {}，已记录 {} 个节点的证书，明文连接: {}",
+        FINGERPRINT.read().unwrap(),
+        pins.len(),
+        if allow { "允许" } else { "不允许" }
+    );
+    *PINS.lock().unwrap() = Some(pins.into_iter().collect());
+    let _ = PIN_STORE.set(pool.clone());
+    Ok(())
+}
+
+/// 是否允许与不支持 TLS 的节点明文通信（连接对方和接受对方的连接都受它控制）
+pub async fn set_allow_plaintext(pool: &Pool<Sqlite>, allow: bool) -> Result<(), String> {
+    sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES ('tls_allow_plaintext', ?)")
+        .bind(if allow { "1" } else { "0" })
+        .execute(pool)
+        .await
+        .map_err(|e| format!("保存明文连接设置失败: {}", e))?;
+    ALLOW_PLAINTEXT.store(allow, Ordering::Relaxed);
+    log::info!("[TLS] 明文连接: {}", if allow { "允许" } else { "不允许" });
+    Ok(())
+}
+
+/// 删除某个节点记录的证书（对方重新安装后证书会变化），下次连接时重新记录
+pub async fn forget_peer(pool: &Pool<Sqlite>, peer_id: &str) -> Result<(), String> {
+    sqlx::query("DELETE FROM peer_certificates WHERE peer_id = ?")
+        .bind(peer_id)
+        .execute(pool)
+        .await
+        .map_err(|e| format!("删除证书记录失败: {}", e))?;
+    if let Some(pins) = PINS.lock().unwrap().as_mut() {
+        pins.remove(peer_id);
+    }
+    if let Some(clients) = CLIENTS.lock().unwrap().as_mut() {
+        clients.remove(peer_id);
+    }
+    log::info!("[TLS] 已删除节点 {} 的证书记录", peer_id);
+    Ok(())
+}
+
+/// 节点接口使用的 TLS 配置（load_state 之前为 None）
+pub fn acceptor() -> Option<tokio_rustls::TlsAcceptor> {
+    SERVER_CONFIG
+        .read()
+        .unwrap()
+        .clone()
+        .map(tokio_rustls::TlsAcceptor::from)
+}
+
+/// 使用配置文件指定的证书（PEM 证书链和私钥）代替自动生成的证书
+/// 其他节点按证书指纹识别本机，更换证书后它们需要重置对本机的证书记录
+pub fn use_certificate_files(cert_path: &Path, key_path: &Path) -> Result<(), String> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("无法读取证书 {}: {}", cert_path.display(), e))?;
+    let Some(leaf) = chain.first() else {
+        return Err(format!("证书文件 {} 中没有证书", cert_path.display()));
+    };
+    let leaf_fingerprint = fingerprint(leaf);
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("无法读取私钥 {}: {}", key_path.display(), e))?;
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+        .map_err(|e| format!("证书和私钥无效: {}", e))?;
+    *SERVER_CONFIG.write().unwrap() = Some(Arc::new(config));
+    *FINGERPRINT.write().unwrap() = leaf_fingerprint;
+    log::info!(
+        "[TLS] 使用证书文件 {}，指纹: {}",
+        cert_path.display(),
+        FINGERPRINT.read().unwrap()
+    );
+    Ok(())
+}
+
+/// 证书的 SHA-256 指纹（十六进制）
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn pinned(peer_id: &str) -> Option<String> {
+    PINS.lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|pins| pins.get(peer_id).cloned())
+}
+
+fn cert_changed_error(peer_id: &str) -> String {
+    format!(
+        "节点 {} 的证书和之前记录的不一致，可能有人在冒充对方；如果对方重新安装过 LANChat，确认后可以重置证书记录",
+        peer_id
+    )
+}
+
+/// PinnedVerifier 拒绝证书时的错误（与其他握手错误区分开）
+#[derive(Debug)]
+struct CertChanged(String);
+
+impl std::fmt::Display for CertChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&cert_changed_error(&self.0))
+    }
+}
+
+impl std::error::Error for CertChanged {}
+
+// 生成自签名证书，返回（证书 DER，PKCS#8 私钥 DER）
+fn generate_certificate() -> Result<(Vec<u8>, Vec<u8>), String> {
+    let certified = rcgen::generate_simple_self_signed(vec![CERT_NAME.to_string()])
+        .map_err(|e| format!("生成证书失败: {}", e))?;
+    Ok((
+        certified.cert.der().to_vec(),
+        certified.key_pair.serialize_der(),
+    ))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn server_config(cert: Vec<u8>, key: Vec<u8>) -> Result<ServerConfig, String> {
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key));
+    ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| {
+            builder
+                .with_no_client_auth()
+                .with_single_cert(vec![CertificateDer::from(cert)], key)
+        })
+        .map_err(|e| format!("证书无效: {}", e))
+}
+
+/// 连接某个节点使用的 TLS 配置：只接受该节点记录的证书（还没有记录时接受并记录对方的证书）
+pub fn client_config(peer_id: &str) -> Result<ClientConfig, String> {
+    let provider = provider();
+    let verifier = PinnedVerifier {
+        peer_id: peer_id.to_string(),
+        provider: provider.clone(),
+    };
+    Ok(ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("TLS 配置失败: {}", e))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// 按证书指纹校验节点（自签名证书没有 CA，也不校验名称），第一次连接时记录指纹
+#[derive(Debug)]
+struct PinnedVerifier {
+    peer_id: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+        match pinned(&self.peer_id) {
+            Some(expected) if expected != actual => {
+                Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                    rustls::OtherError(Arc::new(CertChanged(self.peer_id.clone()))),
+                )))
+            }
+            Some(_) => Ok(ServerCertVerified::assertion()),
+            None => {
+                pin(&self.peer_id, actual);
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// 连接对方的数据流（TLS 或明文）
+pub trait PeerIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerIo for T {}
+
+pub struct PeerConnection {
+    pub stream: Box<dyn PeerIo>,
+    pub encrypted: bool,
+}
+
+/// 连接某个节点：优先使用 TLS，第一次连接时记录对方的证书指纹
+/// 对方不支持 TLS 时，只有允许明文连接、并且没有记录过该节点的证书才会回退；证书变化时总是拒绝
+pub async fn connect(peer_addr: &str, peer_id: &str) -> Result<PeerConnection, String> {
+    let tcp = TcpStream::connect(peer_addr)
+        .await
+        .map_err(|e| format!("连接失败: {}", e))?;
+
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config(peer_id)?));
+    let server_name = ServerName::try_from(CERT_NAME).expect("证书名称无效");
+    match connector.connect(server_name, tcp).await {
+        Ok(stream) => Ok(PeerConnection {
+            stream: Box::new(stream),
+            encrypted: true,
+        }),
+        Err(e) => {
+            if is_cert_changed(&e) {
+                log::error!("[TLS] ✗ 节点 {} 的证书已变化，拒绝连接", peer_id);
+                return Err(cert_changed_error(peer_id));
+            }
+            // 用加密连接访问过的节点不会变回旧版本，握手失败可能是有人在中间阻止加密
+            if pinned(peer_id).is_some() {
+                log::error!(
+                    "[TLS] ✗ 节点 {} 之前使用加密连接，拒绝回退到明文: {}",
+                    peer_id,
+                    e
+                );
+                return Err(format!(
+                    "无法和节点 {} 建立加密连接（{}）；该节点之前使用加密连接，不会回退到明文",
+                    peer_id, e
+                ));
+            }
+            if !allow_plaintext() {
+                return Err(format!(
+                    "无法建立加密连接（{}），对方可能是不支持加密的旧版本；如果信任当前网络，可以在设置中允许明文连接",
+                    e
+                ));
+            }
+            log::info!("[TLS] 对方不支持加密连接，使用明文: {}", e);
+            let tcp = TcpStream::connect(peer_addr)
+                .await
+                .map_err(|e| format!("连接失败: {}", e))?;
+            Ok(PeerConnection {
+                stream: Box::new(tcp),
+                encrypted: false,
+            })
+        }
+    }
+}
+
+/// 访问某个节点 HTTP 接口的客户端：优先 HTTPS（只接受该节点记录的证书），允许明文时可以回退到 HTTP
+/// 请求附带节点签名（设置了局域网密钥时）；知道对方的身份公钥后同时附带身份签名
+pub struct PeerClient {
+    client: reqwest::Client,
+    base_url: String,
+    my_id: String,
+    // 和对方之间的签名密钥（e2e::auth_key）
+    identity: Mutex<Option<[u8; 32]>>,
+}
+
+#[derive(Clone)]
+struct CachedClient {
+    peer_addr: String,
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl PeerClient {
+    /// 同一个节点复用一个客户端；第一次访问（或地址变化）时握手一次，确定对方是否支持 TLS
+    pub async fn connect(peer_addr: &str, peer_id: &str, my_id: &str) -> Result<Self, String> {
+        let cached = CLIENTS
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|clients| clients.get(peer_id).cloned())
+            .filter(|c| c.peer_addr == peer_addr)
+            .filter(|c| {
+                c.base_url.starts_with("https://")
+                    || (allow_plaintext() && pinned(peer_id).is_none())
+            });
+
+        let cached = match cached {
+            Some(cached) => cached,
+            None => {
+                let scheme = if connect(peer_addr, peer_id).await?.encrypted {
+                    "https"
+                } else {
+                    "http"
+                };
+                let client = reqwest::Client::builder()
+                    .use_preconfigured_tls(client_config(peer_id)?)
+                    .timeout(std::time::Duration::from_secs(300))
+                    .build()
+                    .map_err(|e| format!("创建客户端失败: {}", e))?;
+                let cached = CachedClient {
+                    peer_addr: peer_addr.to_string(),
+                    base_url: format!("{}://{}", scheme, peer_addr),
+                    client,
+                };
+                CLIENTS
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(HashMap::new)
+                    .insert(peer_id.to_string(), cached.clone());
+                cached
+            }
+        };
+
+        Ok(Self {
+            client: cached.client,
+            base_url: cached.base_url,
+            my_id: my_id.to_string(),
+            identity: Mutex::new(crate::e2e::known_auth_key(peer_id).await),
+        })
+    }
+
+    /// 取得（或确认）对方的身份公钥之后，后续请求附带身份签名
+    pub fn set_identity(&self, auth_key: [u8; 32]) {
+        *self.identity.lock().unwrap() = Some(auth_key);
+    }
+
+    pub fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.signed(
+            self.client.get(format!("{}{}", self.base_url, path)),
+            "GET",
+            path,
+            &crate::auth::content_hash(&[]),
+        )
+    }
+
+    /// POST 一个 JSON 请求体（签名包含请求体的摘要）
+    pub fn post_json<T: serde::Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<reqwest::RequestBuilder, String> {
+        let body = serde_json::to_vec(body).map_err(|e| format!("序列化请求失败: {}", e))?;
+        let request = self
+            .client
+            .post(format!("{}{}", self.base_url, path))
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        let content_hash = crate::auth::content_hash(&body);
+        Ok(self.signed(request, "POST", path, &content_hash).body(body))
+    }
+
+    /// POST 流式上传的分块（签名不包含请求体，见 auth::UNSIGNED_PAYLOAD）
+    pub fn post_unsigned_payload(&self, path: &str) -> reqwest::RequestBuilder {
+        self.signed(
+            self.client.post(format!("{}{}", self.base_url, path)),
+            "POST",
+            path,
+            crate::auth::UNSIGNED_PAYLOAD,
+        )
+    }
+
+    fn signed(
+        &self,
+        request: reqwest::RequestBuilder,
+        method: &str,
+        path: &str,
+        content_hash: &str,
+    ) -> reqwest::RequestBuilder {
+        let identity = *self.identity.lock().unwrap();
+        crate::auth::peer_headers(&self.my_id, identity.as_ref(), method, path, content_hash)
+            .into_iter()
+            .fold(request, |request, (name, value)| {
+                request.header(name, value)
+            })
+    }
+}
+
+// 握手失败是否是因为 PinnedVerifier 发现证书变化（其他 TLS 错误不算）
+fn is_cert_changed(error: &std::io::Error) -> bool {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        .is_some_and(|e| {
+            matches!(
+                e,
+                rustls::Error::InvalidCertificate(CertificateError::Other(other))
+                    if other.0.downcast_ref::<CertChanged>().is_some()
+            )
+        })
+}
+
+// 第一次连接某个节点时记录它的证书指纹（校验证书时调用，不能等待，数据库在后台写入）
+fn pin(peer_id: &str, fingerprint: String) {
+    {
+        let mut pins = PINS.lock().unwrap();
+        let pins = pins.get_or_insert_with(HashMap::new);
+        if pins.contains_key(peer_id) {
+            return;
+        }
+        pins.insert(peer_id.to_string(), fingerprint.clone());
+    }
+    log::info!("[TLS] 记录节点 {} 的证书指纹: {}", peer_id, fingerprint);
+
+    let (Some(pool), Ok(runtime)) = (PIN_STORE.get(), tokio::runtime::Handle::try_current()) else {
+        return;
+    };
+    let peer_id = peer_id.to_string();
+    runtime.spawn(async move {
+        if let Err(e) = sqlx::query(
+            "INSERT OR IGNORE INTO peer_certificates (peer_id, fingerprint, first_seen) VALUES (?, ?, ?)",
+        )
+        .bind(&peer_id)
+        .bind(&fingerprint)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(pool)
+        .await
+        {
+            log::warn!("[TLS] ⚠ 保存证书指纹失败: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    // 在本机启动一个只做 TLS 握手的节点，返回地址、证书和收到的连接数
+    async fn tls_peer() -> (String, Vec<u8>, Arc<AtomicUsize>) {
+        let (cert, key) = generate_certificate().unwrap();
+        let acceptor =
+            tokio_rustls::TlsAcceptor::from(Arc::new(server_config(cert.clone(), key).unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let _ = acceptor.accept(stream).await;
+                });
+            }
+        });
+        (addr, cert, connections)
+    }
+
+    #[tokio::test]
+    async fn pins_certificate_on_first_connection() {
+        let (addr, cert, _) = tls_peer().await;
+
+        let connection = connect(&addr, "peer-first-use").await.unwrap();
+        assert!(connection.encrypted);
+        assert_eq!(pinned("peer-first-use"), Some(fingerprint(&cert)));
+
+        // 再次连接时证书一致
+        assert!(connect(&addr, "peer-first-use").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_changed_certificate() {
+        let (addr, _, _) = tls_peer().await;
+        PINS.lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert("peer-changed".to_string(), "00".repeat(32));
+
+        let error = connect(&addr, "peer-changed").await.err().unwrap();
+        assert!(error.contains("不一致"));
+    }
+
+    #[test]
+    fn only_verifier_error_counts_as_cert_change() {
+        let wrap = |e: rustls::Error| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        let changed = rustls::Error::InvalidCertificate(CertificateError::Other(
+            rustls::OtherError(Arc::new(CertChanged("peer".to_string()))),
+        ));
+        assert!(is_cert_changed(&wrap(changed)));
+        assert!(!is_cert_changed(&wrap(rustls::Error::General(
+            "其他错误".to_string()
+        ))));
+        assert!(!is_cert_changed(&wrap(rustls::Error::InvalidCertificate(
+            CertificateError::Expired
+        ))));
+    }
+
+    #[tokio::test]
+    async fn reuses_client_for_the_same_peer() {
+        let (addr, _, connections) = tls_peer().await;
+
+        // 第一次访问时握手一次确定对方支持 TLS，之后复用同一个客户端
+        let first = PeerClient::connect(&addr, "peer-reuse", "me")
+            .await
+            .unwrap();
+        let second = PeerClient::connect(&addr, "peer-reuse", "me")
+            .await
+            .unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert_eq!(first.base_url, format!("https://{}", addr));
+        assert_eq!(second.base_url, first.base_url);
+    }
+
+    // 在本机启动一个不支持 TLS 的旧版本节点，返回地址
+    async fn plaintext_peer() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn refuses_plaintext_peer_by_default() {
+        let _setting = PLAINTEXT_SETTING.lock().await;
+        let addr = plaintext_peer().await;
+
+        let error = connect(&addr, "peer-plaintext").await.err().unwrap();
+        assert!(error.contains("明文"));
+    }
+
+    #[tokio::test]
+    async fn pinned_peer_never_falls_back_to_plaintext() {
+        let _plaintext = PlaintextAllowed::acquire().await;
+        let addr = plaintext_peer().await;
+
+        // 从未加密连接过的旧版本节点可以使用明文
+        let connection = connect(&addr, "peer-legacy").await.unwrap();
+        assert!(!connection.encrypted);
+
+        // 记录过证书的节点握手失败时拒绝连接，不会降级
+        PINS.lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert("peer-downgrade".to_string(), "00".repeat(32));
+        let error = connect(&addr, "peer-downgrade").await.err().unwrap();
+        assert!(error.contains("不会回退到明文"));
+        assert!(PeerClient::connect(&addr, "peer-downgrade", "me")
+            .await
+            .is_err());
+    }
+}
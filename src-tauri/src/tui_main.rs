@@ -0,0 +1,828 @@
+// 终端界面：在没有图形界面的机器上聊天
+// 左侧是节点列表，右侧是聊天记录、文件传输和输入框；库的日志（默认只有警告和错误）写入数据目录下的 lanchat-tui.log
+// 本机没有节点在运行时自己启动节点，否则和正在运行的 lanchat-web 共用数据库
+use clap::Parser;
+use lanchat::config::Config;
+use lanchat::models::{MessageResponse, Transfer};
+use lanchat::network::transfer::UploadProgress;
+use lanchat::peers::PeerManager;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::{execute, terminal};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+// 刷新节点列表、聊天记录和传输进度的间隔
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+// 聊天记录每次加载的条数，翻到顶部时再加载一页
+const HISTORY_PAGE: i32 = 100;
+// 传输面板显示的记录数（同时用于查找等待接收的文件）
+const TRANSFER_LIMIT: i64 = 20;
+const TRANSFER_PANE_HEIGHT: u16 = 7;
+const CONTACTS_WIDTH: u16 = 28;
+const HELP: &str = "Enter 发送  ↑↓ 切换节点  PgUp/PgDn 翻页  Esc 退出  命令: /file <路径> /accept /reject /unlock <口令> /quit";
+
+#[derive(Parser, Debug)]
+#[command(name = "lanchat-tui")]
+struct Args {
+    /// 配置文件（TOML），默认读取 ~/.config/lanchat/lanchat.toml（存在时）
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// 端口，默认 8888；本机已有节点使用这个端口时与它共用数据库
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// 可选的数据库路径
+    #[arg(long, value_name = "PATH")]
+    db_path: Option<PathBuf>,
+}
+
+// 读取配置文件并合并命令行参数，配置无效时退出
+fn load_config(args: &Args) -> Config {
+    let file = match &args.config {
+        Some(path) => lanchat::config::load(path),
+        None => lanchat::config::load_default(),
+    };
+    let overrides = Config {
+        port: args.port,
+        db_path: args.db_path.clone(),
+        ..Config::default()
+    };
+    let config = match file {
+        Ok(config) => config.merge(overrides),
+        Err(e) => {
+            eprintln!("[Config] {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(errors) = config.validate() {
+        eprintln!("[Config] 配置无效:");
+        for line in errors.lines() {
+            eprintln!("[Config]   {}", line);
+        }
+        std::process::exit(1);
+    }
+    config
+}
+
+// 退出（或 panic）时恢复终端
+fn restore_terminal(out: &mut impl Write) {
+    let _ = terminal::disable_raw_mode();
+    let _ = execute!(out, terminal::LeaveAlternateScreen);
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let config = load_config(&args);
+    // 日志文件留在磁盘上：没有设置 log_level 时只记录警告和错误
+    lanchat::config::init_logging(match config.log_level {
+        Some(_) => config.log_level(),
+        None => log::LevelFilter::Warn,
+    });
+
+    let data_dir = lanchat::db::standalone_data_dir(config.db_path.clone());
+    if let Err(e) = std::fs::create_dir_all(&data_dir) {
+        eprintln!("错误: 无法创建数据目录: {}", e);
+        std::process::exit(1);
+    }
+    let log_path = data_dir.join("lanchat-tui.log");
+    // 日志写入文件（输出到终端会破坏界面）
+    match std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+    {
+        Ok(file) => lanchat::config::log_to_file(file),
+        Err(e) => {
+            eprintln!("错误: 无法打开日志文件 {}: {}", log_path.display(), e);
+            std::process::exit(1);
+        }
+    }
+    log::info!("[TUI] ========== 启动 ==========");
+
+    let mut app = match App::open(config).await {
+        Ok(app) => app,
+        Err(e) => {
+            eprintln!("错误: {}（日志: {}）", e, log_path.display());
+            std::process::exit(1);
+        }
+    };
+
+    let mut tty = std::io::stdout();
+    if let Err(e) =
+        terminal::enable_raw_mode().and_then(|_| execute!(tty, terminal::EnterAlternateScreen))
+    {
+        eprintln!("错误: 无法初始化终端: {}", e);
+        std::process::exit(1);
+    }
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal(&mut std::io::stdout());
+        default_hook(info);
+    }));
+    let mut terminal = match Terminal::new(CrosstermBackend::new(tty)) {
+        Ok(terminal) => terminal,
+        Err(e) => {
+            eprintln!("[TUI] 无法初始化终端: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let result = app.run(&mut terminal).await;
+    restore_terminal(terminal.backend_mut());
+    app.close().await;
+    if let Err(e) = result {
+        let _ = writeln!(terminal.backend_mut(), "错误: {}", e);
+        std::process::exit(1);
+    }
+}
+
+// 节点列表中的一项：在线的节点和有过会话的节点
+struct Contact {
+    id: String,
+    name: String,
+    addr: Option<String>, // 在线时的地址
+    unread: i64,
+}
+
+// 传输面板中的一行
+struct TransferRow {
+    transfer: Transfer,
+    done_bytes: Option<u64>, // 已上传或已接收的大小（正在进行时）
+    speed_mb_s: Option<f64>, // 本机正在上传的速度
+}
+
+struct App {
+    pool: Pool<Sqlite>,
+    config: Config,
+    my_id: String,
+    my_name: String,
+    peers: Arc<PeerManager>,
+    node: Option<tokio_util::sync::CancellationToken>,
+    contacts: Vec<Contact>,
+    contact_state: ListState,
+    history: Vec<MessageResponse>,
+    history_limit: i32,
+    scroll: u16,     // 从底部向上滚动的行数
+    max_scroll: u16, // 上次绘制时最多能滚动的行数
+    transfers: Vec<TransferRow>,
+    // 本机正在上传的文件，key 是传输 ID
+    uploads: Arc<Mutex<HashMap<String, UploadProgress>>>,
+    input: String,
+    status: String,
+    // 后台任务（发送消息、上传文件）的结果，显示在状态栏
+    notes: UnboundedSender<String>,
+    notes_rx: tokio::sync::mpsc::UnboundedReceiver<String>,
+}
+
+impl App {
+    async fn open(config: Config) -> Result<Self, String> {
+        let pool = lanchat::db::init_db_standalone(config.db_path.clone())
+            .await
+            .map_err(|e| format!("无法打开数据库: {}", e))?;
+        let my_id = lanchat::db::get_user_id(&pool).await?;
+        let my_name = lanchat::db::get_username(&pool).await?;
+        let peers = Arc::new(PeerManager::new());
+
+        let node = lanchat::cli::start_node_if_idle(&pool, &config, &my_id, &peers).await;
+        lanchat::cli::listen_for_peers(&pool, &config, &my_id, &my_name, &peers);
+        let status = match lanchat::cli::unlock_from_env(&pool).await {
+            Ok(()) => HELP.to_string(),
+            Err(_) => "聊天记录已加密，输入 /unlock <口令> 解锁".to_string(),
+        };
+
+        let (notes, notes_rx) = unbounded_channel();
+        Ok(App {
+            pool,
+            config,
+            my_id,
+            my_name,
+            peers,
+            node,
+            contacts: Vec::new(),
+            contact_state: ListState::default(),
+            history: Vec::new(),
+            history_limit: HISTORY_PAGE,
+            scroll: 0,
+            max_scroll: 0,
+            transfers: Vec::new(),
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+            input: String::new(),
+            status,
+            notes,
+            notes_rx,
+        })
+    }
+
+    async fn run(
+        &mut self,
+        terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>,
+    ) -> Result<(), String> {
+        // crossterm 读取按键是阻塞的，放在单独的线程里
+        let (keys, mut keys_rx) = unbounded_channel();
+        std::thread::spawn(move || {
+            while let Ok(event) = event::read() {
+                if keys.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut tick = tokio::time::interval(REFRESH_INTERVAL);
+        loop {
+            terminal
+                .draw(|frame| self.draw(frame))
+                .map_err(|e| format!("绘制界面失败: {}", e))?;
+            tokio::select! {
+                _ = tick.tick() => self.refresh().await,
+                Some(event) = keys_rx.recv() => {
+                    if let Event::Key(key) = event {
+                        if key.kind == KeyEventKind::Press && !self.handle_key(key).await {
+                            return Ok(());
+                        }
+                    }
+                }
+                Some(note) = self.notes_rx.recv() => self.status = note,
+            }
+        }
+    }
+
+    // 退出：没有完成的上传标记为中断（之后可以续传），停止自己启动的节点
+    async fn close(&mut self) {
+        let uploading: Vec<String> = self.uploads.lock().unwrap().keys().cloned().collect();
+        for transfer_id in uploading {
+            let _ =
+                lanchat::db::update_transfer_status(&self.pool, &transfer_id, "interrupted").await;
+        }
+        if let Some(shutdown) = self.node.take() {
+            lanchat::cli::stop_node(&self.config, &self.my_id, shutdown);
+        }
+        self.pool.close().await;
+        log::info!("[TUI] 已退出");
+    }
+
+    fn selected(&self) -> Option<&Contact> {
+        self.contact_state
+            .selected()
+            .and_then(|index| self.contacts.get(index))
+    }
+
+    // 节点的显示名称：在线时的用户名、别名或 ID 的前 8 位
+    fn display_name(&self, id: &str) -> String {
+        if id == "me" || id == self.my_id {
+            return self.my_name.clone();
+        }
+        if let Some(peer) = self.peers.get_peer(id) {
+            return peer.name;
+        }
+        self.config
+            .aliases
+            .iter()
+            .flatten()
+            .find(|(_, target)| *target == id)
+            .map(|(alias, _)| alias.clone())
+            .unwrap_or_else(|| id.chars().take(8).collect())
+    }
+
+    // 重新读取节点列表、当前会话的聊天记录和传输记录
+    async fn refresh(&mut self) {
+        // 自己启动的节点出错停止了（例如端口被占用）
+        if self.node.as_ref().is_some_and(|node| node.is_cancelled()) {
+            self.node = None;
+            self.status = "节点无法启动，收不到新消息（详见 lanchat-tui.log）".to_string();
+        }
+        let selected_id = self.selected().map(|contact| contact.id.clone());
+
+        let mut online = self.peers.get_active_peers();
+        online.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+        let conversations = lanchat::db::get_conversations(&self.pool)
+            .await
+            .unwrap_or_default();
+        let unread: HashMap<&str, i64> = conversations
+            .iter()
+            .map(|conversation| (conversation.peer_id.as_str(), conversation.unread_count))
+            .collect();
+
+        let mut contacts: Vec<Contact> = online
+            .iter()
+            .map(|peer| Contact {
+                id: peer.id.clone(),
+                name: peer.name.clone(),
+                addr: Some(peer.addr.clone()),
+                unread: unread.get(peer.id.as_str()).copied().unwrap_or(0),
+            })
+            .collect();
+        for conversation in &conversations {
+            if contacts
+                .iter()
+                .all(|contact| contact.id != conversation.peer_id)
+            {
+                contacts.push(Contact {
+                    id: conversation.peer_id.clone(),
+                    name: self.display_name(&conversation.peer_id),
+                    addr: None,
+                    unread: conversation.unread_count,
+                });
+            }
+        }
+        self.contacts = contacts;
+
+        // 列表变化后仍然选中原来的节点
+        let index = selected_id
+            .and_then(|id| self.contacts.iter().position(|contact| contact.id == id))
+            .or(if self.contacts.is_empty() {
+                None
+            } else {
+                Some(0)
+            });
+        self.contact_state.select(index);
+
+        self.load_history().await;
+        self.load_transfers().await;
+    }
+
+    async fn load_history(&mut self) {
+        let Some((peer_id, unread)) = self
+            .selected()
+            .map(|contact| (contact.id.clone(), contact.unread))
+        else {
+            self.history.clear();
+            return;
+        };
+        match lanchat::network::messaging::get_chat_history_with_offset(
+            &self.pool,
+            &peer_id,
+            self.history_limit,
+            0,
+        )
+        .await
+        {
+            Ok(messages) => {
+                self.history = messages
+                    .into_iter()
+                    .filter_map(|message| serde_json::from_value(message).ok())
+                    .collect();
+            }
+            Err(e) => self.status = e,
+        }
+        // 正在查看的会话标记为已读
+        if unread > 0 {
+            let _ = lanchat::db::mark_conversation_read(&self.pool, &peer_id).await;
+        }
+    }
+
+    async fn load_transfers(&mut self) {
+        let transfers = lanchat::db::get_recent_transfers(&self.pool, TRANSFER_LIMIT)
+            .await
+            .unwrap_or_default();
+        let uploads = self.uploads.lock().unwrap().clone();
+        self.transfers = transfers
+            .into_iter()
+            .map(|transfer| {
+                let upload = uploads.get(&transfer.id);
+                // 正在接收的文件按暂存文件的大小计算进度
+                let done_bytes = match upload {
+                    Some(progress) => Some(progress.uploaded),
+                    None if transfer.status == "downloading" => {
+                        std::fs::metadata(&transfer.file_path)
+                            .map(|metadata| metadata.len())
+                            .ok()
+                    }
+                    None => None,
+                };
+                TransferRow {
+                    done_bytes,
+                    speed_mb_s: upload.map(|progress| progress.speed_mb_s),
+                    transfer,
+                }
+            })
+            .collect();
+    }
+
+    // 处理按键，返回 false 时退出
+    async fn handle_key(&mut self, key: KeyEvent) -> bool {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') if ctrl => return false,
+            KeyCode::Char('u') if ctrl => self.input.clear(),
+            KeyCode::Up => self.select(-1).await,
+            KeyCode::Down => self.select(1).await,
+            KeyCode::PageUp => {
+                // 已经在最上面时再加载一页更早的消息
+                if self.scroll >= self.max_scroll && self.history.len() as i32 >= self.history_limit
+                {
+                    self.history_limit += HISTORY_PAGE;
+                    self.load_history().await;
+                }
+                self.scroll = self.scroll.saturating_add(10);
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Enter => return self.submit().await,
+            KeyCode::Char(c) if !ctrl => self.input.push(c),
+            _ => {}
+        }
+        true
+    }
+
+    async fn select(&mut self, step: isize) {
+        if self.contacts.is_empty() {
+            return;
+        }
+        let current = self.contact_state.selected().unwrap_or(0) as isize;
+        let index = (current + step).rem_euclid(self.contacts.len() as isize);
+        self.contact_state.select(Some(index as usize));
+        self.history_limit = HISTORY_PAGE;
+        self.scroll = 0;
+        self.load_history().await;
+    }
+
+    // 发送输入框的内容或执行命令，返回 false 时退出
+    async fn submit(&mut self) -> bool {
+        let input = std::mem::take(&mut self.input);
+        let input = input.trim();
+        if input.is_empty() {
+            return true;
+        }
+        let (command, arg) = match input.strip_prefix('/') {
+            Some(command) => command
+                .split_once(char::is_whitespace)
+                .map(|(command, arg)| (command, arg.trim()))
+                .unwrap_or((command, "")),
+            None => {
+                self.send_text(input.to_string());
+                return true;
+            }
+        };
+        match command {
+            "quit" | "q" => return false,
+            "file" => self.send_file(arg).await,
+            "accept" => self.decide(true).await,
+            "reject" => self.decide(false).await,
+            "unlock" => {
+                self.status = match lanchat::encryption::unlock(&self.pool, arg).await {
+                    Ok(()) => "已解锁".to_string(),
+                    Err(e) => e,
+                };
+                self.refresh().await;
+            }
+            "help" => self.status = HELP.to_string(),
+            _ => self.status = format!("未知命令: /{}（/help 查看帮助）", command),
+        }
+        true
+    }
+
+    // 选中的在线节点（发送消息和文件需要对方在线）
+    fn selected_online(&mut self) -> Option<(String, String)> {
+        match self.selected() {
+            Some(Contact {
+                id,
+                addr: Some(addr),
+                ..
+            }) => Some((id.clone(), addr.clone())),
+            Some(contact) => {
+                self.status = format!("{} 不在线", contact.name);
+                None
+            }
+            None => {
+                self.status = "还没有发现其他节点".to_string();
+                None
+            }
+        }
+    }
+
+    fn send_text(&mut self, content: String) {
+        let Some((peer_id, peer_addr)) = self.selected_online() else {
+            return;
+        };
+        self.scroll = 0;
+        let pool = self.pool.clone();
+        let my_id = self.my_id.clone();
+        let my_name = self.my_name.clone();
+        let notes = self.notes.clone();
+        tokio::spawn(async move {
+            let msg_uuid = uuid::Uuid::new_v4().to_string();
+            let result = async {
+                lanchat::network::messaging::send_text_message(
+                    &peer_addr,
+                    &peer_id,
+                    my_id,
+                    my_name,
+                    msg_uuid.clone(),
+                    content.clone(),
+                )
+                .await?;
+                lanchat::db::save_text_message(&pool, peer_id, msg_uuid, content).await
+            }
+            .await;
+            if let Err(e) = result {
+                let _ = notes.send(format!("发送失败: {}", e));
+            }
+        });
+    }
+
+    async fn send_file(&mut self, path: &str) {
+        if path.is_empty() {
+            self.status = "用法: /file <路径>".to_string();
+            return;
+        }
+        let Some((peer_id, peer_addr)) = self.selected_online() else {
+            return;
+        };
+        // 支持 ~/ 开头的路径
+        let path = match (path.strip_prefix("~/"), dirs::home_dir()) {
+            (Some(rest), Some(home)) => home.join(rest),
+            _ => PathBuf::from(path),
+        };
+        let opened = async {
+            let path = std::fs::canonicalize(&path)
+                .map_err(|e| format!("无法读取文件 {}: {}", path.display(), e))?;
+            let file = tokio::fs::File::open(&path)
+                .await
+                .map_err(|e| format!("打开文件失败: {}", e))?;
+            let size = file
+                .metadata()
+                .await
+                .map_err(|e| format!("读取文件信息失败: {}", e))?
+                .len();
+            Ok::<_, String>((path, file, size))
+        }
+        .await;
+        let (path, file, size) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                self.status = e;
+                return;
+            }
+        };
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let transfer_id = match lanchat::db::save_file_message(
+            &self.pool,
+            peer_id,
+            file_name.clone(),
+            size as usize,
+            path.to_string_lossy().to_string(),
+            "uploading".to_string(),
+        )
+        .await
+        {
+            Ok((_, transfer_id)) => transfer_id,
+            Err(e) => {
+                self.status = e;
+                return;
+            }
+        };
+        self.status = format!("正在发送 {}（对方确认后开始上传）", file_name);
+        self.uploads.lock().unwrap().insert(
+            transfer_id.clone(),
+            UploadProgress {
+                uploaded: 0,
+                total: size,
+                speed_mb_s: 0.0,
+            },
+        );
+
+        let pool = self.pool.clone();
+        let uploads = self.uploads.clone();
+        let notes = self.notes.clone();
+        tokio::spawn(async move {
+            let progress = {
+                let uploads = uploads.clone();
+                let transfer_id = transfer_id.clone();
+                move |progress: UploadProgress| {
+                    uploads
+                        .lock()
+                        .unwrap()
+                        .insert(transfer_id.clone(), progress);
+                }
+            };
+            let result = lanchat::network::transfer::run_upload(
+                &pool,
+                &transfer_id,
+                &peer_addr,
+                file,
+                progress,
+            )
+            .await;
+            uploads.lock().unwrap().remove(&transfer_id);
+            let _ = notes.send(match result {
+                Ok(()) => format!("✓ 文件已发送: {}", file_name),
+                Err(e) => format!("发送 {} 失败: {}", file_name, e),
+            });
+        });
+    }
+
+    // 接收或拒绝当前节点最近发来的、等待确认的文件
+    async fn decide(&mut self, accept: bool) {
+        let Some(peer_id) = self.selected().map(|contact| contact.id.clone()) else {
+            return;
+        };
+        let Some(transfer) = self
+            .transfers
+            .iter()
+            .map(|row| &row.transfer)
+            .find(|transfer| transfer.sender_id == peer_id && transfer.status == "pending")
+        else {
+            self.status = "没有等待接收的文件".to_string();
+            return;
+        };
+        let file_name = transfer.file_name.clone();
+        let result = if accept {
+            lanchat::offers::accept(&self.pool, &transfer.id, None).await
+        } else {
+            lanchat::offers::reject(&self.pool, &transfer.id).await
+        };
+        self.status = match result {
+            Ok(()) if accept => format!("已接收 {}", file_name),
+            Ok(()) => format!("已拒绝 {}", file_name),
+            Err(e) => e,
+        };
+        self.load_transfers().await;
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Length(CONTACTS_WIDTH), Constraint::Min(0)])
+                .areas(main);
+        let [chat, transfers, composer] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(TRANSFER_PANE_HEIGHT),
+            Constraint::Length(3),
+        ])
+        .areas(right);
+
+        self.draw_contacts(frame, left);
+        self.draw_history(frame, chat);
+        self.draw_transfers(frame, transfers);
+
+        let composer_block = Block::bordered().title(" 消息 ");
+        let input = Paragraph::new(self.input.as_str()).block(composer_block);
+        frame.render_widget(input, composer);
+        let cursor_x = composer.x + 1 + Line::from(self.input.as_str()).width() as u16;
+        frame.set_cursor_position((
+            cursor_x.min(composer.right().saturating_sub(2)),
+            composer.y + 1,
+        ));
+
+        frame.render_widget(
+            Paragraph::new(self.status.as_str()).style(Style::default().fg(Color::DarkGray)),
+            status,
+        );
+    }
+
+    fn draw_contacts(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .contacts
+            .iter()
+            .map(|contact| {
+                let (dot, color) = match contact.addr {
+                    Some(_) => ("● ", Color::Green),
+                    None => ("○ ", Color::DarkGray),
+                };
+                let mut spans = vec![
+                    Span::styled(dot, Style::default().fg(color)),
+                    Span::raw(contact.name.clone()),
+                ];
+                if contact.unread > 0 {
+                    spans.push(Span::styled(
+                        format!(" ({})", contact.unread),
+                        Style::default().fg(Color::Yellow),
+                    ));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(format!(" {} ", self.my_name)))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.contact_state);
+    }
+
+    fn draw_history(&mut self, frame: &mut Frame, area: Rect) {
+        let title = match self.selected() {
+            Some(contact) => format!(" {}  {} ", contact.name, contact.id),
+            None => " 等待发现其他节点… ".to_string(),
+        };
+        let lines: Vec<Line> = self
+            .history
+            .iter()
+            .map(|message| {
+                let mine = message.from_id == "me" || message.from_id == self.my_id;
+                let name_color = if mine { Color::Cyan } else { Color::Yellow };
+                Line::from(vec![
+                    Span::styled(
+                        format!("{}  ", lanchat::cli::format_time(message.timestamp)),
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                    Span::styled(
+                        self.display_name(&message.from_id),
+                        Style::default().fg(name_color).add_modifier(Modifier::BOLD),
+                    ),
+                    Span::raw(format!(": {}", lanchat::cli::message_text(message))),
+                ])
+            })
+            .collect();
+
+        let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false });
+        // 默认显示最新的消息，scroll 是从底部向上滚动的行数
+        let total = paragraph.line_count(area.width.saturating_sub(2)) as u16;
+        self.max_scroll = total.saturating_sub(area.height.saturating_sub(2));
+        self.scroll = self.scroll.min(self.max_scroll);
+        let paragraph = paragraph
+            .scroll((self.max_scroll - self.scroll, 0))
+            .block(Block::bordered().title(title));
+        frame.render_widget(paragraph, area);
+    }
+
+    fn draw_transfers(&self, frame: &mut Frame, area: Rect) {
+        let rows = area.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = self
+            .transfers
+            .iter()
+            .take(rows)
+            .map(|row| {
+                let transfer = &row.transfer;
+                let (arrow, peer_id) = if transfer.sender_id == "me" {
+                    ("↑", transfer.receiver_id.as_str())
+                } else {
+                    ("↓", transfer.sender_id.as_str())
+                };
+                let mut text = format!(
+                    "{} {}  {}  {}",
+                    arrow,
+                    transfer.file_name,
+                    self.display_name(peer_id),
+                    status_label(&transfer.status)
+                );
+                let total = transfer.file_size.max(0) as u64;
+                match row.done_bytes {
+                    Some(done) if total > 0 => {
+                        let percent = (done.min(total) * 100 / total) as usize;
+                        text.push_str(&format!(
+                            "  [{}{}] {}%  {}/{}",
+                            "#".repeat(percent / 10),
+                            ".".repeat(10 - percent / 10),
+                            percent,
+                            format_size(done),
+                            format_size(total)
+                        ));
+                    }
+                    _ => text.push_str(&format!("  {}", format_size(total))),
+                }
+                if let Some(speed) = row.speed_mb_s.filter(|speed| *speed > 0.0) {
+                    text.push_str(&format!("  {:.2} MB/s", speed));
+                }
+                Line::from(text)
+            })
+            .collect();
+        let paragraph = Paragraph::new(lines).block(Block::bordered().title(" 文件传输 "));
+        frame.render_widget(paragraph, area);
+    }
+}
+
+fn status_label(status: &str) -> &str {
+    match status {
+        "uploading" => "上传中",
+        "waiting" => "等待对方确认",
+        "sent" => "已发送",
+        "interrupted" => "已中断",
+        "pending" => "等待接收（/accept 或 /reject）",
+        "downloading" => "接收中",
+        "accepted" => "已接收",
+        "rejected" => "已拒绝",
+        "corrupted" => "校验失败",
+        "imported" => "导入的记录",
+        other => other,
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const KB: f64 = 1024.0;
+    const MB: f64 = 1024.0 * 1024.0;
+    match bytes as f64 {
+        size if size >= MB => format!("{:.1} MB", size / MB),
+        size if size >= KB => format!("{:.1} KB", size / KB),
+        _ => format!("{} B", bytes),
+    }
+}
@@ -11,3 +11,203 @@ pub fn generate_random_name() -> String {
 
     format!("{}-{}-{}", adj, animal, num)
 }
+
+/// 流式计算文件的 SHA-256（阻塞读取，异步代码中请放到 spawn_blocking 里调用）
+pub fn sha256_file(path: &std::path::Path) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// 文件名的最大长度（字节），大多数文件系统的上限是 255
+const MAX_FILE_NAME_BYTES: usize = 255;
+
+// Windows 上不能作为文件名的设备名（不区分大小写，带扩展名也不行）
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// 把对方提供的文件名清理成可以安全放进下载目录的文件名
+/// 只保留最后一段路径，去掉控制字符和各平台不允许的字符、开头的点（隐藏文件和 `..`）、
+/// 结尾的点和空格，避开 Windows 保留的设备名，并限制长度
+pub fn sanitize_file_name(name: &str) -> String {
+    // 两种路径分隔符都当作分隔符，只取最后一段
+    let last = name.rsplit(['/', '\\']).next().unwrap_or("");
+
+    let cleaned: String = last
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+
+    let mut cleaned = cleaned
+        .trim_start_matches(['.', ' '])
+        .trim_end_matches(['.', ' '])
+        .to_string();
+
+    if cleaned.is_empty() {
+        return "file".to_string();
+    }
+
+    let stem = cleaned.split('.').next().unwrap_or("");
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.trim_end().eq_ignore_ascii_case(reserved))
+    {
+        cleaned.insert(0, '_');
+    }
+
+    truncate_file_name(&cleaned, MAX_FILE_NAME_BYTES)
+}
+
+// 把文件名截断到指定字节数以内，尽量保留扩展名，不截断多字节字符
+fn truncate_file_name(name: &str, max_bytes: usize) -> String {
+    if name.len() <= max_bytes {
+        return name.to_string();
+    }
+
+    let (stem, ext) = split_extension(name);
+    // 扩展名太长时不再保留
+    let ext = if ext.len() < max_bytes / 2 { ext } else { "" };
+
+    let mut end = max_bytes - ext.len();
+    while !stem.is_char_boundary(end.min(stem.len())) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end.min(stem.len())], ext)
+}
+
+// 拆分文件名和扩展名（扩展名包含点；没有扩展名时为空）
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(pos) if pos > 0 => name.split_at(pos),
+        _ => (name, ""),
+    }
+}
+
+/// 在目录中为文件名找一个不冲突的路径，并创建一个空文件占住它
+/// 已存在同名文件时依次尝试 `file (1).pdf`、`file (2).pdf`……
+/// 用 create_new 原子地创建，同时到达的两个同名文件不会写到同一个路径
+pub fn reserve_unique_path(
+    dir: &std::path::Path,
+    file_name: &str,
+) -> std::io::Result<std::path::PathBuf> {
+    let (stem, ext) = split_extension(file_name);
+
+    for n in 0..10000 {
+        let candidate = if n == 0 {
+            file_name.to_string()
+        } else {
+            // 加上编号后仍然不能超过文件名的长度上限
+            let suffix = format!(" ({}){}", n, ext);
+            let stem = truncate_file_name(stem, MAX_FILE_NAME_BYTES - suffix.len());
+            format!("{}{}", stem, suffix)
+        };
+
+        let path = dir.join(&candidate);
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!("同名文件太多: {}", file_name),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_ordinary_names() {
+        assert_eq!(sanitize_file_name("report.pdf"), "report.pdf");
+        assert_eq!(sanitize_file_name("照片 2024.jpg"), "照片 2024.jpg");
+        assert_eq!(sanitize_file_name("archive.tar.gz"), "archive.tar.gz");
+    }
+
+    #[test]
+    fn strips_path_components() {
+        assert_eq!(sanitize_file_name("../../.bashrc"), "bashrc");
+        assert_eq!(sanitize_file_name("/etc/passwd"), "passwd");
+        assert_eq!(sanitize_file_name("..\\..\\Windows\\win.ini"), "win.ini");
+        assert_eq!(sanitize_file_name("C:\\Users\\a\\evil.exe"), "evil.exe");
+        assert_eq!(sanitize_file_name("dir/"), "file");
+        assert_eq!(sanitize_file_name(".."), "file");
+        assert_eq!(sanitize_file_name("."), "file");
+        assert_eq!(sanitize_file_name(""), "file");
+    }
+
+    #[test]
+    fn removes_control_and_reserved_characters() {
+        assert_eq!(sanitize_file_name("a\u{0}b\nc\r.txt"), "abc.txt");
+        assert_eq!(sanitize_file_name("what?.txt"), "what_.txt");
+        assert_eq!(sanitize_file_name("a<b>c:d\"e|f*.txt"), "a_b_c_d_e_f_.txt");
+        assert_eq!(sanitize_file_name("name. . ."), "name");
+        assert_eq!(sanitize_file_name("\u{7f}\u{1b}[31m.txt"), "[31m.txt");
+    }
+
+    #[test]
+    fn avoids_windows_device_names() {
+        assert_eq!(sanitize_file_name("CON"), "_CON");
+        assert_eq!(sanitize_file_name("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_file_name("Com1.tar.gz"), "_Com1.tar.gz");
+        assert_eq!(sanitize_file_name("console.txt"), "console.txt");
+    }
+
+    #[test]
+    fn limits_length_and_keeps_extension() {
+        let long = format!("{}.pdf", "a".repeat(300));
+        let cleaned = sanitize_file_name(&long);
+        assert_eq!(cleaned.len(), MAX_FILE_NAME_BYTES);
+        assert!(cleaned.ends_with(".pdf"));
+
+        // 多字节字符不能被截断在中间
+        let long = format!("{}.txt", "文".repeat(100));
+        let cleaned = sanitize_file_name(&long);
+        assert!(cleaned.len() <= MAX_FILE_NAME_BYTES);
+        assert!(cleaned.ends_with(".txt"));
+    }
+
+    #[test]
+    fn renames_on_collision() {
+        let dir = std::env::temp_dir().join(format!("lanchat-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let first = reserve_unique_path(&dir, "file.pdf").unwrap();
+        let second = reserve_unique_path(&dir, "file.pdf").unwrap();
+        let third = reserve_unique_path(&dir, "file.pdf").unwrap();
+        let no_ext = reserve_unique_path(&dir, "README").unwrap();
+        let no_ext2 = reserve_unique_path(&dir, "README").unwrap();
+
+        assert_eq!(first, dir.join("file.pdf"));
+        assert_eq!(second, dir.join("file (1).pdf"));
+        assert_eq!(third, dir.join("file (2).pdf"));
+        assert_eq!(no_ext, dir.join("README"));
+        assert_eq!(no_ext2, dir.join("README (1)"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
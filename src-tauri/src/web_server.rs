@@ -1,18 +1,25 @@
 use axum::extract::ws::{Message, WebSocket};
 use axum::{
     body::Body,
-    extract::{Json, Multipart, Path, Query, State, WebSocketUpgrade},
+    extract::{Extension, Json, Multipart, Path, Query, State, WebSocketUpgrade},
     http::{header, Response, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
     response::IntoResponse,
-    routing::{get, post},
+    routing::{any, get, post},
     Router,
 };
 use futures_util::StreamExt;
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Sqlite};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::events::{BroadcastSink, Events};
 use tower_http::cors::{Any, CorsLayer};
 
 use crate::peers::PeerManager;
@@ -43,71 +50,591 @@ struct SendMessageRequest {
     content: String,
 }
 
+#[derive(Deserialize, Default)]
+struct ClearConversationRequest {
+    #[serde(default)]
+    delete_files: bool,
+}
+
+// 接收文件分块时的写缓冲区大小
+const UPLOAD_WRITE_BUFFER: usize = 256 * 1024;
+
+// 分块数据超出文件提议中的大小
+const UPLOAD_TOO_LARGE: &str = "分块超出文件大小";
+
+// 不允许明文连接时拒绝没有端到端加密的文件
+const PLAINTEXT_FILES_REFUSED: &str =
+    "该节点只接收端到端加密的文件（可以在该节点的设置中允许明文连接）";
+
+// 节点请求中需要计算摘要的请求体（文件提议等）的大小上限
+const MAX_SIGNED_BODY: usize = 1024 * 1024;
+
+// 页面发送的文件在发送期间暂存的目录（在下载目录下）
+const OUTGOING_DIR: &str = ".lanchat-outgoing";
+
+#[derive(Deserialize)]
+struct PassphraseRequest {
+    passphrase: String,
+}
+
+#[derive(Deserialize)]
+struct AllowPlaintextRequest {
+    allow: bool,
+}
+
+#[derive(Deserialize)]
+struct VerifiedRequest {
+    verified: bool,
+}
+
+#[derive(Deserialize)]
+struct ReactRequest {
+    peer_id: String,
+    peer_addr: String,
+    message_id: String,
+    emoji: String,
+    #[serde(default)]
+    remove: bool,
+}
+
 // Web 服务器的状态
 #[derive(Clone)]
 pub struct AppState {
     pub pool: Pool<Sqlite>,
     pub peer_manager: Arc<PeerManager>,
-    #[cfg(feature = "desktop")]
-    pub app_handle: Option<tauri::AppHandle>,
+    pub events: Events,          // 所有事件从这里发出（桌面端同时发给 Tauri 前端）
+    pub bus: Arc<BroadcastSink>, // 浏览器通过 /api/events 订阅
+    pub shutdown: CancellationToken, // 退出时结束事件订阅，让连接可以关闭
+}
+
+impl AppState {
+    /// 发布事件：推送给所有连接到 /api/events 的浏览器，桌面端同时发送 Tauri 事件
+    pub fn publish(&self, name: &'static str, payload: serde_json::Value) {
+        self.events.emit(name, payload);
+    }
+
+    // 发布传输的当前状态（文件提议、接受、拒绝、接收完成、校验失败）
+    async fn publish_file_status(&self, transfer_id: &str) {
+        if let Ok(Some(transfer)) = crate::db::get_transfer(&self.pool, transfer_id).await {
+            self.publish(
+                "file-status",
+                serde_json::json!({
+                    "transfer_id": transfer.id,
+                    "peer_id": transfer.sender_id,
+                    "file_name": transfer.file_name,
+                    "file_size": transfer.file_size,
+                    "status": transfer.status,
+                }),
+            );
+        }
+    }
+}
+
+/// 控制接口的默认地址：只监听本机，端口为节点端口 + 1
+pub fn default_control_addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port.wrapping_add(1)))
+}
+
+/// 控制接口的监听地址：优先使用设置中的 control_addr，无效或没有设置时使用默认地址
+pub async fn resolve_control_addr(pool: &Pool<Sqlite>, port: u16) -> SocketAddr {
+    match crate::db::get_control_addr(pool).await {
+        Ok(Some(addr)) => addr.parse().unwrap_or_else(|_| {
+            log::info!("[Web Server] 控制接口地址无效: {}，使用默认地址", addr);
+            default_control_addr(port)
+        }),
+        _ => default_control_addr(port),
+    }
 }
 
+/// 启动 HTTP 服务
+/// 节点接口（/ws、/api/upload、/api/offer）监听 peer_addr（默认 0.0.0.0:端口），供其他节点调用；
+/// 页面和控制接口监听 control_addr，默认只有本机可以访问。
+/// control_addr 与 peer_addr 相同（如 0.0.0.0:8888）时两者共用一个端口
+/// shutdown 取消后不再接受新连接，等正在处理的请求（例如上传中的分块）完成后返回；
+/// 节点端口无法监听或控制接口出错时返回错误
 pub async fn start_server(
-    port: u16,
-    _udp_port: u16,
+    peer_addr: SocketAddr,
+    control_addr: SocketAddr,
     pool: Pool<Sqlite>,
     peer_manager: Arc<PeerManager>,
-    #[cfg(feature = "desktop")] app_handle: Option<tauri::AppHandle>,
-) {
+    bus: Arc<BroadcastSink>,
+    events: Events,
+    shutdown: CancellationToken,
+) -> Result<(), String> {
+    let peer_listener = tokio::net::TcpListener::bind(peer_addr)
+        .await
+        .map_err(|e| format!("节点接口无法监听 {}: {}", peer_addr, e))?;
     let state = Arc::new(AppState {
         pool,
         peer_manager,
-        #[cfg(feature = "desktop")]
-        app_handle,
+        events,
+        bus,
+        shutdown: shutdown.clone(),
     });
 
-    // 配置 CORS - 允许所有来源（局域网内部使用）
+    tokio::spawn(watch_peers(state.clone()));
+
+    // 节点接口允许所有来源（旧版本的浏览器页面直接向其他节点上传文件）
+    // 控制接口不设置 CORS，只有同源页面可以调用
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any)
         .allow_credentials(false); // 明确设置不需要凭证
 
-    let app = Router::new()
+    let control = control_routes()
+        .layer(axum::extract::DefaultBodyLimit::disable()) // 无限制
+        .with_state(state.clone());
+    // 不允许明文连接时，节点端口上的明文请求（旧版本节点和旧版本的浏览器页面）只返回错误
+    let plaintext_rejected = Router::new()
+        .route("/api/upload", any(plaintext_rejected_http))
+        .route("/api/upload/:transfer_id", any(plaintext_rejected_http))
+        .route("/api/offer", any(plaintext_rejected_http))
+        .route("/api/identity", any(plaintext_rejected_http))
+        .route("/ws", any(plaintext_rejected_http))
+        .layer(cors.clone());
+    let peer = peer_routes(state.clone())
+        .layer(cors)
+        .layer(axum::extract::DefaultBodyLimit::disable()) // 无限制
+        .with_state(state);
+
+    if control_addr == peer_addr {
+        log::info!("[Web Server] 节点接口和控制接口启动在 {} (无文件大小限制)", peer_addr);
+        serve_peer_port(
+            peer_listener,
+            control.clone().merge(peer),
+            control.merge(plaintext_rejected),
+            shutdown,
+        )
+        .await;
+        return Ok(());
+    }
+
+    log::info!("[Web Server] 节点接口启动在 {} (无文件大小限制)", peer_addr);
+    let control_listener = match tokio::net::TcpListener::bind(control_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            // 控制接口不可用时仍然继续接收其他节点的消息和文件
+            log::error!("[Web Server] ✗ 控制接口无法监听 {}: {}", control_addr, e);
+            serve_peer_port(peer_listener, peer, plaintext_rejected, shutdown).await;
+            return Ok(());
+        }
+    };
+    log::info!("[Web Server] 控制接口启动在 http://{}", control_addr);
+
+    let control_shutdown = shutdown.clone().cancelled_owned();
+    let (_, result) = tokio::join!(
+        serve_peer_port(peer_listener, peer, plaintext_rejected, shutdown),
+        axum::serve(control_listener, control).with_graceful_shutdown(control_shutdown),
+    );
+    result.map_err(|e| format!("控制接口出错: {}", e))
+}
+
+// 本机使用的页面和控制接口（监听 control_addr）
+fn control_routes() -> Router<Arc<AppState>> {
+    // 页面、静态资源和登录接口不需要认证
+    let public = Router::new()
         .route("/", get(serve_index))
         .route("/*path", get(serve_assets))
+        .route("/api/auth/status", get(auth_status_http))
+        .route("/api/auth/login", post(login_http))
+        .route("/api/auth/logout", post(logout_http));
+
+    // 本机用户的控制接口：设置了访问密码时需要登录
+    let control = Router::new()
+        .route("/api/auth/password", post(set_password_http))
+        .route("/api/auth/peer_key", post(set_peer_key_http))
         .route("/api/get_my_name", get(get_name_http))
         .route("/api/get_my_id", get(get_id_http))
         .route("/api/update_my_name", post(update_name_http))
         .route("/api/get_settings", get(get_settings_http))
         .route("/api/update_settings", post(update_settings_http))
         .route("/api/get_peers", get(get_peers_http))
+        .route("/api/events", get(events_http))
         .route("/api/send_message", post(send_message_http))
         .route("/api/chat_history/:peer_id", get(get_chat_history_http))
-        .route("/api/upload", post(upload_file_http))
-        .route("/api/accept_file/:file_id", post(accept_file_http))
-        .route("/api/download/:file_id", get(download_file_http))
-        .route("/api/create_upload_record", post(create_upload_record_http))
-        .route("/api/update_upload_status", post(update_upload_status_http))
-        .route("/api/delete_upload_record", post(delete_upload_record_http))
+        .route("/api/react", post(react_http))
+        .route("/api/search", get(search_http))
+        .route("/api/conversations", get(get_conversations_http))
+        .route("/api/conversations/:peer_id/read", post(mark_conversation_read_http))
+        .route("/api/conversations/:peer_id/clear", post(clear_conversation_http))
+        .route("/api/retention", get(get_retention_http).post(update_retention_http))
+        .route("/api/retention/apply", post(apply_retention_http))
+        .route("/api/export/:peer_id", get(export_history_http))
+        .route("/api/import", post(import_history_http))
+        .route("/api/encryption/status", get(encryption_status_http))
+        .route("/api/encryption/enable", post(enable_encryption_http))
+        .route("/api/encryption/unlock", post(unlock_encryption_http))
+        .route("/api/encryption/lock", post(lock_encryption_http))
+        .route("/api/encryption/disable", post(disable_encryption_http))
+        .route("/api/tls/status", get(tls_status_http))
+        .route("/api/tls/allow_plaintext", post(set_allow_plaintext_http))
+        .route("/api/tls/forget/:peer_id", post(forget_peer_certificate_http))
+        .route("/api/e2e/:peer_id", get(safety_number_http))
+        .route("/api/e2e/:peer_id/verified", post(set_peer_verified_http))
+        .route("/api/e2e/:peer_id/accept_key", post(accept_peer_key_change_http))
+        .route("/api/accept_file/:transfer_id", post(accept_file_http))
+        .route("/api/reject_file/:transfer_id", post(reject_file_http))
+        .route(
+            "/api/file_accept_policy",
+            get(get_file_accept_policy_http).post(update_file_accept_policy_http),
+        )
+        .route("/api/download/:transfer_id", get(download_file_http))
+        .route("/api/send_file", post(send_file_http))
         .route("/api/get_theme_list", get(get_theme_list_http))
         .route("/api/get_theme_css/:theme_name", get(get_theme_css_http))
         .route("/api/save_current_theme", post(save_current_theme_http))
         .route("/api/get_current_theme", get(get_current_theme_http))
+        .route_layer(axum::middleware::from_fn(require_login));
+
+    public.merge(control)
+}
+
+// 其他节点调用的接口：设置了局域网密钥时校验节点签名
+fn peer_routes(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/upload", post(upload_file_http))
+        .route("/api/upload/:transfer_id", get(upload_status_http))
+        .route("/api/offer", post(offer_http))
+        .route("/api/identity", get(identity_http))
         .route("/ws", get(websocket_handler))
-        .layer(cors)
-        .layer(axum::extract::DefaultBodyLimit::disable()) // 无限制
-        .with_state(state);
+        .route_layer(axum::middleware::from_fn_with_state(state, require_peer))
+}
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
-        .await
-        .unwrap();
-    println!("[Web Server] 启动在端口 {} (无文件大小限制)", port);
-    axum::serve(listener, app).await.unwrap();
+// 节点端口：按连接的第一个字节区分 TLS 和明文
+// TLS 连接使用 app；明文连接在允许明文时也使用 app，否则使用 rejected
+// shutdown 取消后停止接受连接，等已有的连接处理完当前请求后返回
+async fn serve_peer_port(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    rejected: Router,
+    shutdown: CancellationToken,
+) {
+    let graceful = hyper_util::server::graceful::GracefulShutdown::new();
+    loop {
+        let (stream, addr) = tokio::select! {
+            result = listener.accept() => match result {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log::error!("[Web Server] 接受连接失败: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        let app = app.clone();
+        let rejected = rejected.clone();
+        let watcher = graceful.watcher();
+        let shutdown = shutdown.clone();
+
+        tokio::spawn(async move {
+            let mut first = [0u8; 1];
+            tokio::select! {
+                result = tokio::time::timeout(Duration::from_secs(10), stream.peek(&mut first)) => {
+                    match result {
+                        Ok(Ok(n)) if n > 0 => {}
+                        _ => return,
+                    }
+                }
+                // 还没有发送请求的连接直接关闭
+                _ = shutdown.cancelled() => return,
+            }
+
+            if first[0] == crate::tls::TLS_HANDSHAKE_BYTE {
+                let Some(acceptor) = crate::tls::acceptor() else {
+                    return;
+                };
+                match acceptor.accept(stream).await {
+                    Ok(stream) => serve_connection(stream, app, watcher).await,
+                    Err(e) => log::info!("[Web Server] 来自 {} 的 TLS 握手失败: {}", addr, e),
+                }
+            } else if crate::tls::allow_plaintext() {
+                serve_connection(stream, app, watcher).await;
+            } else {
+                serve_connection(stream, rejected, watcher).await;
+            }
+        });
+    }
+
+    drop(listener);
+    if graceful.count() > 0 {
+        log::info!(
+            "[Web Server] 节点接口已停止接受连接，等待 {} 个连接完成...",
+            graceful.count()
+        );
+    }
+    graceful.shutdown().await;
+}
+
+async fn serve_connection<S>(stream: S, app: Router, watcher: hyper_util::server::graceful::Watcher)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let service = hyper_util::service::TowerToHyperService::new(app);
+    let builder = hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+    let connection =
+        builder.serve_connection_with_upgrades(hyper_util::rt::TokioIo::new(stream), service);
+    // 对方中途断开很常见，不打印错误
+    let _ = watcher.watch(connection).await;
+}
+
+async fn plaintext_rejected_http() -> impl IntoResponse {
+    (
+        StatusCode::FORBIDDEN,
+        Json(ErrorResponse {
+            error: "该节点只接受加密连接（可以在该节点的设置中允许明文连接）".to_string(),
+        }),
+    )
+}
+
+// 从 Cookie 或 Authorization: Bearer 中取出会话令牌
+fn session_token(headers: &axum::http::HeaderMap) -> Option<String> {
+    if let Some(token) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    {
+        return Some(token.trim().to_string());
+    }
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            (name == crate::auth::SESSION_COOKIE).then(|| value.to_string())
+        })
+}
+
+fn session_cookie(token: &str, max_age: i64) -> String {
+    format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        crate::auth::SESSION_COOKIE,
+        token,
+        max_age
+    )
+}
+
+// 本机控制接口：设置了访问密码时需要登录
+async fn require_login(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if crate::auth::session_valid(session_token(request.headers()).as_deref()) {
+        return next.run(request).await;
+    }
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(ErrorResponse {
+            error: crate::auth::UNAUTHORIZED_ERROR.to_string(),
+        }),
+    )
+        .into_response()
+}
+
+/// 通过节点签名校验的发送者（设置了局域网密钥时），接口核对请求体中的发送者与它一致
+#[derive(Clone)]
+struct SignedPeer(String);
+
+// 请求体中的发送者是否是签名的发送者（没有设置局域网密钥时不检查）
+fn signed_by(signer: &Option<Extension<SignedPeer>>, sender_id: &str) -> bool {
+    signer
+        .as_ref()
+        .is_none_or(|Extension(SignedPeer(id))| id == sender_id)
+}
+
+// 其他节点调用的接口：设置了局域网密钥时校验签名（请求头，或浏览器 WebSocket 使用的同名查询参数）
+// 签名包含请求体的摘要：先读完请求体（文件提议等都很小），流式上传的分块除外
+// 记录过发送者的身份公钥时还要校验身份签名（/api/identity 除外，对方取得本机公钥之前无法计算）
+async fn require_peer(
+    State(state): State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if !crate::auth::peer_key_enabled() {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let query = Query::<std::collections::HashMap<String, String>>::try_from_uri(&parts.uri)
+        .map(|Query(q)| q)
+        .unwrap_or_default();
+    let lookup = |name: &str| -> Option<String> {
+        parts
+            .headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+            .or_else(|| query.get(name).cloned())
+    };
+    let peer_id = lookup(crate::auth::PEER_HEADER);
+    let time = lookup(crate::auth::TIME_HEADER);
+    let signature = lookup(crate::auth::SIGNATURE_HEADER);
+    let streaming = parts.method == axum::http::Method::POST
+        && parts.uri.path() == "/api/upload"
+        && lookup(crate::auth::CONTENT_HASH_HEADER).as_deref()
+            == Some(crate::auth::UNSIGNED_PAYLOAD);
+
+    let (body, content_hash) = if streaming {
+        (body, crate::auth::UNSIGNED_PAYLOAD.to_string())
+    } else {
+        match axum::body::to_bytes(body, MAX_SIGNED_BODY).await {
+            Ok(bytes) => {
+                let hash = crate::auth::content_hash(&bytes);
+                (Body::from(bytes), hash)
+            }
+            Err(e) => {
+                log::error!("[Web Server] ✗ 读取节点请求失败 {}: {}", parts.uri.path(), e);
+                return (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    Json(ErrorResponse {
+                        error: "请求体过大".to_string(),
+                    }),
+                )
+                    .into_response();
+            }
+        }
+    };
+
+    let result = crate::auth::verify_peer(
+        peer_id.as_deref(),
+        time.as_deref(),
+        signature.as_deref(),
+        parts.method.as_str(),
+        parts.uri.path(),
+        &content_hash,
+    );
+    if let Err(e) = result {
+        log::error!(
+            "[Web Server] ✗ 拒绝未认证的节点请求 {}: {}",
+            parts.uri.path(),
+            e
+        );
+        return (StatusCode::UNAUTHORIZED, Json(ErrorResponse { error: e })).into_response();
+    }
+    let peer_id = peer_id.unwrap_or_default();
+    if parts.uri.path() != "/api/identity" {
+        let claimed_key = lookup(crate::auth::IDENTITY_KEY_HEADER);
+        let result = crate::e2e::sender_auth_key(&peer_id, claimed_key.as_deref(), &state.events)
+            .await
+            .and_then(|auth_key| {
+                crate::auth::verify_identity(
+                    auth_key.as_ref(),
+                    &peer_id,
+                    time.as_deref().unwrap_or_default(),
+                    lookup(crate::auth::IDENTITY_SIGNATURE_HEADER).as_deref(),
+                    parts.method.as_str(),
+                    parts.uri.path(),
+                    &content_hash,
+                )
+            });
+        if let Err(e) = result {
+            log::error!(
+                "[Web Server] ✗ 拒绝身份不符的节点请求 {}: {}",
+                parts.uri.path(),
+                e
+            );
+            return (StatusCode::UNAUTHORIZED, Json(ErrorResponse { error: e })).into_response();
+        }
+    }
+
+    let mut request = axum::extract::Request::from_parts(parts, body);
+    request.extensions_mut().insert(SignedPeer(peer_id));
+    next.run(request).await
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct SetPasswordRequest {
+    #[serde(default)]
+    current_password: String,
+    #[serde(default)]
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+struct SetPeerKeyRequest {
+    #[serde(default)]
+    key: String,
+}
+
+async fn auth_status_http(headers: axum::http::HeaderMap) -> impl IntoResponse {
+    let authenticated = crate::auth::session_valid(session_token(&headers).as_deref());
+    Json(crate::auth::status(authenticated))
+}
+
+async fn login_http(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<LoginRequest>,
+) -> impl IntoResponse {
+    match crate::auth::login(&state.pool, &payload.password).await {
+        Ok(token) => (
+            [(header::SET_COOKIE, session_cookie(&token, 7 * 24 * 3600))],
+            Json(serde_json::json!({ "success": true, "token": token })),
+        )
+            .into_response(),
+        Err(e) => {
+            log::info!("[Web Server] 登录失败: {}", e);
+            (StatusCode::UNAUTHORIZED, Json(ErrorResponse { error: e })).into_response()
+        }
+    }
+}
+
+async fn logout_http(headers: axum::http::HeaderMap) -> impl IntoResponse {
+    if let Some(token) = session_token(&headers) {
+        crate::auth::logout(&token);
+    }
+    (
+        [(header::SET_COOKIE, session_cookie("", 0))],
+        Json(serde_json::json!({ "success": true })),
+    )
+}
+
+// 设置、修改或取消访问密码；设置了新密码时当前浏览器直接登录
+async fn set_password_http(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SetPasswordRequest>,
+) -> impl IntoResponse {
+    if let Err(e) =
+        crate::auth::set_password(&state.pool, &payload.current_password, &payload.new_password)
+            .await
+    {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response();
+    }
+    if payload.new_password.is_empty() {
+        return Json(serde_json::json!({ "success": true })).into_response();
+    }
+    match crate::auth::login(&state.pool, &payload.new_password).await {
+        Ok(token) => (
+            [(header::SET_COOKIE, session_cookie(&token, 7 * 24 * 3600))],
+            Json(serde_json::json!({ "success": true, "token": token })),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+async fn set_peer_key_http(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SetPeerKeyRequest>,
+) -> impl IntoResponse {
+    match crate::auth::set_peer_key(&state.pool, &payload.key).await {
+        Ok(_) => Json(serde_json::json!({ "success": true })).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response(),
+    }
 }
 
 async fn get_name_http(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    println!("[Web Server] 收到获取用户名请求");
+    log::info!("[Web Server] 收到获取用户名请求");
 
     match crate::db::get_username(&state.pool).await {
         Ok(name) => Json(NameResponse { name }).into_response(),
@@ -122,7 +649,7 @@ async fn get_name_http(State(state): State<Arc<AppState>>) -> impl IntoResponse
 }
 
 async fn get_id_http(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    println!("[Web Server] 收到获取用户 ID 请求");
+    log::info!("[Web Server] 收到获取用户 ID 请求");
 
     match crate::db::get_user_id(&state.pool).await {
         Ok(id) => Json(serde_json::json!({ "id": id })).into_response(),
@@ -137,7 +664,7 @@ async fn get_id_http(State(state): State<Arc<AppState>>) -> impl IntoResponse {
 }
 
 async fn get_settings_http(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    println!("[Web Server] 收到获取设置请求");
+    log::info!("[Web Server] 收到获取设置请求");
 
     let download_path = crate::db::get_download_path(&state.pool)
         .await
@@ -164,7 +691,7 @@ async fn update_settings_http(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<UpdateSettingsRequest>,
 ) -> impl IntoResponse {
-    println!("[Web Server] 收到更新设置请求");
+    log::info!("[Web Server] 收到更新设置请求");
 
     if let Some(path) = payload.download_path {
         if let Err(e) = crate::db::update_download_path(&state.pool, path).await {
@@ -179,13 +706,13 @@ async fn update_name_http(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<UpdateNameRequest>,
 ) -> impl IntoResponse {
-    println!("[Web Server] 收到改名请求: {}", payload.name);
+    log::info!("[Web Server] 收到改名请求: {}", payload.name);
 
     // 使用数据库的更新函数（包含验证逻辑）
     match crate::db::update_username(&state.pool, payload.name.clone()).await {
         Ok(_) => {
             // 数据库更新后，定时广播线程会自动使用新名称
-            println!("[Web Server] 用户名已更新，广播线程将使用新名称");
+            log::info!("[Web Server] 用户名已更新，广播线程将使用新名称");
 
             Json(NameResponse { name: payload.name }).into_response()
         }
@@ -223,7 +750,12 @@ async fn send_message_http(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<SendMessageRequest>,
 ) -> impl IntoResponse {
-    println!("[Web Server] 收到发送消息请求");
+    log::info!("[Web Server] 收到发送消息请求");
+
+    // 锁定状态下消息无法保存，不要先发出去
+    if let Err(e) = crate::encryption::ensure_unlocked() {
+        return (StatusCode::FORBIDDEN, Json(ErrorResponse { error: e })).into_response();
+    }
 
     // 获取自己的信息
     let my_id = match crate::db::get_user_id(&state.pool).await {
@@ -249,10 +781,13 @@ async fn send_message_http(
     };
 
     // 发送消息
+    let msg_uuid = uuid::Uuid::new_v4().to_string();
     if let Err(e) = crate::network::messaging::send_text_message(
         &payload.peer_addr,
+        &payload.peer_id,
         my_id,
         my_name,
+        msg_uuid.clone(),
         payload.content.clone(),
     )
     .await
@@ -266,7 +801,8 @@ async fn send_message_http(
 
     // 保存到数据库
     if let Err(e) =
-        crate::db::save_text_message(&state.pool, payload.peer_id, payload.content).await
+        crate::db::save_text_message(&state.pool, payload.peer_id, msg_uuid, payload.content)
+            .await
     {
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -304,82 +840,526 @@ async fn get_chat_history_http(
     }
 }
 
-
-// WebSocket 处理器
-async fn websocket_handler(
-    ws: WebSocketUpgrade,
+async fn react_http(
     State(state): State<Arc<AppState>>,
-) -> axum::response::Response {
-    ws.on_upgrade(|socket| handle_websocket(socket, state))
-}
+    Json(payload): Json<ReactRequest>,
+) -> impl IntoResponse {
+    log::info!("[Web Server] 收到表情回应请求: {}", payload.message_id);
 
-// 处理 WebSocket 连接
-async fn handle_websocket(socket: WebSocket, state: Arc<AppState>) {
-    let (_sender, mut receiver) = socket.split();
+    if let Err(e) = crate::db::validate_emoji(&payload.emoji) {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response();
+    }
 
-    println!("[WebSocket] 新的 WebSocket 连接");
+    let my_id = match crate::db::get_user_id(&state.pool).await {
+        Ok(id) => id,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse { error: e }),
+            )
+                .into_response();
+        }
+    };
 
-    // 接收消息
-    while let Some(msg) = receiver.next().await {
-        match msg {
-            Ok(Message::Text(text)) => {
-                println!("[WebSocket] 收到文本消息: {}", text);
+    // 先同步给对方，再保存到本地
+    if let Err(e) = crate::network::messaging::send_reaction(
+        &payload.peer_addr,
+        &payload.peer_id,
+        my_id.clone(),
+        payload.message_id.clone(),
+        payload.emoji.clone(),
+        payload.remove,
+    )
+    .await
+    {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response();
+    }
 
-                // 解析消息
-                if let Ok(message) =
-                    serde_json::from_str::<crate::network::messaging::TextMessage>(&text)
-                {
-                    // 保存到数据库
-                    if let Err(e) = save_message_to_db(&state.pool, &message).await {
-                        eprintln!("[WebSocket] 保存消息失败: {}", e);
-                    } else {
-                        println!(
-                            "[WebSocket] 消息已保存: {} 说: {}",
-                            message.from_name, message.content
-                        );
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
 
-                        // 桌面端: 发送 Tauri 事件通知前端
-                        #[cfg(feature = "desktop")]
-                        if let Some(ref app) = state.app_handle {
-                            use tauri::Emitter;
-                            let _ = app.emit(
-                                "new-message",
-                                serde_json::json!({
-                                    "from_id": message.from_id,
-                                    "from_name": message.from_name,
-                                    "content": message.content,
-                                    "timestamp": message.timestamp,
-                                    "msg_type": message.msg_type,
-                                }),
-                            );
-                            println!("[WebSocket] 已发送 Tauri 事件: new-message");
-                        }
-                    }
-                } else {
-                    eprintln!("[WebSocket] 无法解析消息");
-                }
-            }
-            Ok(Message::Close(_)) => {
-                println!("[WebSocket] 连接关闭");
-                break;
-            }
-            Err(e) => {
-                eprintln!("[WebSocket] 错误: {}", e);
-                break;
-            }
-            _ => {}
-        }
+    let result = if payload.remove {
+        crate::db::remove_reaction(&state.pool, &payload.message_id, &my_id, &payload.emoji).await
+    } else {
+        crate::db::add_reaction(&state.pool, &payload.message_id, &my_id, &payload.emoji, timestamp)
+            .await
+    };
+
+    match result {
+        Ok(_) => Json(serde_json::json!({ "success": true })).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
     }
 }
 
-// 保存消息到数据库
-async fn save_message_to_db(
-    pool: &Pool<Sqlite>,
-    message: &crate::network::messaging::TextMessage,
+async fn search_http(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<crate::models::SearchQuery>,
+) -> impl IntoResponse {
+    log::info!("[Web Server] 收到搜索请求: {}", query.q);
+
+    match crate::db::search_messages(&state.pool, &query).await {
+        Ok(results) => Json(serde_json::json!({ "results": results })).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response(),
+    }
+}
+
+async fn get_conversations_http(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match crate::db::get_conversations(&state.pool).await {
+        Ok(mut conversations) => {
+            // 补充对方的名称和在线状态
+            state.peer_manager.mark_stale_as_offline();
+            for conversation in conversations.iter_mut() {
+                if let Some(peer) = state.peer_manager.get_peer(&conversation.peer_id) {
+                    conversation.peer_name = Some(peer.name);
+                    conversation.is_online = !peer.is_offline;
+                }
+            }
+            Json(serde_json::json!({ "conversations": conversations })).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+async fn mark_conversation_read_http(
+    State(state): State<Arc<AppState>>,
+    Path(peer_id): Path<String>,
+) -> impl IntoResponse {
+    match crate::db::mark_conversation_read(&state.pool, &peer_id).await {
+        Ok(_) => Json(serde_json::json!({ "success": true })).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+// 清空与某个用户的会话，delete_files 为 true 时同时删除收到的文件
+async fn clear_conversation_http(
+    State(state): State<Arc<AppState>>,
+    Path(peer_id): Path<String>,
+    payload: Option<Json<ClearConversationRequest>>,
+) -> impl IntoResponse {
+    let Json(payload) = payload.unwrap_or_default();
+
+    match crate::retention::clear_conversation(&state.pool, &peer_id, payload.delete_files).await {
+        Ok(summary) => Json(summary).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+async fn get_retention_http(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match crate::retention::get_policy(&state.pool).await {
+        Ok(policy) => Json(policy).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+async fn update_retention_http(
+    State(state): State<Arc<AppState>>,
+    Json(policy): Json<crate::models::RetentionPolicy>,
+) -> impl IntoResponse {
+    if let Err(e) = crate::retention::update_policy(&state.pool, &policy).await {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response();
+    }
+
+    // 新策略立即生效
+    match crate::retention::apply_policy(&state.pool).await {
+        Ok(summary) => Json(summary).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+// 立即按保留策略执行一次清理
+async fn apply_retention_http(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match crate::retention::apply_policy(&state.pool).await {
+        Ok(summary) => Json(summary).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+// 导出聊天记录：peer_id 为 "all" 时导出全部
+// 查询参数 format=json|markdown|html（默认 json），bundle=true 时打包 zip 并附带已接收的文件
+async fn export_history_http(
+    State(state): State<Arc<AppState>>,
+    Path(peer_id): Path<String>,
+    Query(params): Query<std::collections::HashMap<String, String>>,
+) -> impl IntoResponse {
+    let format = params.get("format").map(|s| s.as_str()).unwrap_or("json");
+    let bundle = params
+        .get("bundle")
+        .map(|s| s == "true" || s == "1")
+        .unwrap_or(false);
+    let peer = if peer_id == "all" { None } else { Some(peer_id.as_str()) };
+
+    match crate::db::export_history(&state.pool, peer, format, bundle).await {
+        Ok(archive) => Response::builder()
+            .header(header::CONTENT_TYPE, archive.content_type)
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", archive.file_name),
+            )
+            .body(Body::from(archive.data))
+            .unwrap(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response(),
+    }
+}
+
+// 导入聊天记录：请求体为 LANChat 导出的 JSON 文件内容
+async fn import_history_http(
+    State(state): State<Arc<AppState>>,
+    body: axum::body::Bytes,
+) -> impl IntoResponse {
+    match crate::db::import_history(&state.pool, &body).await {
+        Ok(summary) => Json(serde_json::json!({
+            "success": true,
+            "imported": summary.imported,
+            "skipped": summary.skipped
+        }))
+        .into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response(),
+    }
+}
+
+async fn encryption_status_http() -> impl IntoResponse {
+    Json(crate::encryption::status())
+}
+
+async fn enable_encryption_http(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PassphraseRequest>,
+) -> impl IntoResponse {
+    match crate::encryption::enable(&state.pool, &payload.passphrase).await {
+        Ok(_) => Json(serde_json::json!({"success": true})).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response(),
+    }
+}
+
+async fn unlock_encryption_http(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PassphraseRequest>,
+) -> impl IntoResponse {
+    match crate::encryption::unlock(&state.pool, &payload.passphrase).await {
+        Ok(_) => Json(serde_json::json!({"success": true})).into_response(),
+        Err(e) => (StatusCode::FORBIDDEN, Json(ErrorResponse { error: e })).into_response(),
+    }
+}
+
+async fn lock_encryption_http() -> impl IntoResponse {
+    match crate::encryption::lock() {
+        Ok(_) => Json(serde_json::json!({"success": true})).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response(),
+    }
+}
+
+async fn disable_encryption_http(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PassphraseRequest>,
+) -> impl IntoResponse {
+    match crate::encryption::disable(&state.pool, &payload.passphrase).await {
+        Ok(_) => Json(serde_json::json!({"success": true})).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response(),
+    }
+}
+
+async fn tls_status_http() -> impl IntoResponse {
+    Json(crate::tls::status())
+}
+
+async fn set_allow_plaintext_http(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<AllowPlaintextRequest>,
+) -> impl IntoResponse {
+    match crate::tls::set_allow_plaintext(&state.pool, payload.allow).await {
+        Ok(_) => Json(serde_json::json!({"success": true})).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+// 对方重新安装后证书会变化，用户确认后删除旧的证书记录
+async fn forget_peer_certificate_http(
+    State(state): State<Arc<AppState>>,
+    Path(peer_id): Path<String>,
+) -> impl IntoResponse {
+    match crate::tls::forget_peer(&state.pool, &peer_id).await {
+        Ok(_) => Json(serde_json::json!({"success": true})).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+// 和某个节点的安全码（当面核对身份）
+async fn safety_number_http(
+    State(state): State<Arc<AppState>>,
+    Path(peer_id): Path<String>,
+) -> impl IntoResponse {
+    match crate::e2e::safety_number(&state.pool, &peer_id).await {
+        Ok(number) => Json(number).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+async fn set_peer_verified_http(
+    State(state): State<Arc<AppState>>,
+    Path(peer_id): Path<String>,
+    Json(payload): Json<VerifiedRequest>,
+) -> impl IntoResponse {
+    match crate::e2e::set_verified(&state.pool, &peer_id, payload.verified).await {
+        Ok(_) => Json(serde_json::json!({"success": true})).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response(),
+    }
+}
+
+// 对方重新安装后身份密钥会变化，用户核对安全码后信任新的密钥
+async fn accept_peer_key_change_http(
+    State(state): State<Arc<AppState>>,
+    Path(peer_id): Path<String>,
+) -> impl IntoResponse {
+    match crate::e2e::accept_key_change(&state.pool, &peer_id).await {
+        Ok(_) => Json(serde_json::json!({"success": true})).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response(),
+    }
+}
+
+// 本机的身份公钥（其他节点发送消息和文件之前获取）
+async fn identity_http(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match crate::db::get_user_id(&state.pool).await {
+        Ok(peer_id) => Json(crate::e2e::Identity {
+            peer_id,
+            public_key: crate::e2e::public_key(),
+        })
+        .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
+}
+
+// 推送事件给浏览器（Server-Sent Events），代替轮询
+async fn events_http(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl futures_util::Stream<Item = Result<Event, axum::Error>>> {
+    log::info!("[Web Server] 浏览器订阅事件");
+    let receiver = state.bus.subscribe();
+
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(event) => Event::default().event(event.name).json_data(&event.payload),
+            // 浏览器处理得太慢，丢掉了一些事件：让它重新加载
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::info!("[Web Server] 浏览器跳过了 {} 个事件，要求重新加载", skipped);
+                Ok(Event::default().event("resync").data("{}"))
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((event, receiver))
+    });
+
+    // 退出时结束订阅，否则浏览器的长连接会一直占着控制接口
+    let stream = stream.take_until(state.shutdown.clone().cancelled_owned());
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+// 在线用户列表变化（新用户、改名、上线/离线、移除）时推送完整列表
+async fn watch_peers(state: Arc<AppState>) {
+    let mut last = Vec::new();
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let mut peers = state.peer_manager.get_all_peers();
+        peers.sort_by(|a, b| a.id.cmp(&b.id));
+        let snapshot: Vec<_> = peers
+            .iter()
+            .map(|p| (p.id.clone(), p.name.clone(), p.addr.clone(), p.is_offline))
+            .collect();
+
+        if snapshot != last {
+            last = snapshot;
+            state.publish("peers-updated", serde_json::json!(peers));
+        }
+    }
+}
+
+// WebSocket 处理器
+async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+    signer: Option<Extension<SignedPeer>>,
+) -> axum::response::Response {
+    ws.on_upgrade(|socket| handle_websocket(socket, state, signer))
+}
+
+// 处理 WebSocket 连接（signer：连接时签名的发送者，每条消息的发送者都必须是它）
+async fn handle_websocket(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    signer: Option<Extension<SignedPeer>>,
+) {
+    let (_sender, mut receiver) = socket.split();
+
+    log::info!("[WebSocket] 新的 WebSocket 连接");
+
+    // 接收消息
+    while let Some(msg) = receiver.next().await {
+        match msg {
+            Ok(Message::Text(text)) => {
+                log::debug!("[WebSocket] 收到文本帧，长度: {} 字节", text.len());
+
+                // 表情回应帧
+                let frame_type = serde_json::from_str::<serde_json::Value>(&text)
+                    .ok()
+                    .and_then(|v| v.get("msg_type").and_then(|t| t.as_str()).map(String::from));
+                if frame_type.as_deref() == Some("reaction") {
+                    handle_reaction_frame(&state, &text, &signer).await;
+                    continue;
+                }
+
+                // 解析消息
+                if let Ok(mut message) =
+                    serde_json::from_str::<crate::network::messaging::TextMessage>(&text)
+                {
+                    if !signed_by(&signer, &message.from_id) {
+                        log::error!(
+                            "[WebSocket] ✗ 拒绝消息：发送者 {} 与签名的节点不一致",
+                            message.from_id
+                        );
+                        continue;
+                    }
+                    // 解密端到端加密的内容
+                    if let Err(e) =
+                        crate::network::messaging::open_message(&mut message, &state.events).await
+                    {
+                        log::error!("[WebSocket] ✗ 拒绝来自 {} 的消息: {}", message.from_id, e);
+                        continue;
+                    }
+                    // 保存到数据库
+                    if let Err(e) = save_message_to_db(&state.pool, &message).await {
+                        log::error!("[WebSocket] 保存消息失败: {}", e);
+                    } else {
+                        // 日志里不写消息内容
+                        log::info!("[WebSocket] 来自 {} 的消息已保存", message.from_name);
+
+                        // 通知前端（浏览器和桌面端）
+                        state.publish(
+                            "new-message",
+                            serde_json::json!({
+                                "message_id": message.msg_id,
+                                "from_id": message.from_id,
+                                "from_name": message.from_name,
+                                "content": message.content,
+                                "timestamp": message.timestamp,
+                                "msg_type": message.msg_type,
+                            }),
+                        );
+                    }
+                } else {
+                    log::error!("[WebSocket] 无法解析消息");
+                }
+            }
+            Ok(Message::Close(_)) => {
+                log::info!("[WebSocket] 连接关闭");
+                break;
+            }
+            Err(e) => {
+                log::error!("[WebSocket] 错误: {}", e);
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+
+// 处理对方发来的表情回应
+async fn handle_reaction_frame(
+    state: &Arc<AppState>,
+    text: &str,
+    signer: &Option<Extension<SignedPeer>>,
+) {
+    let reaction = match serde_json::from_str::<crate::network::messaging::ReactionMessage>(text) {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("[WebSocket] 无法解析表情回应: {}", e);
+            return;
+        }
+    };
+    if !signed_by(signer, &reaction.from_id) {
+        log::error!(
+            "[WebSocket] ✗ 拒绝表情回应：发送者 {} 与签名的节点不一致",
+            reaction.from_id
+        );
+        return;
+    }
+
+    // 对方只能回应和本机之间的消息（数据库层检查）
+    if let Err(e) = crate::network::messaging::apply_reaction(&state.pool, &reaction).await {
+        log::error!("[WebSocket] ✗ 拒绝来自 {} 的表情回应: {}", reaction.from_id, e);
+        return;
+    }
+
+    log::info!(
+        "[WebSocket] 表情回应已保存: {} {} {}",
+        reaction.from_id, reaction.action, reaction.emoji
+    );
+
+    // 通知前端（浏览器和桌面端）
+    state.publish(
+        "new-reaction",
+        serde_json::to_value(&reaction).unwrap_or_default(),
+    );
+}
+
+// 保存消息到数据库
+async fn save_message_to_db(
+    pool: &Pool<Sqlite>,
+    message: &crate::network::messaging::TextMessage,
 ) -> Result<(), String> {
     crate::db::save_received_text_message(
         pool,
         message.from_id.clone(),
+        message.msg_id.clone(),
         message.content.clone(),
         message.msg_type.clone(),
         message.timestamp as i64,
@@ -389,46 +1369,81 @@ async fn save_message_to_db(
 
 async fn upload_file_http(
     State(state): State<Arc<AppState>>,
+    signer: Option<Extension<SignedPeer>>,
     mut multipart: Multipart,
 ) -> impl IntoResponse {
-    println!("[Web Server] 收到文件上传请求");
+    log::info!("[Web Server] 收到文件上传请求");
 
     let mut sender_id = String::new();
+    let mut msg_uuid: Option<String> = None;
+    let mut transfer_id: Option<String> = None;
+    let mut offset: Option<u64> = None;
+    let mut chunk_hash: Option<String> = None;
+    let mut file_hash: Option<String> = None;
     let mut file_name = String::new();
     let mut file_size: u64 = 0;
     let mut chunk_index: usize = 0;
     let mut chunk_total: usize = 0;
-    let mut _file_path: Option<std::path::PathBuf> = None;
-    let mut chunk_data: Option<Vec<u8>> = None;
+    let mut e2e_nonce: Option<String> = None;
 
     // 获取下载目录
     let download_dir = get_download_dir(&state.pool).await;
     if let Err(e) = fs::create_dir_all(&download_dir).await {
-        eprintln!("[Web Server] 创建目录失败: {}", e);
+        log::error!("[Web Server] 创建目录失败: {}", e);
     }
 
     // 解析 multipart 字段
-    println!("[Web Server] 开始解析 multipart 字段");
-    while let Some(mut field) = multipart.next_field().await.ok().flatten() {
+    // 分块数据不读入内存：读到 chunk 字段就停止解析，确定写入位置后直接流式写入文件
+    // 发送方需要把 chunk 放在最后一个字段（各版本的发送方都是这样做的）
+    log::info!("[Web Server] 开始解析 multipart 字段");
+    let chunk_field = loop {
+        let Some(field) = multipart.next_field().await.ok().flatten() else {
+            break None;
+        };
         let field_name = field.name().map(|s| s.to_string()).unwrap_or_default();
 
         match field_name.as_str() {
             "peer_id" => {
                 if let Ok(text) = field.text().await {
                     sender_id = text;
-                    println!("[Web Server] sender_id (发送者): {}", sender_id);
+                    log::info!("[Web Server] sender_id (发送者): {}", sender_id);
+                }
+            }
+            "msg_id" => {
+                if let Ok(text) = field.text().await {
+                    msg_uuid = Some(text);
+                }
+            }
+            "transfer_id" => {
+                if let Ok(text) = field.text().await {
+                    transfer_id = Some(text).filter(|t| !t.is_empty());
+                }
+            }
+            "offset" => {
+                if let Ok(text) = field.text().await {
+                    offset = text.parse().ok();
+                }
+            }
+            "chunk_hash" => {
+                if let Ok(text) = field.text().await {
+                    chunk_hash = Some(text.to_lowercase());
+                }
+            }
+            "file_hash" => {
+                if let Ok(text) = field.text().await {
+                    file_hash = Some(text.to_lowercase());
                 }
             }
             "file_name" => {
                 if let Ok(text) = field.text().await {
                     file_name = text;
-                    println!("[Web Server] 文件名: {}", file_name);
+                    log::info!("[Web Server] 文件名: {}", file_name);
                 }
             }
             "file_size" => {
                 if let Ok(text) = field.text().await {
                     file_size = text.parse().unwrap_or(0);
-                    println!("[Web Server] 文件总大小: {}", file_size);
+                    log::info!("[Web Server] 文件总大小: {}", file_size);
                 }
             }
             "chunk_index" => {
@@ -439,39 +1454,33 @@ async fn upload_file_http(
             "chunk_total" => {
                 if let Ok(text) = field.text().await {
                     chunk_total = text.parse().unwrap_or(0);
-                    println!("[Web Server] 分块信息: {}/{}", chunk_index + 1, chunk_total);
+                    log::info!("[Web Server] 分块信息: {}/{}", chunk_index + 1, chunk_total);
                 }
             }
-            "chunk" => {
-                // 读取分块数据
-                let mut data = Vec::new();
-                while let Ok(Some(chunk)) = field.chunk().await {
-                    data.extend_from_slice(&chunk);
+            "e2e_nonce" => {
+                if let Ok(text) = field.text().await {
+                    e2e_nonce = Some(text);
                 }
-                chunk_data = Some(data);
-                println!(
-                    "[Web Server] 收到分块数据，大小: {} 字节",
-                    chunk_data.as_ref().map(|d| d.len()).unwrap_or(0)
-                );
             }
+            "chunk" => break Some(field),
             _ => {
-                println!("[Web Server] 忽略未知字段: {}", field_name);
+                log::info!("[Web Server] 忽略未知字段: {}", field_name);
             }
         }
-    }
+    };
 
     // 验证必需字段
     // 第一块需要所有字段，后续块只需要 chunk_index 和 chunk
-    println!(
+    log::info!(
         "[Web Server] 验证字段: file_name={}, chunk_index={}, chunk_total={}, has_chunk={}",
         file_name,
         chunk_index,
         chunk_total,
-        chunk_data.is_some()
+        chunk_field.is_some()
     );
 
-    if chunk_data.is_none() {
-        eprintln!("[Web Server] ✗ 缺少 chunk 数据");
+    let Some(mut chunk_field) = chunk_field else {
+        log::error!("[Web Server] ✗ 缺少 chunk 数据");
         return (
             StatusCode::BAD_REQUEST,
             Json(ErrorResponse {
@@ -479,10 +1488,31 @@ async fn upload_file_http(
             }),
         )
             .into_response();
+    };
+
+    // 没有端到端加密的分块（旧版本节点）只有允许明文连接、并且发送方不支持端到端加密时才接收
+    if e2e_nonce.is_none() {
+        if let Err(e) = crate::e2e::check_plaintext(&sender_id, PLAINTEXT_FILES_REFUSED).await {
+            log::error!("[Web Server] ✗ 拒绝没有端到端加密的分块 (sender_id={})", sender_id);
+            while let Ok(Some(_)) = chunk_field.chunk().await {}
+            return (StatusCode::FORBIDDEN, Json(ErrorResponse { error: e })).into_response();
+        }
+    }
+
+    // 文件名来自对方，只保留可以安全放进下载目录的部分
+    if !file_name.is_empty() {
+        let sanitized = crate::utils::sanitize_file_name(&file_name);
+        if sanitized != file_name {
+            log::info!(
+                "[Web Server] 文件名已清理: {:?} -> {:?}",
+                file_name, sanitized
+            );
+            file_name = sanitized;
+        }
     }
 
     if chunk_index == 0 && file_name.is_empty() {
-        eprintln!("[Web Server] ✗ 第一块缺少 file_name");
+        log::error!("[Web Server] ✗ 第一块缺少 file_name");
         return (
             StatusCode::BAD_REQUEST,
             Json(ErrorResponse {
@@ -492,54 +1522,239 @@ async fn upload_file_http(
             .into_response();
     }
 
-    // 对于后续块，如果 file_name 为空，从数据库查询
-    if chunk_index > 0 && file_name.is_empty() {
-        println!(
-            "[Web Server] 第 {} 块，file_name 为空，从数据库查询 (sender_id={})",
-            chunk_index + 1,
-            sender_id
-        );
-        // 从数据库查询该发送者最近的 downloading 状态的文件
-        match crate::db::get_downloading_file(&state.pool, &sender_id).await {
-            Ok(Some(name)) => {
-                file_name = name;
-                println!(
-                    "[Web Server] 从数据库查询到文件名: {} (sender_id={})",
-                    file_name, sender_id
+    // 新版本发送方带 transfer_id，旧版本只带 msg_id（两者相同）
+    // 都不带的旧版本发送方：第一块开始一次新的传输，后续块接到该发送者正在接收的传输上
+    let transfer_id = match transfer_id.or(msg_uuid) {
+        Some(id) => id,
+        None if chunk_index == 0 => uuid::Uuid::new_v4().to_string(),
+        None => match crate::db::get_downloading_transfer(&state.pool, &sender_id).await {
+            Ok(Some(transfer)) => {
+                log::info!(
+                    "[Web Server] 第 {} 块没有传输 ID，接到传输 {} (sender_id={})",
+                    chunk_index + 1,
+                    transfer.id,
+                    sender_id
                 );
+                transfer.id
             }
             Ok(None) => {
-                eprintln!("[Web Server] ✗ 无法确定文件名 (sender_id={})", sender_id);
+                log::error!("[Web Server] ✗ 无法确定传输 (sender_id={})", sender_id);
                 return (
                     StatusCode::BAD_REQUEST,
                     Json(ErrorResponse {
-                        error: "无法确定文件名".to_string(),
+                        error: "无法确定传输".to_string(),
                     }),
                 )
                     .into_response();
             }
             Err(e) => {
-                eprintln!("[Web Server] ✗ 数据库查询失败: {}", e);
+                log::error!("[Web Server] ✗ 数据库查询失败: {}", e);
                 return (
                     StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ErrorResponse { error: e }),
+                )
+                    .into_response();
+            }
+        },
+    };
+
+    // 只接受收到的传输（自己发出的传输 ID 不能被写入）
+    let mut existing = match crate::db::get_transfer(&state.pool, &transfer_id).await {
+        Ok(record) => record.filter(|t| t.sender_id != "me"),
+        Err(e) => {
+            log::error!("[Web Server] ✗ 数据库查询失败: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse { error: e }),
+            )
+                .into_response();
+        }
+    };
+
+    // 没有先发送文件提议的旧版本发送方：第一块视为提议，只有按规则自动接收时才写入
+    if existing.is_none() && chunk_index == 0 && offset.unwrap_or(0) == 0 {
+        let offer = crate::network::transfer::FileOffer {
+            transfer_id: transfer_id.clone(),
+            sender_id: sender_id.clone(),
+            file_name: file_name.clone(),
+            file_size,
+            file_hash: file_hash.clone(),
+            mime_type: None,
+            sender_key: None,
+        };
+        let reply = crate::offers::handle_offer(&state.pool, &download_dir, offer).await;
+        if reply.is_ok() {
+            state.publish_file_status(&transfer_id).await;
+        }
+        match reply {
+            Ok(reply) if reply.decision == "accepted" => {
+                existing = crate::db::get_transfer(&state.pool, &transfer_id)
+                    .await
+                    .ok()
+                    .flatten();
+            }
+            Ok(reply) => {
+                log::info!(
+                    "[Web Server] 未经确认的文件不会自动接收: {} ({})",
+                    file_name, reply.decision
+                );
+                while let Ok(Some(_)) = chunk_field.chunk().await {}
+                return (
+                    StatusCode::FORBIDDEN,
                     Json(ErrorResponse {
-                        error: format!("数据库查询失败: {}", e),
+                        error: "对方需要先确认接收这个文件".to_string(),
                     }),
                 )
                     .into_response();
             }
+            Err(e) => {
+                log::error!("[Web Server] ✗ 处理文件提议失败: {}", e);
+                while let Ok(Some(_)) = chunk_field.chunk().await {}
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ErrorResponse { error: e }),
+                )
+                    .into_response();
+            }
         }
     }
 
-    let chunk_data = chunk_data.unwrap();
-    let path = download_dir.join(&file_name);
+    // 没有这次传输的记录：告诉发送方从头开始（先发送文件提议）
+    let Some(transfer) = existing else {
+        log::error!("[Web Server] ✗ 没有这次传输的记录: {}", transfer_id);
+        while let Ok(Some(_)) = chunk_field.chunk().await {}
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": "没有这次传输的记录",
+                "received_bytes": 0,
+            })),
+        )
+            .into_response();
+    };
+
+    // 只有文件提议的发送方才能写入这次传输
+    if sender_id != transfer.sender_id || !signed_by(&signer, &sender_id) {
+        log::error!(
+            "[Web Server] ✗ 分块的发送方 {} 与传输 {} 的发送方不一致",
+            sender_id, transfer_id
+        );
+        while let Ok(Some(_)) = chunk_field.chunk().await {}
+        return (
+            StatusCode::FORBIDDEN,
+            Json(ErrorResponse {
+                error: "不是这次传输的发送方".to_string(),
+            }),
+        )
+            .into_response();
+    }
+
+    // 只有接受了的传输才写入数据
+    match transfer.status.as_str() {
+        "pending" | "rejected" => {
+            let error = if transfer.status == "pending" {
+                "等待对方确认接收"
+            } else {
+                "对方拒绝了这个文件"
+            };
+            log::info!("[Web Server] ✗ {}: {}", error, transfer.file_name);
+            while let Ok(Some(_)) = chunk_field.chunk().await {}
+            return (
+                StatusCode::FORBIDDEN,
+                Json(ErrorResponse {
+                    error: error.to_string(),
+                }),
+            )
+                .into_response();
+        }
+        // 已经接收完成（发送方没有收到最后一块的响应而重传）
+        "accepted" => {
+            while let Ok(Some(_)) = chunk_field.chunk().await {}
+            return Json(serde_json::json!({
+                "success": true,
+                "transfer_id": transfer_id,
+                "received_bytes": transfer.file_size,
+            }))
+            .into_response();
+        }
+        _ => {}
+    }
 
-    // 第一块时创建文件
-    if chunk_index == 0 {
-        _file_path = Some(path.clone());
-        println!("[Web Server] 创建新文件: {:?}", path);
+    // 文件名和大小以接受的文件提议为准（接收规则是按提议中的大小判断的）
+    // 后续块可以不带文件名和大小，带了就必须与提议一致
+    let file_name = transfer.file_name.clone();
+    if file_size != 0 && file_size != transfer.file_size as u64 {
+        log::error!(
+            "[Web Server] ✗ 分块声明的文件大小 {} 与文件提议中的 {} 不一致",
+            file_size, transfer.file_size
+        );
+        while let Ok(Some(_)) = chunk_field.chunk().await {}
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: "文件大小与文件提议不一致".to_string(),
+            }),
+        )
+            .into_response();
+    }
+    let file_size = transfer.file_size as u64;
+    let path = std::path::PathBuf::from(&transfer.file_path);
+
+    // 没有 offset 字段的旧版本发送方：第一块从头开始，后续块接在文件末尾
+    let current_size = tokio::fs::metadata(&path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+    let offset = offset.unwrap_or(if chunk_index == 0 { 0 } else { current_size });
+
+    // 偏移与已收到的数据对不上时不写入，告诉发送方应该从哪里继续
+    if offset != 0 && offset != current_size {
+        log::error!(
+            "[Web Server] ✗ 分块偏移 {} 与已接收的 {} 字节不一致",
+            offset, current_size
+        );
+        // 读完请求体再响应，否则发送方还在上传时连接被关闭，收不到这个响应
+        while let Ok(Some(_)) = chunk_field.chunk().await {}
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": "分块偏移与已接收的数据不一致",
+                "received_bytes": current_size,
+            })),
+        )
+            .into_response();
+    }
+
+    // 端到端加密的分块用发送方的会话密钥解密（发送方的身份公钥在文件提议中记录）
+    let mut opener = match &e2e_nonce {
+        Some(prefix) => {
+            let opener = match crate::e2e::peer_key(&transfer.sender_id).await {
+                Ok(Some(key)) => crate::e2e::session_key(&key).and_then(|key| {
+                    crate::e2e::SegmentOpener::new(
+                        &key,
+                        prefix,
+                        crate::e2e::chunk_aad(&transfer_id, offset),
+                    )
+                }),
+                Ok(None) => Err("没有对方的身份密钥，请重新发送文件".to_string()),
+                Err(e) => Err(e),
+            };
+            match opener {
+                Ok(opener) => Some(opener),
+                Err(e) => {
+                    log::error!("[Web Server] ✗ 无法解密分块: {}", e);
+                    while let Ok(Some(_)) = chunk_field.chunk().await {}
+                    return (StatusCode::FORBIDDEN, Json(ErrorResponse { error: e }))
+                        .into_response();
+                }
+            }
+        }
+        None => None,
+    };
 
-        // 删除旧文件（如果存在）
+    // 从头开始的传输（第一块或发送方要求重传）
+    if offset == 0 {
+        log::info!("[Web Server] 开始接收文件: {:?}", path);
         let _ = tokio::fs::remove_file(&path).await;
     }
 
@@ -552,7 +1767,7 @@ async fn upload_file_http(
     {
         Ok(f) => f,
         Err(e) => {
-            eprintln!("[Web Server] ✗ 打开文件失败: {}", e);
+            log::error!("[Web Server] ✗ 打开文件失败: {}", e);
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(ErrorResponse {
@@ -563,327 +1778,405 @@ async fn upload_file_http(
         }
     };
 
-    let mut writer = tokio::io::BufWriter::new(file);
-
-    // 写入分块数据
-    if let Err(e) = tokio::io::AsyncWriteExt::write_all(&mut writer, &chunk_data).await {
-        eprintln!("[Web Server] ✗ 写入文件失败: {}", e);
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("写入文件失败: {}", e),
-            }),
-        )
-            .into_response();
+    // 边接收边写入，同时计算分块的校验值；内存占用只有写缓冲区的大小
+    let mut writer = tokio::io::BufWriter::with_capacity(UPLOAD_WRITE_BUFFER, file);
+    let mut hasher = <sha2::Sha256 as sha2::Digest>::new();
+    let mut written: u64 = 0;
+
+    let write_result: Result<(), String> = async {
+        loop {
+            let data = match chunk_field
+                .chunk()
+                .await
+                .map_err(|e| format!("接收分块失败: {}", e))?
+            {
+                Some(data) => match opener.as_mut() {
+                    Some(opener) => axum::body::Bytes::from(opener.update(&data)?),
+                    None => data,
+                },
+                // 加密的分块最后还有一个不满的分段
+                None => match opener.take() {
+                    Some(opener) => axum::body::Bytes::from(opener.finish()?),
+                    None => break,
+                },
+            };
+            // 超出文件提议中的大小时不再写入
+            if offset + written + data.len() as u64 > file_size {
+                return Err(UPLOAD_TOO_LARGE.to_string());
+            }
+            sha2::Digest::update(&mut hasher, &data);
+            tokio::io::AsyncWriteExt::write_all(&mut writer, &data)
+                .await
+                .map_err(|e| format!("写入文件失败: {}", e))?;
+            written += data.len() as u64;
+        }
+        tokio::io::AsyncWriteExt::flush(&mut writer)
+            .await
+            .map_err(|e| format!("写入文件失败: {}", e))
     }
+    .await;
 
-    // 刷新缓冲区
-    if let Err(e) = tokio::io::AsyncWriteExt::flush(&mut writer).await {
-        eprintln!("[Web Server] ✗ 刷新缓冲区失败: {}", e);
+    // 分块在传输中损坏时丢弃，发送方会重传这一块（旧版本发送方不带校验值）
+    let write_result = write_result.and_then(|_| match &chunk_hash {
+        Some(expected) => {
+            let actual = format!("{:x}", sha2::Digest::finalize(hasher));
+            if &actual == expected {
+                Ok(())
+            } else {
+                log::error!(
+                    "[Web Server] ✗ 分块 {} 校验失败: 期望 {}, 实际 {}",
+                    chunk_index + 1,
+                    expected,
+                    actual
+                );
+                Err("分块校验失败".to_string())
+            }
+        }
+        None => Ok(()),
+    });
+
+    if let Err(e) = write_result {
+        log::error!("[Web Server] ✗ {}", e);
+        // 把文件截回到这一块之前，已收到的部分保持完整，续传时从这里开始
+        if let Err(e) = writer.into_inner().set_len(offset).await {
+            log::error!("[Web Server] ✗ 回滚分块失败: {}", e);
+        }
+        let status = if e == "分块校验失败" || e == crate::e2e::DECRYPT_ERROR {
+            StatusCode::UNPROCESSABLE_ENTITY
+        } else if e == UPLOAD_TOO_LARGE {
+            // 读完请求体再响应，否则发送方收不到这个响应
+            while let Ok(Some(_)) = chunk_field.chunk().await {}
+            StatusCode::PAYLOAD_TOO_LARGE
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        return (status, Json(ErrorResponse { error: e })).into_response();
     }
 
-    println!(
+    log::info!(
         "[Web Server] ✓ 分块 {}/{} 已保存，大小: {} 字节",
         chunk_index + 1,
         chunk_total,
-        chunk_data.len()
+        written
     );
 
-    // 只在第一块时创建数据库记录
-    if chunk_index == 0 && !file_name.is_empty() {
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-
-        match crate::db::create_received_file_record(
-            &state.pool,
-            sender_id.clone(),
-            file_name.clone(),
-            path.to_str().unwrap_or("").to_string(),
-            timestamp,
-        )
-        .await
+    // 重传校验失败的文件、或发送方下线后续传时恢复为下载中
+    if (offset == 0 || transfer.status == "interrupted") && transfer.status != "downloading" {
+        if let Err(e) =
+            crate::db::update_transfer_status(&state.pool, &transfer_id, "downloading").await
         {
-            Ok(msg_id) => {
-                println!(
-                    "[Web Server] ✓ 第一块数据：文件消息已创建，ID: {}, 状态: downloading",
-                    msg_id
+            log::error!("[Web Server] ✗ 更新文件状态失败: {}", e);
+        }
+    }
+
+    // 已接收的数据大小 >= 文件总大小，说明是最后一块
+    let received_size = tokio::fs::metadata(&path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+
+    state.publish(
+        "transfer-progress",
+        serde_json::json!({
+            "transfer_id": transfer_id,
+            "peer_id": transfer.sender_id,
+            "file_name": file_name,
+            "file_size": file_size,
+            "received_bytes": received_size,
+        }),
+    );
+
+    if received_size >= file_size {
+        // 发送方提供了文件校验值时（文件提议或分块中），校验通过才算接收完成
+        if let Some(expected) = transfer.file_hash.as_ref().or(file_hash.as_ref()) {
+            let verify_path = path.clone();
+            let actual = tokio::task::spawn_blocking(move || crate::utils::sha256_file(&verify_path))
+                .await
+                .ok()
+                .and_then(|r| r.ok());
+
+            if received_size != file_size || actual.as_ref() != Some(expected) {
+                log::error!(
+                    "[Web Server] ✗ 文件校验失败: {} (期望 {}, 实际 {:?}, 大小 {}/{})",
+                    file_name, expected, actual, received_size, file_size
                 );
+                if let Err(e) =
+                    crate::db::update_transfer_status(&state.pool, &transfer_id, "corrupted").await
+                {
+                    log::error!("[Web Server] ✗ 更新文件状态失败: {}", e);
+                }
+                state.publish_file_status(&transfer_id).await;
+
+                // 已收到的数据不可用，要求发送方从头重传
+                return (
+                    StatusCode::CONFLICT,
+                    Json(serde_json::json!({
+                        "error": "文件校验失败",
+                        "received_bytes": 0,
+                        "corrupted": true,
+                    })),
+                )
+                    .into_response();
             }
-            Err(e) => {
-                eprintln!("[Web Server] ✗ 第一块数据：创建文件消息失败: {}", e);
+            log::info!("[Web Server] ✓ 文件校验通过: {}", file_name);
+            if let Err(e) = crate::db::set_transfer_hash(&state.pool, &transfer_id, expected).await
+            {
+                log::error!("[Web Server] ✗ {}", e);
             }
         }
-    }
-
-    // 检查是否是最后一块：已接收的数据大小 + 当前块大小 >= 文件总大小
-    let received_size = if chunk_index == 0 {
-        chunk_data.len() as u64
-    } else {
-        // 对于后续块，我们需要从数据库查询已接收的大小
-        // 简单方案：检查文件大小是否匹配
-        let file_metadata = match tokio::fs::metadata(&path).await {
-            Ok(m) => m.len(),
-            Err(_) => 0,
-        };
-        file_metadata
-    };
 
-    // 如果已接收的数据大小 >= 文件总大小，说明是最后一块
-    if received_size >= file_size && !file_name.is_empty() {
-        match crate::db::update_file_status(&state.pool, &file_name, "accepted").await {
+        // 从暂存目录移动到保存目录
+        match crate::offers::finish(&state.pool, &transfer, &download_dir).await {
             Ok(_) => {
-                println!(
-                    "[Web Server] ✓ 最后一块数据：文件状态已更新为 accepted (已接收: {} 字节)",
+                log::info!(
+                    "[Web Server] ✓ 最后一块数据：文件已接收完成 (已接收: {} 字节)",
                     received_size
                 );
+                state.publish_file_status(&transfer_id).await;
             }
             Err(e) => {
-                eprintln!("[Web Server] ✗ 最后一块数据：更新文件状态失败: {}", e);
+                log::error!("[Web Server] ✗ 最后一块数据：保存文件失败: {}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ErrorResponse { error: e }),
+                )
+                    .into_response();
             }
         }
     }
 
-    println!("[Web Server] ========== 文件上传处理完成 ==========");
+    log::info!("[Web Server] ========== 文件上传处理完成 ==========");
     Json(serde_json::json!({
         "success": true,
+        "transfer_id": transfer_id,
         "file_name": file_name,
         "file_size": file_size,
         "chunk_index": chunk_index,
         "chunk_total": chunk_total,
+        "received_bytes": received_size,
     }))
     .into_response()
 }
 
-// 接受文件（手动接收模式）
-#[derive(Deserialize)]
-struct AcceptFileRequest {
-    save_path: Option<String>,
+// 查询某次传输已收到的字节数（发送方断点续传前调用）
+async fn upload_status_http(
+    State(state): State<Arc<AppState>>,
+    Path(transfer_id): Path<String>,
+) -> impl IntoResponse {
+    let transfer = crate::db::get_transfer(&state.pool, &transfer_id)
+        .await
+        .map(|t| t.filter(|t| t.sender_id != "me"));
+    match transfer {
+        Ok(Some(transfer)) => {
+            // 校验失败的文件不可用，发送方需要从头重传
+            let status = transfer.status;
+            let received_bytes = if status == "corrupted" {
+                0
+            } else {
+                tokio::fs::metadata(&transfer.file_path)
+                    .await
+                    .map(|m| m.len())
+                    .unwrap_or(0)
+            };
+            Json(serde_json::json!({
+                "transfer_id": transfer_id,
+                "received_bytes": received_bytes,
+                "status": status,
+            }))
+            .into_response()
+        }
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "没有这次传输的记录".to_string(),
+            }),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse { error: e }),
+        )
+            .into_response(),
+    }
 }
 
-async fn accept_file_http(
+// 对方发来的文件提议：按接收规则答复接受、等待确认或拒绝
+async fn offer_http(
     State(state): State<Arc<AppState>>,
-    Path(file_id): Path<String>,
-    Json(payload): Json<AcceptFileRequest>,
+    signer: Option<Extension<SignedPeer>>,
+    Json(offer): Json<crate::network::transfer::FileOffer>,
 ) -> impl IntoResponse {
-    println!("[Web Server] ========== 开始处理文件接收 ==========");
-    println!("[Web Server] 收到接受文件请求: file_id={}", file_id);
-    println!("[Web Server] save_path: {:?}", payload.save_path);
-
-    // 先列出所有文件消息，方便调试
-    println!("[Web Server] 查询所有文件消息...");
-    if let Ok(rows) = crate::db::get_all_file_messages(&state.pool, 10).await {
-        for (id, sender, content, path, status) in rows {
-            println!(
-                "[Web Server]   ID={}, sender={}, content={}, path={}, status={}",
-                id, sender, content, path, status
-            );
-        }
+    if !signed_by(&signer, &offer.sender_id) {
+        log::error!(
+            "[Web Server] ✗ 拒绝文件提议：发送者 {} 与签名的节点不一致",
+            offer.sender_id
+        );
+        return (
+            StatusCode::FORBIDDEN,
+            Json(ErrorResponse {
+                error: "发送者与节点签名不一致".to_string(),
+            }),
+        )
+            .into_response();
     }
 
-    // 从数据库查询文件信息 - 使用 file_path LIKE 模糊匹配
-    let query_pattern = format!("%{}%", file_id);
-    println!("[Web Server] 查询模式: {}", query_pattern);
+    // 核对发送方的身份公钥；没有端到端加密的提议只有允许明文连接、并且发送方不支持端到端加密时才接收
+    let check = match &offer.sender_key {
+        Some(key) => crate::e2e::trust_sender_key(&offer.sender_id, key, &state.events)
+            .await
+            .map(|_| ()),
+        None => crate::e2e::check_plaintext(&offer.sender_id, PLAINTEXT_FILES_REFUSED).await,
+    };
+    if let Err(e) = check {
+        log::error!("[Web Server] ✗ 拒绝文件提议 {}: {}", offer.file_name, e);
+        return (StatusCode::FORBIDDEN, Json(ErrorResponse { error: e })).into_response();
+    }
 
-    let row = match crate::db::get_pending_file_by_path(&state.pool, &query_pattern).await {
-        Ok(Some((path, name))) => {
-            println!("[Web Server] ✓ 找到匹配的 pending 文件记录");
-            (path, name)
-        }
-        Ok(None) => {
-            println!("[Web Server] ✗ 未找到匹配的 pending 文件");
-            println!("[Web Server] 可能原因:");
-            println!("[Web Server]   1. file_id 不匹配任何文件路径");
-            println!("[Web Server]   2. 文件状态不是 'pending'");
-            println!("[Web Server]   3. 文件已被接收或删除");
-            return (
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: format!("文件不存在或已接收 (file_id={})", file_id),
-                }),
-            )
-                .into_response();
+    let download_dir = get_download_dir(&state.pool).await;
+    match crate::offers::handle_offer(&state.pool, &download_dir, offer).await {
+        Ok(reply) => {
+            // 新的文件出现在聊天记录里（需要确认时前端显示接收/拒绝）
+            state.publish_file_status(&reply.transfer_id).await;
+            Json(reply).into_response()
         }
         Err(e) => {
-            println!("[Web Server] ✗ 数据库查询失败: {}", e);
-            return (
+            log::error!("[Web Server] ✗ 处理文件提议失败: {}", e);
+            (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("查询文件失败: {}", e),
-                }),
+                Json(ErrorResponse { error: e }),
             )
-                .into_response();
+                .into_response()
         }
-    };
-
-    let temp_path = row.0;
-    let file_name = row.1;
-
-    // 检查文件是否存在（可能还在上传中）
-    if !std::path::Path::new(&temp_path).exists() {
-        println!("[Web Server] ⏳ 文件还在下载中");
-        return (
-            StatusCode::ACCEPTED, // 202 表示请求已接受但还在处理中
-            Json(serde_json::json!({
-                "downloading": true,
-                "message": "文件正在下载中，请稍候..."
-            })),
-        )
-            .into_response();
     }
+}
 
-    // 确定最终保存路径
-    let final_path = if let Some(path) = payload.save_path {
-        std::path::PathBuf::from(path).join(&file_name)
-    } else {
-        let download_path = crate::db::get_download_path(&state.pool)
-            .await
-            .unwrap_or_else(|_| {
-                std::env::temp_dir()
-                    .join("lanchat_downloads")
-                    .to_str()
-                    .unwrap()
-                    .to_string()
-            });
-        println!("[Web Server] 使用默认下载路径: {}", download_path);
-        std::path::PathBuf::from(download_path).join(&file_name)
-    };
+// 接受文件（手动接收模式），可以指定保存目录
+#[derive(Deserialize, Default)]
+struct AcceptFileRequest {
+    save_path: Option<String>,
+}
 
-    println!("[Web Server] 最终路径: {:?}", final_path);
+async fn accept_file_http(
+    State(state): State<Arc<AppState>>,
+    Path(transfer_id): Path<String>,
+    payload: Option<Json<AcceptFileRequest>>,
+) -> impl IntoResponse {
+    let payload = payload.map(|Json(p)| p).unwrap_or_default();
+    log::info!(
+        "[Web Server] 接受文件: transfer_id={}, save_path={:?}",
+        transfer_id, payload.save_path
+    );
 
-    // 确保目标目录存在
-    if let Some(parent) = final_path.parent() {
-        if let Err(e) = std::fs::create_dir_all(parent) {
-            println!("[Web Server] ✗ 创建目录失败: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("创建目录失败: {}", e),
-                }),
-            )
-                .into_response();
+    match crate::offers::accept(&state.pool, &transfer_id, payload.save_path).await {
+        Ok(_) => {
+            state.publish_file_status(&transfer_id).await;
+            Json(serde_json::json!({ "success": true })).into_response()
+        }
+        Err(e) => {
+            log::error!("[Web Server] ✗ 接受文件失败: {}", e);
+            (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response()
         }
     }
+}
 
-    // 移动文件 - 使用复制+删除来支持跨文件系统
-    println!("[Web Server] 开始移动文件...");
-    if let Err(e) = std::fs::rename(&temp_path, &final_path) {
-        // rename 失败（可能是跨文件系统），尝试复制+删除
-        println!("[Web Server] rename 失败 ({}), 尝试复制+删除", e);
-
-        if let Err(e) = std::fs::copy(&temp_path, &final_path) {
-            println!("[Web Server] ✗ 复制文件失败: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("复制文件失败: {}", e),
-                }),
-            )
-                .into_response();
+// 拒绝文件（等待确认或正在接收的文件）
+async fn reject_file_http(
+    State(state): State<Arc<AppState>>,
+    Path(transfer_id): Path<String>,
+) -> impl IntoResponse {
+    match crate::offers::reject(&state.pool, &transfer_id).await {
+        Ok(_) => {
+            state.publish_file_status(&transfer_id).await;
+            Json(serde_json::json!({ "success": true })).into_response()
         }
-
-        // 复制成功后删除临时文件
-        if let Err(e) = std::fs::remove_file(&temp_path) {
-            println!("[Web Server] ⚠ 删除临时文件失败: {}", e);
-            // 不返回错误，因为文件已经复制成功了
+        Err(e) => {
+            log::error!("[Web Server] ✗ 拒绝文件失败: {}", e);
+            (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response()
         }
-
-        println!("[Web Server] ✓ 文件已复制到目标位置");
-    } else {
-        println!("[Web Server] ✓ 文件已移动到目标位置");
     }
+}
 
-    // 更新数据库状态
-    if let Err(e) = crate::db::update_file_status_by_path(
-        &state.pool,
-        &temp_path,
-        final_path.to_str().unwrap(),
-        "accepted",
-    )
-    .await
-    {
-        println!("[Web Server] ✗ 更新数据库失败: {}", e);
-        return (
+// 接收文件的规则
+async fn get_file_accept_policy_http(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match crate::offers::get_policy(&state.pool).await {
+        Ok(policy) => Json(policy).into_response(),
+        Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("更新数据库失败: {}", e),
-            }),
+            Json(ErrorResponse { error: e }),
         )
-            .into_response();
+            .into_response(),
     }
+}
 
-    println!("[Web Server] ✓ 文件已接受并保存到: {:?}", final_path);
-    Json(serde_json::json!({
-        "success": true,
-        "path": final_path.to_str().unwrap()
-    }))
-    .into_response()
+async fn update_file_accept_policy_http(
+    State(state): State<Arc<AppState>>,
+    Json(policy): Json<crate::models::FileAcceptPolicy>,
+) -> impl IntoResponse {
+    match crate::offers::update_policy(&state.pool, &policy).await {
+        Ok(_) => Json(serde_json::json!({ "success": true })).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e })).into_response(),
+    }
 }
 
-// 下载文件
+// 下载文件（按传输 ID）
 async fn download_file_http(
     State(state): State<Arc<AppState>>,
-    Path(file_id): Path<String>,
+    Path(transfer_id): Path<String>,
 ) -> impl IntoResponse {
-    println!("[Web Server] 下载文件请求: {}", file_id);
-    
-    // 首先尝试从数据库查询文件路径
-    // 通过 file_path 的文件名匹配
-    let file_path_result = sqlx::query_scalar::<_, String>(
-        "SELECT file_path FROM messages 
-         WHERE file_path IS NOT NULL 
-         AND (file_path LIKE ? OR content = ?)
-         ORDER BY timestamp DESC LIMIT 1"
-    )
-    .bind(format!("%/{}", file_id))  // 匹配路径末尾的文件名
-    .bind(&file_id)
-    .fetch_optional(&state.pool)
-    .await;
+    log::info!("[Web Server] 下载文件请求: {}", transfer_id);
 
-    let file_path = match file_path_result {
-        Ok(Some(path)) => {
-            println!("[Web Server] 从数据库找到文件路径: {}", path);
-            std::path::PathBuf::from(path)
+    let transfer = match crate::db::get_transfer(&state.pool, &transfer_id).await {
+        Ok(Some(transfer)) if !transfer.file_path.is_empty() => transfer,
+        Ok(_) => {
+            log::error!("[Web Server] 没有这次传输的文件: {}", transfer_id);
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("文件不存在"))
+                .unwrap();
         }
-        _ => {
-            println!("[Web Server] 数据库中未找到，尝试从下载目录查找");
-            // 没有找到记录，尝试从下载目录查找
-            let download_dir = get_download_dir(&state.pool).await;
-            download_dir.join(&file_id)
+        Err(e) => {
+            log::error!("[Web Server] ✗ 数据库查询失败: {}", e);
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e))
+                .unwrap();
         }
     };
+    // 旧版本收到的文件名没有清理过，放进响应头之前再清理一次
+    let file_name = crate::utils::sanitize_file_name(&transfer.file_name);
+    let file_path = std::path::PathBuf::from(transfer.file_path);
 
-    println!("[Web Server] 尝试读取文件: {}", file_path.display());
+    log::info!("[Web Server] 尝试读取文件: {}", file_path.display());
 
     match fs::read(&file_path).await {
         Ok(data) => {
             // 根据文件扩展名设置 Content-Type
-            let content_type = if file_id.ends_with(".jpg") || file_id.ends_with(".jpeg") {
+            let lower_name = file_name.to_lowercase();
+            let content_type = if lower_name.ends_with(".jpg") || lower_name.ends_with(".jpeg") {
                 "image/jpeg"
-            } else if file_id.ends_with(".png") {
+            } else if lower_name.ends_with(".png") {
                 "image/png"
-            } else if file_id.ends_with(".gif") {
+            } else if lower_name.ends_with(".gif") {
                 "image/gif"
-            } else if file_id.ends_with(".webp") {
+            } else if lower_name.ends_with(".webp") {
                 "image/webp"
             } else {
                 "application/octet-stream"
             };
 
-            println!("[Web Server] 文件读取成功，大小: {} bytes", data.len());
+            log::info!("[Web Server] 文件读取成功，大小: {} bytes", data.len());
 
             Response::builder()
                 .header(header::CONTENT_TYPE, content_type)
                 .header(
                     header::CONTENT_DISPOSITION,
-                    format!("inline; filename=\"{}\"", file_id),
+                    format!("inline; filename=\"{}\"", file_name),
                 )
                 .body(Body::from(data))
                 .unwrap()
         }
         Err(e) => {
-            eprintln!("[Web Server] 文件不存在: {} - {}", file_path.display(), e);
+            log::error!("[Web Server] 文件不存在: {} - {}", file_path.display(), e);
             Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(Body::from(format!("文件不存在: {} - {}", file_path.display(), e)))
@@ -904,112 +2197,117 @@ async fn get_download_dir(pool: &Pool<Sqlite>) -> std::path::PathBuf {
     }
 }
 
-// 创建上传记录（Web 端发送文件时）
-#[derive(Deserialize)]
-struct CreateUploadRecordRequest {
-    file_name: String,
-    timestamp: i64,
-    receiver_id: String, // 新增接收者ID
-}
-
-async fn create_upload_record_http(
+// 浏览器页面发送文件：页面把文件交给本机节点，由本机节点提议并上传（与桌面端、命令行相同）
+// multipart 字段：peer_id、peer_addr，最后是 file；文件先流式保存到下载目录下的暂存目录，发送结束后删除
+async fn send_file_http(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateUploadRecordRequest>,
+    mut multipart: Multipart,
 ) -> impl IntoResponse {
-    println!("[Web Server] 创建上传记录: {}", payload.file_name);
-
-    match crate::db::create_upload_record(
-        &state.pool,
-        payload.receiver_id.clone(),
-        payload.file_name.clone(),
-        payload.timestamp,
-    )
-    .await
-    {
-        Ok(_) => {
-            println!("[Web Server] ✓ 上传记录已创建");
-            Json(serde_json::json!({ "success": true })).into_response()
-        }
-        Err(e) => {
-            eprintln!("[Web Server] ✗ 创建上传记录失败: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("创建记录失败: {}", e),
-                }),
-            )
-                .into_response()
+    let mut peer_id = String::new();
+    let mut peer_addr = String::new();
+    let file_field = loop {
+        let Some(field) = multipart.next_field().await.ok().flatten() else {
+            break None;
+        };
+        match field.name().unwrap_or_default() {
+            "peer_id" => peer_id = field.text().await.unwrap_or_default(),
+            "peer_addr" => peer_addr = field.text().await.unwrap_or_default(),
+            "file" => break Some(field),
+            _ => {}
         }
-    }
-}
-
-// 更新上传状态
-#[derive(Deserialize)]
-struct UpdateUploadStatusRequest {
-    file_name: String,
-    status: String,
-}
-
-async fn update_upload_status_http(
-    State(state): State<Arc<AppState>>,
-    Json(payload): Json<UpdateUploadStatusRequest>,
-) -> impl IntoResponse {
-    println!(
-        "[Web Server] 更新上传状态: {} -> {}",
-        payload.file_name, payload.status
+    };
+    let Some(mut field) = file_field.filter(|_| !peer_id.is_empty() && !peer_addr.is_empty())
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: "缺少 peer_id、peer_addr 或文件".to_string(),
+            }),
+        )
+            .into_response();
+    };
+    let file_name = field
+        .file_name()
+        .map(String::from)
+        .unwrap_or_else(|| format!("file_{}", chrono::Utc::now().timestamp()));
+    log::info!(
+        "[Web Server] 页面发送文件: {} -> {} ({})",
+        file_name, peer_addr, peer_id
     );
 
-    match crate::db::update_upload_status(
-        &state.pool,
-        payload.file_name.clone(),
-        payload.status.clone(),
-    )
-    .await
-    {
-        Ok(_) => {
-            println!("[Web Server] ✓ 上传状态已更新");
-            Json(serde_json::json!({ "success": true })).into_response()
-        }
-        Err(e) => {
-            eprintln!("[Web Server] ✗ 更新上传状态失败: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("更新状态失败: {}", e),
-                }),
-            )
-                .into_response()
+    let outgoing_dir = get_download_dir(&state.pool).await.join(OUTGOING_DIR);
+    let path = outgoing_dir.join(uuid::Uuid::new_v4().to_string());
+    let saved: Result<(), String> = async {
+        fs::create_dir_all(&outgoing_dir)
+            .await
+            .map_err(|e| format!("创建暂存目录失败: {}", e))?;
+        let file = fs::File::create(&path)
+            .await
+            .map_err(|e| format!("创建文件失败: {}", e))?;
+        let mut writer = tokio::io::BufWriter::with_capacity(UPLOAD_WRITE_BUFFER, file);
+        while let Some(data) = field
+            .chunk()
+            .await
+            .map_err(|e| format!("接收文件失败: {}", e))?
+        {
+            tokio::io::AsyncWriteExt::write_all(&mut writer, &data)
+                .await
+                .map_err(|e| format!("写入文件失败: {}", e))?;
         }
+        tokio::io::AsyncWriteExt::flush(&mut writer)
+            .await
+            .map_err(|e| format!("写入文件失败: {}", e))
     }
-}
-
-// 删除上传记录（上传失败时）
-#[derive(Deserialize)]
-struct DeleteUploadRecordRequest {
-    file_name: String,
-    timestamp: i64,
-}
+    .await;
 
-async fn delete_upload_record_http(
-    State(state): State<Arc<AppState>>,
-    Json(payload): Json<DeleteUploadRecordRequest>,
-) -> impl IntoResponse {
-    println!("[Web Server] 删除上传记录: {}", payload.file_name);
+    let result = match saved {
+        Ok(()) => match fs::File::open(&path).await {
+            Ok(file) => {
+                let events = state.events.clone();
+                crate::network::transfer::send_file(
+                    &state.pool,
+                    &peer_id,
+                    &peer_addr,
+                    &file_name,
+                    &path.to_string_lossy(),
+                    file,
+                    |progress| {
+                        events.emit(
+                            "upload_progress",
+                            serde_json::json!({
+                                "peer_id": peer_id,
+                                "file_name": file_name,
+                                "uploaded": progress.uploaded,
+                                "total": progress.total,
+                                "speed_mb_s": progress.speed_mb_s
+                            }),
+                        )
+                    },
+                )
+                .await
+            }
+            Err(e) => Err(format!("打开文件失败: {}", e)),
+        },
+        Err(e) => Err(e),
+    };
+    // 页面关闭后无法续传，暂存的副本不再保留
+    let _ = fs::remove_file(&path).await;
 
-    match crate::db::delete_upload_record(&state.pool, payload.file_name.clone(), payload.timestamp)
-        .await
-    {
-        Ok(_) => {
-            println!("[Web Server] ✓ 上传记录已删除");
-            Json(serde_json::json!({ "success": true })).into_response()
+    match result {
+        Ok(transfer_id) => {
+            log::info!("[Web Server] ✓ 文件已发送: {} ({})", file_name, transfer_id);
+            Json(serde_json::json!({
+                "success": true,
+                "transfer_id": transfer_id,
+                "file_name": file_name,
+            }))
+            .into_response()
         }
         Err(e) => {
-            eprintln!("[Web Server] ✗ 删除上传记录失败: {}", e);
+            log::error!("[Web Server] ✗ 发送文件失败: {}", e);
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("删除记录失败: {}", e),
-                }),
+                Json(ErrorResponse { error: e }),
             )
                 .into_response()
         }
@@ -1018,7 +2316,7 @@ async fn delete_upload_record_http(
 
 // 主题相关的 HTTP 处理函数
 async fn get_theme_list_http() -> impl IntoResponse {
-    println!("[Web Server] 收到获取主题列表请求");
+    log::info!("[Web Server] 收到获取主题列表请求");
 
     let mut themes = vec![serde_json::json!({
         "name": "default",
@@ -1032,20 +2330,16 @@ async fn get_theme_list_http() -> impl IntoResponse {
 
         if theme_dir.exists() {
             if let Ok(entries) = std::fs::read_dir(&theme_dir) {
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        let path = entry.path();
-                        if path.is_file()
-                            && path.extension().and_then(|s| s.to_str()) == Some("css")
-                        {
-                            if let Some(file_name) = path.file_stem().and_then(|s| s.to_str()) {
-                                themes.push(serde_json::json!({
-                                    "name": file_name,
-                                    "display_name": file_name,
-                                    "is_custom": true,
-                                    "path": path.to_string_lossy()
-                                }));
-                            }
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("css") {
+                        if let Some(file_name) = path.file_stem().and_then(|s| s.to_str()) {
+                            themes.push(serde_json::json!({
+                                "name": file_name,
+                                "display_name": file_name,
+                                "is_custom": true,
+                                "path": path.to_string_lossy()
+                            }));
                         }
                     }
                 }
@@ -1053,12 +2347,12 @@ async fn get_theme_list_http() -> impl IntoResponse {
         }
     }
 
-    println!("[Web Server] 找到 {} 个主题", themes.len());
+    log::info!("[Web Server] 找到 {} 个主题", themes.len());
     Json(themes).into_response()
 }
 
 async fn get_theme_css_http(Path(theme_name): Path<String>) -> impl IntoResponse {
-    println!("[Web Server] 收到获取主题CSS请求: {}", theme_name);
+    log::info!("[Web Server] 收到获取主题CSS请求: {}", theme_name);
 
     if theme_name == "default" {
         return Response::builder()
@@ -1077,7 +2371,7 @@ async fn get_theme_css_http(Path(theme_name): Path<String>) -> impl IntoResponse
         if theme_path.exists() {
             match std::fs::read_to_string(&theme_path) {
                 Ok(css_content) => {
-                    println!(
+                    log::info!(
                         "[Web Server] 成功读取主题文件: {} ({} 字节)",
                         theme_path.display(),
                         css_content.len()
@@ -1089,7 +2383,7 @@ async fn get_theme_css_http(Path(theme_name): Path<String>) -> impl IntoResponse
                         .into_response();
                 }
                 Err(e) => {
-                    eprintln!("[Web Server] 读取主题文件失败: {}", e);
+                    log::error!("[Web Server] 读取主题文件失败: {}", e);
                 }
             }
         }
@@ -1113,15 +2407,15 @@ async fn save_current_theme_http(
     State(state): State<Arc<AppState>>,
     Json(req): Json<SaveThemeRequest>,
 ) -> impl IntoResponse {
-    println!("[Web Server] 收到保存主题请求: {}", req.theme_name);
+    log::info!("[Web Server] 收到保存主题请求: {}", req.theme_name);
 
     match crate::db::save_current_theme(&state.pool, req.theme_name.clone()).await {
         Ok(_) => {
-            println!("[Web Server] 主题设置已保存: {}", req.theme_name);
+            log::info!("[Web Server] 主题设置已保存: {}", req.theme_name);
             Json(serde_json::json!({"success": true})).into_response()
         }
         Err(e) => {
-            eprintln!("[Web Server] 保存主题设置失败: {}", e);
+            log::error!("[Web Server] 保存主题设置失败: {}", e);
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(ErrorResponse {
@@ -1134,16 +2428,16 @@ async fn save_current_theme_http(
 }
 
 async fn get_current_theme_http(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    println!("[Web Server] 收到获取当前主题请求");
+    log::info!("[Web Server] 收到获取当前主题请求");
 
     match crate::db::get_current_theme(&state.pool).await {
         Ok(result) => {
             let theme = result.unwrap_or_else(|| "default".to_string());
-            println!("[Web Server] 当前主题: {}", theme);
+            log::info!("[Web Server] 当前主题: {}", theme);
             Json(serde_json::json!({"theme": theme})).into_response()
         }
         Err(e) => {
-            eprintln!("[Web Server] 查询主题设置失败: {}", e);
+            log::error!("[Web Server] 查询主题设置失败: {}", e);
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(ErrorResponse {
@@ -1154,3 +2448,689 @@ async fn get_current_theme_http(State(state): State<Arc<AppState>>) -> impl Into
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::transfer::FileOffer;
+    use sha2::Digest;
+
+    // 在本机端口上运行一个节点的接口；数据库和下载目录都是临时的
+    // 分块不做端到端加密，测试期间允许明文（peer-a 没有记录身份公钥）
+    struct TestNode {
+        url: String,
+        state: Arc<AppState>,
+        dir: std::path::PathBuf,
+        client: reqwest::Client,
+        _plaintext: crate::tls::PlaintextAllowed,
+    }
+
+    impl TestNode {
+        async fn start(routes: fn(Arc<AppState>) -> Router<Arc<AppState>>) -> Self {
+            let plaintext = crate::tls::PlaintextAllowed::acquire().await;
+            crate::e2e::test_store().await;
+            let dir = std::env::temp_dir().join(format!("lanchat-test-{}", uuid::Uuid::new_v4()));
+            let pool = crate::db::test_pool(&dir).await;
+            let bus = Arc::new(BroadcastSink::new());
+            let state = Arc::new(AppState {
+                pool,
+                peer_manager: Arc::new(PeerManager::new()),
+                events: bus.clone(),
+                bus,
+                shutdown: CancellationToken::new(),
+            });
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let app = routes(state.clone()).with_state(state.clone());
+            let shutdown = state.shutdown.clone().cancelled_owned();
+            tokio::spawn(async move {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            });
+
+            Self {
+                url,
+                state,
+                dir,
+                client: reqwest::Client::new(),
+                _plaintext: plaintext,
+            }
+        }
+
+        // peer-a 发来的文件提议，返回接收方的决定
+        async fn offer(
+            &self,
+            transfer_id: &str,
+            file_size: u64,
+            file_hash: Option<String>,
+        ) -> String {
+            let offer = FileOffer {
+                transfer_id: transfer_id.to_string(),
+                sender_id: "peer-a".to_string(),
+                file_name: "data.bin".to_string(),
+                file_size,
+                file_hash,
+                mime_type: None,
+                sender_key: None,
+            };
+            crate::offers::handle_offer(&self.state.pool, &self.dir, offer)
+                .await
+                .unwrap()
+                .decision
+        }
+
+        // 和发送方一样上传一个分块：chunk 是最后一个字段
+        async fn upload(
+            &self,
+            fields: &[(&'static str, String)],
+            chunk: &[u8],
+        ) -> (u16, serde_json::Value) {
+            let mut form = reqwest::multipart::Form::new();
+            for (name, value) in fields {
+                form = form.text(*name, value.clone());
+            }
+            let form = form.part(
+                "chunk",
+                reqwest::multipart::Part::bytes(chunk.to_vec()).file_name("chunk"),
+            );
+            let response = self
+                .client
+                .post(format!("{}/api/upload", self.url))
+                .multipart(form)
+                .send()
+                .await
+                .unwrap();
+            (response.status().as_u16(), response.json().await.unwrap())
+        }
+
+        async fn status(&self, transfer_id: &str) -> serde_json::Value {
+            self.client
+                .get(format!("{}/api/upload/{}", self.url, transfer_id))
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap()
+        }
+
+        async fn transfer(&self, transfer_id: &str) -> crate::models::Transfer {
+            crate::db::get_transfer(&self.state.pool, transfer_id)
+                .await
+                .unwrap()
+                .unwrap()
+        }
+    }
+
+    impl Drop for TestNode {
+        fn drop(&mut self) {
+            self.state.shutdown.cancel();
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("{:x}", sha2::Sha256::digest(data))
+    }
+
+    fn file_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    // peer-a 发送的一个分块的字段
+    fn chunk_fields(
+        transfer_id: &str,
+        index: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Vec<(&'static str, String)> {
+        vec![
+            ("transfer_id", transfer_id.to_string()),
+            ("peer_id", "peer-a".to_string()),
+            ("file_name", "data.bin".to_string()),
+            ("offset", offset.to_string()),
+            ("chunk_index", index.to_string()),
+            ("chunk_hash", sha256(data)),
+        ]
+    }
+
+    fn set_field(fields: &mut [(&'static str, String)], name: &str, value: &str) {
+        for field in fields.iter_mut().filter(|(n, _)| *n == name) {
+            field.1 = value.to_string();
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupted_chunk_is_dropped_and_sent_again() {
+        let node = TestNode::start(peer_routes).await;
+        let data = file_data(1000);
+        node.offer("t-chunks", 1000, Some(sha256(&data))).await;
+        let (first, second) = data.split_at(600);
+
+        let mut fields = chunk_fields("t-chunks", 0, 0, first);
+        set_field(&mut fields, "chunk_hash", &sha256(b"other"));
+        assert_eq!(node.upload(&fields, first).await.0, 422);
+        assert_eq!(node.status("t-chunks").await["received_bytes"], 0);
+
+        // 重传这一块后继续
+        let (status, _) = node
+            .upload(&chunk_fields("t-chunks", 0, 0, first), first)
+            .await;
+        assert_eq!(status, 200);
+        let (status, body) = node
+            .upload(&chunk_fields("t-chunks", 1, 600, second), second)
+            .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["received_bytes"], 1000);
+
+        let transfer = node.transfer("t-chunks").await;
+        assert_eq!(transfer.status, "accepted");
+        assert_eq!(
+            std::path::PathBuf::from(&transfer.file_path),
+            node.dir.join("data.bin")
+        );
+        assert_eq!(std::fs::read(&transfer.file_path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn file_not_matching_the_offered_hash_is_corrupted() {
+        let node = TestNode::start(peer_routes).await;
+        let data = file_data(1000);
+        node.offer("t-hash", 1000, Some(sha256(&data))).await;
+
+        // 分块本身完好（校验值是按发出的数据算的），但整个文件和提议中的校验值不一致
+        let mut damaged = data.clone();
+        damaged[500] ^= 0xff;
+        let (status, body) = node
+            .upload(&chunk_fields("t-hash", 0, 0, &damaged), &damaged)
+            .await;
+        assert_eq!(status, 409);
+        assert_eq!(body["corrupted"], true);
+        assert_eq!(body["received_bytes"], 0);
+
+        assert_eq!(node.transfer("t-hash").await.status, "corrupted");
+        assert!(!node.dir.join("data.bin").exists());
+        let status = node.status("t-hash").await;
+        assert_eq!(status["status"], "corrupted");
+        assert_eq!(status["received_bytes"], 0);
+
+        // 发送方从头重传后接收完成
+        let (status, _) = node
+            .upload(&chunk_fields("t-hash", 0, 0, &data), &data)
+            .await;
+        assert_eq!(status, 200);
+        let transfer = node.transfer("t-hash").await;
+        assert_eq!(transfer.status, "accepted");
+        assert_eq!(std::fs::read(&transfer.file_path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn chunk_larger_than_the_write_buffer_is_streamed_to_disk() {
+        let node = TestNode::start(peer_routes).await;
+        let data = file_data(UPLOAD_WRITE_BUFFER * 4 + 123);
+        node.offer("t-large", data.len() as u64, Some(sha256(&data)))
+            .await;
+
+        let (status, body) = node
+            .upload(&chunk_fields("t-large", 0, 0, &data), &data)
+            .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["received_bytes"], data.len());
+        let transfer = node.transfer("t-large").await;
+        assert_eq!(transfer.status, "accepted");
+        assert_eq!(std::fs::read(&transfer.file_path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn chunk_past_the_offered_size_is_refused() {
+        let node = TestNode::start(peer_routes).await;
+        let data = file_data(1000);
+        node.offer("t-size", 1000, None).await;
+
+        let first = &data[..600];
+        let (status, _) = node
+            .upload(&chunk_fields("t-size", 0, 0, first), first)
+            .await;
+        assert_eq!(status, 200);
+        let too_long = file_data(500);
+        let (status, _) = node
+            .upload(&chunk_fields("t-size", 1, 600, &too_long), &too_long)
+            .await;
+        assert_eq!(status, 413);
+
+        // 超出的分块不留下任何数据
+        assert_eq!(node.status("t-size").await["received_bytes"], 600);
+        assert_eq!(node.transfer("t-size").await.status, "downloading");
+    }
+
+    #[tokio::test]
+    async fn interrupted_chunk_resumes_from_the_server_offset() {
+        let node = TestNode::start(peer_routes).await;
+        let data = file_data(1_000_000);
+        node.offer("t-resume", 1_000_000, Some(sha256(&data))).await;
+        let (first, second) = data.split_at(400_000);
+        let (status, _) = node
+            .upload(&chunk_fields("t-resume", 0, 0, first), first)
+            .await;
+        assert_eq!(status, 200);
+
+        // 第二块发出一部分后连接中断：等服务端写入了一部分数据再断开
+        let staging = node.transfer("t-resume").await.file_path;
+        let sent = second[..300_000].to_vec();
+        let body = futures_util::stream::once(async move { Ok::<_, std::io::Error>(sent) }).chain(
+            futures_util::stream::once(async move {
+                while std::fs::metadata(&staging).map(|m| m.len()).unwrap_or(0) <= 400_000 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Err(std::io::Error::other("连接中断"))
+            }),
+        );
+        let mut form = reqwest::multipart::Form::new();
+        for (name, value) in chunk_fields("t-resume", 1, 400_000, second) {
+            form = form.text(name, value);
+        }
+        let form = form.part(
+            "chunk",
+            reqwest::multipart::Part::stream(reqwest::Body::wrap_stream(body)),
+        );
+        let interrupted = node
+            .client
+            .post(format!("{}/api/upload", node.url))
+            .multipart(form)
+            .send();
+        assert!(tokio::time::timeout(Duration::from_secs(10), interrupted)
+            .await
+            .unwrap()
+            .is_err());
+
+        // 服务端丢弃不完整的分块，已收到的部分保持完整
+        let mut received = serde_json::Value::Null;
+        for _ in 0..100 {
+            received = node.status("t-resume").await["received_bytes"].clone();
+            if received == 400_000 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(received, 400_000);
+
+        // 偏移不对时告诉发送方从哪里继续
+        let (status, body) = node
+            .upload(
+                &chunk_fields("t-resume", 1, 500_000, &second[100_000..]),
+                &second[100_000..],
+            )
+            .await;
+        assert_eq!(status, 409);
+        assert_eq!(body["received_bytes"], 400_000);
+
+        let offset = body["received_bytes"].as_u64().unwrap();
+        let rest = &data[offset as usize..];
+        let (status, _) = node
+            .upload(&chunk_fields("t-resume", 1, offset, rest), rest)
+            .await;
+        assert_eq!(status, 200);
+        let transfer = node.transfer("t-resume").await;
+        assert_eq!(transfer.status, "accepted");
+        assert_eq!(std::fs::read(&transfer.file_path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn only_the_offering_peer_can_write_a_transfer() {
+        let node = TestNode::start(peer_routes).await;
+        let data = file_data(1000);
+        node.offer("t-sender", 1000, Some(sha256(&data))).await;
+
+        let mut fields = chunk_fields("t-sender", 0, 0, &data);
+        set_field(&mut fields, "peer_id", "peer-b");
+        assert_eq!(node.upload(&fields, &data).await.0, 403);
+        assert_eq!(node.status("t-sender").await["received_bytes"], 0);
+
+        let (status, _) = node
+            .upload(&chunk_fields("t-sender", 0, 0, &data), &data)
+            .await;
+        assert_eq!(status, 200);
+        assert_eq!(node.transfer("t-sender").await.status, "accepted");
+    }
+
+    #[tokio::test]
+    async fn transfers_with_the_same_file_name_do_not_collide() {
+        let node = TestNode::start(peer_routes).await;
+        let one = file_data(1000);
+        let two: Vec<u8> = one.iter().rev().copied().collect();
+        node.offer("t-one", 1000, Some(sha256(&one))).await;
+        node.offer("t-two", 1000, Some(sha256(&two))).await;
+
+        // 两次传输的分块交替到达
+        for (index, offset) in [(0, 0), (1, 500)] {
+            for (id, data) in [("t-one", &one), ("t-two", &two)] {
+                let chunk = &data[offset..offset + 500];
+                let (status, _) = node
+                    .upload(&chunk_fields(id, index, offset as u64, chunk), chunk)
+                    .await;
+                assert_eq!(status, 200);
+            }
+        }
+
+        let first = node.transfer("t-one").await;
+        let second = node.transfer("t-two").await;
+        assert_eq!(
+            (first.status.as_str(), second.status.as_str()),
+            ("accepted", "accepted")
+        );
+        assert_ne!(first.file_path, second.file_path);
+        assert_eq!(std::fs::read(&first.file_path).unwrap(), one);
+        assert_eq!(std::fs::read(&second.file_path).unwrap(), two);
+    }
+
+    #[tokio::test]
+    async fn offers_over_the_size_limit_wait_for_confirmation() {
+        let node = TestNode::start(peer_routes).await;
+        let policy = crate::models::FileAcceptPolicy {
+            max_auto_accept_size: 1000,
+            ..Default::default()
+        };
+        crate::offers::update_policy(&node.state.pool, &policy)
+            .await
+            .unwrap();
+
+        for (id, size, decision) in [("t-small", 1000, "accepted"), ("t-big", 1001, "pending")] {
+            let reply: serde_json::Value = node
+                .client
+                .post(format!("{}/api/offer", node.url))
+                .json(&serde_json::json!({
+                    "transfer_id": id,
+                    "sender_id": "peer-a",
+                    "file_name": "data.bin",
+                    "file_size": size,
+                }))
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            assert_eq!(reply["decision"], decision);
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_and_rejected_transfers_take_no_data() {
+        let node = TestNode::start(peer_routes).await;
+        sqlx::query(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES ('file_accept_default', 'ask')",
+        )
+        .execute(&node.state.pool)
+        .await
+        .unwrap();
+        let data = file_data(1000);
+        assert_eq!(node.offer("t-ask", 1000, None).await, "pending");
+        assert_eq!(node.offer("t-no", 1000, None).await, "pending");
+
+        assert_eq!(
+            node.upload(&chunk_fields("t-ask", 0, 0, &data), &data)
+                .await
+                .0,
+            403
+        );
+        crate::offers::accept(&node.state.pool, "t-ask", None)
+            .await
+            .unwrap();
+        let (status, _) = node
+            .upload(&chunk_fields("t-ask", 0, 0, &data), &data)
+            .await;
+        assert_eq!(status, 200);
+        assert_eq!(node.transfer("t-ask").await.status, "accepted");
+
+        crate::offers::reject(&node.state.pool, "t-no")
+            .await
+            .unwrap();
+        assert_eq!(
+            node.upload(&chunk_fields("t-no", 0, 0, &data), &data)
+                .await
+                .0,
+            403
+        );
+        assert_eq!(node.status("t-no").await["received_bytes"], 0);
+    }
+
+    #[tokio::test]
+    async fn declared_size_must_match_the_offer() {
+        let node = TestNode::start(peer_routes).await;
+        let data = file_data(1000);
+        node.offer("t-declared", 1000, None).await;
+
+        // 提议时声明的大小决定了是否自动接收，分块不能改变它
+        let mut fields = chunk_fields("t-declared", 0, 0, &data);
+        fields.push(("file_size", "5000".to_string()));
+        assert_eq!(node.upload(&fields, &data).await.0, 400);
+        assert_eq!(node.status("t-declared").await["received_bytes"], 0);
+
+        set_field(&mut fields, "file_size", "1000");
+        assert_eq!(node.upload(&fields, &data).await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn browser_receives_events_until_shutdown() {
+        let node = TestNode::start(|_| control_routes()).await;
+        let response = node
+            .client
+            .get(format!("{}/api/events", node.url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let mut stream = response.bytes_stream();
+
+        node.state.publish(
+            "new-message",
+            serde_json::json!({ "sender_id": "peer-a", "content": "你好" }),
+        );
+        let mut received = String::new();
+        while !received.contains("\n\n") {
+            let bytes = tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            received.push_str(&String::from_utf8_lossy(&bytes));
+        }
+        assert!(received.contains("event: new-message\n"));
+        assert!(received.contains("\"content\":\"你好\""));
+
+        // 退出时结束订阅
+        node.state.shutdown.cancel();
+        let end = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(_)) = stream.next().await {}
+        });
+        assert!(end.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn uploads_publish_progress_and_file_status() {
+        let node = TestNode::start(peer_routes).await;
+        let mut events = node.state.bus.subscribe();
+        let data = file_data(1000);
+        node.offer("t-events", 1000, Some(sha256(&data))).await;
+
+        for (index, offset) in [(0, 0), (1, 500)] {
+            let chunk = &data[offset..offset + 500];
+            let (status, _) = node
+                .upload(
+                    &chunk_fields("t-events", index, offset as u64, chunk),
+                    chunk,
+                )
+                .await;
+            assert_eq!(status, 200);
+        }
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push((event.name, event.payload));
+        }
+        let progress: Vec<_> = received
+            .iter()
+            .filter(|(name, _)| *name == "transfer-progress")
+            .map(|(_, payload)| payload["received_bytes"].as_u64().unwrap())
+            .collect();
+        assert_eq!(progress, vec![500, 1000]);
+        let (name, status) = received.last().unwrap();
+        assert_eq!(*name, "file-status");
+        assert_eq!(status["transfer_id"], "t-events");
+        assert_eq!(status["status"], "accepted");
+    }
+
+    #[tokio::test]
+    async fn peer_port_does_not_serve_the_control_api() {
+        let node = TestNode::start(peer_routes).await;
+        for path in [
+            "/api/get_settings",
+            "/api/get_my_name",
+            "/api/chat_history/peer-a",
+            "/api/conversations",
+            "/api/events",
+            "/api/export/peer-a",
+            "/",
+        ] {
+            let response = node
+                .client
+                .get(format!("{}{}", node.url, path))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 404, "{}", path);
+        }
+        let response = node
+            .client
+            .post(format!("{}/api/update_my_name", node.url))
+            .json(&serde_json::json!({ "name": "Mallory" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(
+            crate::db::get_username(&node.state.pool).await.unwrap(),
+            "Me"
+        );
+    }
+
+    #[tokio::test]
+    async fn control_port_does_not_accept_peer_requests() {
+        let node = TestNode::start(|_| control_routes()).await;
+        let mut form = reqwest::multipart::Form::new();
+        for (name, value) in chunk_fields("t-control", 0, 0, b"data") {
+            form = form.text(name, value);
+        }
+        let requests = [
+            node.client
+                .post(format!("{}/api/upload", node.url))
+                .multipart(form.part("chunk", reqwest::multipart::Part::bytes(b"data".to_vec()))),
+            node.client
+                .post(format!("{}/api/offer", node.url))
+                .json(&serde_json::json!({
+                    "transfer_id": "t-control",
+                    "sender_id": "peer-a",
+                    "file_name": "data.bin",
+                    "file_size": 4,
+                })),
+            node.client.get(format!("{}/api/identity", node.url)),
+            node.client
+                .get(format!("{}/api/upload/t-control", node.url)),
+        ];
+        for request in requests {
+            let response = request.send().await.unwrap();
+            assert!(response.status().is_client_error(), "{}", response.url());
+        }
+        assert!(crate::db::get_transfer(&node.state.pool, "t-control")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn control_api_listens_on_localhost_by_default() {
+        let dir = std::env::temp_dir().join(format!("lanchat-test-{}", uuid::Uuid::new_v4()));
+        let pool = crate::db::test_pool(&dir).await;
+        assert_eq!(
+            resolve_control_addr(&pool, 8888).await,
+            SocketAddr::from(([127, 0, 0, 1], 8889))
+        );
+
+        for (value, expected) in [
+            ("0.0.0.0:9000", "0.0.0.0:9000"),
+            ("not an address", "127.0.0.1:8889"),
+        ] {
+            sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES ('control_addr', ?)")
+                .bind(value)
+                .execute(&pool)
+                .await
+                .unwrap();
+            assert_eq!(
+                resolve_control_addr(&pool, 8888).await,
+                expected.parse::<SocketAddr>().unwrap()
+            );
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn peers_with_a_stored_key_cannot_send_plaintext() {
+        let node = TestNode::start(peer_routes).await;
+        crate::e2e::trust_sender_key("peer-e2e", &crate::e2e::public_key(), &node.state.events)
+            .await
+            .unwrap();
+
+        let response = node
+            .client
+            .post(format!("{}/api/offer", node.url))
+            .json(&serde_json::json!({
+                "transfer_id": "t-e2e",
+                "sender_id": "peer-e2e",
+                "file_name": "data.bin",
+                "file_size": 4,
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+
+        // 即使传输记录已经存在，没有加密的分块也不写入
+        let offer = FileOffer {
+            transfer_id: "t-e2e".to_string(),
+            sender_id: "peer-e2e".to_string(),
+            file_name: "data.bin".to_string(),
+            file_size: 4,
+            file_hash: None,
+            mime_type: None,
+            sender_key: None,
+        };
+        crate::offers::handle_offer(&node.state.pool, &node.dir, offer)
+            .await
+            .unwrap();
+        let mut fields = chunk_fields("t-e2e", 0, 0, b"data");
+        set_field(&mut fields, "peer_id", "peer-e2e");
+        assert_eq!(node.upload(&fields, b"data").await.0, 403);
+        assert_eq!(node.status("t-e2e").await["received_bytes"], 0);
+    }
+
+    #[tokio::test]
+    async fn start_server_reports_a_busy_port() {
+        let busy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = busy.local_addr().unwrap();
+        let pool = crate::db::test_pool(&std::env::temp_dir()).await;
+        let bus = Arc::new(BroadcastSink::new());
+        let result = start_server(
+            addr,
+            addr,
+            pool,
+            Arc::new(PeerManager::new()),
+            bus.clone(),
+            bus,
+            CancellationToken::new(),
+        )
+        .await;
+        assert!(result.unwrap_err().contains(&addr.to_string()));
+    }
+}
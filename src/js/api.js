@@ -177,11 +177,36 @@ async function apiListen(eventName, callback) {
 		console.log("[JS-API] ✓ 事件监听器注册成功:", eventName);
 		return unlisten;
 	} else {
-		console.warn(`[JS-API] ✗ 当前环境不支持监听事件: ${eventName}`);
-		return () => { }; // 返回空函数
+		// Web 端：通过服务器推送事件（SSE）监听，事件名与桌面端相同
+		const source = getServerEvents();
+		const handler = (e) => callback({ payload: JSON.parse(e.data) });
+		source.addEventListener(eventName, handler);
+		return () => source.removeEventListener(eventName, handler);
 	}
 }
 
+// Web 端共用一个 SSE 连接（断开后浏览器会自动重连）
+function getServerEvents() {
+	if (!window.serverEvents) {
+		const source = new EventSource('/api/events');
+		source.onopen = () => {
+			console.log("[JS-API] ✓ 已连接服务器推送");
+			window.serverEventsConnected = true;
+		};
+		source.onerror = () => {
+			console.warn("[JS-API] 服务器推送断开，暂时改用轮询");
+			window.serverEventsConnected = false;
+		};
+		window.serverEvents = source;
+	}
+	return window.serverEvents;
+}
+
+// Web 端是否在接收服务器推送（断开时仍然需要轮询）
+function apiServerEventsConnected() {
+	return !getTauri() && window.serverEventsConnected === true;
+}
+
 // 获取在线用户列表（仅 Web 端使用）
 async function apiGetPeers() {
 	const tauri = getTauri();
@@ -314,51 +339,686 @@ async function apiGetChatHistory(peerId, limit = 10, offset = 0) {
 	}
 }
 
+// 对消息添加/取消表情回应
+async function apiReactToMessage(peerId, peerAddr, messageId, emoji, remove = false) {
+	const tauri = getTauri();
 
-// 发送文件
-// 获取设备可用内存（估算）
-function getAvailableMemory() {
-	if (navigator.deviceMemory) {
-		// 使用 Device Memory API（如果可用）
-		return navigator.deviceMemory * 1024 * 1024 * 1024; // 转换为字节
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('react_to_message', {
+				peerId,
+				peerAddr,
+				messageId,
+				emoji,
+				remove
+			});
+		} catch (e) {
+			console.error("[JS-API] 表情回应失败:", e);
+			throw new Error("表情回应失败: " + e);
+		}
+	} else {
+		// Web 端
+		try {
+			const resp = await fetch('/api/react', {
+				method: 'POST',
+				headers: { 'Content-Type': 'application/json' },
+				body: JSON.stringify({
+					peer_id: peerId,
+					peer_addr: peerAddr,
+					message_id: messageId,
+					emoji,
+					remove
+				})
+			});
+			const data = await resp.json();
+			if (data.error) {
+				throw new Error(data.error);
+			}
+			return data;
+		} catch (e) {
+			console.error("[JS-API] 表情回应失败:", e);
+			throw new Error("表情回应失败: " + e.message);
+		}
+	}
+}
+
+
+// 搜索聊天记录（options: peerId, since, until, msgType, limit, offset）
+async function apiSearchMessages(q, options = {}) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			const query = {
+				q,
+				peer_id: options.peerId,
+				since: options.since,
+				until: options.until,
+				msg_type: options.msgType,
+				limit: options.limit,
+				offset: options.offset,
+			};
+			return await tauri.core.invoke('search_messages', { query });
+		} catch (e) {
+			console.error("[JS-API] 搜索失败:", e);
+			throw new Error("搜索失败: " + e);
+		}
+	} else {
+		// Web 端
+		const params = new URLSearchParams({ q });
+		if (options.peerId) params.set('peer_id', options.peerId);
+		if (options.since) params.set('since', options.since);
+		if (options.until) params.set('until', options.until);
+		if (options.msgType) params.set('msg_type', options.msgType);
+		if (options.limit) params.set('limit', options.limit);
+		if (options.offset) params.set('offset', options.offset);
+
+		const resp = await fetch(`/api/search?${params}`);
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("搜索失败: " + data.error);
+		}
+		return data.results || [];
+	}
+}
+
+// 获取会话列表（按最近消息排序，包含未读数）
+async function apiGetConversations() {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('get_conversations');
+		} catch (e) {
+			console.error("[JS-API] 获取会话列表失败:", e);
+			return [];
+		}
+	} else {
+		// Web 端
+		try {
+			const resp = await fetch('/api/conversations');
+			const data = await resp.json();
+			return data.conversations || [];
+		} catch (e) {
+			console.error("[JS-API] 获取会话列表失败:", e);
+			return [];
+		}
+	}
+}
+
+// 将会话标记为已读
+async function apiMarkConversationRead(peerId) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			await tauri.core.invoke('mark_conversation_read', { peerId });
+		} catch (e) {
+			console.error("[JS-API] 标记已读失败:", e);
+		}
+	} else {
+		// Web 端
+		try {
+			await fetch(`/api/conversations/${encodeURIComponent(peerId)}/read`, { method: 'POST' });
+		} catch (e) {
+			console.error("[JS-API] 标记已读失败:", e);
+		}
+	}
+}
+
+// 获取聊天记录保留策略
+async function apiGetRetentionPolicy() {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		return await tauri.core.invoke('get_retention_policy');
+	} else {
+		// Web 端
+		const resp = await fetch('/api/retention');
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error(data.error);
+		}
+		return data;
+	}
+}
+
+// 更新聊天记录保留策略（保存后立即执行一次清理）
+async function apiUpdateRetentionPolicy(policy) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('update_retention_policy', { policy });
+		} catch (e) {
+			throw new Error(e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch('/api/retention', {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify(policy)
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error(data.error);
+		}
+		return data;
+	}
+}
+
+// 获取接收文件的规则
+async function apiGetFileAcceptPolicy() {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		return await tauri.core.invoke('get_file_accept_policy');
+	} else {
+		// Web 端
+		const resp = await fetch('/api/file_accept_policy');
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error(data.error);
+		}
+		return data;
+	}
+}
+
+// 更新接收文件的规则
+async function apiUpdateFileAcceptPolicy(policy) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('update_file_accept_policy', { policy });
+		} catch (e) {
+			throw new Error(e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch('/api/file_accept_policy', {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify(policy)
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error(data.error);
+		}
+		return data;
+	}
+}
+
+// 接受对方发来的文件，savePath 为空时保存到下载目录
+async function apiAcceptFile(transferId, savePath) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('accept_file', { transferId, savePath: savePath || null });
+		} catch (e) {
+			throw new Error(e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch(`/api/accept_file/${encodeURIComponent(transferId)}`, {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify({ save_path: savePath || null })
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error(data.error);
+		}
+		return data;
+	}
+}
+
+// 拒绝对方发来的文件
+async function apiRejectFile(transferId) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('reject_file', { transferId });
+		} catch (e) {
+			throw new Error(e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch(`/api/reject_file/${encodeURIComponent(transferId)}`, { method: 'POST' });
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error(data.error);
+		}
+		return data;
+	}
+}
+
+// 清空与某个用户的会话
+async function apiClearConversation(peerId, deleteFiles = false) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('clear_conversation', { peerId, deleteFiles });
+		} catch (e) {
+			console.error("[JS-API] 清空会话失败:", e);
+			throw new Error("清空会话失败: " + e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch(`/api/conversations/${encodeURIComponent(peerId)}/clear`, {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify({ delete_files: deleteFiles })
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("清空会话失败: " + data.error);
+		}
+		return data;
+	}
+}
+
+// 聊天内容加密
+async function apiGetEncryptionStatus() {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		return await tauri.core.invoke('get_encryption_status');
+	} else {
+		// Web 端
+		const resp = await fetch('/api/encryption/status');
+		return await resp.json();
+	}
+}
+
+async function apiEnableEncryption(passphrase) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('enable_encryption', { passphrase });
+		} catch (e) {
+			console.error("[JS-API] 开启加密失败:", e);
+			throw new Error("开启加密失败: " + e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch('/api/encryption/enable', {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify({ passphrase })
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("开启加密失败: " + data.error);
+		}
+		return data;
+	}
+}
+
+async function apiUnlockDatabase(passphrase) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('unlock_database', { passphrase });
+		} catch (e) {
+			console.error("[JS-API] 解锁失败:", e);
+			throw new Error("解锁失败: " + e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch('/api/encryption/unlock', {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify({ passphrase })
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("解锁失败: " + data.error);
+		}
+		return data;
+	}
+}
+
+async function apiLockDatabase() {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('lock_database');
+		} catch (e) {
+			console.error("[JS-API] 锁定失败:", e);
+			throw new Error("锁定失败: " + e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch('/api/encryption/lock', {
+			method: 'POST',
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("锁定失败: " + data.error);
+		}
+		return data;
+	}
+}
+
+async function apiDisableEncryption(passphrase) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('disable_encryption', { passphrase });
+		} catch (e) {
+			console.error("[JS-API] 关闭加密失败:", e);
+			throw new Error("关闭加密失败: " + e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch('/api/encryption/disable', {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify({ passphrase })
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("关闭加密失败: " + data.error);
+		}
+		return data;
+	}
+}
+
+// 导出聊天记录（peerId 为空时导出全部；format: json / markdown / html）
+async function apiGetAuthStatus() {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		return await tauri.core.invoke('get_auth_status');
+	} else {
+		// Web 端
+		const resp = await fetch('/api/auth/status');
+		return await resp.json();
+	}
+}
+
+// 登录 Web 界面（仅 Web 端；登录后服务器设置会话 Cookie）
+async function apiLogin(password) {
+	const resp = await fetch('/api/auth/login', {
+		method: 'POST',
+		headers: { 'Content-Type': 'application/json' },
+		body: JSON.stringify({ password })
+	});
+	const data = await resp.json();
+	if (data.error) {
+		throw new Error("登录失败: " + data.error);
+	}
+	return data;
+}
+
+async function apiLogout() {
+	await fetch('/api/auth/logout', { method: 'POST' });
+}
+
+// 设置访问密码；newPassword 为空时取消密码
+async function apiSetWebPassword(currentPassword, newPassword) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('set_web_password', { currentPassword, newPassword });
+		} catch (e) {
+			console.error("[JS-API] 设置访问密码失败:", e);
+			throw new Error("设置访问密码失败: " + e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch('/api/auth/password', {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify({ current_password: currentPassword, new_password: newPassword })
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("设置访问密码失败: " + data.error);
+		}
+		return data;
+	}
+}
+
+// 设置局域网密钥；key 为空时取消节点认证
+async function apiSetPeerKey(key) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('set_peer_key', { key });
+		} catch (e) {
+			console.error("[JS-API] 设置局域网密钥失败:", e);
+			throw new Error("设置局域网密钥失败: " + e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch('/api/auth/peer_key', {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify({ key })
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("设置局域网密钥失败: " + data.error);
+		}
+		return data;
+	}
+}
+
+async function apiGetTlsStatus() {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		return await tauri.core.invoke('get_tls_status');
+	} else {
+		// Web 端
+		const resp = await fetch('/api/tls/status');
+		return await resp.json();
+	}
+}
+
+async function apiSetAllowPlaintext(allow) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('set_allow_plaintext', { allow });
+		} catch (e) {
+			console.error("[JS-API] 保存明文连接设置失败:", e);
+			throw new Error("保存明文连接设置失败: " + e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch('/api/tls/allow_plaintext', {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify({ allow })
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("保存明文连接设置失败: " + data.error);
+		}
+		return data;
 	}
-	// 默认估算：假设设备有 2GB 内存
-	return 2 * 1024 * 1024 * 1024;
 }
 
-// 根据设备内存和文件大小计算最优分块大小
-function calculateOptimalChunkSize(fileSize) {
-	const availableMemory = getAvailableMemory();
-	// 使用可用内存的 80%（大胆使用内存以获得更快的速度）
-	const maxChunkMemory = availableMemory * 0.8;
+// 删除某个节点记录的证书（对方重新安装后证书会变化）
+async function apiForgetPeerCertificate(peerId) {
+	const tauri = getTauri();
 
-	// 根据文件大小选择分块策略，基础大小调大
-	let baseChunkSize;
-	if (fileSize < 100 * 1024 * 1024) {
-		// < 100MB：100MB 分块
-		baseChunkSize = 100 * 1024 * 1024;
-	} else if (fileSize < 500 * 1024 * 1024) {
-		// 100-500MB：200MB 分块
-		baseChunkSize = 200 * 1024 * 1024;
-	} else if (fileSize < 1024 * 1024 * 1024) {
-		// 500MB-1GB：300MB 分块
-		baseChunkSize = 300 * 1024 * 1024;
-	} else if (fileSize < 5 * 1024 * 1024 * 1024) {
-		// 1-5GB：400MB 分块
-		baseChunkSize = 400 * 1024 * 1024;
+	if (tauri) {
+		// 桌面端
+		return await tauri.core.invoke('forget_peer_certificate', { peerId });
 	} else {
-		// > 5GB：500MB 分块
-		baseChunkSize = 500 * 1024 * 1024;
+		// Web 端
+		const resp = await fetch(`/api/tls/forget/${encodeURIComponent(peerId)}`, { method: 'POST' });
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("重置证书记录失败: " + data.error);
+		}
+		return data;
 	}
+}
+
+// 和某个节点的安全码（端到端加密，当面核对身份）
+async function apiGetSafetyNumber(peerId) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		return await tauri.core.invoke('get_safety_number', { peerId });
+	} else {
+		// Web 端
+		const resp = await fetch(`/api/e2e/${encodeURIComponent(peerId)}`);
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("获取安全码失败: " + data.error);
+		}
+		return data;
+	}
+}
+
+async function apiSetPeerVerified(peerId, verified) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		return await tauri.core.invoke('set_peer_verified', { peerId, verified });
+	} else {
+		// Web 端
+		const resp = await fetch(`/api/e2e/${encodeURIComponent(peerId)}/verified`, {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: JSON.stringify({ verified })
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("保存验证状态失败: " + data.error);
+		}
+		return data;
+	}
+}
+
+// 信任对方变化后的身份密钥（对方重新安装过）
+async function apiAcceptPeerKeyChange(peerId) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端
+		return await tauri.core.invoke('accept_peer_key_change', { peerId });
+	} else {
+		// Web 端
+		const resp = await fetch(`/api/e2e/${encodeURIComponent(peerId)}/accept_key`, { method: 'POST' });
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("信任新的身份密钥失败: " + data.error);
+		}
+		return data;
+	}
+}
+
+async function apiExportHistory(peerId, format = 'json', bundleFiles = false) {
+	const tauri = getTauri();
+
+	if (tauri) {
+		// 桌面端：写入下载目录，返回文件路径
+		try {
+			return await tauri.core.invoke('export_history', {
+				peerId: peerId || null,
+				format,
+				bundleFiles
+			});
+		} catch (e) {
+			console.error("[JS-API] 导出聊天记录失败:", e);
+			throw new Error("导出聊天记录失败: " + e);
+		}
+	} else {
+		// Web 端：由浏览器直接下载
+		const params = new URLSearchParams({ format, bundle: bundleFiles });
+		const url = `/api/export/${encodeURIComponent(peerId || 'all')}?${params}`;
+		const a = document.createElement('a');
+		a.href = url;
+		a.download = '';
+		document.body.appendChild(a);
+		a.click();
+		a.remove();
+		return url;
+	}
+}
+
+// 导入聊天记录（桌面端传文件路径，Web 端传 File 对象）
+async function apiImportHistory(source) {
+	const tauri = getTauri();
 
-	// 根据可用内存调整分块大小（不超过可用内存的 80%）
-	const chunkSize = Math.min(baseChunkSize, Math.floor(maxChunkMemory));
+	if (tauri) {
+		// 桌面端
+		try {
+			return await tauri.core.invoke('import_history', { filePath: source });
+		} catch (e) {
+			console.error("[JS-API] 导入聊天记录失败:", e);
+			throw new Error("导入聊天记录失败: " + e);
+		}
+	} else {
+		// Web 端
+		const resp = await fetch('/api/import', {
+			method: 'POST',
+			headers: { 'Content-Type': 'application/json' },
+			body: source
+		});
+		const data = await resp.json();
+		if (data.error) {
+			throw new Error("导入聊天记录失败: " + data.error);
+		}
+		return data;
+	}
+}
 
-	console.log("[JS-API] 设备内存:", Math.round(availableMemory / (1024 * 1024 * 1024)), "GB");
-	console.log("[JS-API] 可用内存预算:", Math.round(maxChunkMemory / (1024 * 1024)), "MB");
-	console.log("[JS-API] 计算的分块大小:", Math.round(chunkSize / (1024 * 1024)), "MB");
+// 发送文件
+// 继续中断的上传（仅桌面端，Web 端页面关闭后无法再读取原文件）
+async function apiResumeFileTransfer(transferId) {
+	const tauri = getTauri();
 
-	return chunkSize;
+	if (!tauri) {
+		throw new Error("Web 端不支持续传，请重新发送文件");
+	}
+	try {
+		return await tauri.core.invoke('resume_file_transfer', { transferId });
+	} catch (e) {
+		console.error("[JS-API] 续传失败:", e);
+		throw new Error("续传失败: " + e);
+	}
 }
 
 async function apiSendFile(peerId, peerAddr, file, filePath) {
@@ -429,92 +1089,37 @@ async function apiSendFile(peerId, peerAddr, file, filePath) {
 			throw new Error("发送失败: " + e.message);
 		}
 	} else {
-		// Web 端 - 通过 HTTP 上传（使用分块协议）
-		try {
-			// 获取自己的 ID（发送者 ID）
-			const myId = await apiGetMyId();
-
-			const fileName = file.name;
-			const fileSize = file.size;
-
-			console.log("[JS-API] Web 端分块上传");
-			console.log("[JS-API] 文件信息:", fileName, fileSize, "字节");
-			console.log("[JS-API] sender_id (我的ID):", myId);
-
-			// 根据设备内存动态计算分块大小
-			const chunkSize = calculateOptimalChunkSize(fileSize);
-			const totalChunks = Math.ceil(fileSize / chunkSize);
-
-			console.log("[JS-API] 计算的分块大小:", Math.round(chunkSize / (1024 * 1024)), "MB, 总分块数:", totalChunks);
-
-			const uploadUrl = `http://${peerAddr}/api/upload`;
-			console.log("[JS-API] 上传地址:", uploadUrl);
-
-			let offset = 0;
-			let chunkIndex = 0;
-			const startTime = Date.now();
-			let lastLogTime = startTime;
-
-			while (offset < fileSize) {
-				const size = Math.min(chunkSize, fileSize - offset);
-				const chunk = file.slice(offset, offset + size);
-
-				// 构造 FormData 上传这一块
-				const formData = new FormData();
-				formData.append('peer_id', myId);
-				formData.append('file_name', fileName);
-				formData.append('file_size', fileSize.toString());
-				formData.append('chunk_index', chunkIndex.toString());
-				formData.append('chunk_total', totalChunks.toString());
-				formData.append('chunk', chunk, 'chunk');
-
-				console.log("[JS-API] 上传分块", chunkIndex + 1, "大小:", size, "字节");
-
-				const resp = await fetch(uploadUrl, {
-					method: 'POST',
-					body: formData,
-					mode: 'cors',
-				});
-
-				if (!resp.ok) {
-					const errorText = await resp.text();
-					console.error("[JS-API] ✗ 上传分块失败，状态码:", resp.status);
-					console.error("[JS-API] ✗ 错误响应:", errorText);
-					throw new Error(`HTTP ${resp.status}: ${errorText}`);
-				}
-
-				offset += size;
-				chunkIndex++;
-
-				// 每秒打印一次进度并更新 UI
-				const now = Date.now();
-				if (now - lastLogTime > 1000) {
-					const elapsed = (now - startTime) / 1000;
-					const speed = offset / (1024 * 1024) / elapsed;
-					console.log("[JS-API] 已上传:", Math.round(offset / 1024 / 1024), "MB, 速度:", Math.round(speed), "MB/s");
+		// Web 端 - 文件交给本机节点，由本机节点提议并加密上传（与桌面端相同）
+		const unlistenProgress = await apiListen('upload_progress', (event) => {
+			if (event.payload.peer_id !== peerId) return;
+			const speed = event.payload.speed_mb_s;
+			document.querySelectorAll('.file-uploading').forEach(div => {
+				div.textContent = Math.round(speed) + ' MB/s';
+			});
+		});
 
-					// 更新 UI 中的速度显示
-					const statusDivs = document.querySelectorAll('.file-uploading');
-					statusDivs.forEach(div => {
-						div.textContent = Math.round(speed) + ' MB/s';
-					});
+		try {
+			console.log("[JS-API] Web 端发送文件:", file.name, file.size, "字节");
+			const formData = new FormData();
+			formData.append('peer_id', peerId);
+			formData.append('peer_addr', peerAddr);
+			formData.append('file', file, file.name);
 
-					lastLogTime = now;
-				}
+			const resp = await fetch('/api/send_file', {
+				method: 'POST',
+				body: formData,
+			});
+			const result = await resp.json();
+			if (!resp.ok) {
+				throw new Error(result.error || `HTTP ${resp.status}`);
 			}
-
-			const totalTime = (Date.now() - startTime) / 1000;
-			const avgSpeed = (fileSize / (1024 * 1024)) / totalTime;
-			console.log("[JS-API] ✓ 文件上传完成，共", chunkIndex, "块，耗时:", totalTime.toFixed(2), "秒，平均速度:", avgSpeed.toFixed(2), "MB/s");
-
-			return {
-				success: true,
-				file_name: fileName,
-				file_size: fileSize,
-			};
+			console.log("[JS-API] ✓ 文件发送完成:", result.transfer_id);
+			return result;
 		} catch (e) {
-			console.error("[JS-API] 文件上传失败:", e);
-			throw new Error("上传失败: " + e.message);
+			console.error("[JS-API] 文件发送失败:", e);
+			throw new Error("发送失败: " + e.message);
+		} finally {
+			unlistenProgress();
 		}
 	}
 }
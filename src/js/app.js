@@ -1,6 +1,9 @@
 // src/js/app.js
 async function renderPage() {
     console.log("[JS-App] 页面初始化开始...");
+
+    // Web 端设置了访问密码时，先登录
+    await loginIfNeeded();
     
     const myName = await apiGetMyName();
     const nameElement = document.getElementById('my-name');
@@ -8,6 +11,16 @@ async function renderPage() {
         nameElement.innerText = myName;
     }
 
+    // 缓存自己的 ID（用于判断表情回应是否是自己发的）
+    try {
+        window.myId = await apiGetMyId();
+    } catch (e) {
+        console.error("[JS-App] 获取 ID 失败:", e);
+    }
+
+    // 开启了聊天内容加密时，先输入口令解锁
+    await unlockIfNeeded();
+
     // 初始化改名功能
     initNameEditor();
     
@@ -22,10 +35,10 @@ async function renderPage() {
 
     // 使用我们封装好的 apiListen
     await apiListen('new-peer', (event) => {
-        addUserToList(event.payload.id, event.payload.name, event.payload.addr, false);
+        addUserToList(event.payload.id, event.payload.name, event.payload.addr, !!event.payload.is_offline);
     });
 
-    // 监听新消息事件(桌面端)
+    // 监听新消息事件（桌面端为 Tauri 事件，Web 端为服务器推送）
     await apiListen('new-message', (event) => {
         console.log("[JS-App] ========== 收到 new-message 事件 ==========");
         console.log("[JS-App] 事件类型:", typeof event);
@@ -36,6 +49,39 @@ async function renderPage() {
         onReceiveMessage(event.payload);
     });
 
+    // 文件状态变化（收到文件提议、接受、拒绝、接收完成）时刷新当前聊天
+    await apiListen('file-status', (event) => {
+        const peerId = event.payload.peer_id;
+        if (window.currentChatPeer && window.currentChatPeer.id === peerId) {
+            loadChatHistory(peerId, true);
+        }
+    });
+
+    // 接收进度
+    await apiListen('transfer-progress', (event) => {
+        const { received_bytes, file_size } = event.payload;
+        if (!file_size) return;
+        const percent = Math.floor(received_bytes * 100 / file_size);
+        document.querySelectorAll('.file-downloading').forEach(div => {
+            div.textContent = percent + '%';
+        });
+    });
+
+    // 对方的身份密钥变化了（消息和文件已被拒收），醒目地提醒用户核对安全码
+    await apiListen('peer-key-changed', (event) => {
+        const peerId = event.payload.peer_id;
+        const li = document.querySelector(`#user-list li[data-id="${peerId}"]`);
+        const name = li ? li.dataset.name || peerId : peerId;
+        alert(`⚠ ${name} 的身份密钥变化了！可能有人在冒充对方，对方发来的消息和文件已被拒收。\n\n请打开和对方的聊天，点击 🔒 当面核对安全码。`);
+    });
+
+    // 推送跟不上时丢失了事件，重新加载当前聊天
+    await apiListen('resync', () => {
+        if (window.currentChatPeer) {
+            loadChatHistory(window.currentChatPeer.id, true);
+        }
+    });
+
     // 启动用户列表轮询（桌面端和 Web 端都需要）
     console.log("[JS-App] 启动用户列表轮询");
     startPeerPolling();
@@ -46,6 +92,53 @@ async function renderPage() {
     startMessagePolling();
 }
 
+// 数据库处于锁定状态时提示输入口令，直到解锁成功或用户取消
+async function loginIfNeeded() {
+    if (getTauri()) {
+        return;
+    }
+    try {
+        const status = await apiGetAuthStatus();
+        if (!status.password_enabled || status.authenticated) {
+            return;
+        }
+        while (true) {
+            const password = prompt('请输入访问密码:');
+            if (password === null) {
+                return;
+            }
+            try {
+                await apiLogin(password);
+                return;
+            } catch (e) {
+                alert(e.message);
+            }
+        }
+    } catch (e) {
+        console.error("[JS-App] 获取登录状态失败:", e);
+    }
+}
+
+async function unlockIfNeeded() {
+    try {
+        const status = await apiGetEncryptionStatus();
+        while (status.locked) {
+            const passphrase = prompt('聊天记录已加密，请输入口令解锁:');
+            if (passphrase === null) {
+                return;
+            }
+            try {
+                await apiUnlockDatabase(passphrase);
+                return;
+            } catch (e) {
+                alert(e.message);
+            }
+        }
+    } catch (e) {
+        console.error("[JS-App] 获取加密状态失败:", e);
+    }
+}
+
 // Web 端轮询用户列表
 async function startPeerPolling() {
     const pollInterval = 1000;
@@ -74,13 +167,30 @@ async function startPeerPolling() {
                 removeUserFromList(id);
             }
         }
+
+        // 根据会话列表的未读数显示红点（当前打开的聊天除外）
+        const conversations = await apiGetConversations();
+        for (const conv of conversations) {
+            const li = document.querySelector(`#user-list li[data-id="${conv.peer_id}"]`);
+            if (!li) continue;
+            const isCurrent = window.currentChatPeer && window.currentChatPeer.id === conv.peer_id;
+            li.classList.toggle('has-unread', conv.unread_count > 0 && !isCurrent);
+        }
     };
     
     // 立即执行一次
     await updatePeerList();
+
+    // 用户列表变化和新消息（未读红点）由服务器推送
+    await apiListen('peers-updated', updatePeerList);
+    await apiListen('new-message', updatePeerList);
     
-    // 定时轮询
-    setInterval(updatePeerList, pollInterval);
+    // 定时轮询（Web 端收到服务器推送时跳过）
+    setInterval(() => {
+        if (!apiServerEventsConnected()) {
+            updatePeerList();
+        }
+    }, pollInterval);
 }
 
 document.addEventListener('DOMContentLoaded', renderPage);
@@ -226,6 +336,11 @@ async function startMessagePolling() {
         if (!window.messagePollingEnabled || !window.currentChatPeer) {
             return;
         }
+
+        // Web 端收到服务器推送时，新消息通过 new-message 事件显示
+        if (apiServerEventsConnected()) {
+            return;
+        }
         
         try {
             const chatMessages = document.getElementById('chat-messages');
@@ -260,6 +375,9 @@ async function startMessagePolling() {
                     }
                 }
                 
+                // 正在查看的会话，新消息直接算已读
+                apiMarkConversationRead(window.currentChatPeer.id);
+
                 // 维护懒加载的总数量计数器
                 if (window.currentChatMessages) {
                     window.currentChatMessages.loadedCount += newMessages.length;
@@ -188,6 +188,11 @@ function initChat() {
 		closeChat();
 	});
 
+	// 查看安全码（端到端加密）
+	document.getElementById('safety-number-btn').addEventListener('click', () => {
+		showSafetyNumber();
+	});
+
 	// 发送消息
 	sendBtn.addEventListener('click', () => {
 		sendMessage();
@@ -264,6 +269,7 @@ function openChat(peer) {
 	if (userLi) {
 		userLi.classList.remove('has-unread');
 	}
+	apiMarkConversationRead(peer.id);
 
 	const chatWithName = document.getElementById('chat-with-name');
 	const chatMessages = document.getElementById('chat-messages');
@@ -349,10 +355,66 @@ async function sendMessage() {
 		console.log('[UI] 发送消息:', content);
 	} catch (e) {
 		console.error('[UI] 发送消息失败:', e);
+		// 对方的证书变化：确认对方重新安装过后，删除旧的证书记录再发送一次
+		if (e.message.includes('证书和之前记录的不一致')) {
+			if (confirm(e.message + '\n\n确认对方重新安装过 LANChat 并信任新的证书吗？')) {
+				await apiForgetPeerCertificate(window.currentChatPeer.id);
+				await sendMessage();
+			}
+			return;
+		}
+		// 对方的身份密钥变化：先核对安全码再决定是否信任
+		if (e.message.includes('身份密钥和之前记录的不一致')) {
+			alert('⚠ ' + e.message);
+			await showSafetyNumber();
+			return;
+		}
 		alert('发送失败: ' + e.message);
 	}
 }
 
+// 安全码每 4 组一行显示
+function formatSafetyNumber(number) {
+	const groups = number.split(' ');
+	const lines = [];
+	for (let i = 0; i < groups.length; i += 4) {
+		lines.push(groups.slice(i, i + 4).join(' '));
+	}
+	return lines.join('\n');
+}
+
+// 显示和当前聊天对象的安全码：双方看到的数字一致，说明没有人在中间冒充
+async function showSafetyNumber() {
+	const peer = window.currentChatPeer;
+	if (!peer) return;
+
+	try {
+		const info = await apiGetSafetyNumber(peer.id);
+		if (!info.safety_number) {
+			alert(`还没有 ${peer.name} 的身份密钥，互相发送一条消息后再查看安全码`);
+			return;
+		}
+		const number = formatSafetyNumber(info.safety_number);
+
+		if (info.key_changed) {
+			if (confirm(`⚠ ${peer.name} 的身份密钥变化了！可能有人在冒充对方。\n\n新的安全码：\n${number}\n\n请当面和对方核对，只有一致时才信任新的密钥。确认信任吗？`)) {
+				await apiAcceptPeerKeyChange(peer.id);
+				alert('已信任新的身份密钥');
+			}
+			return;
+		}
+
+		const status = info.verified ? '✓ 已验证' : '未验证';
+		const question = info.verified ? '取消“已验证”标记吗？' : '和对方当面核对一致后，标记为已验证吗？';
+		if (confirm(`和 ${peer.name} 的安全码（${status}）：\n\n${number}\n\n${question}`)) {
+			await apiSetPeerVerified(peer.id, !info.verified);
+		}
+	} catch (e) {
+		console.error('[UI] 查看安全码失败:', e);
+		alert(e.message || e);
+	}
+}
+
 // 添加消息到聊天窗口
 function addMessageToChat(message, isSent) {
 	const chatMessages = document.getElementById('chat-messages');
@@ -757,6 +819,70 @@ function createMessageElement(message, isSent) {
 			statusDiv.className = 'file-uploading';
 			const speed = message.transfer_speed ? Math.round(message.transfer_speed) + ' MB/s' : '上传中...';
 			statusDiv.textContent = speed;
+		} else if (fileStatus === 'interrupted') {
+			// 上传中断：桌面端点击从断点继续
+			statusDiv.className = 'file-interrupted';
+			statusDiv.textContent = '已中断';
+			if (window.__TAURI__ && message.message_id) {
+				statusDiv.textContent = '已中断，点击继续';
+				statusDiv.style.cursor = 'pointer';
+				statusDiv.addEventListener('click', async () => {
+					if (statusDiv.className === 'file-uploading') {
+						return;
+					}
+					statusDiv.className = 'file-uploading';
+					statusDiv.textContent = '上传中...';
+					try {
+						await apiResumeFileTransfer(message.message_id);
+						statusDiv.className = '';
+						statusDiv.textContent = '已发送';
+					} catch (e) {
+						statusDiv.className = 'file-interrupted';
+						statusDiv.textContent = '已中断，点击继续';
+						alert(e.message);
+					}
+				});
+			}
+		} else if (fileStatus === 'pending' && !isSent) {
+			// 对方发来的文件需要确认：点击接收或拒绝
+			statusDiv.className = 'file-pending';
+			statusDiv.textContent = '等待接收：';
+			const actions = [
+				['接收', () => apiAcceptFile(message.message_id)],
+				['拒绝', () => apiRejectFile(message.message_id)],
+			];
+			actions.forEach(([label, action], i) => {
+				const link = document.createElement('span');
+				link.textContent = label;
+				link.style.cursor = 'pointer';
+				link.style.textDecoration = 'underline';
+				link.style.marginLeft = i === 0 ? '0' : '8px';
+				link.addEventListener('click', async () => {
+					try {
+						await action();
+						if (window.currentChatPeer) {
+							loadChatHistory(window.currentChatPeer.id, true);
+						}
+					} catch (e) {
+						alert(e.message);
+					}
+				});
+				statusDiv.appendChild(link);
+			});
+		} else if (fileStatus === 'waiting') {
+			statusDiv.className = 'file-pending';
+			statusDiv.textContent = '等待对方接收';
+		} else if (fileStatus === 'rejected') {
+			statusDiv.className = 'file-interrupted';
+			statusDiv.textContent = '已拒绝';
+		} else if (fileStatus === 'corrupted') {
+			// 收到的文件与发送方的校验值不一致
+			statusDiv.className = 'file-interrupted';
+			statusDiv.textContent = '校验失败';
+		} else if (fileStatus === 'imported') {
+			// 导入的聊天记录中没有完成的传输
+			statusDiv.className = 'file-interrupted';
+			statusDiv.textContent = '导入的记录';
 		} else if (fileStatus === 'accepted' && !isSent) {
 			statusDiv.className = 'file-finish';
 			statusDiv.textContent = 'finish';
@@ -813,9 +939,78 @@ function createMessageElement(message, isSent) {
 	messageDiv.appendChild(contentDiv);
 	messageDiv.appendChild(timeDiv);
 
+	// 表情回应（旧消息没有 message_id 时不支持）
+	if (message.message_id) {
+		messageDiv.dataset.messageId = message.message_id;
+		if (message.reactions && message.reactions.length > 0) {
+			messageDiv.appendChild(createReactionBar(message));
+		}
+		messageDiv.addEventListener('contextmenu', (e) => {
+			e.preventDefault();
+			showReactionPicker(message, e.clientX, e.clientY);
+		});
+	}
+
 	return messageDiv;
 }
 
+const REACTION_EMOJIS = ['👍', '❤️', '😂', '😮', '😢', '🎉'];
+
+// 创建表情回应栏
+function createReactionBar(message) {
+	const bar = document.createElement('div');
+	bar.className = 'message-reactions';
+
+	for (const reaction of message.reactions) {
+		const chip = document.createElement('span');
+		const mine = window.myId && reaction.reactors.includes(window.myId);
+		chip.className = mine ? 'reaction-chip mine' : 'reaction-chip';
+		chip.textContent = `${reaction.emoji} ${reaction.count}`;
+		chip.title = '点击切换';
+		chip.addEventListener('click', () => toggleReaction(message, reaction.emoji, mine));
+		bar.appendChild(chip);
+	}
+
+	return bar;
+}
+
+// 显示表情选择器
+function showReactionPicker(message, x, y) {
+	document.querySelectorAll('.reaction-picker').forEach(el => el.remove());
+
+	const picker = document.createElement('div');
+	picker.className = 'reaction-picker';
+	picker.style.left = x + 'px';
+	picker.style.top = y + 'px';
+
+	for (const emoji of REACTION_EMOJIS) {
+		const btn = document.createElement('span');
+		btn.textContent = emoji;
+		btn.addEventListener('click', () => {
+			picker.remove();
+			toggleReaction(message, emoji, false);
+		});
+		picker.appendChild(btn);
+	}
+
+	document.body.appendChild(picker);
+	setTimeout(() => document.addEventListener('click', () => picker.remove(), { once: true }), 0);
+}
+
+// 添加或取消表情回应，然后刷新聊天记录
+async function toggleReaction(message, emoji, remove) {
+	if (!window.currentChatPeer) return;
+	try {
+		if (!window.myId) {
+			window.myId = await apiGetMyId();
+		}
+		await apiReactToMessage(window.currentChatPeer.id, window.currentChatPeer.addr, message.message_id, emoji, remove);
+		await loadChatHistory(window.currentChatPeer.id, true);
+	} catch (e) {
+		alert(e.message);
+	}
+}
+
 // 接收到新消息
 function onReceiveMessage(message) {
 	console.log('[UI] ========== onReceiveMessage 被调用 ==========');
@@ -1073,9 +1268,6 @@ async function sendFile(file) {
 		console.log('[UI] 目标地址:', window.currentChatPeer.addr);
 
 		// 立即显示发送中的消息
-		const tempFileId = 'temp_' + Date.now();
-		const timestamp = Math.floor(Date.now() / 1000);
-
 		console.log('[UI] 1. 在前端显示上传中消息');
 		addMessageToChat({
 			msg_type: 'file',
@@ -1083,59 +1275,24 @@ async function sendFile(file) {
 			content: file.name,
 			file_name: file.name,
 			file_size: file.size,
-			file_id: tempFileId,
+			file_id: 'temp_' + Date.now(),
 			file_status: 'uploading',  // 上传中状态
-			timestamp: timestamp
+			timestamp: Math.floor(Date.now() / 1000)
 		}, true);
 
 		try {
-			// 先在本地数据库创建上传记录
-			console.log('[UI] 2. 调用 /api/create_upload_record');
-			const createResp = await fetch('/api/create_upload_record', {
-				method: 'POST',
-				headers: { 'Content-Type': 'application/json' },
-				body: JSON.stringify({
-					file_name: file.name,
-					timestamp: timestamp,
-					receiver_id: window.currentChatPeer.id  // 添加接收者ID
-				})
-			});
-
-			if (!createResp.ok) {
-				throw new Error('创建上传记录失败: ' + createResp.status);
-			}
-
-			console.log('[UI] ✓ 上传记录已创建');
-
-			console.log('[UI] 3. 开始上传文件到对方');
-			const result = await apiSendFile(
+			// 本机节点保存发送记录，提议并上传，完成后返回
+			console.log('[UI] 2. 把文件交给本机节点发送');
+			await apiSendFile(
 				window.currentChatPeer.id,
 				window.currentChatPeer.addr,
-				file
+				file,
+				null
 			);
-
-			console.log('[UI] ✓ 文件上传成功');
-
-			// 上传成功，更新本地数据库状态为 'sent'
-			console.log('[UI] 4. 更新上传状态为 sent');
-			const updateResp = await fetch('/api/update_upload_status', {
-				method: 'POST',
-				headers: { 'Content-Type': 'application/json' },
-				body: JSON.stringify({
-					file_name: file.name,
-					timestamp: timestamp,
-					status: 'sent'
-				})
-			});
-
-			if (!updateResp.ok) {
-				console.warn('[UI] ⚠ 更新上传状态失败:', updateResp.status);
-			} else {
-				console.log('[UI] ✓ 上传状态已更新');
-			}
+			console.log('[UI] ✓ 文件发送成功');
 
 			// 刷新聊天历史以显示正确的状态
-			console.log('[UI] 5. 刷新聊天历史');
+			console.log('[UI] 3. 刷新聊天历史');
 			if (window.currentChatPeer) {
 				await loadChatHistory(window.currentChatPeer.id);
 			}
@@ -1147,17 +1304,7 @@ async function sendFile(file) {
 		} catch (e) {
 			console.error('[UI] ✗ 文件发送失败:', e);
 			alert('文件发送失败: ' + e.message);
-			// 删除失败的上传记录
-			console.log('[UI] 删除失败的上传记录');
-			await fetch('/api/delete_upload_record', {
-				method: 'POST',
-				headers: { 'Content-Type': 'application/json' },
-				body: JSON.stringify({
-					file_name: file.name,
-					timestamp: timestamp
-				})
-			});
-			// 刷新聊天历史以移除失败的消息
+			// 刷新聊天历史以显示失败的状态
 			if (window.currentChatPeer) {
 				await loadChatHistory(window.currentChatPeer.id);
 			}
@@ -1276,6 +1423,15 @@ function initSettings() {
 	const downloadPathInput = document.getElementById('download-path-input');
 	const settingsErrorMsg = document.getElementById('settings-error-msg');
 	const settingsSuccessMsg = document.getElementById('settings-success-msg');
+	const retentionDaysInput = document.getElementById('retention-days-input');
+	const retentionMaxInput = document.getElementById('retention-max-input');
+	const retentionFilesInput = document.getElementById('retention-files-input');
+	const webPasswordInput = document.getElementById('web-password-input');
+	const webPasswordClear = document.getElementById('web-password-clear');
+	const peerKeyInput = document.getElementById('peer-key-input');
+	const peerKeyClear = document.getElementById('peer-key-clear');
+	const allowPlaintextInput = document.getElementById('allow-plaintext-input');
+	const tlsFingerprint = document.getElementById('tls-fingerprint');
 
 	// 打开/关闭设置面板 - 切换显示/隐藏
 	settingsBtn.addEventListener('click', async () => {
@@ -1290,6 +1446,20 @@ function initSettings() {
 			try {
 				const settings = await apiGetSettings();
 				downloadPathInput.value = settings.download_path;
+				const policy = await apiGetRetentionPolicy();
+				retentionDaysInput.value = policy.max_age_days;
+				retentionMaxInput.value = policy.max_messages_per_conversation;
+				retentionFilesInput.checked = policy.delete_files;
+				const auth = await apiGetAuthStatus();
+				webPasswordInput.value = '';
+				webPasswordInput.placeholder = auth.password_enabled ? '已设置' : '未设置';
+				webPasswordClear.checked = false;
+				peerKeyInput.value = '';
+				peerKeyInput.placeholder = auth.peer_key_enabled ? '已设置' : '未设置';
+				peerKeyClear.checked = false;
+				const tls = await apiGetTlsStatus();
+				allowPlaintextInput.checked = tls.allow_plaintext;
+				tlsFingerprint.textContent = tls.fingerprint;
 				settingsPanel.style.display = 'block';
 				settingsErrorMsg.textContent = '';
 				settingsSuccessMsg.textContent = '';
@@ -1379,6 +1549,33 @@ function initSettings() {
 				downloadPathInput.value
 			);
 
+			await apiUpdateRetentionPolicy({
+				max_age_days: parseInt(retentionDaysInput.value, 10) || 0,
+				max_messages_per_conversation: parseInt(retentionMaxInput.value, 10) || 0,
+				delete_files: retentionFilesInput.checked
+			});
+
+			// 修改或取消访问密码时需要先输入当前密码
+			if (webPasswordInput.value || webPasswordClear.checked) {
+				const auth = await apiGetAuthStatus();
+				let currentPassword = '';
+				if (auth.password_enabled) {
+					currentPassword = prompt('请输入当前访问密码:');
+					if (currentPassword === null) {
+						return;
+					}
+				}
+				await apiSetWebPassword(currentPassword, webPasswordClear.checked ? '' : webPasswordInput.value);
+				webPasswordInput.value = '';
+			}
+
+			await apiSetAllowPlaintext(allowPlaintextInput.checked);
+
+			if (peerKeyInput.value || peerKeyClear.checked) {
+				await apiSetPeerKey(peerKeyClear.checked ? '' : peerKeyInput.value);
+				peerKeyInput.value = '';
+			}
+
 			// 显示成功消息
 			settingsSuccessMsg.textContent = '✓ 设置保存成功';
 			settingsSuccessMsg.classList.add('show');